@@ -9,50 +9,227 @@ use commands::{
     SelectorTeam, Target, Team, players};
 use commands::Command::*;
 use fab;
-use hw::{Computer, MemoryRegion};
-use std::boxed::FnBox;
+use hw::{self, Computer, MemoryRegion, REGISTER_FILE};
+use lexer::Location;
 use nbt::*;
+use ordered_map::OrderedMap;
 use types::{self, Block, Extent, Interval, REL_ZERO};
 
-use std::collections::{HashMap, VecDeque};
-use std::fmt;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::mem;
 use std::{i32, u32};
 
 use self::AssembledItem::*;
 
-pub type PendingFn = Box<FnBox(Extent) -> Block>;
+static LDR_CONT_PREFIX: &'static str = "@ldr_cont_";
+static STR_CONT_PREFIX: &'static str = "@str_cont_";
+static BR_CONT_PREFIX: &'static str = "@br_cont_";
+// The layout makes these to continue a line that it had to break.
+pub static LINE_CONT_PREFIX: &'static str = "@line_cont_";
+
+// Continuation labels are generated by the assembler to pick execution back
+// up a tick later, after a memory access or a branch-with-link, and by the
+// layout where it breaks a line.  They belong to whichever source label came
+// before them.
+pub fn is_continuation_label(label: &str) -> bool {
+    label.starts_with(LDR_CONT_PREFIX) ||
+        label.starts_with(STR_CONT_PREFIX) ||
+        label.starts_with(BR_CONT_PREFIX) ||
+        label.starts_with(LINE_CONT_PREFIX)
+}
+
+// Every generated label is a prefix and a number, and starts with @, which
+// can't start a label in source.  That alone keeps them apart from the
+// program's labels.  The assembler, the layout and the macro expander number
+// their labels separately, so they keep apart from each other by their
+// prefixes: the expander's have a dot in them, and the others' don't.
+pub fn unique_label(prefix: &str, n: u32) -> String {
+    format!("{}{}", prefix, n)
+}
 
 // REVIEW: AssembledItem is now used by fab, so maybe it should be renamed and
 // put somewhere more general.  Seems like fab should not be use'ing assembler.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum AssembledItem {
     Label(String),
     Complete(Block),
-    Pending(String, PendingFn),
+    Pending(String, PendingBlock),
     Terminal,
 
 }
 
-impl fmt::Debug for AssembledItem {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            Label(ref label) => write!(formatter, "Label({:?})", label),
-            Complete(ref block) => write!(formatter, "Complete({:?}", block),
-            Pending(ref name, _) => write!(formatter, "Pending({:?}, ...)", name),
-            Terminal => formatter.write_str("Terminal"),
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum PendingKind {
+    // Fills the label's power extent with the block: redstone to run the
+    // label, or obsidian to turn it back off.
+    Fill(String),
+    // Sets the register to the label's address, for an indirect branch to
+    // return to.  Only relocatable code has these, as the linker numbers the
+    // labels.
+    Address(String),
+}
+
+// A command block that refers to a label, so it can't be made until the
+// label's power extent, or its address, is known.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct PendingBlock {
+    pub kind: PendingKind,
+    // The computer, with the conditions the command runs under in its
+    // scores, if there are any.
+    pub cond: Option<Target>,
+    pub track_output: bool,
+    pub loc: Option<Location>,
+    // The command block's CustomName, which its output is logged under.
+    pub name: Option<String>,
+}
+
+impl PendingBlock {
+    pub fn fill(fill: &str, cond: Option<Target>, track_output: bool) -> PendingBlock {
+        PendingBlock {
+            kind: PendingKind::Fill(fill.to_string()),
+            cond: cond,
+            track_output: track_output,
+            loc: None,
+            name: None,
+        }
+    }
+
+    fn finish(self, cmd: Command) -> Block {
+        let cmd = match self.cond {
+            Some(cond) => Execute(cond, types::REL_ZERO, Box::new(cmd)),
+            None => cmd,
+        };
+        let mut block = fab::cmd_block(cmd, self.track_output);
+        block.loc = self.loc;
+        if let Some(name) = self.name {
+            block.nbt.insert("CustomName".to_string(), Nbt::String(name));
+        }
+        block
+    }
+
+    pub fn resolve(self, extent: Extent) -> Block {
+        let fill = match self.kind {
+            PendingKind::Fill(ref fill) => fill.clone(),
+            PendingKind::Address(_) => panic!("label addresses are assigned by the linker"),
+        };
+        match extent {
+            Extent::Empty => {
+                panic!("oh no!");
+            }
+            Extent::MinMax(min, max) => {
+                self.finish(Fill(min.as_abs(), max.as_abs(), fill, None, None, None))
+            }
         }
     }
+
+    pub fn resolve_address(self, addr: i32) -> Block {
+        let reg = match self.kind {
+            PendingKind::Address(ref reg) => reg.clone(),
+            PendingKind::Fill(_) => panic!("a fill needs the label's extent, not its address"),
+        };
+        let target = computer_selector().into_target();
+        self.finish(players::set(target, reg, addr, None))
+    }
+}
+
+// Which parts of the program need the memory controller, or the bitwise
+// entities.  Code that falls through from one label to the next is credited
+// to the later label.
+#[derive(Clone, Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct Usage {
+    pub memory: bool,
+    pub bitwise: bool,
+    pub memory_labels: BTreeSet<String>,
+    pub bitwise_labels: BTreeSet<String>,
+    // The most scratch registers any one instruction needed.
+    pub temps: i32,
+}
+
+// The entity that holds the registers.
+fn computer_selector() -> Selector {
+    Selector {
+        name: Some(SelectorName::Is("computer".to_string())),
+        ..Selector::entity()
+    }
+}
+
+// The code an indirect branch runs: it powers whichever label has the address
+// in IndAddr.
+pub fn indirect_jump_table(mut label_addrs: Vec<(String, i32)>, track_output: bool)
+    -> Vec<AssembledItem>
+{
+    let label = "@jump_indirect".to_string();
+    let mut items = vec!(Label(label.clone()), fab::power_off(label, track_output));
+
+    let ind_addr_reg = Register::Spec("IndAddr".to_string());
+    label_addrs.sort_by(|a, b| a.1.cmp(&b.1));
+    for (label, addr) in label_addrs.into_iter() {
+        let conds = vec![Cond::eq(ind_addr_reg.clone(), addr)];
+        let cond = cond_target(computer_selector(), conds);
+        items.push(Pending(label, PendingBlock::fill(
+            "minecraft:redstone_block", cond, track_output)));
+    }
+
+    items.push(Terminal);
+    items
+}
+
+// Hands out the scratch registers, 0t0 and up, that an expansion needs.  An
+// expansion marks the pool, takes what it needs as it goes, and gives back
+// everything taken since the mark when it is done, so expansions can nest.
+// The helpers an expansion calls write into the registers it hands them.
+pub struct TempPool {
+    held: i32,
+    most: i32,
+}
+
+impl TempPool {
+    pub fn new() -> TempPool {
+        TempPool { held: 0, most: 0 }
+    }
+
+    pub fn take(&mut self) -> Objective {
+        let obj = hw::temp(self.held);
+        self.held += 1;
+        self.most = cmp::max(self.most, self.held);
+        obj
+    }
+
+    pub fn mark(&self) -> i32 {
+        self.held
+    }
+
+    pub fn release(&mut self, mark: i32) {
+        assert!(mark <= self.held, "released scratch registers that were not taken");
+        self.held = mark;
+    }
+
+    // Whether the scratch register has been taken and not given back.
+    pub fn is_held(&self, obj: &str) -> bool {
+        (0..self.held).any(|n| hw::temp(n) == obj)
+    }
+
+    // The most scratch registers held at once.
+    pub fn most(&self) -> i32 {
+        self.most
+    }
 }
 
 pub struct Assembler<'c, Source : Iterator<Item=Statement>> {
     computer: &'c Computer,
     input: Source,
     track_output: bool,
+    relocatable: bool,
     buffer: VecDeque<AssembledItem>,
     target: Target,
     selector: Selector,
-    uses_memory: bool,
-    uses_bitwise: bool,
+    usage: Usage,
+    cur_label: Option<String>,
+    locations: VecDeque<Location>,
+    cur_loc: Option<Location>,
+    announcements: VecDeque<Option<String>>,
+    cur_announcement: Option<String>,
     done: bool,
     unique: u32,
     pending_labels: Vec<String>,
@@ -63,9 +240,7 @@ pub struct Assembler<'c, Source : Iterator<Item=Statement>> {
     tgt_bit_one: Target,
     obj_bit_comp: Objective,
     obj_bit_num: Objective,
-    obj_tmp0: Objective,
-    obj_tmp1: Objective,
-    obj_tmp2: Objective,
+    temps: TempPool,
     obj_two: Objective,
     obj_min: Objective,
     obj_mem_op: Objective,
@@ -76,22 +251,23 @@ pub struct Assembler<'c, Source : Iterator<Item=Statement>> {
 
 impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     pub fn new(computer: &'c Computer, assembly: S) -> Assembler<'c, S> {
-        let entity_name = "computer".to_string();
-        let selector = Selector {
-            name: Some(SelectorName::Is(entity_name)),
-            ..Selector::entity()
-        };
+        let selector = computer_selector();
         let target = Target::Sel(selector.clone());
         let team_bit = "Shifters";
         Assembler {
             computer: computer,
             input: assembly,
             track_output: false,
+            relocatable: false,
             buffer: VecDeque::new(),
             target: target,
             selector: selector,
-            uses_memory: false,
-            uses_bitwise: false,
+            usage: Default::default(),
+            cur_label: None,
+            locations: VecDeque::new(),
+            cur_loc: None,
+            announcements: VecDeque::new(),
+            cur_announcement: None,
             done: false,
             unique: 0,
             pending_labels: vec!(),
@@ -107,9 +283,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
                 ..Selector::entity() }),
             obj_bit_comp: "BitComponent".to_string(),
             obj_bit_num: "BitNumber".to_string(),
-            obj_tmp0: "t0".to_string(),
-            obj_tmp1: "t1".to_string(),
-            obj_tmp2: "t2".to_string(),
+            temps: TempPool::new(),
             obj_two: "TWO".to_string(),
             obj_min: "MIN".to_string(),
             obj_mem_op: "MemOp".to_string(),
@@ -123,13 +297,62 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         self.track_output = value;
     }
 
-    pub fn uses_memory(&self) -> bool {
-        self.uses_memory
+    // Relocatable code is linked with other code before it is laid out.  Its
+    // branches with link leave the return address to the linker, which also
+    // makes the indirect jump table for the whole program.
+    pub fn set_relocatable(&mut self, value: bool) {
+        self.relocatable = value;
+    }
+
+    // Gives the source location of each statement, in the same order as the
+    // statements.  Command blocks are stamped with the location of the
+    // statement they were assembled from.
+    pub fn set_locations(&mut self, locations: Vec<Location>) {
+        self.locations = locations.into_iter().collect();
+    }
+
+    // Names the first command block of each statement, so that its output is
+    // logged under that name.  Like the locations, there is one entry per
+    // statement.
+    pub fn set_announcements(&mut self, announcements: Vec<Option<String>>) {
+        self.announcements = announcements.into_iter().collect();
+    }
+
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    // The address each label that code returns to was given, for relocatable
+    // code.  The rest is assembled into the indirect jump table.
+    pub fn label_addrs(&self) -> Vec<(String, i32)> {
+        let mut addrs: Vec<(String, i32)> =
+            self.label_addr_map.iter().map(|(l, a)| (l.clone(), *a)).collect();
+        addrs.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        addrs
+    }
+
+    fn use_memory(&mut self) {
+        self.usage.memory = true;
+        if let Some(ref label) = self.cur_label {
+            self.usage.memory_labels.insert(label.clone());
+        }
+    }
+
+    fn use_bitwise(&mut self) {
+        self.usage.bitwise = true;
+        if let Some(ref label) = self.cur_label {
+            self.usage.bitwise_labels.insert(label.clone());
+        }
     }
 
     fn assemble(&mut self, stmt: Statement) {
+        self.cur_loc = self.locations.pop_front();
+        self.cur_announcement = self.announcements.pop_front().and_then(|a| a);
         match stmt {
-            LabelStmt(label) => { self.emit(Label(label)); }
+            LabelStmt(label) => {
+                self.cur_label = Some(label.clone());
+                self.emit(Label(label));
+            }
             Instr(conds, op) => { self.assemble_instr(conds, op); }
         }
     }
@@ -137,6 +360,16 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     fn assemble_instr(&mut self, conds: Vec<Cond>, op: Op) {
         use commands::PlayerOp as PlOp;
 
+        // The lowerings count on the constant registers never changing.
+        for reg in op.written_regs().into_iter() {
+            if !REGISTER_FILE.is_writable(reg) {
+                let at = self.cur_loc.map_or(String::new(), |loc| format!("{}: ", loc));
+                panic!("{}cannot write to the constant register {}", at, reg);
+            }
+        }
+
+        let mark = self.temps.mark();
+
         match op {
             LdrRR(dst, src) => self.emit_ldr_rr(conds, dst, src),
             StrRR(src, dst) => self.emit_str_rr(conds, src, dst),
@@ -156,6 +389,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             LslRR(dst, src) => self.emit_lsl_rr(&conds, &dst, &src),
             MovRR(dst, src) => self.emit_rr(&conds, &dst, PlOp::Asn, &src),
             MovRI(dst, imm) => self.emit_rset(&conds, &dst, imm),
+            AdrRL(dst, label) => self.emit_label_addr(&conds, dst, &label),
             MovRX(dst, tgt, obj) =>
                 self.emit_rx(&conds, &dst, PlOp::Asn, &tgt, &obj),
             MovXR(tgt, obj, src, success) =>
@@ -181,6 +415,22 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             }
             _ => panic!("not implemented: {:?}", op)
         }
+
+        assert!(self.temps.mark() == mark, "a lowering kept scratch registers it took");
+    }
+
+    // Takes a scratch register until the expansion that took it releases it.
+    fn take_temp(&mut self) -> Objective {
+        let obj = self.temps.take();
+        self.usage.temps = cmp::max(self.usage.temps, self.temps.most());
+        obj
+    }
+
+    // Checks that a helper was handed a scratch register that is still taken,
+    // so that nothing else can be using it.
+    fn check_held(&self, obj: &str) {
+        assert!(self.temps.is_held(obj), "scratch register {} was used after it was released",
+                obj);
     }
 
     fn get_label_addr(&mut self, label: &str) -> i32 {
@@ -238,14 +488,53 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
                     }
 
                     self.buffer.push_back(
-                        fab::power_off(first_label, self.track_output));
+                        self.stamp(fab::power_off(first_label, self.track_output)));
                 }
 
+                let item = self.stamp(item);
+                let item = self.announce(item);
                 self.buffer.push_back(item);
             }
         };
     }
 
+    fn stamp(&self, item: AssembledItem) -> AssembledItem {
+        let loc = match self.cur_loc {
+            Some(loc) => loc,
+            None => return item,
+        };
+        match item {
+            Complete(mut block) => {
+                block.loc = Some(loc);
+                Complete(block)
+            }
+            Pending(label, mut pending) => {
+                pending.loc = Some(loc);
+                Pending(label, pending)
+            }
+            _ => item,
+        }
+    }
+
+    // Gives the statement's announcement to the first block it emits.
+    fn announce(&mut self, item: AssembledItem) -> AssembledItem {
+        match item {
+            Complete(mut block) => {
+                if let Some(name) = self.cur_announcement.take() {
+                    block.nbt.insert("CustomName".to_string(), Nbt::String(name));
+                }
+                Complete(block)
+            }
+            Pending(label, mut pending) => {
+                if pending.name.is_none() {
+                    pending.name = self.cur_announcement.take();
+                }
+                Pending(label, pending)
+            }
+            _ => item,
+        }
+    }
+
     fn gen_unique_int(&mut self) -> u32 {
         let value = self.unique;
         self.unique += 1;
@@ -253,7 +542,8 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     }
 
     fn gen_unique_label(&mut self, prefix: &str) -> String {
-        format!("{}{}", prefix, self.gen_unique_int())
+        let n = self.gen_unique_int();
+        unique_label(prefix, n)
     }
 
     fn make_op_cmd_rr(
@@ -347,24 +637,9 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     }
 
     fn emit_power_label(&mut self, conds: Vec<Cond>, label: String) {
-        let selector = self.selector.clone();
-        let track_output = self.track_output;
-        self.emit(Pending(label, Box::new(move |extent| {
-            match extent {
-                Extent::Empty => {
-                    panic!("oh no!");
-                }
-                Extent::MinMax(min, max) => {
-                    make_cmd_block(
-                        selector, conds,
-                        Fill(
-                            min.as_abs(), max.as_abs(),
-                            "minecraft:redstone_block".to_string(),
-                            None, None, None),
-                        track_output)
-                }
-            }
-        })));
+        let cond = cond_target(self.selector.clone(), conds);
+        self.emit(Pending(label, PendingBlock::fill(
+            "minecraft:redstone_block", cond, self.track_output)));
     }
 
     // REVIEW: This can be a free function, or maybe attached to MemoryRegion as
@@ -407,14 +682,15 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         let tag_obj = self.obj_mem_tag.clone();
         Target::Sel(Selector {
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(tag_obj, Interval::Bounded(id as i32, id as i32));
                 s },
             ..Selector::entity() })
     }
 
     fn emit_ldr_rr(&mut self, conds: Vec<Cond>, dst: Register, src: Register) {
-        self.uses_memory = true;
+        let mark = self.temps.mark();
+        self.use_memory();
 
         let ldr_id = self.gen_unique_int();
         self.emit_mem_tag(&conds, &src, ldr_id);
@@ -430,7 +706,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // mov tagged, MemAddr, src
         // FIXME: Pass t0 for the success register to ignore the success count.
         // It would be nice to eventually handle the aux outs more generically.
-        let t0 = Register::Spec(self.obj_tmp0.clone());
+        let t0 = Register::Spec(self.take_temp());
         // FIXME: Awkward cloning.
         let obj_mem_addr = self.obj_mem_addr.clone();
         self.emit_xr(&conds, &tagged, &obj_mem_addr, PlayerOp::Asn, &src, &t0);
@@ -440,7 +716,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // REVIEW: It would be nice to coalesce terminals here.  ldr just needs
         // a one tick delay to allow the memory controller time to produce the
         // value.
-        let cont_label = self.gen_unique_label("ldr_cont_");
+        let cont_label = self.gen_unique_label(LDR_CONT_PREFIX);
         self.emit_power_label(conds.clone(), cont_label.clone());
         self.emit(Terminal);
         self.emit(Label(cont_label));
@@ -449,10 +725,12 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // FIXME: Awkward cloning.
         let obj_mem_data = self.obj_mem_data.clone();
         self.emit_rx(&conds, &dst, PlayerOp::Asn, &tagged, &obj_mem_data);
+        self.temps.release(mark);
     }
 
     fn emit_str_rr(&mut self, conds: Vec<Cond>, src: Register, dst: Register) {
-        self.uses_memory = true;
+        let mark = self.temps.mark();
+        self.use_memory();
 
         let str_id = self.gen_unique_int();
         self.emit_mem_tag(&conds, &dst, str_id);
@@ -466,15 +744,12 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // mov tagged, MemAddr, dst
         // FIXME: Pass t0 for the success register to ignore the success count.
         // It would be nice to eventually handle the aux outs more generically.
-        let t0 = Register::Spec(self.obj_tmp0.clone());
+        let t0 = Register::Spec(self.take_temp());
         // FIXME: Awkward cloning.
         let obj_mem_addr = self.obj_mem_addr.clone();
         self.emit_xr(&conds, &tagged, &obj_mem_addr, PlayerOp::Asn, &dst, &t0);
 
         // mov tagged, MemData, src
-        // FIXME: Pass t0 for the success register to ignore the success count.
-        // It would be nice to eventually handle the aux outs more generically.
-        let t0 = Register::Spec(self.obj_tmp0.clone());
         // FIXME: Awkward cloning.
         let obj_mem_data = self.obj_mem_data.clone();
         self.emit_xr(&conds, &tagged, &obj_mem_data, PlayerOp::Asn, &src, &t0);
@@ -484,43 +759,49 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // REVIEW: It would be nice to coalesce terminals here.  str just needs
         // a one tick delay to allow the memory controller time to produce the
         // value.
-        let cont_label = self.gen_unique_label("str_cont_");
+        let cont_label = self.gen_unique_label(STR_CONT_PREFIX);
         self.emit_power_label(conds.clone(), cont_label.clone());
         self.emit(Terminal);
         self.emit(Label(cont_label));
+        self.temps.release(mark);
     }
 
     fn emit_and_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        let t0_obj = self.obj_tmp0.clone();
-        let t1_obj = self.obj_tmp1.clone();
+        let t0_obj = self.take_temp();
+        let t1_obj = self.take_temp();
 
         self.expand_bits(conds.clone(), dst.clone(), t0_obj.clone());
         self.expand_bits(conds.clone(), src.clone(), t1_obj.clone());
         // 'and' the bits together.
         self.bit_vec_op(conds.clone(), t0_obj.clone(), PlayerOp::Mul, t1_obj);
         self.accum_bits(conds.clone(), dst.clone(), t0_obj);
+        self.temps.release(mark);
     }
 
     fn emit_orr_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        let t0_obj = self.obj_tmp0.clone();
-        let t1_obj = self.obj_tmp1.clone();
+        let t0_obj = self.take_temp();
+        let t1_obj = self.take_temp();
 
         self.expand_bits(conds.clone(), dst.clone(), t0_obj.clone());
         self.expand_bits(conds.clone(), src.clone(), t1_obj.clone());
         // 'orr' the bits together.
         self.bit_vec_op(conds.clone(), t0_obj.clone(), PlayerOp::Max, t1_obj);
         self.accum_bits(conds.clone(), dst.clone(), t0_obj);
+        self.temps.release(mark);
     }
 
     fn emit_eor_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        let t0_obj = self.obj_tmp0.clone();
-        let t1_obj = self.obj_tmp1.clone();
+        let t0_obj = self.take_temp();
+        let t1_obj = self.take_temp();
 
         self.expand_bits(conds.clone(), dst.clone(), t0_obj.clone());
         self.expand_bits(conds.clone(), src.clone(), t1_obj.clone());
@@ -533,14 +814,16 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             self.track_output);
         self.emit(Complete(block));
         self.accum_bits(conds.clone(), dst.clone(), t0_obj);
+        self.temps.release(mark);
     }
 
     fn emit_asr_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        self.raw_shift_right(conds.clone(), dst.clone(), src.clone());
+        let t0_obj = self.take_temp();
+        self.raw_shift_right(conds.clone(), dst.clone(), src.clone(), t0_obj.clone());
 
-        let t0_obj = self.obj_tmp0.clone();
         let lt_zero_conds = {
             let mut c = conds.clone();
             c.push(Cond::lt(dst.clone(), 0));
@@ -550,7 +833,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         let sign_bits_tgt = Target::Sel(Selector {
             team: Some(SelectorTeam::On(self.team_bit.clone())),
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(t0_obj, Interval::Min(-1));
                 s },
             ..Selector::entity()
@@ -567,14 +850,16 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
 
         // copy computer t0 to dst
         self.emit_rr(&conds, dst, PlayerOp::Asn, &t0);
+        self.temps.release(mark);
     }
 
     fn emit_lsr_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        self.raw_shift_right(conds.clone(), dst.clone(), src.clone());
+        let tmp0 = self.take_temp();
+        self.raw_shift_right(conds.clone(), dst.clone(), src.clone(), tmp0.clone());
 
-        let tmp0 = self.obj_tmp0.clone();
         let mut lt_zero_conds = conds.clone();
         lt_zero_conds.push(Cond::lt(dst.clone(), 0));
         let t0 = Register::Spec(tmp0.clone());
@@ -583,7 +868,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             team: Some(SelectorTeam::On(self.team_bit.clone())),
             count: Some(1),
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(tmp0, Interval::Bounded(-1, -1));
                 s },
             ..Selector::entity()
@@ -598,20 +883,22 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
 
         // copy computer t0 to dst
         self.emit_rr(&conds, dst, PlayerOp::Asn, &t0);
+        self.temps.release(mark);
     }
 
     fn emit_lsl_rr(&mut self, conds: &Vec<Cond>, dst: &Register, src: &Register) {
-        self.uses_bitwise = true;
+        let mark = self.temps.mark();
+        self.use_bitwise();
 
-        self.activate_bitwise_entities(conds.clone(), src.clone());
+        let tmp0 = self.take_temp();
+        self.activate_bitwise_entities(conds.clone(), src.clone(), tmp0.clone());
 
-        let tmp0 = self.obj_tmp0.clone();
         let two_reg = Register::Spec(self.obj_two.clone());
 
         let active_bit_tgt = Target::Sel(Selector {
             team: Some(SelectorTeam::On(self.team_bit.clone())),
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(tmp0, Interval::Min(0));
                 s },
             ..Selector::entity()
@@ -624,14 +911,16 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
                     dst.clone(), PlayerOp::Mul, two_reg))),
             self.track_output);
         self.emit(Complete(block));
+        self.temps.release(mark);
     }
 
     fn emit_srng(
         &mut self, conds: Vec<Cond>, dst: Register, test: Register,
         min: Option<i32>, max: Option<i32>)
     {
-        let t0 = Register::Spec(self.obj_tmp0.clone());
+        let mark = self.temps.mark();
         let safe_test = if dst == test {
+            let t0 = Register::Spec(self.take_temp());
             self.emit_rr(&conds, &t0, PlayerOp::Asn, &test);
             t0
         } else {
@@ -649,12 +938,14 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
 
         self.emit_rset(&conds, &dst, 0);
         self.emit_rset(&one_conds, &dst, 1);
+        self.temps.release(mark);
     }
 
     fn emit_urng(
         &mut self, conds: Vec<Cond>, dst: Register, test: Register,
         min: Option<u32>, max: Option<u32>)
     {
+        let mark = self.temps.mark();
         let min = min.unwrap_or(u32::MIN);
         let max = max.unwrap_or(u32::MAX);
 
@@ -675,8 +966,8 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         } else {
             // All other ranges (except invalid ones where min > max, which are
             // undefined behavior) require two signed ranges.
-            let t0 = Register::Spec(self.obj_tmp0.clone());
             let safe_test = if dst == test {
+                let t0 = Register::Spec(self.take_temp());
                 self.emit_rr(&conds, &t0, PlayerOp::Asn, &dst);
                 t0
             } else {
@@ -697,6 +988,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             self.emit_rset(&a_conds, &dst, 1);
             self.emit_rset(&b_conds, &dst, 1);
         }
+        self.temps.release(mark);
     }
 
     fn emit_br_l(&mut self, conds: Vec<Cond>, label: String) {
@@ -717,14 +1009,15 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
 
     // REVIEW: Can emit_br_label and emit_br_reg share more code?
     fn emit_br_label(&mut self, conds: Vec<Cond>, label: String, link: bool) {
-        let t0 = Register::Spec(self.obj_tmp0.clone());
+        let mark = self.temps.mark();
+        let t0 = Register::Spec(self.take_temp());
         self.emit_rset(&vec!(), &t0, 0);
         self.emit_rset(&conds, &t0, 1);
 
         let true_conds = vec!(Cond::eq(t0.clone(), 1));
         let false_conds = vec!(Cond::eq(t0, 0));
 
-        let cont_label = self.gen_unique_label("br_cont_");
+        let cont_label = self.gen_unique_label(BR_CONT_PREFIX);
         if link {
             self.emit_branch_link(&true_conds, &cont_label);
         }
@@ -733,17 +1026,19 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         self.emit_power_label(false_conds, cont_label.clone());
         self.emit(Terminal);
         self.emit(Label(cont_label));
+        self.temps.release(mark);
     }
 
     fn emit_br_reg(&mut self, conds: Vec<Cond>, reg: Register, link: bool) {
-        let t0 = Register::Spec(self.obj_tmp0.clone());
+        let mark = self.temps.mark();
+        let t0 = Register::Spec(self.take_temp());
         self.emit_rset(&vec!(), &t0, 0);
         self.emit_rset(&conds, &t0, 1);
 
         let true_conds = vec!(Cond::eq(t0.clone(), 1));
         let false_conds = vec!(Cond::eq(t0, 0));
 
-        let cont_label = self.gen_unique_label("br_cont_");
+        let cont_label = self.gen_unique_label(BR_CONT_PREFIX);
         if link {
             self.emit_branch_link(&true_conds, &cont_label);
         }
@@ -756,15 +1051,36 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         self.emit_power_label(false_conds, cont_label.clone());
         self.emit(Terminal);
         self.emit(Label(cont_label));
+        self.temps.release(mark);
     }
 
     fn emit_branch_link(&mut self, conds: &Vec<Cond>, label: &String) {
-        let lr = Register::Spec("lr".to_string());
-        let addr = self.get_label_addr(&label[..]);
-        self.emit_rset(&conds, &lr, addr);
+        // Return addresses are numbered here even in relocatable code, so
+        // that the ones at the same place share an address.
+        self.get_label_addr(&label[..]);
+        self.emit_label_addr(conds, Register::Spec("lr".to_string()), label);
+    }
+
+    // Sets the register to the label's address.  In relocatable code, the
+    // label may be in another object, so the linker sets it.
+    fn emit_label_addr(&mut self, conds: &Vec<Cond>, reg: Register, label: &String) {
+        if self.relocatable {
+            let cond = cond_target(self.selector.clone(), conds.clone());
+            self.emit(Pending(label.clone(), PendingBlock {
+                kind: PendingKind::Address(reg_name(reg)),
+                cond: cond,
+                track_output: self.track_output,
+                loc: None,
+                name: None,
+            }));
+        } else {
+            let addr = self.get_label_addr(&label[..]);
+            self.emit_rset(&conds, &reg, addr);
+        }
     }
 
     fn expand_bits(&mut self, conds: Vec<Cond>, reg: Register, bit_obj: Objective) {
+        self.check_held(&bit_obj);
         let tgt_all = self.tgt_bit_all.clone();
         let lt_zero_conds = {
             let mut c = conds.clone();
@@ -809,7 +1125,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         let tgt_high = Target::Sel(Selector {
             team: Some(SelectorTeam::On(self.team_bit.clone())),
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(self.obj_bit_num.clone(), Interval::Bounded(31, 31));
                 s },
             ..Selector::entity()
@@ -822,6 +1138,7 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     }
 
     fn accum_bits(&mut self, conds: Vec<Cond>, dst: Register, bit_obj: String) {
+        self.check_held(&bit_obj);
         // Multiply all bit entities' bit_obj by their bit component.
         // Like this: [1, 0, 1, 1] * [8, 4, 2, 1] = [8, 0, 2, 1]
         let bit_comp = self.obj_bit_comp.clone();
@@ -855,9 +1172,11 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         self.emit(Complete(block));
     }
 
-    fn activate_bitwise_entities(&mut self, conds: Vec<Cond>, amount: Register) {
+    fn activate_bitwise_entities(
+        &mut self, conds: Vec<Cond>, amount: Register, tmp0: Objective)
+    {
+        self.check_held(&tmp0);
         let bit_num = self.obj_bit_num.clone();
-        let tmp0 = self.obj_tmp0.clone();
 
         // SIMD copy bitwise entities' BitNumber to tmp0
         self.bit_vec_op(conds.clone(), tmp0.clone(), PlayerOp::Asn, bit_num);
@@ -878,8 +1197,12 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         self.emit(Complete(block));
     }
 
-    fn raw_shift_right(&mut self, conds: Vec<Cond>, dst: Register, src: Register) {
-        let tmp0 = self.obj_tmp0.clone();
+    // Shifts dst right by src into tmp0, on the computer.  The active bitwise
+    // entities are left with tmp0 at zero or more.
+    fn raw_shift_right(
+        &mut self, conds: Vec<Cond>, dst: Register, src: Register, tmp0: Objective)
+    {
+        self.check_held(&tmp0);
         let mut lt_zero_conds = conds.clone();
         lt_zero_conds.push(Cond::lt(dst.clone(), 0));
 
@@ -893,12 +1216,12 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
         // if dst < 0, t0 -= i32::MIN
         self.emit_rr(&lt_zero_conds, &t0, PlayerOp::Sub, &min_reg);
 
-        self.activate_bitwise_entities(conds.clone(), src);
+        self.activate_bitwise_entities(conds.clone(), src, tmp0.clone());
 
         let active_bit_tgt = Target::Sel(Selector {
             team: Some(SelectorTeam::On(self.team_bit.clone())),
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(tmp0, Interval::Min(0));
                 s },
             ..Selector::entity()
@@ -914,9 +1237,10 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     }
 
     fn emit_udiv(&mut self, conds: Vec<Cond>, dst: Register, src: Register) {
-        let t0 = Register::Spec(self.obj_tmp0.clone());
-        let t1 = Register::Spec(self.obj_tmp1.clone());
-        let t2 = Register::Spec(self.obj_tmp2.clone());
+        let mark = self.temps.mark();
+        let t0 = Register::Spec(self.take_temp());
+        let t1 = Register::Spec(self.take_temp());
+        let t2 = Register::Spec(self.take_temp());
         let min_reg = Register::Spec(self.obj_min.clone());
         let two_reg = Register::Spec(self.obj_two.clone());
 
@@ -977,11 +1301,13 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             c.push(Cond::lt(t0.clone(), 0));
             c.push(Cond::ge(t1.clone(), 0));
             c }, &dst, 1);
+        self.temps.release(mark);
     }
 
     fn emit_urem(&mut self, conds: Vec<Cond>, dst: Register, src: Register) {
-        let t0 = Register::Spec(self.obj_tmp0.clone());
-        let t1 = Register::Spec(self.obj_tmp1.clone());
+        let mark = self.temps.mark();
+        let t0 = Register::Spec(self.take_temp());
+        let t1 = Register::Spec(self.take_temp());
         let min_reg = Register::Spec(self.obj_min.clone());
         let two_reg = Register::Spec(self.obj_two.clone());
 
@@ -1039,22 +1365,12 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
             c.push(Cond::ge(dst.clone(), 0));
             c }, &dst, PlayerOp::Add, &src);
         self.emit_rr(&neg_pos_conds, &dst, PlayerOp::Sub, &src);
+        self.temps.release(mark);
     }
 
     fn emit_indirect_jump_table(&mut self) {
-        self.emit(Label("@jump_indirect".to_string()));
-
-        let ind_addr_reg = Register::Spec("IndAddr".to_string());
-        let mut label_addr_map = HashMap::new();
-        mem::swap(&mut label_addr_map, &mut self.label_addr_map);
-        let mut label_addrs: Vec<_> = label_addr_map.into_iter().collect();
-        label_addrs.sort_by(|a, b| a.1.cmp(&b.1));
-        for (label, addr) in label_addrs.into_iter() {
-            let conds = vec![Cond::eq(ind_addr_reg.clone(), addr)];
-            self.emit_power_label(conds, label);
-        }
-
-        self.emit(Terminal);
+        let label_addrs = self.label_addrs();
+        self.buffer.extend(indirect_jump_table(label_addrs, self.track_output).into_iter());
     }
 
     fn add_success_count(&self, block: &mut Block, reg: Register) {
@@ -1069,17 +1385,26 @@ impl<'c, S : Iterator<Item=Statement>> Assembler<'c, S> {
     }
 }
 
+// The selector with the conditions in its scores, for a command to execute
+// as, if there are any conditions.
+fn cond_target(selector: Selector, conds: Vec<Cond>) -> Option<Target> {
+    if conds.is_empty() {
+        return None;
+    }
+    let mut sel = selector;
+    for cond in conds.into_iter() {
+        sel.scores.insert(reg_name(cond.reg), cond.interval);
+    }
+    Some(sel.into_target())
+}
+
 fn make_cmd_block(
     selector: Selector, conds: Vec<Cond>, cmd: Command, track_output: bool)
     -> Block
 {
-    let cmd = if conds.is_empty() { cmd } else {
-        let mut sel = selector;
-        for cond in conds.into_iter() {
-            sel.scores.insert(reg_name(cond.reg), cond.interval);
-        }
-
-        Execute(sel.into_target(), types::REL_ZERO, Box::new(cmd))
+    let cmd = match cond_target(selector, conds) {
+        Some(target) => Execute(target, types::REL_ZERO, Box::new(cmd)),
+        None => cmd,
     };
     fab::cmd_block(cmd, track_output)
 }
@@ -1111,8 +1436,12 @@ impl<'c, S : Iterator<Item=Statement>> Iterator for Assembler<'c, S> {
             if let Some(stmt) = self.input.next() {
                 self.assemble(stmt);
             } else if !self.done {
+                self.cur_loc = None;
+                self.cur_announcement = None;
                 self.emit(Terminal);
-                self.emit_indirect_jump_table();
+                if !self.relocatable {
+                    self.emit_indirect_jump_table();
+                }
                 self.done = true;
             } else {
                 break;
@@ -1121,3 +1450,60 @@ impl<'c, S : Iterator<Item=Statement>> Iterator for Assembler<'c, S> {
         self.buffer.pop_front()
     }
 }
+
+// Assembles ops after a label on their own thread, so that a panic can be
+// checked for, and gives back what they used.
+#[cfg(test)]
+fn assemble_ops(ops: Vec<Op>) -> ::std::thread::Result<Usage> {
+    use std::thread;
+    use types::Vec3;
+
+    thread::spawn(move || {
+        let computer = Computer::new(Vec3::new(0, 0, 0));
+        let mut stmts = vec!(LabelStmt("main".to_string()));
+        stmts.extend(ops.into_iter().map(|op| Instr(vec!(), op)));
+        let mut assembler = Assembler::new(&computer, stmts.into_iter());
+        assembler.by_ref().count();
+        assembler.usage().clone()
+    }).join()
+}
+
+#[test]
+fn test_constant_regs_are_read_only() {
+    let assemble = |op: Op| assemble_ops(vec!(op));
+
+    assert!(assemble(MovRR(Register::Gen(0), Register::Spec("TWO".to_string()))).is_ok());
+    assert!(assemble(MovRI(Register::Spec("ZERO".to_string()), 1)).is_err());
+    assert!(assemble(AddRR(Register::Spec("MIN".to_string()), Register::Gen(0))).is_err());
+}
+
+#[test]
+fn test_temp_pool() {
+    let mut pool = TempPool::new();
+    assert_eq!("0t0", &pool.take()[..]);
+    let mark = pool.mark();
+    assert_eq!("0t1", &pool.take()[..]);
+    assert_eq!("0t2", &pool.take()[..]);
+    pool.release(mark);
+    assert!(pool.is_held("0t0") && !pool.is_held("0t1"));
+    assert_eq!("0t1", &pool.take()[..]);
+    assert_eq!(3, pool.most());
+}
+
+#[test]
+fn test_expansion_temps() {
+    let temps = |ops: Vec<Op>| assemble_ops(ops).map(|usage| usage.temps);
+
+    // Each instruction gives its scratch registers back.
+    let (r0, r1) = (Register::Gen(0), Register::Gen(1));
+    assert_eq!(0, temps(vec!(MovRR(r0.clone(), r1.clone()))).unwrap());
+    assert_eq!(3, temps(vec!(
+        UdivRR(r0.clone(), r1.clone()),
+        UremRR(r0.clone(), r1.clone()),
+        UdivRR(r0.clone(), r1.clone()))).unwrap());
+
+    // A nested expansion gives back what it took along with the one around it.
+    assert_eq!(1, temps(vec!(
+        Urng(r0.clone(), r0.clone(), Some(0), Some(5)),
+        Urng(r0.clone(), r0.clone(), Some(5), Some(u32::MAX - 5)))).unwrap());
+}
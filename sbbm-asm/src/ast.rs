@@ -2,8 +2,12 @@
 // Distributed under the GNU GPL v3. See COPYING for details.
 
 use commands::{Target, Objective};
+use lexer::{Lexer, quote};
+use lexer::Token::{Ident, Selector, Eof};
 use types::Interval;
 
+use std::fmt;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Statement {
     LabelStmt(String),
@@ -103,6 +107,9 @@ pub enum Op {
     MovXI(Target, Objective, i32, Register),
     MovXX(Target, Objective, Target, Objective, Register),
 
+    // Moves a label's address into a register, for an indirect branch.
+    AdrRL(Register, String),
+
     MulRR(Register, Register),
     MulRX(Register, Target, Objective, Register),
     MulXR(Target, Objective, Register, Register),
@@ -136,6 +143,97 @@ pub enum Op {
     RawCmd(Vec<(CommandBlockOut, Register)>, String),
 }
 
+impl Op {
+    // The registers named in the op that it writes to.  Branches with link
+    // also write lr, which is not named.
+    pub fn written_regs(&self) -> Vec<&Register> {
+        use self::Op::*;
+        match *self {
+            LdrRR(ref dst, _) | LdrhRR(ref dst, _) | LdrhsRR(ref dst, _) |
+            LdrbRR(ref dst, _) | LdrbsRR(ref dst, _) |
+            LdrRL(ref dst, _) | LdrhRL(ref dst, _) | LdrhsRL(ref dst, _) |
+            LdrbRL(ref dst, _) | LdrbsRL(ref dst, _) |
+            Pop(ref dst) |
+            AddRR(ref dst, _) | AddRI(ref dst, _) | SubRR(ref dst, _) | SubRI(ref dst, _) |
+            AndRR(ref dst, _) | OrrRR(ref dst, _) | EorRR(ref dst, _) |
+            AsrRR(ref dst, _) | AsrRI(ref dst, _) | LslRR(ref dst, _) | LslRI(ref dst, _) |
+            LsrRR(ref dst, _) | LsrRI(ref dst, _) |
+            MovRR(ref dst, _) | MovRI(ref dst, _) | MovRX(ref dst, _, _) |
+            AdrRL(ref dst, _) |
+            MulRR(ref dst, _) | SdivRR(ref dst, _) | UdivRR(ref dst, _) |
+            SremRR(ref dst, _) | UremRR(ref dst, _) |
+            Srng(ref dst, _, _, _) | Urng(ref dst, _, _, _) => vec!(dst),
+
+            AddRX(ref dst, _, _, ref success) | SubRX(ref dst, _, _, ref success) |
+            MulRX(ref dst, _, _, ref success) | SdivRX(ref dst, _, _, ref success) |
+            UdivRX(ref dst, _, _, ref success) | SremRX(ref dst, _, _, ref success) |
+            UremRX(ref dst, _, _, ref success) => vec!(dst, success),
+
+            AddXI(_, _, _, ref success) | AddXR(_, _, _, ref success) |
+            AddXX(_, _, _, _, ref success) |
+            SubXI(_, _, _, ref success) | SubXR(_, _, _, ref success) |
+            SubXX(_, _, _, _, ref success) |
+            MovXR(_, _, _, ref success) | MovXI(_, _, _, ref success) |
+            MovXX(_, _, _, _, ref success) |
+            MulXR(_, _, _, ref success) | SdivXR(_, _, _, ref success) |
+            UdivXR(_, _, _, ref success) | SremXR(_, _, _, ref success) |
+            UremXR(_, _, _, ref success) => vec!(success),
+
+            RawCmd(ref outs, _) => outs.iter().map(|o| &o.1).collect(),
+
+            StrRR(_, _) | StrhRR(_, _) | StrbRR(_, _) |
+            StrRL(_, _) | StrhRL(_, _) | StrbRL(_, _) |
+            Push(_) |
+            BrR(_) | BrL(_) | BrLnkR(_) | BrLnkL(_) | BrInd(_) | BRLnkInd(_) |
+            Halt => vec!(),
+        }
+    }
+
+    // The registers named in the op that it reads from.
+    pub fn read_regs(&self) -> Vec<&Register> {
+        use self::Op::*;
+        match *self {
+            LdrRR(_, ref src) | LdrhRR(_, ref src) | LdrhsRR(_, ref src) |
+            LdrbRR(_, ref src) | LdrbsRR(_, ref src) |
+            MovRR(_, ref src) |
+            Srng(_, ref src, _, _) | Urng(_, ref src, _, _) |
+            StrRL(ref src, _) | StrhRL(ref src, _) | StrbRL(ref src, _) |
+            Push(ref src) => vec!(src),
+
+            StrRR(ref src, ref dst) | StrhRR(ref src, ref dst) | StrbRR(ref src, ref dst) =>
+                vec!(src, dst),
+
+            AddRR(ref dst, ref src) | SubRR(ref dst, ref src) |
+            AndRR(ref dst, ref src) | OrrRR(ref dst, ref src) | EorRR(ref dst, ref src) |
+            AsrRR(ref dst, ref src) | LslRR(ref dst, ref src) | LsrRR(ref dst, ref src) |
+            MulRR(ref dst, ref src) | SdivRR(ref dst, ref src) | UdivRR(ref dst, ref src) |
+            SremRR(ref dst, ref src) | UremRR(ref dst, ref src) => vec!(dst, src),
+
+            AddRI(ref dst, _) | SubRI(ref dst, _) |
+            AsrRI(ref dst, _) | LslRI(ref dst, _) | LsrRI(ref dst, _) |
+            AddRX(ref dst, _, _, _) | SubRX(ref dst, _, _, _) | MulRX(ref dst, _, _, _) |
+            SdivRX(ref dst, _, _, _) | UdivRX(ref dst, _, _, _) |
+            SremRX(ref dst, _, _, _) | UremRX(ref dst, _, _, _) => vec!(dst),
+
+            AddXR(_, _, ref src, _) | SubXR(_, _, ref src, _) | MovXR(_, _, ref src, _) |
+            MulXR(_, _, ref src, _) | SdivXR(_, _, ref src, _) | UdivXR(_, _, ref src, _) |
+            SremXR(_, _, ref src, _) | UremXR(_, _, ref src, _) => vec!(src),
+
+            BrR(ref reg) | BrLnkR(ref reg) | BrInd(ref reg) | BRLnkInd(ref reg) => vec!(reg),
+
+            LdrRL(_, _) | LdrhRL(_, _) | LdrhsRL(_, _) | LdrbRL(_, _) | LdrbsRL(_, _) |
+            Pop(_) |
+            AddXI(_, _, _, _) | AddXX(_, _, _, _, _) |
+            SubXI(_, _, _, _) | SubXX(_, _, _, _, _) |
+            MovRI(_, _) | MovRX(_, _, _) | MovXI(_, _, _, _) | MovXX(_, _, _, _, _) |
+            AdrRL(_, _) |
+            BrL(_) | BrLnkL(_) |
+            RawCmd(_, _) |
+            Halt => vec!(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum CommandBlockOut {
     SuccessCount,
@@ -146,6 +244,18 @@ pub enum CommandBlockOut {
 }
 
 impl CommandBlockOut {
+    // The letter that names it after raw~.
+    pub fn modifier(&self) -> char {
+        use self::CommandBlockOut::*;
+        match *self {
+            SuccessCount => 's',
+            AffectedBlocks => 'b',
+            AffectedEntities => 'e',
+            AffectedItems => 'i',
+            QueryResult => 'q',
+        }
+    }
+
     pub fn selector(&self) -> &str {
         use self::CommandBlockOut::*;
         match *self {
@@ -175,3 +285,211 @@ pub enum Register {
     Pred(i32),
     Spec(String),
 }
+
+// Statements display as the assembly the parser reads them from, so that
+// tools that transform programs can write them back out.  Ops that the parser
+// has no syntax for yet are written the way it would most likely read them.
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Register::Gen(num) => write!(f, "r{}", num),
+            Register::Pred(num) => write!(f, "p{}", num),
+            Register::Spec(ref name) => f.write_str(&name[..]),
+        }
+    }
+}
+
+// An immediate, or * where a range is open.
+struct Bound<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Bound<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.0 {
+            Some(ref value) => write!(f, "#{}", value),
+            None => f.write_str("*"),
+        }
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let (min, max) = match self.interval {
+            Interval::Min(min) => (Some(min), None),
+            Interval::Max(max) => (None, Some(max)),
+            Interval::Bounded(min, max) => (Some(min), Some(max)),
+        };
+        write!(f, "{{{}, {}, {}}}", self.reg, Bound(min), Bound(max))
+    }
+}
+
+// A target or objective, which is written bare when it lexes back as a single
+// identifier (or selector, for targets), and quoted otherwise.
+struct Name<'a>(&'a str, bool);
+
+impl<'a> fmt::Display for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let Name(name, selector_ok) = *self;
+        let mut lexer = Lexer::mem(name);
+        let bare = match (lexer.next().map(|t| t.item), lexer.next().map(|t| t.item)) {
+            (Ok(Ident(_)), Ok(Eof)) => true,
+            (Ok(Selector(_)), Ok(Eof)) => selector_ok,
+            _ => false,
+        };
+        if bare {
+            f.write_str(name)
+        } else {
+            f.write_str(&quote(name)[..])
+        }
+    }
+}
+
+fn target(t: &Target) -> String {
+    Name(&t.to_string()[..], true).to_string()
+}
+
+fn obj(o: &Objective) -> Name {
+    Name(&o[..], false)
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        use self::Op::*;
+
+        match *self {
+            LdrRR(ref dst, ref src) => write!(f, "ldr {}, [{}]", dst, src),
+            LdrhRR(ref dst, ref src) => write!(f, "ldrh {}, [{}]", dst, src),
+            LdrhsRR(ref dst, ref src) => write!(f, "ldrhs {}, [{}]", dst, src),
+            LdrbRR(ref dst, ref src) => write!(f, "ldrb {}, [{}]", dst, src),
+            LdrbsRR(ref dst, ref src) => write!(f, "ldrbs {}, [{}]", dst, src),
+
+            LdrRL(ref dst, ref label) => write!(f, "ldr {}, ={}", dst, label),
+            LdrhRL(ref dst, ref label) => write!(f, "ldrh {}, ={}", dst, label),
+            LdrhsRL(ref dst, ref label) => write!(f, "ldrhs {}, ={}", dst, label),
+            LdrbRL(ref dst, ref label) => write!(f, "ldrb {}, ={}", dst, label),
+            LdrbsRL(ref dst, ref label) => write!(f, "ldrbs {}, ={}", dst, label),
+
+            StrRR(ref src, ref dst) => write!(f, "str {}, [{}]", src, dst),
+            StrhRR(ref src, ref dst) => write!(f, "strh {}, [{}]", src, dst),
+            StrbRR(ref src, ref dst) => write!(f, "strb {}, [{}]", src, dst),
+
+            StrRL(ref src, ref label) => write!(f, "str {}, ={}", src, label),
+            StrhRL(ref src, ref label) => write!(f, "strh {}, ={}", src, label),
+            StrbRL(ref src, ref label) => write!(f, "strb {}, ={}", src, label),
+
+            Push(ref reg) => write!(f, "push {}", reg),
+            Pop(ref reg) => write!(f, "pop {}", reg),
+
+            AddRR(ref dst, ref src) => write!(f, "add {}, {}", dst, src),
+            AddRI(ref dst, imm) => write!(f, "add {}, #{}", dst, imm),
+            AddRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "add {}, {}, {}, {}", dst, target(t), obj(o), out),
+            AddXI(ref t, ref o, imm, ref out) =>
+                write!(f, "add {}, {}, #{}, {}", target(t), obj(o), imm, out),
+            AddXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "add {}, {}, {}, {}", target(t), obj(o), src, out),
+            AddXX(ref t, ref o, ref st, ref so, ref out) =>
+                write!(f, "add {}, {}, {}, {}, {}", target(t), obj(o), target(st), obj(so), out),
+
+            SubRR(ref dst, ref src) => write!(f, "sub {}, {}", dst, src),
+            SubRI(ref dst, imm) => write!(f, "sub {}, #{}", dst, imm),
+            SubRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "sub {}, {}, {}, {}", dst, target(t), obj(o), out),
+            SubXI(ref t, ref o, imm, ref out) =>
+                write!(f, "sub {}, {}, #{}, {}", target(t), obj(o), imm, out),
+            SubXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "sub {}, {}, {}, {}", target(t), obj(o), src, out),
+            SubXX(ref t, ref o, ref st, ref so, ref out) =>
+                write!(f, "sub {}, {}, {}, {}, {}", target(t), obj(o), target(st), obj(so), out),
+
+            AndRR(ref dst, ref src) => write!(f, "and {}, {}", dst, src),
+            OrrRR(ref dst, ref src) => write!(f, "orr {}, {}", dst, src),
+            EorRR(ref dst, ref src) => write!(f, "eor {}, {}", dst, src),
+
+            AsrRR(ref dst, ref src) => write!(f, "asr {}, {}", dst, src),
+            AsrRI(ref dst, imm) => write!(f, "asr {}, #{}", dst, imm),
+            LslRR(ref dst, ref src) => write!(f, "lsl {}, {}", dst, src),
+            LslRI(ref dst, imm) => write!(f, "lsl {}, #{}", dst, imm),
+            LsrRR(ref dst, ref src) => write!(f, "lsr {}, {}", dst, src),
+            LsrRI(ref dst, imm) => write!(f, "lsr {}, #{}", dst, imm),
+
+            MovRR(ref dst, ref src) => write!(f, "mov {}, {}", dst, src),
+            MovRI(ref dst, imm) => write!(f, "mov {}, #{}", dst, imm),
+            MovRX(ref dst, ref t, ref o) => write!(f, "mov {}, {}, {}", dst, target(t), obj(o)),
+            MovXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "mov {}, {}, {}, {}", target(t), obj(o), src, out),
+            MovXI(ref t, ref o, imm, ref out) =>
+                write!(f, "mov {}, {}, #{}, {}", target(t), obj(o), imm, out),
+            MovXX(ref t, ref o, ref st, ref so, ref out) =>
+                write!(f, "mov {}, {}, {}, {}, {}", target(t), obj(o), target(st), obj(so), out),
+
+            AdrRL(ref dst, ref label) => write!(f, "mov {}, #={}", dst, label),
+
+            MulRR(ref dst, ref src) => write!(f, "mul {}, {}", dst, src),
+            MulRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "mul {}, {}, {}, {}", dst, target(t), obj(o), out),
+            MulXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "mul {}, {}, {}, {}", target(t), obj(o), src, out),
+
+            SdivRR(ref dst, ref src) => write!(f, "sdiv {}, {}", dst, src),
+            SdivRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "sdiv {}, {}, {}, {}", dst, target(t), obj(o), out),
+            SdivXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "sdiv {}, {}, {}, {}", target(t), obj(o), src, out),
+            UdivRR(ref dst, ref src) => write!(f, "udiv {}, {}", dst, src),
+            UdivRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "udiv {}, {}, {}, {}", dst, target(t), obj(o), out),
+            UdivXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "udiv {}, {}, {}, {}", target(t), obj(o), src, out),
+
+            SremRR(ref dst, ref src) => write!(f, "srem {}, {}", dst, src),
+            SremRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "srem {}, {}, {}, {}", dst, target(t), obj(o), out),
+            SremXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "srem {}, {}, {}, {}", target(t), obj(o), src, out),
+            UremRR(ref dst, ref src) => write!(f, "urem {}, {}", dst, src),
+            UremRX(ref dst, ref t, ref o, ref out) =>
+                write!(f, "urem {}, {}, {}, {}", dst, target(t), obj(o), out),
+            UremXR(ref t, ref o, ref src, ref out) =>
+                write!(f, "urem {}, {}, {}, {}", target(t), obj(o), src, out),
+
+            Srng(ref dst, ref src, min, max) =>
+                write!(f, "srng {}, {}, {}, {}", dst, src, Bound(min), Bound(max)),
+            Urng(ref dst, ref src, min, max) =>
+                write!(f, "urng {}, {}, {}, {}", dst, src, Bound(min), Bound(max)),
+
+            BrR(ref reg) => write!(f, "b {}", reg),
+            BrL(ref label) => write!(f, "b ={}", label),
+            BrLnkR(ref reg) => write!(f, "bl {}", reg),
+            BrLnkL(ref label) => write!(f, "bl ={}", label),
+            BrInd(ref reg) => write!(f, "b [{}]", reg),
+            BRLnkInd(ref reg) => write!(f, "bl [{}]", reg),
+
+            Halt => f.write_str("halt"),
+
+            RawCmd(ref outs, ref cmd) => {
+                if outs.is_empty() {
+                    write!(f, "raw {}", cmd)
+                } else {
+                    let chars: String = outs.iter().map(|o| o.0.modifier()).collect();
+                    let regs: Vec<String> = outs.iter().map(|o| o.1.to_string()).collect();
+                    write!(f, "raw~{} {}, {}", chars, regs.connect(", "), cmd)
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Statement::LabelStmt(ref label) => write!(f, "{}:", label),
+            Statement::Instr(ref conds, ref op) => {
+                for cond in conds.iter() {
+                    try!(write!(f, "{} ", cond));
+                }
+                op.fmt(f)
+            }
+        }
+    }
+}
@@ -0,0 +1,432 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use ast::Op;
+use ast::Op::*;
+use ast::Statement;
+use ast::Statement::*;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    // Control continues on to the next block without a branch.
+    Fallthrough,
+    // A direct branch to a label.
+    Jump,
+    // A direct branch-with-link to a label.
+    Call,
+    // An indirect branch.  Indirect branches can only reach addresses that
+    // were handed out by a branch-with-link or moved into a register, so they
+    // are connected to every return site and every label whose address is
+    // taken.
+    Indirect,
+}
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    pub labels: Vec<String>,
+    // The range of statements (labels included) that make up the block.
+    pub start: usize,
+    pub end: usize,
+    pub succs: Vec<(usize, EdgeKind)>,
+}
+
+impl BasicBlock {
+    fn new(start: usize) -> BasicBlock {
+        BasicBlock {
+            labels: vec!(),
+            start: start,
+            end: start,
+            succs: vec!(),
+        }
+    }
+
+    pub fn name(&self, index: usize) -> String {
+        match self.labels.first() {
+            Some(label) => label.clone(),
+            None => format!("<block {}>", index),
+        }
+    }
+}
+
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    label_blocks: HashMap<String, usize>,
+    referenced: HashSet<String>,
+    data_refs: HashSet<String>,
+}
+
+// Where control goes after a block's last statement.
+enum Exit {
+    Fallthrough,
+    Branch(Option<String>, EdgeKind, bool),
+    Halt,
+}
+
+fn exit_of(stmt: &Statement) -> Exit {
+    match *stmt {
+        Instr(ref conds, ref op) => {
+            let cond = !conds.is_empty();
+            match *op {
+                BrL(ref label) =>
+                    Exit::Branch(Some(label.clone()), EdgeKind::Jump, cond),
+                BrLnkL(ref label) =>
+                    Exit::Branch(Some(label.clone()), EdgeKind::Call, true),
+                BrR(_) | BrInd(_) =>
+                    Exit::Branch(None, EdgeKind::Indirect, cond),
+                BrLnkR(_) | BRLnkInd(_) =>
+                    Exit::Branch(None, EdgeKind::Indirect, true),
+                // halt always ends the line, even when predicated.
+                Halt => Exit::Halt,
+                _ => Exit::Fallthrough,
+            }
+        }
+        LabelStmt(_) => Exit::Fallthrough,
+    }
+}
+
+fn data_ref(op: &Op) -> Option<&String> {
+    match *op {
+        LdrRL(_, ref label) | LdrhRL(_, ref label) | LdrhsRL(_, ref label) |
+        LdrbRL(_, ref label) | LdrbsRL(_, ref label) |
+        StrRL(_, ref label) | StrhRL(_, ref label) | StrbRL(_, ref label) =>
+            Some(label),
+        _ => None,
+    }
+}
+
+fn is_link(stmt: &Statement) -> bool {
+    match *stmt {
+        Instr(_, BrLnkL(_)) | Instr(_, BrLnkR(_)) | Instr(_, BRLnkInd(_)) => true,
+        _ => false,
+    }
+}
+
+impl Cfg {
+    pub fn new(stmts: &[Statement]) -> Cfg {
+        let mut blocks = vec!();
+        let mut cur = BasicBlock::new(0);
+        let mut has_instrs = false;
+
+        for (i, stmt) in stmts.iter().enumerate() {
+            match *stmt {
+                LabelStmt(ref label) => {
+                    if has_instrs {
+                        let next = BasicBlock::new(i);
+                        blocks.push(cur);
+                        cur = next;
+                        has_instrs = false;
+                    }
+                    cur.labels.push(label.clone());
+                    cur.end = i + 1;
+                }
+                Instr(_, _) => {
+                    has_instrs = true;
+                    cur.end = i + 1;
+                    if let Exit::Fallthrough = exit_of(stmt) { continue; }
+                    let next = BasicBlock::new(i + 1);
+                    blocks.push(cur);
+                    cur = next;
+                    has_instrs = false;
+                }
+            }
+        }
+        if cur.end > cur.start {
+            blocks.push(cur);
+        }
+
+        let mut label_blocks = HashMap::new();
+        for (i, block) in blocks.iter().enumerate() {
+            for label in block.labels.iter() {
+                label_blocks.insert(label.clone(), i);
+            }
+        }
+
+        let mut cfg = Cfg {
+            blocks: blocks,
+            label_blocks: label_blocks,
+            referenced: HashSet::new(),
+            data_refs: HashSet::new(),
+        };
+        cfg.connect(stmts);
+        cfg
+    }
+
+    fn connect(&mut self, stmts: &[Statement]) {
+        let mut addr_taken = vec!();
+        for stmt in stmts.iter() {
+            if let Instr(_, ref op) = *stmt {
+                if let Some(label) = data_ref(op) {
+                    self.referenced.insert(label.clone());
+                    self.data_refs.insert(label.clone());
+                }
+                if let AdrRL(_, ref label) = *op {
+                    self.referenced.insert(label.clone());
+                    if let Some(&block) = self.label_blocks.get(label) {
+                        addr_taken.push(block);
+                    }
+                }
+            }
+        }
+
+        // Blocks that follow a branch-with-link are where indirect branches
+        // return to, along with the labels whose addresses are taken.
+        let mut return_sites: Vec<usize> = (1..self.blocks.len())
+            .filter(|&i| {
+                let prev = &self.blocks[i - 1];
+                prev.end > prev.start && is_link(&stmts[prev.end - 1])
+            })
+            .collect();
+        return_sites.extend(addr_taken.into_iter());
+        return_sites.sort();
+        return_sites.dedup();
+
+        for i in 0..self.blocks.len() {
+            let mut succs = vec!();
+            let has_next = i + 1 < self.blocks.len();
+            let exit = if self.blocks[i].end > self.blocks[i].start {
+                exit_of(&stmts[self.blocks[i].end - 1])
+            } else {
+                Exit::Fallthrough
+            };
+
+            match exit {
+                Exit::Fallthrough => {
+                    if has_next { succs.push((i + 1, EdgeKind::Fallthrough)); }
+                }
+                Exit::Branch(target, kind, falls_through) => {
+                    match target {
+                        Some(label) => {
+                            if let Some(&block) = self.label_blocks.get(&label) {
+                                succs.push((block, kind));
+                            }
+                            self.referenced.insert(label);
+                        }
+                        None => {
+                            for &site in return_sites.iter() {
+                                succs.push((site, kind));
+                            }
+                        }
+                    }
+                    if falls_through && has_next {
+                        succs.push((i + 1, EdgeKind::Fallthrough));
+                    }
+                }
+                Exit::Halt => { }
+            }
+            self.blocks[i].succs = succs;
+        }
+    }
+
+    pub fn block_of(&self, label: &str) -> Option<usize> {
+        self.label_blocks.get(label).map(|b| *b)
+    }
+
+    pub fn reachable(&self, entry: &str) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut queue = VecDeque::new();
+
+        // Labels whose address is taken by a load or store are kept alive,
+        // because there is no telling where they are used from.
+        let roots = Some(entry.to_string()).into_iter()
+            .chain(self.data_refs.iter().cloned());
+        for root in roots {
+            if let Some(block) = self.block_of(&root[..]) {
+                queue.push_back(block);
+            }
+        }
+
+        while let Some(block) = queue.pop_front() {
+            if seen[block] { continue; }
+            seen[block] = true;
+            for &(succ, _) in self.blocks[block].succs.iter() {
+                queue.push_back(succ);
+            }
+        }
+        seen
+    }
+
+    pub fn unreachable_blocks(&self, entry: &str) -> Vec<usize> {
+        self.reachable(entry).into_iter()
+            .enumerate()
+            .filter(|&(_, live)| !live)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn unreferenced_labels(&self, entry: &str) -> Vec<String> {
+        let mut labels = vec!();
+        for block in self.blocks.iter() {
+            for label in block.labels.iter() {
+                if label != entry && !self.referenced.contains(label) {
+                    labels.push(label.clone());
+                }
+            }
+        }
+        labels
+    }
+
+    // Returns, for every statement the graph was built from, whether it
+    // belongs to a block that is reachable from entry.
+    pub fn live_statements(&self, entry: &str) -> Vec<bool> {
+        let reachable = self.reachable(entry);
+        let len = self.blocks.last().map(|b| b.end).unwrap_or(0);
+        let mut live = vec![false; len];
+        for (block, &is_live) in self.blocks.iter().zip(reachable.iter()) {
+            for i in block.start..block.end {
+                live[i] = is_live;
+            }
+        }
+        live
+    }
+
+    pub fn write_dot(&self, w: &mut Write, entry: &str) -> io::Result<()> {
+        let reachable = self.reachable(entry);
+
+        try!(write!(w, "digraph cfg {{\n"));
+        try!(write!(w, "    node [shape=box];\n"));
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut text = block.name(i);
+            for label in block.labels.iter().skip(1) {
+                text.push_str("\\n");
+                text.push_str(&label[..]);
+            }
+            let instrs = block.end - block.start - block.labels.len();
+            text.push_str(&format!("\\n{} instrs", instrs)[..]);
+
+            let style = if reachable[i] { "" } else { ", style=filled, fillcolor=gray" };
+            try!(write!(w, "    b{} [label=\"{}\"{}];\n", i, dot_escape(&text[..]), style));
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            for &(succ, kind) in block.succs.iter() {
+                let style = match kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [style=bold]",
+                    EdgeKind::Call => " [style=dashed]",
+                    EdgeKind::Indirect => " [style=dotted]",
+                };
+                try!(write!(w, "    b{} -> b{}{};\n", i, succ, style));
+            }
+        }
+        write!(w, "}}\n")
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace("\"", "\\\"")
+}
+
+// Drops every statement that cannot be reached from entry.  If entry is not
+// defined, nothing is known to be live, so the program is returned as-is.
+pub fn eliminate_dead_code(stmts: Vec<Statement>, entry: &str) -> Vec<Statement> {
+    let tags = vec![(); stmts.len()];
+    eliminate_dead_code_tagged(stmts, tags, entry).0
+}
+
+// Like eliminate_dead_code, but also drops the tags (source locations, for
+// instance) that go along with the dropped statements.
+pub fn eliminate_dead_code_tagged<T>(
+    stmts: Vec<Statement>, tags: Vec<T>, entry: &str) -> (Vec<Statement>, Vec<T>)
+{
+    let cfg = Cfg::new(&stmts[..]);
+    if cfg.block_of(entry).is_none() {
+        return (stmts, tags);
+    }
+
+    let live = cfg.live_statements(entry);
+    let mut live_stmts = vec!();
+    let mut live_tags = vec!();
+    for ((stmt, tag), is_live) in stmts.into_iter().zip(tags.into_iter()).zip(live.into_iter()) {
+        if is_live {
+            live_stmts.push(stmt);
+            live_tags.push(tag);
+        }
+    }
+    (live_stmts, live_tags)
+}
+
+#[cfg(test)]
+fn parse(input: &str) -> Vec<Statement> {
+    use lexer::{Arena, Lexer};
+    use parser::Parser;
+    Parser::new(Lexer::mem(input), &Arena::new()).parse_program()
+}
+
+#[test]
+fn test_blocks() {
+    let cfg = Cfg::new(&parse("
+_start:
+mov r0, #1
+b =foo
+mov r0, #2
+foo:
+bar:
+halt")[..]);
+    assert_eq!(3, cfg.blocks.len());
+    assert_eq!(vec!("foo".to_string(), "bar".to_string()), cfg.blocks[2].labels);
+    assert_eq!(vec!((2, EdgeKind::Jump)), cfg.blocks[0].succs);
+    assert_eq!(vec!((2, EdgeKind::Fallthrough)), cfg.blocks[1].succs);
+    assert!(cfg.blocks[2].succs.is_empty());
+}
+
+#[test]
+fn test_unreachable() {
+    let cfg = Cfg::new(&parse("
+_start:
+{p0, #1, #1} b =foo
+halt
+foo:
+halt
+unused:
+b =foo")[..]);
+    assert_eq!(vec!(3), cfg.unreachable_blocks("_start"));
+    assert_eq!(vec!("unused".to_string()), cfg.unreferenced_labels("_start"));
+}
+
+#[test]
+fn test_indirect_returns() {
+    let cfg = Cfg::new(&parse("
+_start:
+bl =foo
+halt
+foo:
+b lr")[..]);
+    assert!(cfg.unreachable_blocks("_start").is_empty());
+    assert_eq!(vec!((1, EdgeKind::Indirect)), cfg.blocks[2].succs);
+}
+
+#[test]
+fn test_address_taken() {
+    let cfg = Cfg::new(&parse("
+_start:
+mov r0, #=next
+b r0
+halt
+next:
+halt
+unused:
+halt")[..]);
+    assert_eq!(vec!(1, 3), cfg.unreachable_blocks("_start"));
+    assert_eq!(vec!((2, EdgeKind::Indirect)), cfg.blocks[0].succs);
+}
+
+#[test]
+fn test_eliminate_dead_code() {
+    let stmts = eliminate_dead_code(parse("
+_start:
+bl =used
+halt
+unused:
+mov r0, #1
+b lr
+used:
+b lr"), "_start");
+    assert_eq!(parse("
+_start:
+bl =used
+halt
+used:
+b lr"), stmts);
+}
@@ -0,0 +1,176 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use lexer::Location;
+use nbt::Nbt;
+use rcon;
+use types::{Block, Extent};
+use world::Obstacles;
+
+// The longest command a command block will accept.
+pub const MAX_COMMAND_LEN: usize = 32767;
+
+pub const MIN_BUILD_HEIGHT: i32 = 0;
+pub const MAX_BUILD_HEIGHT: i32 = 255;
+
+// The default radius, in chunks, of the area around spawn that stays loaded.
+pub const DEFAULT_SPAWN_RADIUS: u32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub loc: Option<Location>,
+    pub msg: String,
+}
+
+impl Diagnostic {
+    fn warning(msg: String) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, loc: None, msg: msg }
+    }
+
+    fn error(loc: Option<Location>, msg: String) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, loc: loc, msg: msg }
+    }
+
+    pub fn describe(&self, filename: &str) -> String {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.loc {
+            Some(loc) => format!("{}:{}: {}: {}", filename, loc, severity, self.msg),
+            None => format!("{}: {}: {}", filename, severity, self.msg),
+        }
+    }
+}
+
+// The length of a command block's command, as the game sees it.
+pub fn command_len(block: &Block) -> Option<usize> {
+    match block.nbt.get("Command") {
+        Some(&Nbt::String(ref cmd)) => Some(unescaped_len(&cmd[..])),
+        _ => None,
+    }
+}
+
+// Command strings are stored escaped for NBT, so each escape sequence counts
+// as a single character.
+fn unescaped_len(s: &str) -> usize {
+    let mut len = 0;
+    let mut escaped = false;
+    for c in s.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+        } else {
+            escaped = false;
+            len += 1;
+        }
+    }
+    len
+}
+
+pub fn check_block(block: &Block) -> Option<Diagnostic> {
+    match command_len(block) {
+        Some(len) if len > MAX_COMMAND_LEN => Some(Diagnostic::error(
+            block.loc,
+            format!("command is {} characters long, but the limit is {}",
+                    len, MAX_COMMAND_LEN))),
+        _ => None,
+    }
+}
+
+// Deploying sends each block as a setblock command over RCON, which takes
+// much shorter commands than a command block does.
+pub fn check_deploy(loc: Option<Location>, setblock: &str) -> Option<Diagnostic> {
+    if setblock.len() > rcon::MAX_COMMAND_LEN {
+        Some(Diagnostic::error(
+            loc,
+            format!("placing the command block takes {} bytes, but RCON takes at most {}",
+                    setblock.len(), rcon::MAX_COMMAND_LEN)))
+    } else {
+        None
+    }
+}
+
+fn chunk(coord: i32) -> i32 {
+    // Chunks are 16 blocks on a side.  The shift rounds toward negative
+    // infinity, which is what is needed for negative coordinates.
+    coord >> 4
+}
+
+// Warns about an extent that leaves build height, or that is too large to
+// fit in the spawn chunks, which are the only chunks that are sure to be
+// loaded.  what names the extent in the warnings.
+pub fn check_extent(what: &str, extent: &Extent, spawn_radius: u32) -> Vec<Diagnostic> {
+    let mut diags = vec!();
+    if let Extent::MinMax(min, max) = *extent {
+        if min.y < MIN_BUILD_HEIGHT || max.y > MAX_BUILD_HEIGHT {
+            diags.push(Diagnostic::warning(format!(
+                "{} spans y={} to y={}, outside of build height ({} to {})",
+                what, min.y, max.y, MIN_BUILD_HEIGHT, MAX_BUILD_HEIGHT)));
+        }
+
+        let chunks_x = chunk(max.x) - chunk(min.x) + 1;
+        let chunks_z = chunk(max.z) - chunk(min.z) + 1;
+        let spawn_chunks = 2 * spawn_radius as i32 + 1;
+        if chunks_x > spawn_chunks || chunks_z > spawn_chunks {
+            diags.push(Diagnostic::warning(format!(
+                "{} spans {}x{} chunks, which will not fit in the {}x{} spawn chunks",
+                what, chunks_x, chunks_z, spawn_chunks, spawn_chunks)));
+        }
+    }
+    diags
+}
+
+// Warns about an extent that would overwrite blocks that are already in the
+// world.  The layout routes around obstacles, but nothing else does.
+pub fn check_obstacles(what: &str, extent: &Extent, obstacles: &Obstacles)
+    -> Option<Diagnostic>
+{
+    if obstacles.intersects(extent) {
+        Some(Diagnostic::warning(format!(
+            "{} at {} overwrites blocks that are already there", what, extent)))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_command_len() {
+    use commands::Command;
+    use fab;
+
+    let block = fab::cmd_block(Command::Say("\"hi\"".to_string()), false);
+    assert_eq!(Some(8), command_len(&block));
+    assert!(check_block(&block).is_none());
+
+    let block = fab::cmd_block(Command::Say(
+        (0..MAX_COMMAND_LEN).map(|_| "x").collect()), false);
+    assert!(check_block(&block).is_some());
+
+    assert!(check_deploy(None, "setblock 0 56 0 minecraft:stone").is_none());
+    let setblock: String = (0..rcon::MAX_COMMAND_LEN + 1).map(|_| "x").collect();
+    assert!(check_deploy(None, &setblock[..]).is_some());
+}
+
+#[test]
+fn test_check_extent() {
+    use types::Vec3;
+
+    let small = Extent::MinMax(Vec3::new(-1, 0, 0), Vec3::new(15, 255, 31));
+    assert!(check_extent("program", &small, 1).is_empty());
+
+    let wide = Extent::MinMax(Vec3::new(-17, 0, 0), Vec3::new(16, 255, 0));
+    let diags = check_extent("program", &wide, 1);
+    assert_eq!(1, diags.len());
+    assert_eq!("program spans 4x1 chunks, which will not fit in the 3x3 spawn chunks",
+               &diags[0].msg[..]);
+
+    let high = Extent::MinMax(Vec3::new(0, 250, 0), Vec3::new(0, 256, 0));
+    assert_eq!(1, check_extent("program", &high, 1).len());
+}
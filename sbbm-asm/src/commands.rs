@@ -3,7 +3,8 @@
 
 use types::{Extent, Interval, Pos3, Vec3};
 use nbt::Nbt;
-use std::collections::HashMap;
+use ordered_map::OrderedMap;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
 use std::fmt;
 
 pub type Objective = String;
@@ -30,6 +31,20 @@ impl fmt::Display for Target {
     }
 }
 
+// Targets are written out as they appear in commands, and read back as raw
+// targets, which display the same.
+impl Encodable for Target {
+    fn encode<S : Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string()[..])
+    }
+}
+
+impl Decodable for Target {
+    fn decode<D : Decoder>(d: &mut D) -> Result<Target, D::Error> {
+        Ok(Target::Raw(try!(d.read_str())))
+    }
+}
+
 pub trait ToTarget {
     fn to_target(&self) -> Target;
 }
@@ -65,7 +80,7 @@ pub struct Selector {
     pub game_mode: Option<i32>,
     pub count: Option<i32>,
     pub level: Option<Interval<i32>>,
-    pub scores: HashMap<String, Interval<i32>>,
+    pub scores: OrderedMap<String, Interval<i32>>,
     pub team: Option<SelectorTeam>,
     pub name: Option<SelectorName>,
     pub volume: Option<Vec3>,
@@ -113,7 +128,7 @@ impl Default for Selector {
             game_mode: None,
             count: None,
             level: None,
-            scores: HashMap::new(),
+            scores: OrderedMap::new(),
             team: None,
             name: None,
             volume: None,
@@ -404,18 +419,16 @@ impl fmt::Display for Command {
         use self::Command::*;
 
         match *self {
-            Execute(ref tgt, ref pos, ref cmd) => {
-                let cmd_str = cmd.to_string();
-                write!(f, "execute {} {} {}", tgt, pos, escape(&cmd_str[..]))
-            }
+            // The command after execute is taken as it is, without quotes,
+            // so it is only escaped once, along with the rest, when it goes
+            // in a command block.
+            Execute(ref tgt, ref pos, ref cmd) => write!(f, "execute {} {} {}", tgt, pos, cmd),
             ExecuteDetect(
                 ref tgt, ref pos, ref block_pos, ref block_id, ref block_data,
                 ref cmd) =>
             {
-                let cmd_str = cmd.to_string();
                 write!(f, "execute {} {} detect {} {} {} {}",
-                       tgt, pos, block_pos, block_id, block_data,
-                       escape(&cmd_str[..]))
+                       tgt, pos, block_pos, block_id, block_data, cmd)
             }
             Fill(
                 ref min, ref max, ref block_id, ref block_data, ref action,
@@ -470,6 +483,7 @@ impl fmt::Display for Command {
     }
 }
 
+// Escapes a command for the quoted Command string of a command block.
 pub fn escape(cmd: &str) -> String {
     let mut out = String::new();
     for c in cmd.chars() {
@@ -624,6 +638,13 @@ impl fmt::Display for PlayerCmd {
         use self::PlayerCmd::*;
 
         match *self {
+            List(ref tgt) => {
+                try!(write!(f, "list"));
+                if let Some(ref tgt) = *tgt {
+                    try!(write!(f, " {}", tgt));
+                }
+                Ok(())
+            }
             Set(ref tgt, ref obj, ref value, ref data_tag) => {
                 try!(write!(f, "set {} {} {}", tgt, obj, value));
                 if let Some(ref data_tag) = *data_tag {
@@ -0,0 +1,230 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Constant expressions, for immediates, condition bounds and .equ.  They are
+// made of integer and character literals, constants named by .equ, and the
+// operators + - * / % << >> & | ~, with C's precedence.  They are evaluated
+// as the program is parsed, with 64-bit arithmetic, so that 0xffffffff and
+// -1 both mean all 32 bits set; the result must then fit in 32 bits, and an
+// expression that does not is an error rather than something that wraps
+// around in the scoreboard.
+//
+// A label's address (=label) is only known once the program is assembled, so
+// it cannot be part of a larger expression; it can only be moved into a
+// register on its own.
+
+use lexer::unescape;
+
+use std::collections::HashMap;
+use std::{i32, i64, u32};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Const(String),
+    LabelAddr(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Or,
+}
+
+impl BinOp {
+    // Higher binds tighter.
+    pub fn precedence(self) -> u32 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 5,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Shl | BinOp::Shr => 3,
+            BinOp::And => 2,
+            BinOp::Or => 1,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+        }
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, consts: &HashMap<String, i64>) -> Result<i64, String> {
+        match *self {
+            Expr::Num(value) => Ok(value),
+            Expr::Const(ref name) => match consts.get(name) {
+                Some(&value) => Ok(value),
+                None => Err(format!("undefined constant '{}'", name)),
+            },
+            Expr::LabelAddr(ref label) => Err(format!(
+                "the address of '{}' is not known until the program is assembled, so it \
+                 can only be moved into a register on its own", label)),
+            Expr::Neg(ref e) => {
+                let value = try!(e.eval(consts));
+                if value == i64::MIN {
+                    Err(overflow("-", value, None))
+                } else {
+                    Ok(-value)
+                }
+            }
+            Expr::Not(ref e) => Ok(!try!(e.eval(consts))),
+            Expr::Binary(op, ref l, ref r) => {
+                let l = try!(l.eval(consts));
+                let r = try!(r.eval(consts));
+                eval_binary(op, l, r)
+            }
+        }
+    }
+}
+
+fn overflow(op: &str, l: i64, r: Option<i64>) -> String {
+    match r {
+        Some(r) => format!("{} {} {} overflows", l, op, r),
+        None => format!("{}{} overflows", op, l),
+    }
+}
+
+fn eval_binary(op: BinOp, l: i64, r: i64) -> Result<i64, String> {
+    let result = match op {
+        BinOp::Add => l.checked_add(r),
+        BinOp::Sub => l.checked_sub(r),
+        BinOp::Mul => l.checked_mul(r),
+        BinOp::Div | BinOp::Rem if r == 0 => {
+            return Err(format!("{} {} 0 divides by zero", l, op.symbol()));
+        }
+        BinOp::Div => l.checked_div(r),
+        BinOp::Rem => l.checked_rem(r),
+        BinOp::Shl | BinOp::Shr if r < 0 || r > 31 => {
+            return Err(format!("cannot shift by {}; shifts are from 0 to 31", r));
+        }
+        BinOp::Shl => l.checked_mul(1i64 << r),
+        BinOp::Shr => Some(l >> r),
+        BinOp::And => Some(l & r),
+        BinOp::Or => Some(l | r),
+    };
+    result.ok_or_else(|| overflow(op.symbol(), l, Some(r)))
+}
+
+// The types an expression's value can be used as.
+pub trait FromValue: Sized {
+    fn from_value(value: i64) -> Result<Self, String>;
+}
+
+impl FromValue for i32 {
+    // Values past i32's maximum are taken as the bits of an unsigned number.
+    fn from_value(value: i64) -> Result<i32, String> {
+        if value >= i32::MIN as i64 && value <= u32::MAX as i64 {
+            Ok(value as i32)
+        } else {
+            Err(format!("{} does not fit in 32 bits", value))
+        }
+    }
+}
+
+impl FromValue for u32 {
+    fn from_value(value: i64) -> Result<u32, String> {
+        if value >= 0 && value <= u32::MAX as i64 {
+            Ok(value as u32)
+        } else {
+            Err(format!("{} does not fit in 32 bits unsigned", value))
+        }
+    }
+}
+
+// The value of an integer literal, with or without the # that starts an
+// immediate.  The lexer has already checked its digits.
+pub fn parse_int_literal(text: &str) -> Result<i64, String> {
+    let digits = text.trim_left_matches('#');
+    let (negative, digits) = if digits.starts_with("-") {
+        (true, &digits[1..])
+    } else {
+        (false, digits)
+    };
+    let (radix, digits) = if digits.starts_with("0x") || digits.starts_with("0X") {
+        (16, &digits[2..])
+    } else if digits.starts_with("0b") || digits.starts_with("0B") {
+        (2, &digits[2..])
+    } else {
+        (10, digits)
+    };
+    let value = try!(i64::from_str_radix(digits, radix)
+                     .map_err(|_| format!("{} is too large", text)));
+    Ok(if negative { -value } else { value })
+}
+
+// The value of a character literal, quotes included.
+pub fn parse_char_literal(text: &str) -> Result<i64, String> {
+    let inner = try!(unescape(&text[1..text.len() - 1]));
+    let mut chars = inner.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c as i64),
+        _ => Err(format!("{} is not a single character", text)),
+    }
+}
+
+#[test]
+fn test_eval() {
+    use self::Expr::*;
+
+    let num = |n| Box::new(Num(n));
+    let mut consts = HashMap::new();
+    consts.insert("BASE".to_string(), 0x100);
+
+    let expr = Binary(BinOp::Add, Box::new(Const("BASE".to_string())),
+                      Box::new(Binary(BinOp::Shl, num(1), num(4))));
+    assert_eq!(Ok(0x110), expr.eval(&consts));
+    assert_eq!(Ok(-0x101), Not(Box::new(Const("BASE".to_string()))).eval(&consts));
+    assert_eq!(Ok(-3), Binary(BinOp::Div, num(-7), num(2)).eval(&consts));
+
+    assert_eq!(Err("undefined constant 'TOP'".to_string()),
+               Const("TOP".to_string()).eval(&consts));
+    assert_eq!(Err("1 / 0 divides by zero".to_string()),
+               Binary(BinOp::Div, num(1), num(0)).eval(&consts));
+    assert_eq!(Err("cannot shift by 32; shifts are from 0 to 31".to_string()),
+               Binary(BinOp::Shl, num(1), num(32)).eval(&consts));
+    assert_eq!(Err(format!("{} * 2 overflows", i64::MAX)),
+               Binary(BinOp::Mul, num(i64::MAX), num(2)).eval(&consts));
+    assert!(LabelAddr("foo".to_string()).eval(&consts).is_err());
+}
+
+#[test]
+fn test_from_value() {
+    assert_eq!(Ok(-1), i32::from_value(0xffffffff));
+    assert_eq!(Ok(i32::MIN), i32::from_value(i32::MIN as i64));
+    assert_eq!(Err("4294967296 does not fit in 32 bits".to_string()),
+               i32::from_value(0x100000000));
+    assert_eq!(Ok(4000000000), u32::from_value(4000000000));
+    assert!(u32::from_value(-1).is_err());
+}
+
+#[test]
+fn test_literals() {
+    assert_eq!(Ok(123), parse_int_literal("#123"));
+    assert_eq!(Ok(-100), parse_int_literal("#-100"));
+    assert_eq!(Ok(0x1f), parse_int_literal("0x1F"));
+    assert_eq!(Ok(5), parse_int_literal("#0b101"));
+    assert_eq!(Ok(97), parse_char_literal("'a'"));
+    assert_eq!(Ok(10), parse_char_literal("'\\n'"));
+    assert_eq!(Ok(0x263a), parse_char_literal("'\\u{263a}'"));
+    assert!(parse_char_literal("'ab'").is_err());
+}
@@ -1,16 +1,16 @@
 // Copyright 2015, Christopher Chambers
 // Distributed under the GNU GPL v3. See COPYING for details.
 
-use assembler::AssembledItem;
+use assembler::{AssembledItem, PendingBlock};
 use assembler::AssembledItem::*;
 use commands::{
     self, players,
     Command, IntoTarget, Selector, SelectorName, SelectorTeam, ToTarget};
-use hw::MemoryRegion;
+use hw::{self, MemoryRegion};
 use nbt::{Nbt, NbtCompound};
-use types::{Block, Extent, Interval, Pos3};
+use ordered_map::OrderedMap;
+use types::{Block, Interval, Pos3};
 
-use std::collections::HashMap;
 use std::vec::IntoIter;
 
 pub fn cmd_block(cmd: Command, track_output: bool) -> Block {
@@ -23,22 +23,12 @@ pub fn cmd_block(cmd: Command, track_output: bool) -> Block {
         id: "minecraft:command_block".to_string(),
         data: 0,
         nbt: nbt,
+        loc: None,
     }
 }
 
 pub fn power_off(label: String, track_output: bool) -> AssembledItem {
-    Pending(label, Box::new(move |extent| {
-        match extent {
-            Extent::Empty => {
-                panic!("oh no!");
-            }
-            Extent::MinMax(min, max) => {
-                cmd_block(Command::Fill(
-                    min.as_abs(), max.as_abs(), "minecraft:obsidian".to_string(),
-                    None, None, None), track_output)
-            }
-        }
-    }))
+    Pending(label, PendingBlock::fill("minecraft:obsidian", None, track_output))
 }
 
 pub fn mem_label(region: &MemoryRegion) -> String {
@@ -59,7 +49,7 @@ pub fn mem_selector(region: &MemoryRegion) -> Selector {
 fn mem_op_selector(region: &MemoryRegion, op: i32) -> Selector {
     Selector {
         scores: {
-            let mut s = HashMap::new();
+            let mut s = OrderedMap::new();
             s.insert("MemOp".to_string(), Interval::Bounded(op, op));
             s },
         ..mem_selector(region)
@@ -142,6 +132,9 @@ fn mem_ctrl_move(
     use types::REL_ZERO;
 
     let sel = mem_selector(region);
+    // The memory controller's scratch register is kept on the memory entity,
+    // so it never clashes with one the assembler has taken on the computer.
+    let t0 = hw::temp(0);
 
     // sub MemAddr, region.start
     let cmd = cmd_block(players::remove(
@@ -149,19 +142,19 @@ fn mem_ctrl_move(
     items.push(Complete(cmd));
     // mov t0, 4
     let cmd = cmd_block(players::set(
-        sel.to_target(), "t0".to_string(), 4, None), track_output);
+        sel.to_target(), t0.clone(), 4, None), track_output);
     items.push(Complete(cmd));
     // sdiv MemAddr, t0
     let cmd = cmd_block(players::div_op(
         sel.to_target(), "MemAddr".to_string(),
-        sel.to_target(), "t0".to_string()), track_output);
+        sel.to_target(), t0), track_output);
     items.push(Complete(cmd));
     for (part, pos) in region.traversal() {
         let part = part as i32;
 
         let part_sel = Selector {
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert("MemAddr".to_string(), Interval::Min(part));
                 s },
             ..sel.clone()
@@ -208,7 +201,7 @@ fn mem_ctrl_read(
             main_sel.to_target(), "MemData".to_string(), 0, None));
     items.push(Complete(cmd_block(cmd, track_output)));
 
-    let t0 = "t0".to_string();
+    let t0 = hw::temp(0);
 
     let clay = "minecraft:stained_hardened_clay".to_string();
     for data in (0..16) {
@@ -268,7 +261,7 @@ fn mem_ctrl_write(
     };
     let high_comp = mem_components(region).swap_remove(7);
 
-    let t0 = "t0".to_string();
+    let t0 = hw::temp(0);
 
     // mov mem_comps, t0, mem_entity, MemData
     let cmd = mem_write_cmd(region, players::asn_op(
@@ -319,7 +312,7 @@ fn mem_ctrl_write(
     for data in (0..16) {
         let comps_data_sel = Selector {
             scores: {
-                let mut s = HashMap::new();
+                let mut s = OrderedMap::new();
                 s.insert(t0.clone(), Interval::Bounded(data, data));
                 s },
             ..comps_sel.clone()
@@ -331,3 +324,22 @@ fn mem_ctrl_write(
         items.push(Complete(block));
     }
 }
+
+#[test]
+fn test_cmd_block_escape() {
+    use commands::Target;
+    use nbt;
+    use types::REL_ZERO;
+
+    // A command nested in execute is escaped once, along with the rest, and
+    // reads back from the block as it was written.
+    let tellraw = r#"tellraw @a {"text":"a \"quote\" and a \\"}"#;
+    let cmd = Command::Execute(
+        Target::Raw("@a".to_string()), REL_ZERO, Box::new(Command::Raw(tellraw.to_string())));
+    let tag = Nbt::Compound(cmd_block(cmd, false).nbt).to_string();
+    let command = match nbt::parse(&tag[..]).unwrap() {
+        Nbt::Compound(compound) => compound.get("Command").cloned(),
+        _ => None,
+    };
+    assert_eq!(Some(Nbt::String(format!("execute @a ~ ~ ~ {}", tellraw))), command);
+}
@@ -0,0 +1,156 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Formats assembly source in one style: labels on lines of their own,
+// instructions indented, directives at the start of the line, ", " between
+// operands, and no spaces just inside braces, brackets and parentheses.  It
+// works a line of tokens at a time, rather than on parsed statements, so that
+// comments, directives, macros and the expressions in immediates come out as
+// they were written.
+
+use lexer::{Lexer, Token};
+use lexer::Token::*;
+
+const INDENT: &'static str = "    ";
+
+pub fn format(input: &str, filename: &str) -> Result<String, String> {
+    let mut lexer = Lexer::new(input, filename);
+    let mut lines = vec!();
+    let mut tokens = vec!();
+    let mut line_start = 0;
+    let mut code_end = 0;
+    loop {
+        let token = try!(lexer.next());
+        match token.item {
+            Newline(_) | Eof => {
+                // Comments are skipped by the lexer, so whatever is left
+                // after the last token is the comment.
+                let comment = input[code_end..token.start.offset].trim();
+                let indented = input[line_start..].starts_with(|c: char| c == ' ' || c == '\t');
+                format_line(&tokens[..], comment, indented, &mut lines);
+                if token.item == Eof {
+                    break;
+                }
+                tokens.clear();
+                line_start = token.end.offset;
+                code_end = line_start;
+            }
+            item => {
+                code_end = token.end.offset;
+                tokens.push(item);
+            }
+        }
+    }
+
+    // One blank line at most between lines, and none at either end.
+    let mut out = String::new();
+    let mut blank = false;
+    for line in lines.iter().map(|l| l.trim_right()) {
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+fn format_line(tokens: &[Token], comment: &str, indented: bool, lines: &mut Vec<String>) {
+    let labels = tokens.iter().take_while(|t| match **t { Label(_) => true, _ => false }).count();
+    let rest = &tokens[labels..];
+    for (i, label) in tokens[..labels].iter().enumerate() {
+        if i + 1 == labels && rest.is_empty() && !comment.is_empty() {
+            lines.push(format!("{} {}", label, comment));
+        } else {
+            lines.push(label.to_string());
+        }
+    }
+
+    if rest.is_empty() {
+        if labels == 0 {
+            let indent = if indented { INDENT } else { "" };
+            lines.push(format!("{}{}", indent, comment));
+        }
+        return;
+    }
+    let indent = match rest[0] {
+        Meta(_) => "",
+        _ => INDENT,
+    };
+    let mut line = format!("{}{}", indent, join(rest));
+    if !comment.is_empty() {
+        line.push(' ');
+        line.push_str(comment);
+    }
+    lines.push(line);
+}
+
+fn is_operator(token: &Token) -> bool {
+    match *token {
+        Star | Slash | Percent | Plus | Minus | Shl | Shr | Amp | Pipe | Tilde => true,
+        _ => false,
+    }
+}
+
+// Joins a line's tokens with the spacing of the canonical style.  Operators
+// have a space on each side, except the unary ones, which stick to what they
+// apply to; a * where a value should be is the open end of a range.
+fn join(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut glue = true;
+    let mut operand_expected = true;
+    for token in tokens.iter() {
+        let binary = !operand_expected && is_operator(token);
+        let attach = glue || match *token {
+            Comma | RBrace | RBracket | RParen | Equals => true,
+            _ => false,
+        };
+        if !attach {
+            out.push(' ');
+        }
+        out.push_str(&token.to_string()[..]);
+
+        glue = match *token {
+            LBrace | LBracket | LParen | Hash | Equals => true,
+            Plus | Minus | Tilde => !binary,
+            _ => false,
+        };
+        operand_expected = match *token {
+            Comma | LBrace | LBracket | LParen | Hash | Equals => true,
+            Star => binary,
+            _ => is_operator(token),
+        };
+    }
+    out
+}
+
+#[test]
+fn test_format() {
+    let src = "\n\n; A comment\nfoo:   bar:  {p0,#1 , *}mov r0,#( BASE+1 )*-2 ; set it\n\n\n\
+               \t; inside\n  ldr  r1,[ r0 ]   \n.equ BASE , 0x10\n\
+               .macro inc reg,n=#1\nadd $reg,$n\n.endm\n\
+               done: ;end\n  raw~s   r0 ,  say hi\nmov r0, #=foo\n\n";
+    let formatted = format(src, "test.s").unwrap();
+    assert_eq!("\
+; A comment
+foo:
+bar:
+    {p0, #1, *} mov r0, #(BASE + 1) * -2 ; set it
+
+    ; inside
+    ldr r1, [r0]
+.equ BASE, 0x10
+.macro inc reg, n=#1
+    add $reg, $n
+.endm
+done: ;end
+    raw~s r0, say hi
+    mov r0, #=foo
+", formatted);
+    assert_eq!(formatted, format(&formatted[..], "test.s").unwrap());
+}
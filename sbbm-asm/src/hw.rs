@@ -4,12 +4,15 @@
 use commands::{
     Command, IntoTarget, Selector, SelectorName, SelectorTeam, Target, ToTarget,
     self, objectives, players, teams};
+use ast::Register;
 use fab;
 use nbt::{Nbt, NbtCompound};
 use types::{Extent, Pos3, Vec3};
 
+use rustc_serialize::json;
+use std::cmp;
 use std::i32;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 
 // REVIEW: Where should the write_cmd macro go?  sbbm-asm/main.rs would like to
 // use it, too.
@@ -21,9 +24,30 @@ pub struct Computer {
     pub name: String,
     pub origin: Vec3,
     pub memory: Vec<MemoryRegion>,
+    // How many scratch registers to create.  The assembler takes more than
+    // the register file starts with when an expansion needs them.
+    pub temps: i32,
 }
 
 impl Computer {
+    // The computer that sbbm-asm builds, with its memory beside it to the
+    // west.
+    pub fn new(origin: Vec3) -> Computer {
+        Computer {
+            name: "computer".to_string(),
+            origin: origin,
+            memory: vec![
+                MemoryRegion {
+                    start: 0x10,
+                    size: 0x8000,
+                    origin: Vec3::new(origin.x - 1, 0, origin.z),
+                    growth: Vec3::new(-1, 1, 1),
+                    stride: MemoryStride::XY(32, 256)
+                }],
+            temps: REGISTER_FILE.temps,
+        }
+    }
+
     pub fn selector(&self) -> Selector {
         Selector {
             name: Some(SelectorName::Is(self.name.clone())),
@@ -44,6 +68,118 @@ impl Computer {
         }
         Ok(())
     }
+
+    // Reserves the volumes the computer occupies outside of its program: its
+    // entities and its memory.
+    pub fn reserve(&self, map: &mut ReservationMap) -> Result<(), String> {
+        // The computer entity sits at the origin, and the bit_N entities
+        // follow it along z.  A block placed on one would bury it.
+        let origin = self.origin;
+        let bits = BitwiseInitDestroy::count();
+        try!(map.reserve(
+            &format!("{} entities", self.name)[..],
+            Extent::MinMax(origin, Vec3::new(origin.x, origin.y, origin.z + bits - 1))));
+
+        for region in self.memory.iter() {
+            try!(map.reserve(
+                &format!("memory region {}", fab::mem_name(region))[..],
+                region.extent()));
+        }
+        Ok(())
+    }
+}
+
+// A volume that belongs to one part of the machine.  Nothing else may be
+// placed in it.
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Reservation {
+    pub owner: String,
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Reservation {
+    pub fn extent(&self) -> Extent {
+        Extent::MinMax(self.min, self.max)
+    }
+}
+
+pub struct ReservationMap {
+    reservations: Vec<Reservation>,
+}
+
+impl ReservationMap {
+    pub fn new() -> ReservationMap {
+        ReservationMap { reservations: vec!() }
+    }
+
+    pub fn reservations(&self) -> &[Reservation] {
+        &self.reservations[..]
+    }
+
+    pub fn reserve(&mut self, owner: &str, extent: Extent) -> Result<(), String> {
+        if let Extent::MinMax(min, max) = extent {
+            if let Some(other) = self.conflicts(&extent).first() {
+                return Err(format!(
+                    "{} at {} overlaps {} at {}",
+                    owner, extent, other.owner, other.extent()));
+            }
+            self.reservations.push(Reservation {
+                owner: owner.to_string(),
+                min: min,
+                max: max,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn conflicts(&self, extent: &Extent) -> Vec<&Reservation> {
+        self.reservations.iter()
+            .filter(|r| r.extent().intersects(extent))
+            .collect()
+    }
+
+    // The reservations that overlap any of extents.
+    pub fn conflicts_any(&self, extents: &[Extent]) -> Vec<&Reservation> {
+        self.reservations.iter()
+            .filter(|r| extents.iter().any(|e| r.extent().intersects(e)))
+            .collect()
+    }
+
+    // Reserves everything listed in a JSON array of reservations, such as the
+    // circuits of other computers.
+    pub fn read_json(&mut self, r: &mut Read) -> Result<(), String> {
+        let mut text = String::new();
+        try!(r.read_to_string(&mut text).map_err(|e| e.to_string()));
+        let reservations: Vec<Reservation> =
+            try!(json::decode(&text[..]).map_err(|e| e.to_string()));
+        for reservation in reservations.into_iter() {
+            let extent = reservation.extent();
+            try!(self.reserve(&reservation.owner[..], extent));
+        }
+        Ok(())
+    }
+
+    // How far extent has to move toward +x so that it no longer overlaps any
+    // reservation.  Each move is checked against every reservation again, and
+    // it fails when nothing is clear within limit blocks.
+    pub fn clear_x(&self, extent: &Extent, limit: i32) -> Result<i32, String> {
+        let mut shift: i64 = 0;
+        while shift <= limit as i64 {
+            let moved = extent.translate(Vec3::new(shift as i32, 0, 0));
+            let min_x = match moved {
+                Extent::MinMax(min, _) => min.x,
+                Extent::Empty => return Ok(0),
+            };
+            let conflicts = self.conflicts(&moved);
+            if conflicts.is_empty() {
+                return Ok(shift as i32);
+            }
+            let max_x = conflicts.iter().fold(i32::MIN, |x, r| cmp::max(x, r.max.x));
+            shift += max_x as i64 - min_x as i64 + 1;
+        }
+        Err(format!("nothing within {} blocks along x is clear of the reservations", limit))
+    }
 }
 
 pub enum MemoryStride {
@@ -128,6 +264,131 @@ impl MemoryRegion {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegisterClass {
+    General,
+    Predicate,
+    // Named registers that programs may use: the link register and the stack
+    // pointer.
+    Special,
+    // Registers that only the assembler's lowerings use.
+    Internal,
+    // Registers that hold a value that the lowerings depend on.  They are set
+    // when the computer is initialized, and nothing may write to them.
+    Constant,
+}
+
+// The registers the computer has.  Each is an objective on the computer
+// entity, named the way the register is written.
+pub struct RegisterFile {
+    pub general: i32,
+    pub predicate: i32,
+    pub special: &'static [&'static str],
+    // Scratch registers, 0t0 and up, for the lowerings.  This is how many are
+    // created at the least.
+    pub temps: i32,
+    pub internal: &'static [&'static str],
+    pub constants: &'static [(&'static str, i32)],
+}
+
+pub static REGISTER_FILE: RegisterFile = RegisterFile {
+    general: 32,
+    predicate: 8,
+    special: &["lr", "sp"],
+    temps: 4,
+    internal: &["IndAddr"],
+    constants: &[("ZERO", 0), ("TWO", 2), ("MIN", i32::MIN)],
+};
+
+// The name of the nth scratch register.  It starts with a digit, so a program
+// can't name it as a register or an objective without quoting it, yet it is
+// still a word, as the score arguments of a selector need to be.
+pub fn temp(n: i32) -> String {
+    format!("0t{}", n)
+}
+
+impl RegisterFile {
+    pub fn class_of(&self, reg: &Register) -> Option<RegisterClass> {
+        match *reg {
+            Register::Gen(num) if 0 <= num && num < self.general => Some(RegisterClass::General),
+            Register::Pred(num) if 0 <= num && num < self.predicate => {
+                Some(RegisterClass::Predicate)
+            }
+            Register::Spec(ref name) => {
+                let name = &name[..];
+                if self.special.contains(&name) {
+                    Some(RegisterClass::Special)
+                } else if self.internal.contains(&name) || self.is_temp(name) {
+                    Some(RegisterClass::Internal)
+                } else if self.constants.iter().any(|c| c.0 == name) {
+                    Some(RegisterClass::Constant)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Every 0t<n> is kept for the assembler, not just the ones created up
+    // front, since the pool grows when an expansion needs more.
+    fn is_temp(&self, name: &str) -> bool {
+        name.starts_with("0t") && match name[2..].parse::<i32>() {
+            Ok(num) => num >= 0 && name[2..] == num.to_string()[..],
+            Err(_) => false,
+        }
+    }
+
+    // Checks that a program may name the register.
+    pub fn check_named(&self, reg: &Register) -> Result<(), String> {
+        match self.class_of(reg) {
+            Some(RegisterClass::General) | Some(RegisterClass::Predicate) |
+            Some(RegisterClass::Special) => Ok(()),
+            Some(_) => Err(format!("{} is reserved for the assembler", reg)),
+            None => match *reg {
+                Register::Gen(_) => Err(format!(
+                    "no such register {}: the general registers are r0 to r{}",
+                    reg, self.general - 1)),
+                Register::Pred(_) => Err(format!(
+                    "no such register {}: the predicate registers are p0 to p{}",
+                    reg, self.predicate - 1)),
+                Register::Spec(_) => Err(format!("no such register {}", reg)),
+            },
+        }
+    }
+
+    pub fn is_writable(&self, reg: &Register) -> bool {
+        match self.class_of(reg) {
+            Some(RegisterClass::Constant) => false,
+            _ => true,
+        }
+    }
+
+    // Every register, with the value it starts with.
+    pub fn initial_values(&self) -> Vec<(String, i32)> {
+        let mut regs = vec![];
+        for i in 0..self.general {
+            regs.push((format!("r{}", i), 0));
+        }
+        for i in 0..self.predicate {
+            regs.push((format!("p{}", i), 0));
+        }
+        for i in 0..self.temps {
+            regs.push((temp(i), 0));
+        }
+        for &(name, value) in self.constants.iter() {
+            regs.push((name.to_string(), value));
+        }
+        for &name in self.internal.iter() {
+            regs.push((name.to_string(), 0));
+        }
+        for &name in self.special.iter() {
+            regs.push((name.to_string(), 0));
+        }
+        regs
+    }
+}
+
 trait InitDestroy {
     fn init(&self, computer: &Computer, w: &mut Write) -> io::Result<()>;
     fn destroy(&self, computer: &Computer, w: &mut Write) -> io::Result<()>;
@@ -157,49 +418,27 @@ impl InitDestroy for CompInitDestroy {
     }
 }
 
-impl RegsInitDestroy {
-    fn regs() -> Vec<(String, i32)> {
-        let mut regs = vec![];
-
-        // General registers
-        for i in (0..32) {
-            regs.push((format!("r{}", i), 0));
-        }
-
-        // Predicate registers
-        for i in (0..8) {
-            regs.push((format!("p{}", i), 0));
-        }
-
-        // Temp registers (implementation details)
-        for i in (0..4) {
-            regs.push((format!("t{}", i), 0));
-        }
-
-        regs.push(("ZERO".to_string(), 0));
-        regs.push(("TWO".to_string(), 2));
-        regs.push(("MIN".to_string(), i32::MIN));
-
-        regs.push(("IndAddr".to_string(), 0));
-        regs.push(("lr".to_string(), 0));
-        regs.push(("sp".to_string(), 0));
-
-        regs
+// The computer's registers, with as many scratch registers as it was assembled
+// for.
+fn computer_registers(computer: &Computer) -> RegisterFile {
+    RegisterFile {
+        temps: cmp::max(REGISTER_FILE.temps, computer.temps),
+        ..REGISTER_FILE
     }
 }
 
 impl InitDestroy for RegsInitDestroy {
     fn init(&self, computer: &Computer, w: &mut Write) -> io::Result<()> {
         let sel = computer.selector();
-        for (obj, value) in Self::regs() {
+        for (obj, value) in computer_registers(computer).initial_values() {
             try!(write_cmd!(w, objectives::add(obj.clone(), "dummy".to_string(), None)));
             try!(write_cmd!(w, players::set(sel.to_target(), obj.clone(), value, None)));
         }
         Ok(())
     }
 
-    fn destroy(&self, _: &Computer, w: &mut Write) -> io::Result<()> {
-        for (obj, _) in Self::regs() {
+    fn destroy(&self, computer: &Computer, w: &mut Write) -> io::Result<()> {
+        for (obj, _) in computer_registers(computer).initial_values() {
             try!(write_cmd!(w, objectives::remove(obj)));
         }
         Ok(())
@@ -207,6 +446,7 @@ impl InitDestroy for RegsInitDestroy {
 }
 
 impl BitwiseInitDestroy {
+    fn count() -> i32 { 32 }
     fn bit_comp() -> String { "BitComponent".to_string() }
     fn bit_num() -> String { "BitNumber".to_string() }
     fn team() -> String { "Shifters".to_string() }
@@ -224,7 +464,7 @@ impl InitDestroy for BitwiseInitDestroy {
 
         // Bitwise entities
         let mut entities = vec!();
-        for i in (0..32) {
+        for i in (0..Self::count()) {
             let name = format!("bit_{}", i);
             let target = Target::Sel(Selector {
                 name: Some(SelectorName::Is(name.clone())),
@@ -341,3 +581,57 @@ fn make_entity(name: &str, pos: Pos3) -> Command {
 
     Command::Summon(entity_name, Some(pos), Some(Nbt::Compound(data_tag)))
 }
+
+#[test]
+fn test_register_file() {
+    let regs = &REGISTER_FILE;
+    assert_eq!(Ok(()), regs.check_named(&Register::Gen(31)));
+    assert_eq!(Ok(()), regs.check_named(&Register::Spec("lr".to_string())));
+    assert!(regs.check_named(&Register::Gen(32)).is_err());
+    assert!(regs.check_named(&Register::Pred(8)).is_err());
+    assert!(regs.check_named(&Register::Spec("0t0".to_string())).is_err());
+    assert!(regs.check_named(&Register::Spec("0t9".to_string())).is_err());
+    // Names a program could use as objectives are left alone.
+    assert_eq!(None, regs.class_of(&Register::Spec("t0".to_string())));
+    assert!(regs.check_named(&Register::Spec("lnk".to_string())).is_err());
+
+    assert!(!regs.is_writable(&Register::Spec("ZERO".to_string())));
+    assert!(regs.is_writable(&Register::Spec("0t1".to_string())));
+    assert_eq!(None, regs.class_of(&Register::Spec("0t01".to_string())));
+
+    // Every register the lowerings use is created.
+    let names: Vec<String> = regs.initial_values().into_iter().map(|r| r.0).collect();
+    for name in ["0t0", "0t1", "0t2", "ZERO", "TWO", "MIN", "IndAddr", "lr", "sp"].iter() {
+        assert!(names.contains(&name.to_string()), "{}", name);
+    }
+    assert_eq!(32 + 8 + 4 + 3 + 1 + 2, names.len());
+
+    // A computer assembled for more scratch registers creates them all.
+    let mut computer = Computer::new(Vec3::new(0, 0, 0));
+    computer.temps = 6;
+    let mut init = vec!();
+    computer.write_init_script(&mut init).unwrap();
+    let init = String::from_utf8(init).unwrap();
+    assert!(init.contains("objectives add 0t5 dummy"));
+    assert!(!init.contains("objectives add 0t6 dummy"));
+}
+
+#[test]
+fn test_reservations() {
+    let mut map = ReservationMap::new();
+    map.reserve("a", Extent::MinMax(Vec3::new(0, 0, 0), Vec3::new(3, 3, 3))).unwrap();
+    map.reserve("b", Extent::MinMax(Vec3::new(6, 0, 0), Vec3::new(6, 3, 3))).unwrap();
+    assert!(map.reserve("c", Extent::MinMax(Vec3::new(3, 3, 3), Vec3::new(4, 4, 4))).is_err());
+
+    // Clearing a goes straight into b, so the extent has to move past both.
+    let extent = Extent::MinMax(Vec3::new(2, 0, 0), Vec3::new(4, 0, 0));
+    assert_eq!(Ok(5), map.clear_x(&extent, 64));
+    let extent = Extent::MinMax(Vec3::new(2, 0, 0), Vec3::new(3, 0, 0));
+    assert_eq!(Ok(2), map.clear_x(&extent, 64));
+    assert!(map.clear_x(&extent, 1).is_err());
+
+    // Something that runs along +x without end can't be cleared.
+    map.reserve("d", Extent::MinMax(Vec3::new(7, 0, 0), Vec3::new(i32::MAX, 0, 0))).unwrap();
+    let extent = Extent::MinMax(Vec3::new(2, 0, 0), Vec3::new(4, 0, 0));
+    assert!(map.clear_x(&extent, 1024).is_err());
+}
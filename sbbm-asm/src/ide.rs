@@ -0,0 +1,543 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// What an editor wants to know about a source file: what is wrong with it,
+// where its labels and def names are defined and used, what an instruction
+// lowers to, and what could be typed next.  The parser and the assembler
+// report errors by panicking, so they run on threads of their own, and a
+// panic becomes a diagnostic.
+
+use assembler::{Assembler, AssembledItem};
+use ast::Statement;
+use ast::Statement::*;
+use check::{Diagnostic, Severity};
+use hw::{Computer, REGISTER_FILE};
+use lexer::{Arena, Lexer, Location};
+use lexer::Token::*;
+use link::{self, Unit, Visibility};
+use parser::{Parser, SpannedStatement};
+use stats::ProgramStats;
+use types::Vec3;
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+
+static ENTRY_LABEL: &'static str = "_start";
+
+// The label that an instruction is assembled under, to see how it lowers.
+static LOWER_LABEL: &'static str = "lower";
+
+pub static MNEMONICS: [(&'static str, &'static str); 22] = [
+    ("add", "add"),
+    ("and", "bitwise and"),
+    ("asr", "arithmetic shift right"),
+    ("b", "branch"),
+    ("bl", "branch with link"),
+    ("eor", "bitwise exclusive or"),
+    ("halt", "stop the program"),
+    ("ldr", "load a word from memory"),
+    ("lsl", "shift left"),
+    ("lsr", "logical shift right"),
+    ("mov", "move"),
+    ("mul", "multiply"),
+    ("orr", "bitwise or"),
+    ("raw", "run a command"),
+    ("sdiv", "signed divide"),
+    ("srem", "signed remainder"),
+    ("srng", "1 if the register is in a signed range, else 0"),
+    ("str", "store a word to memory"),
+    ("sub", "subtract"),
+    ("udiv", "unsigned divide"),
+    ("urem", "unsigned remainder"),
+    ("urng", "1 if the register is in an unsigned range, else 0"),
+];
+
+// The registers a program can name.
+pub fn registers() -> Vec<String> {
+    let regs = &REGISTER_FILE;
+    let mut names: Vec<String> = (0..regs.general).map(|i| format!("r{}", i)).collect();
+    names.extend((0..regs.predicate).map(|i| format!("p{}", i)));
+    names.extend(regs.special.iter().map(|s| s.to_string()));
+    names
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    Def,
+}
+
+// A label or def name, where it is defined or used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub is_definition: bool,
+    pub start: Location,
+    pub end: Location,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    Mnemonic,
+    Register,
+    Label,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    pub detail: String,
+}
+
+// How an instruction is assembled: how many command blocks it takes, how many
+// ticks they run over, and which of the computer's subsystems they use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lowering {
+    pub blocks: u32,
+    pub ticks: u32,
+    pub uses_memory: bool,
+    pub uses_bitwise: bool,
+}
+
+impl Lowering {
+    pub fn describe(&self) -> String {
+        fn plural(n: u32, what: &str) -> String {
+            format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+        }
+
+        let mut desc = format!("{}, over {}.",
+                               plural(self.blocks, "command block"),
+                               plural(self.ticks, "tick"));
+        if self.uses_memory {
+            desc.push_str("\nUses the memory controller.");
+        }
+        if self.uses_bitwise {
+            desc.push_str("\nUses the bitwise entities.");
+        }
+        desc
+    }
+}
+
+// Assembles a single instruction on its own, to see how it lowers.
+pub fn lower(stmt: &Statement) -> Option<Lowering> {
+    let stmts = vec!(LabelStmt(LOWER_LABEL.to_string()), stmt.clone());
+    let handle = thread::spawn(move || {
+        let computer = Computer::new(Vec3::new(0, 0, 0));
+        let mut assembler = Assembler::new(&computer, stmts.into_iter());
+        let items: Vec<AssembledItem> = assembler.by_ref().collect();
+        let stats = ProgramStats::new(&items[..], assembler.usage());
+        let label = &stats.labels[0];
+        Lowering {
+            blocks: label.blocks,
+            ticks: label.own_ticks,
+            uses_memory: label.uses_memory,
+            uses_bitwise: label.uses_bitwise,
+        }
+    });
+    handle.join().ok()
+}
+
+// A source file, as an editor has it, and what is known about it.
+pub struct Document {
+    pub filename: String,
+    pub text: String,
+    // The statements before the first error, if there is one.
+    pub statements: Vec<SpannedStatement>,
+    pub diagnostics: Vec<Diagnostic>,
+    // In the order they appear.
+    pub symbols: Vec<Symbol>,
+}
+
+impl Document {
+    pub fn new(filename: &str, text: &str) -> Document {
+        let (statements, parsed) = parse(filename, text);
+        let stopped = statements.iter().rev()
+            .map(|s| s.end)
+            .find(|loc| loc.file == 0 && loc.offset <= text.len())
+            .unwrap_or(Location { offset: 0, col: 0, line: 0, file: 0 });
+
+        let mut doc = Document {
+            filename: filename.to_string(),
+            text: text.to_string(),
+            statements: vec!(),
+            diagnostics: vec!(),
+            symbols: find_symbols(filename, text),
+        };
+        match parsed {
+            Ok((declarations, files)) => {
+                let unit = Unit {
+                    filename: filename.to_string(),
+                    files: files,
+                    stmts: statements,
+                    declarations: declarations,
+                };
+                let mut errors = vec!();
+                let syms = link::symbols(&unit, ENTRY_LABEL, &mut errors);
+                for msg in errors.iter() {
+                    let diag = doc.diagnostic(Severity::Error, &msg[..], stopped);
+                    doc.diagnostics.push(diag);
+                }
+                // A label that this file does not define may still be a
+                // global in another one.
+                for &(ref label, loc) in syms.refs.iter() {
+                    if loc.file == 0 && !syms.defined.contains_key(label) {
+                        doc.diagnostics.push(Diagnostic {
+                            severity: Severity::Warning,
+                            loc: Some(loc),
+                            msg: format!("'{}' is not defined in this file", label),
+                        });
+                    }
+                }
+                doc.statements = unit.stmts;
+            }
+            Err(msg) => {
+                let diag = doc.diagnostic(Severity::Error, &msg[..], stopped);
+                doc.diagnostics.push(diag);
+                doc.statements = statements;
+            }
+        }
+        doc
+    }
+
+    // Errors start with the file and the location they are in.  Those in
+    // another file, one that this one includes, are reported at where the
+    // parser stopped.
+    fn diagnostic(&self, severity: Severity, msg: &str, stopped: Location) -> Diagnostic {
+        let filename = &self.filename[..];
+        if msg.starts_with(filename) && msg[filename.len()..].starts_with(":") {
+            if let Some((line, col, rest)) = split_line_col(&msg[filename.len() + 1..]) {
+                let mut rest = rest.trim_left_matches(':').trim_left();
+                // The lexer's errors also give where the bad token ends.
+                if let Some((_, _, after)) = split_line_col(rest) {
+                    if after.starts_with(" ") {
+                        rest = after.trim_left();
+                    }
+                }
+                return Diagnostic {
+                    severity: severity,
+                    loc: Some(self.location(line, col)),
+                    msg: rest.to_string(),
+                };
+            }
+        }
+        Diagnostic { severity: severity, loc: Some(stopped), msg: msg.to_string() }
+    }
+
+    pub fn location(&self, line: usize, col: usize) -> Location {
+        let start = if line == 0 {
+            0
+        } else {
+            self.text.char_indices()
+                .filter(|&(_, c)| c == '\n')
+                .nth(line - 1)
+                .map_or(self.text.len(), |(i, _)| i + 1)
+        };
+        let line_len = self.text[start..].find('\n').unwrap_or(self.text.len() - start);
+        let col = if col > line_len { line_len } else { col };
+        Location { offset: start + col, col: col, line: line, file: 0 }
+    }
+
+    pub fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.start.offset <= offset && offset <= s.end.offset)
+    }
+
+    pub fn definition(&self, offset: usize) -> Option<&Symbol> {
+        self.symbol_at(offset).and_then(|sym| {
+            self.symbols.iter().find(|s| {
+                s.is_definition && s.kind == sym.kind && s.name == sym.name
+            })
+        })
+    }
+
+    pub fn references(&self, offset: usize, include_definition: bool) -> Vec<&Symbol> {
+        match self.symbol_at(offset) {
+            Some(sym) => self.symbols.iter().filter(|s| {
+                s.kind == sym.kind && s.name == sym.name &&
+                    (include_definition || !s.is_definition)
+            }).collect(),
+            None => vec!(),
+        }
+    }
+
+    // Describes the instruction on the line at offset, and how it lowers.
+    pub fn hover(&self, offset: usize) -> Option<String> {
+        let line = self.text[..offset].chars().filter(|&c| c == '\n').count();
+        let stmt = self.statements.iter().find(|s| {
+            s.start.file == 0 && s.start.line == line && match s.item { Instr(_, _) => true, _ => false }
+        });
+        stmt.and_then(|s| {
+            lower(&s.item).map(|lowering| format!("{}\n\n{}", s.item, lowering.describe()))
+        })
+    }
+
+    // What could be typed at offset: a mnemonic where an instruction starts,
+    // a label after =, and otherwise a register.  Only those that start with
+    // the word being typed are given.
+    pub fn completions(&self, offset: usize) -> Vec<Completion> {
+        let line_start = self.text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let before = &self.text[line_start..offset];
+        if before.contains(';') {
+            return vec!();
+        }
+        let word_start = before.rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |i| i + 1);
+        let (prefix, word) = before.split_at(word_start);
+
+        let mut completions = vec!();
+        if prefix.ends_with("=") {
+            let labels = self.symbols.iter()
+                .filter(|s| s.kind == SymbolKind::Label && s.is_definition);
+            for sym in labels {
+                completions.push(Completion {
+                    label: sym.name.clone(),
+                    kind: CompletionKind::Label,
+                    detail: "label".to_string(),
+                });
+            }
+        } else if is_mnemonic_position(prefix) {
+            for &(mnemonic, detail) in MNEMONICS.iter() {
+                completions.push(Completion {
+                    label: mnemonic.to_string(),
+                    kind: CompletionKind::Mnemonic,
+                    detail: detail.to_string(),
+                });
+            }
+        } else if !prefix.trim_left().starts_with(".") {
+            for reg in registers().into_iter() {
+                completions.push(Completion {
+                    label: reg,
+                    kind: CompletionKind::Register,
+                    detail: "register".to_string(),
+                });
+            }
+        }
+        completions.retain(|c| c.label.starts_with(word));
+        completions
+    }
+}
+
+// Parses the text on a thread of its own.  The statements before the first
+// error are kept, along with the error.  Without one, the labels the file
+// declares are given, and the files it includes.
+fn parse(filename: &str, text: &str)
+    -> (Vec<SpannedStatement>,
+        Result<(Vec<(String, Visibility, Location)>, Vec<String>), String>)
+{
+    let (tx, rx) = mpsc::channel();
+    let filename = filename.to_string();
+    let input = text.to_string();
+    let handle = thread::spawn(move || {
+        let arena = Arena::new();
+        let mut parser = Parser::new(Lexer::new(&input[..], &filename[..]), &arena);
+        for stmt in parser.by_ref() {
+            tx.send(stmt).unwrap();
+        }
+        let files = parser.files().iter().map(|f| f.to_string()).collect();
+        (parser.declarations().to_vec(), files)
+    });
+    let parsed = handle.join().map_err(|payload| panic_message(&*payload));
+    (rx.iter().collect(), parsed)
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&'static str>() {
+        msg.to_string()
+    } else {
+        "the parser failed".to_string()
+    }
+}
+
+// Splits a one-based line:col off of the start of s, and gives them
+// zero-based.
+fn split_line_col(s: &str) -> Option<(usize, usize, &str)> {
+    let colon = match s.find(':') {
+        Some(colon) => colon,
+        None => return None,
+    };
+    let line = match s[..colon].parse::<usize>() {
+        Ok(line) if line > 0 => line,
+        _ => return None,
+    };
+    let rest = &s[colon + 1..];
+    let digits = rest.find(|c: char| !c.is_digit(10)).unwrap_or(rest.len());
+    match rest[..digits].parse::<usize>() {
+        Ok(col) if col > 0 => Some((line - 1, col - 1, &rest[digits..])),
+        _ => None,
+    }
+}
+
+fn advance(loc: Location, n: usize) -> Location {
+    Location { offset: loc.offset + n, col: loc.col + n, line: loc.line, file: loc.file }
+}
+
+// Finds the labels and def names in the text as it was written, before any
+// macros are expanded.  A label is used where = refers to it, and where it is
+// declared .global or .local.  A def name is used wherever it appears as an
+// identifier.
+fn find_symbols(filename: &str, text: &str) -> Vec<Symbol> {
+    fn symbol(name: &str, kind: SymbolKind, is_definition: bool, start: Location) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            kind: kind,
+            is_definition: is_definition,
+            start: start,
+            end: advance(start, name.len()),
+        }
+    }
+
+    let mut lexer = Lexer::new(text, filename);
+    let mut symbols = vec!();
+    let mut idents = vec!();
+    let mut declaring = false;
+    loop {
+        let token = match lexer.next() {
+            Ok(token) => token,
+            Err(_) => break,
+        };
+        match token.item {
+            Label(label) => {
+                let name = &label[..label.len() - 1];
+                symbols.push(symbol(name, SymbolKind::Label, true, token.start));
+            }
+            LabelRef(label) => {
+                let start = advance(token.start, 1);
+                symbols.push(symbol(&label[1..], SymbolKind::Label, false, start));
+            }
+            Def(name, _) => {
+                // The token starts at the value, so the name is found in the
+                // text before it, after the def.
+                let line_start = token.start.offset - token.start.col;
+                let line = &text[line_start..token.start.offset];
+                let after_def = line.find("def").map_or(0, |i| i + 3);
+                if let Some(i) = line[after_def..].find(name) {
+                    let col = after_def + i;
+                    let start = Location {
+                        offset: line_start + col,
+                        col: col,
+                        line: token.start.line,
+                        file: token.start.file,
+                    };
+                    symbols.push(symbol(name, SymbolKind::Def, true, start));
+                }
+            }
+            Meta(".global") | Meta(".local") => declaring = true,
+            Ident(name) if declaring => {
+                symbols.push(symbol(name, SymbolKind::Label, false, token.start));
+            }
+            Ident(name) => idents.push((name, token.start)),
+            Newline(_) => declaring = false,
+            Eof | Error => break,
+            _ => { }
+        }
+    }
+
+    let defs: HashSet<&str> = symbols.iter()
+        .filter(|s| s.kind == SymbolKind::Def)
+        .map(|s| &s.name[..])
+        .collect();
+    let uses: Vec<Symbol> = idents.into_iter()
+        .filter(|&(name, _)| defs.contains(name))
+        .map(|(name, start)| symbol(name, SymbolKind::Def, false, start))
+        .collect();
+    symbols.extend(uses.into_iter());
+    symbols.sort_by(|a, b| a.start.offset.cmp(&b.start.offset));
+    symbols
+}
+
+// Whether an instruction's mnemonic would come next, after the labels and
+// conditions at the start of a line.
+fn is_mnemonic_position(prefix: &str) -> bool {
+    let mut lexer = Lexer::mem(prefix);
+    let mut in_cond = false;
+    loop {
+        match lexer.next().map(|t| t.item) {
+            Ok(Label(_)) if !in_cond => { }
+            Ok(LBrace) if !in_cond => in_cond = true,
+            Ok(RBrace) if in_cond => in_cond = false,
+            Ok(Eof) => return !in_cond,
+            Ok(_) if in_cond => { }
+            _ => return false,
+        }
+    }
+}
+
+#[test]
+fn test_diagnostics() {
+    let doc = Document::new("test.s", "_start:\nmov r0, #1\nb =nowhere\nmov r0,, r1\n");
+    assert_eq!(1, doc.diagnostics.len());
+    assert_eq!(Severity::Error, doc.diagnostics[0].severity);
+    assert_eq!(Some(doc.location(3, 7)), doc.diagnostics[0].loc);
+    // The statements before the error are still known.
+    assert_eq!(3, doc.statements.len());
+
+    let doc = Document::new("test.s", "_start:\nb =nowhere\nfoo:\nfoo:\n");
+    let found: Vec<(Severity, Location)> =
+        doc.diagnostics.iter().map(|d| (d.severity, d.loc.unwrap())).collect();
+    assert_eq!(vec!((Severity::Error, doc.location(3, 0)),
+                    (Severity::Warning, doc.location(1, 0))),
+               found);
+
+    let doc = Document::new("test.s", "mov r0, \"open\n");
+    assert_eq!(Some(doc.location(0, 8)), doc.diagnostics[0].loc);
+    assert!(!doc.diagnostics[0].msg.starts_with("test.s"), "{}", doc.diagnostics[0].msg);
+}
+
+#[test]
+fn test_symbols() {
+    let text = "_start:\n    bl =double\nloop:\n    b =loop\ndef answer, 42\n\
+                double:\n    b lr\n.global double\n";
+    let doc = Document::new("test.s", text);
+    let at = |s: &str| text.find(s).unwrap();
+
+    let def = doc.definition(at("=loop") + 2).unwrap();
+    assert_eq!((at("loop:"), true), (def.start.offset, def.is_definition));
+    let declared = at(".global") + 8;
+    assert_eq!(at("double:"), doc.definition(declared).unwrap().start.offset);
+
+    let refs: Vec<usize> =
+        doc.references(at("double:"), false).iter().map(|s| s.start.offset).collect();
+    assert_eq!(vec!(at("=double") + 1, declared), refs);
+    assert_eq!(3, doc.references(at("double:"), true).len());
+
+    let answer = doc.symbols.iter().find(|s| s.kind == SymbolKind::Def).unwrap();
+    assert_eq!((at("answer"), at("answer") + 6), (answer.start.offset, answer.end.offset));
+    assert_eq!(None, doc.symbol_at(at("lr")));
+}
+
+#[test]
+fn test_hover() {
+    let text = "_start:\n    mov r0, #1\n    and r0, r1\n    ldr r0, [r1]\n";
+    let doc = Document::new("test.s", text);
+
+    let mov = doc.hover(text.find("mov").unwrap()).unwrap();
+    assert_eq!("mov r0, #1\n\n2 command blocks, over 1 tick.", &mov[..]);
+
+    let and = lower(&doc.statements[2].item).unwrap();
+    assert!(and.uses_bitwise && !and.uses_memory);
+    let ldr = lower(&doc.statements[3].item).unwrap();
+    assert!(ldr.uses_memory && ldr.ticks > 1);
+
+    assert_eq!(None, doc.hover(0));
+}
+
+#[test]
+fn test_completions() {
+    let text = "_start:\nfoo: {p0, #1, *} su\n    add r\nb =\n.equ N, r\n";
+    let doc = Document::new("test.s", text);
+    let labels = |offset: usize| -> Vec<String> {
+        doc.completions(offset).into_iter().map(|c| c.label).collect()
+    };
+
+    assert_eq!(vec!("sub"), labels(text.find(" su").unwrap() + 3));
+    assert_eq!(MNEMONICS.len(), labels(text.find(" su").unwrap() + 1).len());
+    assert_eq!(32, labels(text.find(" r\n").unwrap() + 2).len());
+    assert_eq!(vec!("_start", "foo"), labels(text.find("=").unwrap() + 1));
+    assert!(labels(text.find("N, r").unwrap() + 4).is_empty());
+    assert!(labels(text.find("{p0").unwrap() + 2).contains(&"p0".to_string()));
+}
@@ -0,0 +1,47 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// A language server for sbbm assembly, for editors that speak the language
+// server protocol.  It talks to the editor over stdin and stdout, and needs
+// nothing else.
+
+extern crate rustc_serialize;
+extern crate sbbm_asm;
+
+use rustc_serialize::json::Json;
+use sbbm_asm::lsp::{self, LanguageServer};
+
+use std::io::{self, Write};
+use std::process;
+
+#[cfg(not(test))]
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut server = LanguageServer::new();
+
+    loop {
+        let body = match lsp::read_message(&mut input) {
+            Ok(Some(body)) => body,
+            Ok(None) => break,
+            Err(e) => {
+                writeln!(io::stderr(), "sbbm-lsp: {}", e).unwrap();
+                break;
+            }
+        };
+        let replies = match Json::from_str(&body[..]) {
+            Ok(msg) => server.handle(&msg),
+            Err(e) => vec!(lsp::parse_error(&e.to_string()[..])),
+        };
+        for reply in replies.iter() {
+            lsp::write_message(&mut out, reply).unwrap();
+        }
+        if let Some(code) = server.exit_code() {
+            process::exit(code);
+        }
+    }
+    // The client went away without telling the server to exit.
+    process::exit(1);
+}
@@ -1,43 +1,115 @@
 // Copyright 2015, Christopher Chambers
 // Distributed under the GNU GPL v3. See COPYING for details.
 
-use assembler::{AssembledItem, PendingFn};
+use assembler::{self, AssembledItem, PendingBlock};
 use assembler::AssembledItem::*;
+use commands::Command;
+use fab;
 use types::{Block, Extent, Vec3};
+use world::Obstacles;
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::VecDeque;
+use std::io::{self, Write};
 use std::mem;
+use std::rc::Rc;
 
 pub trait LayoutMotion {
+    // Called before each line is laid out, with the number of blocks in each
+    // of its segments.  A new segment starts at each label, so every segment
+    // but the first begins with a label.  The first segment may be empty.
+    // Gives where the line has to be broken to fit, as counts of the line's
+    // blocks before each break, and fails when there is nowhere to put it.
+    //
+    // At a break, the layout adds a block that powers the rest of the line
+    // from the label that starts it, and then terminates.  When the break is
+    // inside a segment, the rest starts with a new label, and the block that
+    // turns the label off; it is a segment of its own, after an empty first
+    // one.
+    fn plan(&mut self, _segments: &[usize]) -> Result<Vec<usize>, String> { Ok(vec!()) }
+    // Called before plan, with a name for the line that stays the same
+    // across edits to other parts of the program.
+    fn name_line(&mut self, _name: &str) { }
     fn advance(&mut self);
     fn punctuate(&mut self);
     fn terminate(&mut self);
     fn pos(&self) -> Vec3;
     fn power_pos(&self) -> Vec3;
+    // Everything the motion has handed out so far: command block positions,
+    // power positions, and anything else the motion sets aside.
+    fn extent(&self) -> Extent;
 }
 
+// Walks a line on a copy of motion, the same way Layout will lay it out, and
+// reports whether any position it uses is taken by an obstacle.
+fn line_blocked<M>(motion: &M, segments: &[usize], obstacles: &Obstacles) -> bool
+    where M : LayoutMotion + Clone
+{
+    let mut probe = motion.clone();
+    for (i, &blocks) in segments.iter().enumerate() {
+        if i > 0 {
+            probe.punctuate();
+            if obstacles.contains(probe.power_pos()) {
+                return true;
+            }
+        }
+        for _ in 0..blocks {
+            if obstacles.contains(probe.pos()) || obstacles.contains(probe.power_pos()) {
+                return true;
+            }
+            probe.advance();
+        }
+    }
+    false
+}
+
+#[derive(Clone)]
 pub struct LinearMotion {
     start: Vec3,
     pos: Vec3,
+    extent: Extent,
+    obstacles: Option<Rc<Obstacles>>,
 }
 
 impl LinearMotion {
+    // The first line goes one block east of start, clear of the computer's
+    // entities, which stand in a column along z from the start.
     pub fn new(start: Vec3) -> LinearMotion {
         LinearMotion {
             start: start,
-            pos: Vec3::new(start.x, start.y + 1, start.z),
+            pos: Vec3::new(start.x + 1, start.y + 1, start.z),
+            extent: Extent::Empty,
+            obstacles: None,
         }
     }
+
+    pub fn set_obstacles(&mut self, obstacles: Rc<Obstacles>) {
+        self.obstacles = Some(obstacles);
+    }
 }
 
 impl LayoutMotion for LinearMotion {
+    // A line that would run into an obstacle is moved to the next line over.
+    // Lines are never split, because a label's power positions have to form
+    // a single box.
+    fn plan(&mut self, segments: &[usize]) -> Result<Vec<usize>, String> {
+        if let Some(obstacles) = self.obstacles.clone() {
+            while line_blocked(self, segments, &obstacles) {
+                self.terminate();
+            }
+        }
+        Ok(vec!())
+    }
+
     fn advance(&mut self) {
+        self.extent.add(self.pos());
+        self.extent.add(self.power_pos());
         self.pos.z += 1;
     }
 
     fn punctuate(&mut self) {
-        // do nothing.
+        self.extent.add(self.power_pos());
     }
 
     fn terminate(&mut self) {
@@ -54,13 +126,20 @@ impl LayoutMotion for LinearMotion {
         pos.y -= 1;
         pos
     }
+
+    fn extent(&self) -> Extent {
+        self.extent
+    }
 }
 
+#[derive(Clone)]
 pub struct PackedMotion {
     start: Vec3,
     pos: Vec3,
     dir: u8,
     level: u8,
+    extent: Extent,
+    obstacles: Option<Rc<Obstacles>>,
 }
 
 impl PackedMotion {
@@ -70,12 +149,52 @@ impl PackedMotion {
             pos: Vec3::new(start.x + 1, start.y + 1, start.z),
             dir: 0,
             level: 0,
+            extent: Extent::Empty,
+            obstacles: None,
+        }
+    }
+
+    pub fn set_obstacles(&mut self, obstacles: Rc<Obstacles>) {
+        self.obstacles = Some(obstacles);
+    }
+
+    fn row_full(&self) -> bool {
+        self.pos.z - self.start.z > 14
+    }
+
+    fn next_row(&mut self) {
+        self.pos.x += 2;
+        self.level = (self.level + 1) % 2;
+        if self.level == 0 {
+            self.pos.y -= 1;
+        } else {
+            self.pos.y += 1;
         }
+        self.pos.z = self.start.z;
+        self.dir = 0;
     }
 }
 
 impl LayoutMotion for PackedMotion {
+    // A line that would run into an obstacle is moved along the row, one
+    // power position at a time, and then on to the next row.
+    fn plan(&mut self, segments: &[usize]) -> Result<Vec<usize>, String> {
+        if let Some(obstacles) = self.obstacles.clone() {
+            while line_blocked(self, segments, &obstacles) {
+                if self.row_full() {
+                    self.next_row();
+                } else {
+                    self.pos.z += 1;
+                    self.dir = 0;
+                }
+            }
+        }
+        Ok(vec!())
+    }
+
     fn advance(&mut self) {
+        self.extent.add(self.pos());
+        self.extent.add(self.power_pos());
         if self.dir == 3 {
             self.pos.z += 1;
         }
@@ -88,6 +207,7 @@ impl LayoutMotion for PackedMotion {
             self.pos.z += 1;
             self.dir = 0;
         }
+        self.extent.add(self.power_pos());
     }
 
     fn terminate(&mut self) {
@@ -95,17 +215,299 @@ impl LayoutMotion for PackedMotion {
         //       In order to really fill a 3d space, a bin-packing component
         //       will probably be needed.  It will take a little bit of thought
         //       to pack plus-shaped bins.
-        if self.pos.z - self.start.z <= 14 {
+        if !self.row_full() {
             self.punctuate();
         } else {
-            self.pos.x += 2;
-            self.level = (self.level + 1) % 2;
-            if self.level == 0 {
-                self.pos.y -= 1;
+            self.next_row();
+        }
+    }
+
+    fn pos(&self) -> Vec3 {
+        let mut pos = self.pos;
+        match self.dir {
+            0 => { pos.x -= 1 }
+            1 => { pos.x += 1 }
+            2 => { pos.y -= 1 }
+            3 => { pos.y += 1 }
+            _ => { unreachable!() }
+        }
+        pos
+    }
+
+    fn power_pos(&self) -> Vec3 {
+        self.pos
+    }
+
+    fn extent(&self) -> Extent {
+        self.extent
+    }
+}
+
+// BoundedMotion packs lines into a fixed volume.  Power positions run along z
+// in tracks.  The tracks sit on a lattice where (x + 2y) % 5 == 0, so that the
+// plus-shaped cross sections (a power position and the four command blocks
+// around it) tile the x-y plane without gaps, and no command block touches
+// more than one power position.
+//
+// A line goes wherever it adds the least to the volume used so far, clear of
+// obstacles.  When no track has room for all of it, it is broken into pieces
+// that continue from one track to another, at labels where it can be, so
+// that a line may be longer than the bounds are deep.
+#[derive(Clone)]
+pub struct BoundedMotion {
+    start: Vec3,
+    size: Vec3,
+    tracks: Vec<(i32, i32)>,
+    // The next free z offset in each track.
+    used: Vec<i32>,
+    pos: Vec3,
+    dir: u8,
+    extent: Extent,
+    obstacles: Option<Rc<Obstacles>>,
+    // Where each piece of the line after the first starts.
+    pieces: VecDeque<Vec3>,
+}
+
+// Where a piece of a line could go, and the extent it would leave.
+struct Placement {
+    track: usize,
+    z: i32,
+    blocks: usize,
+    volume: u64,
+}
+
+impl BoundedMotion {
+    pub fn new(start: Vec3, size: Vec3) -> BoundedMotion {
+        let mut tracks = vec!();
+        for y in 1..size.y - 1 {
+            for x in 1..size.x - 1 {
+                if (x + 2 * y) % 5 == 0 {
+                    tracks.push((x, y));
+                }
+            }
+        }
+        // Favor tracks near the start, when the extent doesn't choose.
+        tracks.sort_by(|a, b| (a.0 + a.1, a.1).cmp(&(b.0 + b.1, b.1)));
+
+        let used = vec![0; tracks.len()];
+        BoundedMotion {
+            start: start,
+            size: size,
+            tracks: tracks,
+            used: used,
+            pos: start,
+            dir: 0,
+            extent: Extent::Empty,
+            obstacles: None,
+            pieces: VecDeque::new(),
+        }
+    }
+
+    pub fn set_obstacles(&mut self, obstacles: Rc<Obstacles>) {
+        self.obstacles = Some(obstacles);
+    }
+
+    // The number of power positions a line takes up, found by walking it the
+    // same way advance and punctuate will.
+    fn line_len(segments: &[usize]) -> i32 {
+        let mut z = 0;
+        let mut dir = 0;
+        for (i, &blocks) in segments.iter().enumerate() {
+            if i > 0 && dir != 0 {
+                z += 1;
+                dir = 0;
+            }
+            for _ in 0..blocks {
+                if dir == 3 {
+                    z += 1;
+                }
+                dir = (dir + 1) % 4;
+            }
+        }
+        // A label that ends the line still needs a power position.
+        let ends_with_label = segments.len() > 1 && segments[segments.len() - 1] == 0;
+        if dir != 0 || ends_with_label { z + 1 } else { cmp::max(z, 1) }
+    }
+
+    // The segments of the piece of a line that holds count of its blocks,
+    // starting with block first, laid out the way plan describes.  The line
+    // is given as a label (true) or block (false) for each item.
+    fn piece(items: &[bool], first: usize, count: usize) -> Vec<usize> {
+        let blocks: Vec<usize> = (0..items.len()).filter(|&i| !items[i]).collect();
+        let start = if first == 0 { 0 } else { blocks[first - 1] + 1 };
+        let broken = first + count < blocks.len();
+        let end = if broken { blocks[first + count - 1] + 1 } else { items.len() };
+
+        let mut piece = vec!();
+        if first > 0 && !items[start] {
+            piece.push(true);
+            piece.push(false);
+        }
+        piece.extend(items[start..end].iter().cloned());
+        if broken {
+            piece.push(false);
+        }
+
+        let mut segments = vec!(0);
+        for &is_label in piece.iter() {
+            if is_label {
+                segments.push(0);
             } else {
-                self.pos.y += 1;
+                let last = segments.len() - 1;
+                segments[last] += 1;
+            }
+        }
+        segments
+    }
+
+    // The volume used once the piece is placed at z in the track, or None if
+    // it doesn't fit there.
+    fn try_piece(&self, track: usize, z: i32, segments: &[usize]) -> Option<u64> {
+        if Self::line_len(segments) > self.size.z - z {
+            return None;
+        }
+        let (x, y) = self.tracks[track];
+        let mut probe = self.clone();
+        probe.pos = Vec3::new(self.start.x + x, self.start.y + y, self.start.z + z);
+        probe.dir = 0;
+        if let Some(ref obstacles) = self.obstacles {
+            if line_blocked(&probe, segments, obstacles) {
+                return None;
+            }
+        }
+        for (i, &blocks) in segments.iter().enumerate() {
+            if i > 0 {
+                probe.punctuate();
+            }
+            for _ in 0..blocks {
+                probe.advance();
             }
-            self.pos.z = self.start.z;
+        }
+        Some(probe.extent.volume())
+    }
+
+    // The best place in the track for as much of the rest of the line, from
+    // block first, as fits.  Only breaks between segments are considered
+    // when whole is set.
+    fn best_in_track(&self, track: usize, items: &[bool], first: usize, whole: bool)
+        -> Option<Placement>
+    {
+        let total = items.iter().filter(|&&is_label| !is_label).count();
+        // Breaking after a block is between segments when a label follows it.
+        let at_label = |n: usize| {
+            let after = (0..items.len()).filter(|&i| !items[i]).nth(n - 1).unwrap() + 1;
+            after < items.len() && items[after]
+        };
+        for z in self.used[track]..self.size.z {
+            let mut best = None;
+            // A line without blocks is still placed, for its labels.
+            let least = if total == first { 0 } else { 1 };
+            for blocks in least..total - first + 1 {
+                if blocks < total - first && blocks > 0 && whole && !at_label(first + blocks) {
+                    continue;
+                }
+                let segments = Self::piece(items, first, blocks);
+                match self.try_piece(track, z, &segments[..]) {
+                    Some(volume) => {
+                        best = Some(Placement {
+                            track: track, z: z, blocks: blocks, volume: volume });
+                    }
+                    // Without obstacles, a longer piece can't fit either.
+                    None if self.obstacles.is_none() => break,
+                    None => { }
+                }
+            }
+            if best.is_some() {
+                return best;
+            }
+        }
+        None
+    }
+}
+
+impl LayoutMotion for BoundedMotion {
+    fn plan(&mut self, segments: &[usize]) -> Result<Vec<usize>, String> {
+        let mut items = vec!();
+        for (i, &blocks) in segments.iter().enumerate() {
+            if i > 0 {
+                items.push(true);
+            }
+            items.extend((0..blocks).map(|_| false));
+        }
+        let total = items.iter().filter(|&&is_label| !is_label).count();
+
+        let mut breaks = vec!();
+        let mut first = 0;
+        loop {
+            // The whole rest of the line in the track where it adds the least
+            // volume, or else the most of it that fits anywhere, broken at a
+            // label if possible.
+            let mut candidates: Vec<Placement> = (0..self.tracks.len())
+                .filter_map(|t| self.best_in_track(t, &items[..], first, false))
+                .collect();
+            let rest = total - first;
+            let best = if candidates.iter().any(|p| p.blocks == rest) {
+                candidates.retain(|p| p.blocks == rest);
+                candidates.sort_by(|a, b| a.volume.cmp(&b.volume));
+                candidates.into_iter().next()
+            } else {
+                let mut at_labels: Vec<Placement> = (0..self.tracks.len())
+                    .filter_map(|t| self.best_in_track(t, &items[..], first, true))
+                    .filter(|p| p.blocks > 0)
+                    .collect();
+                if at_labels.is_empty() {
+                    at_labels = candidates;
+                }
+                at_labels.sort_by(|a, b| (b.blocks, a.volume).cmp(&(a.blocks, b.volume)));
+                at_labels.into_iter().next()
+            };
+            let best = match best {
+                Some(best) => best,
+                None => return Err(format!("the program does not fit in the bounds {},{},{}",
+                                           self.size.x, self.size.y, self.size.z)),
+            };
+
+            let piece = Self::piece(&items[..], first, best.blocks);
+            self.used[best.track] = best.z + Self::line_len(&piece[..]);
+            let (x, y) = self.tracks[best.track];
+            let pos = Vec3::new(self.start.x + x, self.start.y + y, self.start.z + best.z);
+            if first == 0 {
+                self.pos = pos;
+                self.dir = 0;
+            } else {
+                self.pieces.push_back(pos);
+            }
+            first += best.blocks;
+            if first == total {
+                return Ok(breaks);
+            }
+            breaks.push(first);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.extent.add(self.pos());
+        self.extent.add(self.power_pos());
+        if self.dir == 3 {
+            self.pos.z += 1;
+        }
+        self.dir += 1;
+        self.dir %= 4;
+    }
+
+    fn punctuate(&mut self) {
+        if self.dir != 0 {
+            self.pos.z += 1;
+            self.dir = 0;
+        }
+        self.extent.add(self.power_pos());
+    }
+
+    fn terminate(&mut self) {
+        // The next piece of the line was placed by plan, and so is the next
+        // line.
+        if let Some(pos) = self.pieces.pop_front() {
+            self.pos = pos;
             self.dir = 0;
         }
     }
@@ -125,6 +527,83 @@ impl LayoutMotion for PackedMotion {
     fn power_pos(&self) -> Vec3 {
         self.pos
     }
+
+    fn extent(&self) -> Extent {
+        self.extent
+    }
+}
+
+// StableMotion lays lines out like LinearMotion, a line to a column, but puts
+// each line back in the column it had in an earlier layout, so that an edit
+// only moves the lines it touches.  Lines that are new get columns past all of
+// the earlier ones.
+pub struct StableMotion {
+    start: Vec3,
+    pos: Vec3,
+    extent: Extent,
+    // The x of each line's column in the earlier layout, by name.
+    previous: HashMap<String, i32>,
+    used: HashSet<i32>,
+    next_x: i32,
+}
+
+impl StableMotion {
+    // New columns start one block east of start, clear of the computer's
+    // entities, as in LinearMotion.
+    pub fn new(start: Vec3, previous: HashMap<String, i32>) -> StableMotion {
+        let next_x = previous.values().fold(start.x + 1, |x, &p| cmp::max(x, p + 2));
+        StableMotion {
+            start: start,
+            pos: Vec3::new(start.x, start.y + 1, start.z),
+            extent: Extent::Empty,
+            previous: previous,
+            used: HashSet::new(),
+            next_x: next_x,
+        }
+    }
+}
+
+impl LayoutMotion for StableMotion {
+    fn name_line(&mut self, name: &str) {
+        let x = match self.previous.get(name) {
+            Some(&x) if !self.used.contains(&x) => x,
+            _ => {
+                let x = self.next_x;
+                self.next_x += 2;
+                x
+            }
+        };
+        self.used.insert(x);
+        self.pos = Vec3::new(x, self.start.y + 1, self.start.z);
+    }
+
+    fn advance(&mut self) {
+        self.extent.add(self.pos());
+        self.extent.add(self.power_pos());
+        self.pos.z += 1;
+    }
+
+    fn punctuate(&mut self) {
+        self.extent.add(self.power_pos());
+    }
+
+    fn terminate(&mut self) {
+        // Nothing to do.  The next line is placed by name_line.
+    }
+
+    fn pos(&self) -> Vec3 {
+        self.pos
+    }
+
+    fn power_pos(&self) -> Vec3 {
+        let mut pos = self.pos;
+        pos.y -= 1;
+        pos
+    }
+
+    fn extent(&self) -> Extent {
+        self.extent
+    }
 }
 
 pub struct Layout<Source>
@@ -133,10 +612,29 @@ pub struct Layout<Source>
     input: Source,
     input_done: bool,
     motion: Box<LayoutMotion>,
+    // Items are held until a whole line is available, so the motion can plan
+    // for it.
+    line: Vec<AssembledItem>,
     buffer: VecDeque<(Vec3, Block)>,
-    complete_extents: HashMap<String, Extent>,
-    active_extents: HashMap<String, Extent>,
-    pending: Vec<(String, Vec3, PendingFn)>,
+    complete_extents: BTreeMap<String, Extent>,
+    active_extents: BTreeMap<String, Extent>,
+    pending: Vec<(String, Vec3, PendingBlock)>,
+    // The label whose code is being laid out.  Continuation labels count as
+    // part of the label before them.
+    label: Option<String>,
+    // Which label each block was laid out under, and where the blocks that
+    // refer to labels went.  These are kept for tools that show the layout.
+    owners: Vec<(Vec3, String)>,
+    references: Vec<(Vec3, String)>,
+    // The name and starting power position of each line.  A line is named
+    // for the label it starts with, or for the label before it and how many
+    // lines have passed since, when it starts with a continuation.
+    lines: Vec<(String, Vec3)>,
+    lines_since_label: usize,
+    // How many labels the layout has made to continue broken lines.
+    breaks: u32,
+    // Why the layout stopped early, if the motion could not place a line.
+    error: Option<String>,
 }
 
 impl<Source> Layout<Source>
@@ -147,10 +645,18 @@ impl<Source> Layout<Source>
             input: input,
             input_done: false,
             motion: motion,
+            line: vec!(),
             buffer: VecDeque::new(),
-            complete_extents: HashMap::new(),
-            active_extents: HashMap::new(),
+            complete_extents: BTreeMap::new(),
+            active_extents: BTreeMap::new(),
             pending: Vec::new(),
+            label: None,
+            owners: vec!(),
+            references: vec!(),
+            lines: vec!(),
+            lines_since_label: 0,
+            breaks: 0,
+            error: None,
         }
     }
 
@@ -158,6 +664,64 @@ impl<Source> Layout<Source>
         self.complete_extents.get(label).map(|e| *e)
     }
 
+    // The whole volume used by the layout so far, including power positions
+    // and the positions held for pending blocks.
+    pub fn extent(&self) -> Extent {
+        self.motion.extent()
+    }
+
+    // The power extents of every label that has been laid out, by label.
+    pub fn power_extents(&self) -> Vec<(String, Extent)> {
+        self.complete_extents.iter().map(|(l, e)| (l.clone(), *e)).collect()
+    }
+
+    // Writes the commands that power the entry label, starting the program.
+    pub fn write_boot_script(&self, w: &mut Write, entry: &str) -> io::Result<()> {
+        if let Some(Extent::MinMax(min, max)) = self.get_power_extent(entry) {
+            let cmd = Command::Fill(
+                min.as_abs(), max.as_abs(),
+                "minecraft:redstone_block".to_string(), None, None, None);
+            try!(write!(w, "{}\n", cmd));
+        }
+        Ok(())
+    }
+
+    pub fn owners(&self) -> &[(Vec3, String)] {
+        &self.owners[..]
+    }
+
+    pub fn references(&self) -> &[(Vec3, String)] {
+        &self.references[..]
+    }
+
+    pub fn lines(&self) -> &[(String, Vec3)] {
+        &self.lines[..]
+    }
+
+    // Iteration ends early when a line cannot be placed.  This says why.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_ref().map(|e| &e[..])
+    }
+
+    fn name_line(&mut self, line: &[AssembledItem]) -> String {
+        if let Some(&Label(ref label)) = line.first() {
+            if !assembler::is_continuation_label(&label[..]) {
+                return label.clone();
+            }
+        }
+        let name = format!(
+            "{}+{}", self.label.as_ref().map(|l| &l[..]).unwrap_or(""),
+            self.lines_since_label);
+        self.lines_since_label += 1;
+        name
+    }
+
+    fn add_owner(&mut self, pos: Vec3) {
+        if let Some(ref label) = self.label {
+            self.owners.push((pos, label.clone()));
+        }
+    }
+
     fn update_active_extents(&mut self) {
         let power_pos = self.motion.power_pos();
         for (_, extent) in self.active_extents.iter_mut() {
@@ -167,6 +731,7 @@ impl<Source> Layout<Source>
 
     fn emit(&mut self, block: Block) {
         let pos = self.motion.pos();
+        self.add_owner(pos);
         self.emit_raw(pos, block);
         self.update_active_extents();
         self.motion.advance();
@@ -177,17 +742,24 @@ impl<Source> Layout<Source>
     }
 
     fn add_label(&mut self, label: String) {
+        if !assembler::is_continuation_label(&label[..]) {
+            self.label = Some(label.clone());
+            self.lines_since_label = 0;
+        }
         self.motion.punctuate();
         let pos = self.motion.power_pos();
         let extent = Extent::MinMax(pos, pos);
         self.active_extents.insert(label, extent);
     }
 
-    fn add_pending(&mut self, label: String, func: PendingFn) {
+    fn add_pending(&mut self, label: String, pending: PendingBlock) {
+        let pos = self.motion.pos();
+        self.references.push((pos, label.clone()));
         match self.resolve_extent(&label) {
-            Some(extent) => { self.emit(func(extent)); }
+            Some(extent) => { self.emit(pending.resolve(extent)); }
             None => {
-                self.pending.push((label, self.motion.pos(), func));
+                self.add_owner(pos);
+                self.pending.push((label, pos, pending));
                 self.update_active_extents();
                 self.motion.advance();
             }
@@ -199,8 +771,8 @@ impl<Source> Layout<Source>
         for i in (0..self.pending.len()).rev() {
             match self.resolve_extent(&self.pending[i].0) {
                 Some(extent) => {
-                    let (_, pos, func) = self.pending.swap_remove(i);
-                    self.emit_raw(pos, func(extent));
+                    let (_, pos, pending) = self.pending.swap_remove(i);
+                    self.emit_raw(pos, pending.resolve(extent));
                     made_progress = true;
                 }
                 None => (),
@@ -215,7 +787,7 @@ impl<Source> Layout<Source>
 
     fn new_line(&mut self) {
         // FIXME: Use drain when it is no longer unstable.
-        let mut active_extents = HashMap::new();
+        let mut active_extents = BTreeMap::new();
         mem::swap(&mut self.active_extents, &mut active_extents);
         for (label, extent) in active_extents.into_iter() {
             self.complete_extents.insert(label, extent);
@@ -225,11 +797,87 @@ impl<Source> Layout<Source>
         self.motion.terminate();
     }
 
+    fn add_item(&mut self, item: AssembledItem) {
+        let is_terminal = if let Terminal = item { true } else { false };
+        self.line.push(item);
+        if is_terminal {
+            self.layout_line();
+        }
+    }
+
+    fn layout_line(&mut self) {
+        let line = mem::replace(&mut self.line, vec!());
+        let mut segments = vec!(0);
+        for item in line.iter() {
+            match *item {
+                Label(_) => { segments.push(0); }
+                Complete(_) | Pending(_, _) => {
+                    let last = segments.len() - 1;
+                    segments[last] += 1;
+                }
+                Terminal => { }
+            }
+        }
+        let name = self.name_line(&line[..]);
+        self.motion.name_line(&name[..]);
+        let mut breaks = match self.motion.plan(&segments[..]) {
+            Ok(breaks) => breaks.into_iter(),
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            }
+        };
+        self.lines.push((name, self.motion.power_pos()));
+
+        let mut next_break = breaks.next();
+        let mut blocks = 0;
+        for item in line.into_iter() {
+            let is_block = match item {
+                Complete(_) | Pending(_, _) => true,
+                Label(_) | Terminal => false,
+            };
+            if next_break == Some(blocks) && item != Terminal {
+                let label = match item {
+                    Label(ref label) => Some(label.clone()),
+                    _ => None,
+                };
+                self.break_line(label);
+                next_break = breaks.next();
+            }
+            if is_block {
+                blocks += 1;
+            }
+            self.layout_item(item);
+        }
+    }
+
+    // Ends the piece of a line that the motion had room for, with a block
+    // that powers the rest from the label it starts with, or from a new label
+    // when it doesn't start with one.
+    fn break_line(&mut self, label: Option<String>) {
+        let (label, is_new) = match label {
+            Some(label) => (label, false),
+            None => {
+                self.breaks += 1;
+                (assembler::unique_label(assembler::LINE_CONT_PREFIX, self.breaks - 1), true)
+            }
+        };
+        self.add_pending(label.clone(), PendingBlock::fill("minecraft:redstone_block", None, false));
+        self.new_line();
+
+        let name = self.name_line(&[Label(label.clone())]);
+        self.lines.push((name, self.motion.power_pos()));
+        if is_new {
+            self.add_label(label.clone());
+            self.layout_item(fab::power_off(label, false));
+        }
+    }
+
     fn layout_item(&mut self, item: AssembledItem) {
         match item {
             Label(label) => { self.add_label(label); }
             Complete(block) => { self.emit(block); }
-            Pending(label, func) => { self.add_pending(label, func); }
+            Pending(label, pending) => { self.add_pending(label, pending); }
             Terminal => { self.new_line(); }
         }
     }
@@ -245,12 +893,15 @@ impl<'a, Source> Iterator for &'a mut Layout<Source>
     type Item = (Vec3, Block);
 
     fn next(&mut self) -> Option<(Vec3, Block)> {
-        while self.buffer.is_empty() {
+        while self.buffer.is_empty() && self.error.is_none() {
             if !self.input_done {
                 match self.input.next() {
-                    Some(item) => { self.layout_item(item); }
+                    Some(item) => { self.add_item(item); }
                     None => {
                         self.input_done = true;
+                        if !self.line.is_empty() {
+                            self.layout_line();
+                        }
                         self.new_line();
                     }
                 }
@@ -271,3 +922,210 @@ impl<'a, Source> Iterator for &'a mut Layout<Source>
         self.buffer.pop_front()
     }
 }
+
+#[test]
+fn test_bounded_line_len() {
+    assert_eq!(1, BoundedMotion::line_len(&[0]));
+    assert_eq!(1, BoundedMotion::line_len(&[0, 4]));
+    assert_eq!(2, BoundedMotion::line_len(&[0, 5]));
+    assert_eq!(2, BoundedMotion::line_len(&[0, 4, 0]));
+    assert_eq!(2, BoundedMotion::line_len(&[0, 1, 1]));
+}
+
+#[test]
+fn test_bounded_placement() {
+    let mut motion = BoundedMotion::new(Vec3::new(0, 0, 0), Vec3::new(5, 5, 3));
+    assert_eq!(vec!((1, 2), (3, 1)), motion.tracks);
+
+    assert_eq!(Ok(vec!()), motion.plan(&[0, 8]));
+    assert_eq!(Vec3::new(1, 2, 0), motion.power_pos());
+    // Only one position is left in the first track.
+    assert_eq!(Ok(vec!()), motion.plan(&[0, 5]));
+    assert_eq!(Vec3::new(3, 1, 0), motion.power_pos());
+    motion.plan(&[0, 3]).unwrap();
+    assert_eq!(Vec3::new(1, 2, 2), motion.power_pos());
+
+    assert_eq!(
+        Err("the program does not fit in the bounds 5,5,3".to_string()),
+        motion.plan(&[0, 13]));
+}
+
+#[test]
+fn test_bounded_breaks() {
+    // A line deeper than the bounds continues in another track, with a block
+    // to power the rest of it.
+    let mut motion = BoundedMotion::new(Vec3::new(0, 0, 0), Vec3::new(5, 5, 3));
+    assert_eq!(Ok(vec!(11)), motion.plan(&[0, 20]));
+    assert_eq!(Vec3::new(1, 2, 0), motion.power_pos());
+    motion.terminate();
+    assert_eq!(Vec3::new(3, 1, 0), motion.power_pos());
+
+    // Lines are broken at labels where they can be.
+    let mut motion = BoundedMotion::new(Vec3::new(0, 0, 0), Vec3::new(5, 5, 3));
+    assert_eq!(Ok(vec!(6)), motion.plan(&[0, 6, 6]));
+
+    // The pieces after a break start at a label.
+    assert_eq!(vec!(7), BoundedMotion::piece(&[false; 20], 0, 6));
+    assert_eq!(vec!(0, 15), BoundedMotion::piece(&[false; 20], 6, 14));
+    let items = [true, false, true, false, false, true];
+    assert_eq!(vec!(0, 2), BoundedMotion::piece(&items, 0, 1));
+    assert_eq!(vec!(0, 2, 0), BoundedMotion::piece(&items, 1, 2));
+}
+
+#[test]
+fn test_bounded_layout() {
+    use commands::Command;
+    use fab;
+
+    let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+    let mut items = vec!(Label("foo".to_string()));
+    for _ in 0..20 {
+        items.push(Complete(block.clone()));
+    }
+    items.push(Terminal);
+
+    let motion = Box::new(BoundedMotion::new(Vec3::new(0, 0, 0), Vec3::new(5, 5, 3)));
+    let mut layout = Layout::new(motion, items.clone().into_iter());
+    // Two more blocks: one to power the rest of the line, and one to turn it
+    // back off.
+    assert_eq!(22, (&mut layout).count());
+    assert_eq!(None, layout.error());
+    assert_eq!(
+        Some(Extent::MinMax(Vec3::new(1, 2, 0), Vec3::new(1, 2, 2))),
+        layout.get_power_extent("foo"));
+    assert_eq!(
+        Some(Extent::MinMax(Vec3::new(3, 1, 0), Vec3::new(3, 1, 2))),
+        layout.get_power_extent("@line_cont_0"));
+    assert_eq!(vec!("foo", "foo+0"),
+               layout.lines().iter().map(|l| &l.0[..]).collect::<Vec<_>>());
+
+    let motion = Box::new(BoundedMotion::new(Vec3::new(0, 0, 0), Vec3::new(5, 5, 1)));
+    let mut layout = Layout::new(motion, items.into_iter());
+    assert_eq!(0, (&mut layout).count());
+    assert_eq!(Some("the program does not fit in the bounds 5,5,1"), layout.error());
+}
+
+#[test]
+fn test_linear_extent() {
+    use commands::Command;
+    use fab;
+
+    let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+    let items = vec!(
+        Label("foo".to_string()), Complete(block.clone()), Complete(block), Terminal);
+    let motion = Box::new(LinearMotion::new(Vec3::new(0, 0, 0)));
+    let mut layout = Layout::new(motion, items.into_iter());
+    assert_eq!(2, (&mut layout).count());
+
+    // The power positions sit below the command blocks.
+    assert_eq!(
+        Extent::MinMax(Vec3::new(1, 0, 0), Vec3::new(1, 1, 1)),
+        layout.extent());
+}
+
+#[test]
+fn test_obstacles() {
+    use commands::Command;
+    use fab;
+
+    let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+    let items = vec!(
+        Label("foo".to_string()), Complete(block.clone()), Complete(block.clone()), Terminal,
+        Label("bar".to_string()), Complete(block), Terminal);
+
+    // The first power position is taken, and so is the command block spot
+    // east of the second.
+    let mut obstacles = Obstacles::new();
+    obstacles.add_block(Vec3::new(1, 1, 0));
+    obstacles.add_block(Vec3::new(2, 1, 1));
+    let mut motion = PackedMotion::new(Vec3::new(0, 0, 0));
+    motion.set_obstacles(Rc::new(obstacles));
+    let mut layout = Layout::new(Box::new(motion), items.into_iter());
+    let placed: Vec<Vec3> = (&mut layout).map(|(pos, _)| pos).collect();
+
+    assert_eq!(
+        Some(Extent::MinMax(Vec3::new(1, 1, 2), Vec3::new(1, 1, 2))),
+        layout.get_power_extent("foo"));
+    assert_eq!(vec!(Vec3::new(0, 1, 2), Vec3::new(2, 1, 2), Vec3::new(0, 1, 3)), placed);
+    assert_eq!(
+        Some(Extent::MinMax(Vec3::new(1, 1, 3), Vec3::new(1, 1, 3))),
+        layout.get_power_extent("bar"));
+}
+
+#[test]
+fn test_stable_lines() {
+    use commands::Command;
+    use fab;
+
+    fn lay_out(labels: &[&str], previous: HashMap<String, i32>) -> Vec<(String, Vec3)> {
+        let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+        let mut items = vec!();
+        for label in labels.iter() {
+            items.push(Label(label.to_string()));
+            items.push(Complete(block.clone()));
+            items.push(Terminal);
+        }
+        let motion = Box::new(StableMotion::new(Vec3::new(0, 0, 0), previous));
+        let mut layout = Layout::new(motion, items.into_iter());
+        for _ in &mut layout { }
+        layout.lines().to_vec()
+    }
+
+    let before = lay_out(&["foo", "@br_cont_0", "bar"], HashMap::new());
+    assert_eq!(
+        vec!(("foo".to_string(), Vec3::new(1, 0, 0)),
+             ("foo+0".to_string(), Vec3::new(3, 0, 0)),
+             ("bar".to_string(), Vec3::new(5, 0, 0))),
+        before);
+
+    // A new line in the middle goes on the end, and the others stay put.
+    let previous = before.iter().map(|&(ref n, p)| (n.clone(), p.x)).collect();
+    let after = lay_out(&["foo", "baz", "bar"], previous);
+    assert_eq!(
+        vec!(("foo".to_string(), Vec3::new(1, 0, 0)),
+             ("baz".to_string(), Vec3::new(7, 0, 0)),
+             ("bar".to_string(), Vec3::new(5, 0, 0))),
+        after);
+}
+
+#[test]
+fn test_layouts_clear_computer() {
+    use commands::Command;
+    use fab;
+    use hw::{Computer, ReservationMap};
+
+    let origin = Vec3::new(10, 4, 20);
+    let computer = Computer::new(origin);
+    let mut reservations = ReservationMap::new();
+    computer.reserve(&mut reservations).unwrap();
+    // Where the computer entity and the bit_N entities stand.
+    let entities: Vec<Extent> = (0..32)
+        .map(|i| Vec3::new(origin.x, origin.y, origin.z + i))
+        .map(|pos| Extent::MinMax(pos, pos))
+        .collect();
+    assert_eq!(1, reservations.conflicts_any(&entities[..]).len());
+
+    let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+    let mut items = vec!();
+    for label in ["foo", "bar", "baz"].iter() {
+        items.push(Label(label.to_string()));
+        for _ in 0..40 {
+            items.push(Complete(block.clone()));
+        }
+        items.push(Terminal);
+    }
+    let motions: Vec<Box<LayoutMotion>> = vec!(
+        Box::new(LinearMotion::new(origin)),
+        Box::new(PackedMotion::new(origin)),
+        Box::new(StableMotion::new(origin, HashMap::new())));
+    for motion in motions.into_iter() {
+        let mut layout = Layout::new(motion, items.clone().into_iter());
+        let mut used: Vec<Extent> =
+            (&mut layout).map(|(pos, _)| Extent::MinMax(pos, pos)).collect();
+        used.extend(layout.power_extents().into_iter().map(|(_, e)| e));
+        for entity in entities.iter() {
+            assert!(!used.iter().any(|e| e.intersects(entity)), "{}", entity);
+        }
+        assert_eq!(0, reservations.conflicts_any(&used[..]).len());
+    }
+}
@@ -3,52 +3,70 @@
 
 use self::Token::*;
 
-use regex::Regex;
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::fmt;
 
-// FIXME: Work out the lifetime issues and use &'a str for value, then
-//        re-derive Copy.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum Token {
-    Newline(String),
+// Tokens borrow their text from the input, so lexing does not allocate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    Newline(&'a str),
     Comma,
     LBrace,
     RBrace,
     LBracket,
     RBracket,
     Star,
+    Equals,
     LParen,
     RParen,
-    LitStr(String),
-    LitInt(String),
-    Selector(String),
-    Attr(String),
-    Label(String),
-    LabelRef(String),
-    Meta(String),
-    Ident(String),
-    GenReg(String),
-    PredReg(String),
-    SpecReg(String),
-    Def(String, String),
-    Raw(Vec<(char, Token)>, String),
+    Hash,
+    Plus,
+    Minus,
+    Slash,
+    Percent,
+    Amp,
+    Pipe,
+    Tilde,
+    Shl,
+    Shr,
+    LitStr(&'a str),
+    // An integer, in decimal, hex (0x) or binary (0b).  It starts with # when
+    // it is an immediate on its own, rather than part of an expression.
+    LitInt(&'a str),
+    LitChar(&'a str),
+    Selector(&'a str),
+    Attr(&'a str),
+    Label(&'a str),
+    LabelRef(&'a str),
+    Meta(&'a str),
+    Ident(&'a str),
+    GenReg(&'a str),
+    PredReg(&'a str),
+    SpecReg(&'a str),
+    Def(&'a str, &'a str),
+    // The letters of the outputs after raw~, the registers they go to, and
+    // the command.  See raw_outs.
+    Raw(&'a str, &'a str, &'a str),
     Eof,
     Error,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Spanned<T, Loc> {
     pub item: T,
     pub start: Loc,
     pub end: Loc,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, RustcEncodable, RustcDecodable)]
 pub struct Location {
     pub offset: usize,
     pub col: usize,
     pub line: usize,
+    // Which file the location is in, when there is more than one: 0 for the
+    // file being read, and the others in the order they were included.
+    pub file: usize,
 }
 
 impl fmt::Display for Location {
@@ -57,7 +75,76 @@ impl fmt::Display for Location {
     }
 }
 
-pub type SpannedToken = Spanned<Token, Location>;
+// Tokens display as the source text they were lexed from, give or take the
+// spacing inside def and raw.
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Comma => f.write_str(","),
+            LBrace => f.write_str("{"),
+            RBrace => f.write_str("}"),
+            LBracket => f.write_str("["),
+            RBracket => f.write_str("]"),
+            Star => f.write_str("*"),
+            Equals => f.write_str("="),
+            LParen => f.write_str("("),
+            RParen => f.write_str(")"),
+            Hash => f.write_str("#"),
+            Plus => f.write_str("+"),
+            Minus => f.write_str("-"),
+            Slash => f.write_str("/"),
+            Percent => f.write_str("%"),
+            Amp => f.write_str("&"),
+            Pipe => f.write_str("|"),
+            Tilde => f.write_str("~"),
+            Shl => f.write_str("<<"),
+            Shr => f.write_str(">>"),
+            Newline(s) | LitStr(s) | LitInt(s) | LitChar(s) | Selector(s) | Attr(s) | Label(s) |
+            LabelRef(s) | Meta(s) | Ident(s) | GenReg(s) | PredReg(s) | SpecReg(s) => {
+                f.write_str(s)
+            }
+            Def(name, value) => write!(f, "def {}, {}", name, value),
+            Raw("", _, cmd) => write!(f, "raw {}", cmd),
+            Raw(chars, regs, cmd) => {
+                let regs: Vec<String> =
+                    raw_outs(chars, regs).iter().map(|o| o.1.to_string()).collect();
+                write!(f, "raw~{} {}, {}", chars, regs.connect(", "), cmd)
+            }
+            Eof | Error => Ok(()),
+        }
+    }
+}
+
+// The registers that a raw command's outputs are stored in, with the letter
+// that names each output.  The lexer has already checked them.
+pub fn raw_outs<'a>(chars: &'a str, regs: &'a str) -> Vec<(char, Token<'a>)> {
+    chars.chars()
+        .zip(regs.split(',').map(|reg| classify_register(reg.trim()).unwrap()))
+        .collect()
+}
+
+// Text that tokens borrow, other than the input: the files that the input
+// includes, and what macros expand to.  It lives as long as the input, and is
+// freed along with the arena.
+pub struct Arena {
+    strings: RefCell<Vec<String>>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena { strings: RefCell::new(vec!()) }
+    }
+
+    pub fn alloc(&self, s: String) -> &str {
+        let text: *const str = &s[..];
+        self.strings.borrow_mut().push(s);
+        // The text is on the heap, so it stays put when the vector grows, and
+        // it is neither changed nor freed until the arena is.
+        unsafe { &*text }
+    }
+}
+
+pub type SpannedToken<'a> = Spanned<Token<'a>, Location>;
 
 pub type LexResult<T> = Result<T, String>;
 struct StateFn(fn(&mut Lexer) -> StateFn);
@@ -68,8 +155,9 @@ pub struct Lexer<'a> {
     pos: usize,
     width: usize,
     mark: Location,
+    file: usize,
     state: StateFn,
-    tokbuf: VecDeque<LexResult<SpannedToken>>,
+    tokbuf: VecDeque<LexResult<SpannedToken<'a>>>,
     line: usize,
 
     line_start: usize,
@@ -82,7 +170,8 @@ impl<'a> Lexer<'a> {
             filename: filename,
             pos: 0,
             width: 0,
-            mark: Location { offset: 0, col: 0, line: 0 },
+            mark: Location { offset: 0, col: 0, line: 0, file: 0 },
+            file: 0,
             state: StateFn(lex_start),
             tokbuf: VecDeque::new(),
             line: 0,
@@ -94,7 +183,17 @@ impl<'a> Lexer<'a> {
         Lexer::new(input, "<memory>")
     }
 
-    pub fn next(&mut self) -> LexResult<SpannedToken> {
+    pub fn filename(&self) -> &'a str {
+        self.filename
+    }
+
+    // Marks the locations of the tokens as being in another file.
+    pub fn set_file(&mut self, file: usize) {
+        self.file = file;
+        self.mark.file = file;
+    }
+
+    pub fn next(&mut self) -> LexResult<SpannedToken<'a>> {
         loop {
             if let Some(tok) = self.tokbuf.pop_front() {
                 return tok;
@@ -104,17 +203,19 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn rest(&self) -> &str {
-        &self.input[self.pos..]
+    fn rest(&self) -> &'a str {
+        let input = self.input;
+        &input[self.pos..]
     }
 
-    fn piece(&self) -> &str {
+    fn piece(&self) -> &'a str {
+        let input = self.input;
         let end = self.pos + self.width;
-        &self.input[self.mark.offset..end]
+        &input[self.mark.offset..end]
     }
 
     fn accept<Pred>(&mut self, pred: Pred) -> Option<char>
-        where Pred : FnOnce(char) -> LexResult<()>
+        where Pred : FnOnce(char) -> bool
     {
         match self.rest().chars().next() {
             None => {
@@ -122,25 +223,20 @@ impl<'a> Lexer<'a> {
                 self.width = 0;
                 None
             }
-            Some(c) => {
-                match pred(c) {
-                    Ok(_) => {
-                        self.pos += c.len_utf8();
-                        Some(c)
-                    }
-                    Err(_) => None,
-                }
+            Some(c) if pred(c) => {
+                self.pos += c.len_utf8();
+                Some(c)
             }
+            Some(_) => None,
         }
     }
 
     fn accept_any(&mut self) -> Option<char> {
-        self.accept(move |_| Ok(()))
+        self.accept(|_| true)
     }
 
     fn accept_char(&mut self, c: char) -> bool {
-        let pred = move |x| if x == c { Ok(()) } else { Err("".to_string()) };
-        self.accept(pred).is_some()
+        self.accept(|x| x == c).is_some()
     }
 
     fn loc(&self) -> Location {
@@ -148,10 +244,11 @@ impl<'a> Lexer<'a> {
             offset: self.pos,
             col: self.pos - self.line_start,
             line: self.line,
+            file: self.file,
         }
     }
 
-    fn emit(&mut self, token: Token) {
+    fn emit(&mut self, token: Token<'a>) {
         let end = self.loc();
         let spanned = SpannedToken {
             item: token,
@@ -175,59 +272,115 @@ impl<'a> Lexer<'a> {
     }
 
     fn expect_char(&mut self, c: char) -> LexResult<()> {
-        self.expect(move |x: char| {
-            if x == c {
-                Ok(())
-            } else {
-                // FIXME: Change to a different kind of Result to allow
-                //        formatting an error message.
-                Err(format!("expected '{}' but found '{}'", c, x))
-            }
-        })
+        match self.rest().chars().next() {
+            Some(x) if x == c => { self.accept_any(); Ok(()) }
+            Some(x) => Err(format!("expected '{}' but found '{}'", c, x)),
+            None => Err("unexpected eof".to_string()),
+        }
     }
 
-    fn expect<Pred>(&mut self, pred: Pred) -> LexResult<()>
-        where Pred : FnOnce(char) -> LexResult<()>
+    // Accepts a character that matches pred, which is described by what, for
+    // the error.
+    fn expect<Pred>(&mut self, pred: Pred, what: &str) -> LexResult<()>
+        where Pred : FnOnce(char) -> bool
     {
         match self.rest().chars().next() {
+            Some(c) if pred(c) => { self.accept_any(); Ok(()) }
+            Some(c) => Err(format!("expected {} but found '{}'", what, c)),
             None => Err("unexpected eof".to_string()),
-            Some(c) => {
-                let res = pred(c);
-                if res.is_ok() {
-                    self.accept_any();
-                }
-                res
-            }
         }
     }
 
     fn zero_or_more<Pred>(&mut self, pred: Pred)
-        where Pred : Fn(char) -> LexResult<()>
+        where Pred : Fn(char) -> bool
     {
-        loop {
-            match self.rest().chars().next() {
-                None => { break; }
-                Some(c) => match pred(c) {
-                    Ok(_) => { self.accept_any(); }
-                    Err(_) => { break; }
-                }
-            }
-        }
+        while self.accept(&pred).is_some() { }
     }
 
-    fn one_or_more<Pred>(&mut self, pred: Pred) -> LexResult<()>
-        where Pred : Fn(char) -> LexResult<()>
+    fn one_or_more<Pred>(&mut self, pred: Pred, what: &str) -> LexResult<()>
+        where Pred : Fn(char) -> bool
     {
-        try!(self.expect(&pred));
+        try!(self.expect(&pred, what));
         self.zero_or_more(pred);
         Ok(())
     }
 }
 
-pub fn unquote(s: &str) -> String {
-    // FIXME: Process escape codes, verify existence of opening and closing ",
-    //        return Option<String> or a Result type.
-    s[1..s.len()-1].to_string()
+// Reads the escape after a backslash, at the start of s: one of \n \r \t \0
+// \\ \' \", or \u{...} with up to six hex digits.  Returns the character and
+// how many characters the escape took, not counting the backslash.
+pub fn unescape_char(s: &str) -> Result<(char, usize), String> {
+    let mut chars = s.chars();
+    let c = match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c,
+        Some('u') => {
+            if chars.next() != Some('{') {
+                return Err("expected '{' after \\u".to_string());
+            }
+            let digits: String = chars.by_ref().take_while(|&c| c != '}').collect();
+            if digits.is_empty() || digits.len() > 6 || !digits.chars().all(is_hex_digit) {
+                return Err(format!("invalid escape \\u{{{}", digits));
+            }
+            let code = u32::from_str_radix(&digits[..], 16).unwrap();
+            return match char::from_u32(code) {
+                Some(c) if s[2 + digits.len()..].starts_with("}") => Ok((c, 3 + digits.len())),
+                Some(_) => Err(format!("expected '}}' after \\u{{{}", digits)),
+                None => Err(format!("\\u{{{}}} is not a character", digits)),
+            };
+        }
+        Some(c) => return Err(format!("unknown escape \\{}", c)),
+        None => return Err("expected an escape after \\".to_string()),
+    };
+    Ok((c, 1))
+}
+
+// Replaces the escapes in s with the characters they stand for.
+pub fn unescape(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            let (c, len) = try!(unescape_char(&s[i + 1..]));
+            out.push(c);
+            for _ in 0..len {
+                chars.next();
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    Ok(out)
+}
+
+// The text of a string literal, quotes and all.
+pub fn unquote(s: &str) -> Result<String, String> {
+    if s.len() < 2 || !s.starts_with("\"") || !s.ends_with("\"") {
+        return Err(format!("{} is not a quoted string", s));
+    }
+    unescape(&s[1..s.len() - 1])
+}
+
+// The string literal for s, which unquote turns back into s.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => { out.push('\\'); out.push(c); }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)[..]),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
 
 macro_rules! try_lex {
@@ -242,69 +395,73 @@ macro_rules! try_lex {
     }
 }
 
-fn is_whitespace(c: char) -> LexResult<()> {
-    if c.is_whitespace() {
-        Ok(())
-    } else {
-        Err(format!("expected whitespace but found '{}'", c))
-    }
+// Newlines are tokens of their own, so they are not whitespace here.
+fn is_whitespace(c: char) -> bool {
+    c.is_whitespace() && c != '\r' && c != '\n'
 }
 
-fn is_alpha(c: char) -> LexResult<()> {
+fn is_alpha(c: char) -> bool {
     match c {
-        'a' ... 'z' | 'A' ... 'Z' => Ok(()),
-        _ => Err(format!("expected alpha but found '{}'", c)),
+        'a' ... 'z' | 'A' ... 'Z' => true,
+        _ => false,
     }
 }
 
-fn is_digit(c: char) -> LexResult<()> {
+fn is_digit(c: char) -> bool {
     match c {
-        '0' ... '9' => Ok(()),
-        _ => Err(format!("expected digit but found '{}'", c)),
+        '0' ... '9' => true,
+        _ => false,
     }
 }
 
-fn is_ident_start(c: char) -> LexResult<()> {
+fn is_ident_start(c: char) -> bool {
     match c {
-        'a'...'z' | 'A'...'Z' | '_' | '.' | '$' => Ok (()),
-        _ => Err(format!("expected the start of an ident but found '{}'", c)),
+        'a'...'z' | 'A'...'Z' | '_' | '.' | '$' => true,
+        _ => false,
     }
 }
 
-fn is_ident_rest(c: char) -> LexResult<()> {
+fn is_ident_rest(c: char) -> bool {
     match c {
-        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' | '.' | '$' => Ok (()),
-        _ => Err(format!("expected the rest of an ident but found '{}'", c)),
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' | '.' | '$' => true,
+        _ => false,
     }
 }
 
-fn is_not_newline(c: char) -> LexResult<()> {
+fn is_hex_digit(c: char) -> bool {
     match c {
-        '\r' | '\n' => Err("".to_string()),
-        _ => Ok(()),
+        '0' ... '9' | 'a' ... 'f' | 'A' ... 'F' => true,
+        _ => false,
     }
 }
 
-fn is_selector_start(c: char) -> LexResult<()> {
+fn is_bin_digit(c: char) -> bool {
+    c == '0' || c == '1'
+}
+
+fn is_not_newline(c: char) -> bool {
+    c != '\r' && c != '\n'
+}
+
+fn is_selector_start(c: char) -> bool {
     match c {
-        'a' | 'p' | 'r' | 'e' => Ok (()),
-        _ => Err("".to_string())
+        'a' | 'p' | 'r' | 'e' => true,
+        _ => false,
     }
 }
 
-static GEN_REG_REGEX : Regex = regex!(r"^r\d+$");
-static PRED_REG_REGEX : Regex = regex!(r"^p\d+$");
+// Whether s is the prefix followed by a number, like r12.
+fn is_numbered(s: &str, prefix: char) -> bool {
+    s.len() > 1 && s.starts_with(prefix) && s[1..].chars().all(is_digit)
+}
 
 fn classify_register(s: &str) -> Option<Token> {
-    if GEN_REG_REGEX.is_match(s) {
-        let reg = s.to_string();
-        Some(GenReg(reg))
-    } else if PRED_REG_REGEX.is_match(s) {
-        let reg = s.to_string();
-        Some(PredReg(reg))
+    if is_numbered(s, 'r') {
+        Some(GenReg(s))
+    } else if is_numbered(s, 'p') {
+        Some(PredReg(s))
     } else if s == "lr" || s == "sp" {
-        let reg = s.to_string();
-        Some(SpecReg(reg))
+        Some(SpecReg(s))
     } else {
         None
     }
@@ -319,16 +476,26 @@ fn lex_start(lexer: &mut Lexer) -> StateFn {
             '(' => { lexer.accept_any(); lexer.emit(LParen); StateFn(lex_start) }
             ')' => { lexer.accept_any(); lexer.emit(RParen); StateFn(lex_start) }
             '*' => { lexer.accept_any(); lexer.emit(Star); StateFn(lex_start) }
+            '+' => { lexer.accept_any(); lexer.emit(Plus); StateFn(lex_start) }
+            '-' => { lexer.accept_any(); lexer.emit(Minus); StateFn(lex_start) }
+            '/' => { lexer.accept_any(); lexer.emit(Slash); StateFn(lex_start) }
+            '%' => { lexer.accept_any(); lexer.emit(Percent); StateFn(lex_start) }
+            '&' => { lexer.accept_any(); lexer.emit(Amp); StateFn(lex_start) }
+            '|' => { lexer.accept_any(); lexer.emit(Pipe); StateFn(lex_start) }
+            '~' => { lexer.accept_any(); lexer.emit(Tilde); StateFn(lex_start) }
+            '<' | '>' => { StateFn(lex_shift) }
             ',' => { lexer.accept_any(); lexer.emit(Comma); StateFn(lex_start) }
             '[' => { lexer.accept_any(); lexer.emit(LBracket); StateFn(lex_start) }
             ']' => { lexer.accept_any(); lexer.emit(RBracket); StateFn(lex_start) }
             ';' => { StateFn(lex_comment) }
             '"' => { StateFn(lex_lit_str) }
             '#' => { StateFn(lex_lit_int) }
+            '\'' => { StateFn(lex_lit_char) }
+            '0' ... '9' => { StateFn(lex_number) }
             '@' => { StateFn(lex_selector_or_attr) }
             '\r' | '\n' => { StateFn(lex_newline) }
             '=' => { StateFn(lex_label_ref) }
-            c if is_whitespace(c).is_ok() => { StateFn(lex_whitespace) }
+            c if is_whitespace(c) => { StateFn(lex_whitespace) }
             _ => { StateFn(lex_ident_like) }
         }
     }
@@ -336,65 +503,165 @@ fn lex_start(lexer: &mut Lexer) -> StateFn {
 
 fn lex_lit_str(lexer: &mut Lexer) -> StateFn {
     try_lex!(lexer, lexer.expect_char('"'));
-    // FIXME: support escapes
-    lexer.zero_or_more(|c| if c != '"' {
-        Ok(())
-    } else {
-        Err("".to_string())
-    });
-    try_lex!(lexer, lexer.expect_char('"'));
+    loop {
+        match lexer.accept(is_not_newline) {
+            Some('"') => break,
+            Some('\\') => try_lex!(lexer, lex_escape(lexer)),
+            Some(_) => { }
+            None => {
+                lexer.error("unterminated string".to_string());
+                return StateFn(lex_error);
+            }
+        }
+    }
 
-    let litstr = lexer.piece().to_string();
+    let litstr = lexer.piece();
     lexer.emit(LitStr(litstr));
     StateFn(lex_start)
 }
 
+// Checks the escape after a backslash, and accepts it.
+fn lex_escape(lexer: &mut Lexer) -> LexResult<()> {
+    let (_, len) = try!(unescape_char(lexer.rest()));
+    for _ in 0..len {
+        lexer.accept_any();
+    }
+    Ok(())
+}
+
+// An immediate is # and either a plain integer, which is a single token, or
+// an expression, which starts with a Hash of its own.
 fn lex_lit_int(lexer: &mut Lexer) -> StateFn {
     try_lex!(lexer, lexer.expect_char('#'));
+    let number = {
+        let rest = lexer.rest();
+        let rest = if rest.starts_with("-") { &rest[1..] } else { rest };
+        rest.chars().next().map_or(false, is_digit)
+    };
+    if !number {
+        lexer.emit(Hash);
+        return StateFn(lex_start);
+    }
     lexer.accept_char('-');
-    try_lex!(lexer, lexer.one_or_more(is_digit));
+    lex_number(lexer)
+}
+
+fn lex_number(lexer: &mut Lexer) -> StateFn {
+    let radix = if lexer.rest().starts_with("0x") || lexer.rest().starts_with("0X") {
+        16
+    } else if lexer.rest().starts_with("0b") || lexer.rest().starts_with("0B") {
+        2
+    } else {
+        10
+    };
+    if radix == 10 {
+        try_lex!(lexer, lexer.one_or_more(is_digit, "digit"));
+    } else {
+        lexer.accept_any();
+        lexer.accept_any();
+        let (digit, what): (fn(char) -> bool, _) = if radix == 16 {
+            (is_hex_digit, "hex digit")
+        } else {
+            (is_bin_digit, "binary digit")
+        };
+        try_lex!(lexer, lexer.one_or_more(digit, what));
+    }
+    if let Some(c) = lexer.rest().chars().next() {
+        if is_ident_rest(c) {
+            let err = format!("unexpected '{}' in a number", c);
+            lexer.error(err);
+            return StateFn(lex_error);
+        }
+    }
 
-    let litint = lexer.piece().to_string();
+    let litint = lexer.piece();
     lexer.emit(LitInt(litint));
     StateFn(lex_start)
 }
 
+fn lex_lit_char(lexer: &mut Lexer) -> StateFn {
+    try_lex!(lexer, lexer.expect_char('\''));
+    if lexer.accept_char('\\') {
+        try_lex!(lexer, lex_escape(lexer));
+    } else {
+        try_lex!(lexer, lexer.expect(|c| c != '\'' && is_not_newline(c), "a character"));
+    }
+    try_lex!(lexer, lexer.expect_char('\''));
+
+    let litchar = lexer.piece();
+    lexer.emit(LitChar(litchar));
+    StateFn(lex_start)
+}
+
+fn lex_shift(lexer: &mut Lexer) -> StateFn {
+    if lexer.accept_char('<') {
+        try_lex!(lexer, lexer.expect_char('<'));
+        lexer.emit(Shl);
+    } else {
+        try_lex!(lexer, lexer.expect_char('>'));
+        try_lex!(lexer, lexer.expect_char('>'));
+        lexer.emit(Shr);
+    }
+    StateFn(lex_start)
+}
+
 fn lex_selector_or_attr(lexer: &mut Lexer) -> StateFn {
     try_lex!(lexer, lexer.expect_char('@'));
 
     if let Some(_) = lexer.accept(is_selector_start) {
         if lexer.accept(is_ident_rest).is_some() {
             lexer.zero_or_more(is_ident_rest);
-            let attr = lexer.piece().to_string();
+            let attr = lexer.piece();
             lexer.emit(Attr(attr));
         } else {
             if lexer.accept_char('[') {
-                // FIXME: Support quoted ]
-                lexer.zero_or_more(|c| if c != ']' { Ok (()) } else { Err("".to_string()) });
-                try_lex!(lexer, lexer.expect_char(']'));
+                try_lex!(lexer, lex_selector_args(lexer));
             }
-            let selector = lexer.piece().to_string();
+            let selector = lexer.piece();
             lexer.emit(Selector(selector));
         }
     } else {
-        try_lex!(lexer, lexer.expect(is_ident_start));
+        try_lex!(lexer, lexer.expect(is_ident_start, "the start of an ident"));
         lexer.zero_or_more(is_ident_rest);
 
-        let attr = lexer.piece().to_string();
+        let attr = lexer.piece();
         lexer.emit(Attr(attr));
     }
     StateFn(lex_start)
 }
 
+// Accepts the arguments of a selector, up to the ] that closes them.  They
+// may hold brackets of their own, and quoted strings with ] in them.
+fn lex_selector_args(lexer: &mut Lexer) -> LexResult<()> {
+    let mut depth = 1;
+    while depth > 0 {
+        match lexer.accept(is_not_newline) {
+            Some('[') => depth += 1,
+            Some(']') => depth -= 1,
+            Some('"') => loop {
+                match lexer.accept(is_not_newline) {
+                    Some('"') => break,
+                    Some('\\') => try!(lex_escape(lexer)),
+                    Some(_) => { }
+                    None => return Err("unterminated string in selector".to_string()),
+                }
+            },
+            Some(_) => { }
+            None => return Err("unterminated selector".to_string()),
+        }
+    }
+    Ok(())
+}
+
 fn lex_ident_like(lexer: &mut Lexer) -> StateFn {
-    try_lex!(lexer, lexer.expect(is_ident_start));
+    try_lex!(lexer, lexer.expect(is_ident_start, "the start of an ident"));
     lexer.zero_or_more(is_ident_rest);
     if lexer.accept_char(':') {
-        let label = lexer.piece().to_string();
+        let label = lexer.piece();
         lexer.emit(Label(label));
     } else {
         if lexer.piece().starts_with(".") {
-            let meta = lexer.piece().to_string();
+            let meta = lexer.piece();
             lexer.emit(Meta(meta));
         } else if lexer.piece() == "def" {
             return StateFn(lex_def)
@@ -403,7 +670,7 @@ fn lex_ident_like(lexer: &mut Lexer) -> StateFn {
         } else if let Some(reg) = classify_register(lexer.piece()) {
             lexer.emit(reg);
         } else {
-            let ident = lexer.piece().to_string();
+            let ident = lexer.piece();
             lexer.emit(Ident(ident));
         }
     }
@@ -414,13 +681,13 @@ fn lex_ident_like(lexer: &mut Lexer) -> StateFn {
 fn lex_def(lexer: &mut Lexer) -> StateFn {
     // FIXME: Assert that lexer.piece() == "def"
     lexer.skip();
-    try_lex!(lexer, lexer.one_or_more(is_whitespace));
+    try_lex!(lexer, lexer.one_or_more(is_whitespace, "whitespace"));
     lexer.skip();
 
-    try_lex!(lexer, lexer.expect(is_ident_start));
+    try_lex!(lexer, lexer.expect(is_ident_start, "the start of an ident"));
     lexer.zero_or_more(is_ident_rest);
 
-    let name = lexer.piece().to_string();
+    let name = lexer.piece();
     lexer.skip();
 
     lexer.zero_or_more(is_whitespace);
@@ -429,7 +696,7 @@ fn lex_def(lexer: &mut Lexer) -> StateFn {
     lexer.skip();
 
     lexer.zero_or_more(is_not_newline);
-    let value = lexer.piece().to_string();
+    let value = lexer.piece();
 
     lexer.emit(Def(name, value));
     StateFn(lex_start)
@@ -439,63 +706,65 @@ fn lex_raw(lexer: &mut Lexer) -> StateFn {
     // FIXME: Assert that lexer.piece() == "raw"
     lexer.skip();
 
-    let mut out_chars = vec!();
+    let mut out_chars = "";
     if lexer.accept_char('~') {
         lexer.skip();
-
-        while let Some(c) = lexer.accept(is_alpha) {
-            out_chars.push(c);
-        }
+        lexer.zero_or_more(is_alpha);
+        out_chars = lexer.piece();
         lexer.skip();
     }
 
-    try_lex!(lexer, lexer.one_or_more(is_whitespace));
+    try_lex!(lexer, lexer.one_or_more(is_whitespace, "whitespace"));
     lexer.skip();
 
-    let mut outs = vec!();
-    for c in out_chars.into_iter() {
+    let input = lexer.input;
+    let regs_start = lexer.pos;
+    let mut regs_end = lexer.pos;
+    for _ in out_chars.chars() {
         lexer.zero_or_more(is_whitespace);
-        lexer.skip();
-
-        try_lex!(lexer, lexer.expect(is_ident_start));
+        let reg_start = lexer.pos;
+        try_lex!(lexer, lexer.expect(is_ident_start, "the start of an ident"));
         lexer.zero_or_more(is_ident_rest);
-        if let Some(reg) = classify_register(lexer.piece()) {
-            outs.push((c, reg));
-            lexer.skip();
-        } else {
-            let err = format!("expected register but found '{}'", lexer.piece());
+        regs_end = lexer.pos;
+        let reg = &input[reg_start..regs_end];
+        if classify_register(reg).is_none() {
+            let err = format!("expected register but found '{}'", reg);
             lexer.error(err);
             return StateFn(lex_error)
         }
 
         lexer.zero_or_more(is_whitespace);
-        lexer.skip();
-
         try_lex!(lexer, lexer.expect_char(','));
     }
+    let regs = &input[regs_start..regs_end];
 
     lexer.zero_or_more(is_whitespace);
     lexer.skip();
 
     lexer.zero_or_more(is_not_newline);
 
-    let raw = lexer.piece().to_string();
-    lexer.emit(Raw(outs, raw));
+    let raw = lexer.piece();
+    lexer.emit(Raw(out_chars, regs, raw));
     StateFn(lex_start)
 }
 
 fn lex_label_ref(lexer: &mut Lexer) -> StateFn {
     try_lex!(lexer, lexer.expect_char('='));
-    try_lex!(lexer, lexer.expect(is_ident_start));
+    // A lone = separates a macro parameter from its default value.
+    if lexer.rest().chars().next().map_or(true, |c| !is_ident_start(c)) {
+        lexer.emit(Equals);
+        return StateFn(lex_start);
+    }
+    try_lex!(lexer, lexer.expect(is_ident_start, "the start of an ident"));
     lexer.zero_or_more(is_ident_rest);
 
-    let label_ref = lexer.piece().to_string();
+    let label_ref = lexer.piece();
     lexer.emit(LabelRef(label_ref));
     StateFn(lex_start)
 }
 
 fn lex_whitespace(lexer: &mut Lexer) -> StateFn {
-    try_lex!(lexer, lexer.one_or_more(is_whitespace));
+    try_lex!(lexer, lexer.one_or_more(is_whitespace, "whitespace"));
     lexer.skip();
 
     StateFn(lex_start)
@@ -505,11 +774,13 @@ fn lex_newline(lexer: &mut Lexer) -> StateFn {
     lexer.accept_char('\r');
     try_lex!(lexer, lexer.expect_char('\n'));
 
-    let newline = lexer.piece().to_string();
+    let newline = lexer.piece();
     lexer.emit(Newline(newline));
 
     lexer.line += 1;
     lexer.line_start = lexer.pos;
+    // The next token starts on the new line.
+    lexer.mark = lexer.loc();
 
     StateFn(lex_start)
 }
@@ -555,105 +826,173 @@ fn test_punctuation() {
 
 #[test]
 fn test_label_ref() {
-    assert_eq!(first_tok!("=foo"), LabelRef("=foo".to_string()));
+    assert_eq!(first_tok!("=foo"), LabelRef("=foo"));
+}
+
+#[test]
+fn test_equals() {
+    let mut lexer = Lexer::mem("n=#1");
+    assert_eq!(lexer.next().unwrap().item, Ident("n"));
+    assert_eq!(lexer.next().unwrap().item, Equals);
+    assert_eq!(lexer.next().unwrap().item, LitInt("#1"));
 }
 
 #[test]
 fn test_label() {
-    assert_eq!(first_tok!("foo:"), Label("foo:".to_string()));
+    assert_eq!(first_tok!("foo:"), Label("foo:"));
 }
 
 #[test]
 fn test_ident() {
-    assert_eq!(first_tok!("add"), Ident("add".to_string()));
+    assert_eq!(first_tok!("add"), Ident("add"));
 }
 
 #[test]
 fn test_def() {
     assert_eq!(
         first_tok!("def foo, bar"),
-        Def("foo".to_string(), "bar".to_string()));
+        Def("foo", "bar"));
 }
 
 #[test]
 fn test_raw() {
     assert_eq!(
         first_tok!("raw foo bar baz"),
-        Raw(vec!(), "foo bar baz".to_string()));
+        Raw("", "", "foo bar baz"));
 }
 
 #[test]
 fn test_raw_outs() {
-    let outs = vec!(
-        ('s', GenReg("r0".to_string())),
-        ('a', GenReg("r1".to_string())),
-        ('i', GenReg("r2".to_string())));
     assert_eq!(
-        first_tok!("raw~sai r0, r1, r2, foo bar baz"),
-        Raw(outs, "foo bar baz".to_string()));
+        first_tok!("raw~sai r0,r1 , r2, foo bar baz"),
+        Raw("sai", "r0,r1 , r2", "foo bar baz"));
+    assert_eq!(
+        vec!(('s', GenReg("r0")), ('a', GenReg("r1")), ('i', GenReg("r2"))),
+        raw_outs("sai", "r0,r1 , r2"));
 }
 
 #[test]
 fn test_lit_str() {
     assert_eq!(
         first_tok!("\"foo bar baz\""),
-        LitStr("\"foo bar baz\"".to_string()));
+        LitStr("\"foo bar baz\""));
+}
+
+#[test]
+fn test_string_escapes() {
+    assert_eq!(first_tok!(r#""a \"b\" \\ \u{263a}""#), LitStr(r#""a \"b\" \\ \u{263a}""#));
+    assert_eq!(Ok("a \"b\" \\ \u{263a}\n".to_string()), unquote(r#""a \"b\" \\ \u{263a}\n""#));
+    assert!(Lexer::mem(r#""\q""#).next().is_err());
+    assert!(Lexer::mem("\"open\nline\"").next().is_err());
+    assert!(unquote(r#""\u{110000}""#).is_err());
+    assert!(unquote("bare").is_err());
+
+    let text = "tab\there \"quoted\" back\\slash \u{7}";
+    assert_eq!(Ok(text.to_string()), unquote(&quote(text)[..]));
 }
 
 #[test]
 fn test_lit_int() {
-    assert_eq!(first_tok!("#219381"), LitInt("#219381".to_string()));
-    assert_eq!(first_tok!("#-100"), LitInt("#-100".to_string()));
+    assert_eq!(first_tok!("#219381"), LitInt("#219381"));
+    assert_eq!(first_tok!("#-100"), LitInt("#-100"));
+}
+
+#[test]
+fn test_expression() {
+    let tokens = |input| {
+        let mut lexer = Lexer::mem(input);
+        let mut tokens = vec!();
+        loop {
+            match lexer.next().unwrap().item {
+                Eof => return tokens,
+                token => tokens.push(token),
+            }
+        }
+    };
+    assert_eq!(vec!(LitInt("#0x1F")), tokens("#0x1F"));
+    assert_eq!(vec!(LitInt("#-0b101")), tokens("#-0b101"));
+    assert_eq!(
+        vec!(Hash, LParen, Ident("BASE"), Plus, LitInt("2"), RParen,
+             Shl, LitInt("4"), Minus, LitChar("'a'"), Pipe, Tilde,
+             LabelRef("=foo"), Shr, LitInt("0x3"), Amp, Star, Slash,
+             Percent),
+        tokens("#(BASE + 2) << 4 - 'a' | ~=foo >> 0x3 & * / %"));
+    assert_eq!(vec!(Hash, Minus, Ident("SIZE")), tokens("#-SIZE"));
+    assert_eq!(vec!(LitChar("'\\''")), tokens("'\\''"));
+    assert!(Lexer::mem("12ab").next().is_err());
 }
 
 #[test]
 fn test_selector() {
     assert_eq!(
         first_tok!("@a[score_Foo=100]"),
-        Selector("@a[score_Foo=100]".to_string()));
+        Selector("@a[score_Foo=100]"));
+}
+
+#[test]
+fn test_selector_args() {
+    assert_eq!(
+        first_tok!(r#"@e[name="a]b \"c\"",tag=[x]] rest"#),
+        Selector(r#"@e[name="a]b \"c\"",tag=[x]]"#));
+    assert!(Lexer::mem("@e[name=foo").next().is_err());
+    assert!(Lexer::mem("@e[name=\"foo]\n").next().is_err());
+}
+
+#[test]
+fn test_raw_json() {
+    assert_eq!(
+        first_tok!(r#"raw tellraw @a {"text":"a;b]","color":"red"}"#),
+        Raw("", "", r#"tellraw @a {"text":"a;b]","color":"red"}"#));
 }
 
 #[test]
 fn test_attr() {
-    assert_eq!(first_tok!("@foo"), Attr("@foo".to_string()));
-    assert_eq!(first_tok!("@aroo"), Attr("@aroo".to_string()));
+    assert_eq!(first_tok!("@foo"), Attr("@foo"));
+    assert_eq!(first_tok!("@aroo"), Attr("@aroo"));
 }
 
 #[test]
 fn test_register() {
-    assert_eq!(first_tok!("r0"), GenReg("r0".to_string()));
+    assert_eq!(first_tok!("r0"), GenReg("r0"));
 }
 
 #[test]
 fn test_predicate_reg() {
-    assert_eq!(first_tok!("p0"), PredReg("p0".to_string()));
+    assert_eq!(first_tok!("p0"), PredReg("p0"));
 }
 
 #[test]
 fn test_special_reg() {
-    assert_eq!(first_tok!("lr"), SpecReg("lr".to_string()));
+    assert_eq!(first_tok!("lr"), SpecReg("lr"));
 }
 
 #[test]
 fn test_whitespace() {
     let mut lexer = Lexer::mem("foo \tbar");
-    assert_eq!(lexer.next().unwrap().item, Ident("foo".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Ident("bar".to_string()));
+    assert_eq!(lexer.next().unwrap().item, Ident("foo"));
+    assert_eq!(lexer.next().unwrap().item, Ident("bar"));
 }
 
 #[test]
 fn test_newline() {
     let mut lexer = Lexer::mem("foo\nbar\r\n\n");
-    assert_eq!(lexer.next().unwrap().item, Ident("foo".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Newline("\n".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Ident("bar".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Newline("\r\n".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Newline("\n".to_string()));
+    assert_eq!(lexer.next().unwrap().item, Ident("foo"));
+    assert_eq!(lexer.next().unwrap().item, Newline("\n"));
+    assert_eq!(lexer.next().unwrap().item, Ident("bar"));
+    assert_eq!(lexer.next().unwrap().item, Newline("\r\n"));
+    assert_eq!(lexer.next().unwrap().item, Newline("\n"));
+
+    // Trailing whitespace does not swallow the newline after it.
+    let mut lexer = Lexer::mem("foo \n bar");
+    assert_eq!(lexer.next().unwrap().item, Ident("foo"));
+    assert_eq!(lexer.next().unwrap().item, Newline("\n"));
+    let bar = lexer.next().unwrap();
+    assert_eq!((1, 1), (bar.start.line, bar.start.col));
 }
 
 #[test]
 fn test_comment() {
     let mut lexer = Lexer::mem("; foo\nbar");
-    assert_eq!(lexer.next().unwrap().item, Newline("\n".to_string()));
-    assert_eq!(lexer.next().unwrap().item, Ident("bar".to_string()));
+    assert_eq!(lexer.next().unwrap().item, Newline("\n"));
+    assert_eq!(lexer.next().unwrap().item, Ident("bar"));
 }
@@ -1,19 +1,38 @@
 // Copyright 2015, Christopher Chambers
 // Distributed under the GNU GPL v3. See COPYING for details.
 
-// WHEN(rust-1.0): Remove feature(core), it is for the currently-unstable FnBox
-#![feature(core, plugin)]
+#![feature(plugin)]
 #![plugin(regex_macros)]
 
+extern crate flate2;
 extern crate regex;
+extern crate rustc_serialize;
 
 pub mod assembler;
 pub mod ast;
+pub mod cfg;
+pub mod check;
 pub mod commands;
+pub mod expr;
 pub mod fab;
+pub mod format;
 pub mod hw;
+pub mod ide;
 pub mod types;
 pub mod layout;
 pub mod lexer;
+pub mod link;
+pub mod lsp;
+pub mod macros;
+pub mod manifest;
 pub mod nbt;
+pub mod object;
+pub mod ordered_map;
 pub mod parser;
+pub mod profile;
+pub mod rcon;
+pub mod render;
+pub mod sim;
+pub mod stats;
+pub mod trace;
+pub mod world;
@@ -0,0 +1,317 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Combines separately parsed source files into one program.  Each file has
+// its own labels: a label is visible only in the file that defines it,
+// unless the file declares it with .global.  The entry point is always
+// global.  Libraries are only linked in when the program uses one of their
+// global labels.
+//
+// Labels keep their names where they can.  A local label is only renamed
+// when another file defines the same name, so a program in a single file
+// assembles exactly as it always has.
+
+use ast::Op::*;
+use ast::Statement::*;
+use ast::Op;
+use lexer::{Arena, Lexer, Location};
+use parser::{Parser, SpannedStatement};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Visibility {
+    Global,
+    Local,
+}
+
+pub struct Unit {
+    pub filename: String,
+    // The file and the ones it includes, indexed by Location::file.
+    pub files: Vec<String>,
+    pub stmts: Vec<SpannedStatement>,
+    pub declarations: Vec<(String, Visibility, Location)>,
+}
+
+impl Unit {
+    pub fn parse(filename: &str, input: &str) -> Unit {
+        let arena = Arena::new();
+        let mut parser = Parser::new(Lexer::new(input, filename), &arena);
+        let stmts = parser.parse_program_spanned();
+        Unit {
+            filename: filename.to_string(),
+            files: parser.files().iter().map(|f| f.to_string()).collect(),
+            stmts: stmts,
+            declarations: parser.declarations().to_vec(),
+        }
+    }
+
+    // The name of the file that a location is in.
+    pub fn file_name(&self, loc: Location) -> &str {
+        &self.files[loc.file][..]
+    }
+}
+
+// The label an instruction refers to, if any.
+pub fn label_ref_mut(op: &mut Op) -> Option<&mut String> {
+    match *op {
+        BrL(ref mut label) | BrLnkL(ref mut label) | AdrRL(_, ref mut label) |
+        LdrRL(_, ref mut label) | LdrhRL(_, ref mut label) | LdrhsRL(_, ref mut label) |
+        LdrbRL(_, ref mut label) | LdrbsRL(_, ref mut label) |
+        StrRL(_, ref mut label) | StrhRL(_, ref mut label) | StrbRL(_, ref mut label) => {
+            Some(label)
+        }
+        _ => None,
+    }
+}
+
+// What the linker needs to know about a unit's labels.
+pub struct Symbols {
+    pub filename: String,
+    pub files: Vec<String>,
+    pub defined: BTreeMap<String, Location>,
+    pub globals: HashSet<String>,
+    // Each label reference, in order.
+    pub refs: Vec<(String, Location)>,
+}
+
+fn at(syms: &Symbols, loc: Location) -> String {
+    format!("{}:{}", syms.files[loc.file], loc)
+}
+
+// Finds the labels a unit defines and refers to, and checks its
+// declarations.
+pub fn symbols(unit: &Unit, entry: &str, errors: &mut Vec<String>) -> Symbols {
+    let at = |loc: Location| format!("{}:{}", unit.file_name(loc), loc);
+    let mut defined = BTreeMap::new();
+    let mut refs = vec!();
+    for stmt in unit.stmts.iter() {
+        match stmt.item {
+            LabelStmt(ref label) => {
+                if let Some(&first) = defined.get(label) {
+                    errors.push(format!("{}: duplicate symbol '{}', first defined at {}",
+                                        at(stmt.start), label, at(first)));
+                } else {
+                    defined.insert(label.clone(), stmt.start);
+                }
+            }
+            Instr(_, ref op) => {
+                if let Some(label) = label_ref_mut(&mut op.clone()) {
+                    refs.push((label.clone(), stmt.start));
+                }
+            }
+        }
+    }
+
+    let mut globals = HashSet::new();
+    let mut locals = HashSet::new();
+    if defined.contains_key(entry) {
+        globals.insert(entry.to_string());
+    }
+    for &(ref label, visibility, loc) in unit.declarations.iter() {
+        if !defined.contains_key(label) {
+            errors.push(format!("{}: '{}' is declared but never defined", at(loc), label));
+            continue;
+        }
+        let (set, other) = match visibility {
+            Visibility::Global => (&mut globals, &locals),
+            Visibility::Local => (&mut locals, &globals),
+        };
+        if other.contains(label) && label != entry {
+            errors.push(format!("{}: '{}' is declared both .global and .local",
+                                at(loc), label));
+        }
+        set.insert(label.clone());
+    }
+    if locals.contains(entry) {
+        errors.push(format!("{}: the entry point {} cannot be .local", unit.filename, entry));
+    }
+
+    Symbols {
+        filename: unit.filename.clone(),
+        files: unit.files.clone(),
+        defined: defined,
+        globals: globals,
+        refs: refs,
+    }
+}
+
+// Decides which of the units and libraries are linked, in order, and how each
+// one's local labels are renamed.  Units are numbered before libraries.
+// Builtins are labels that the linker adds itself, which any unit may use.
+pub fn resolve(units: &[Symbols], libs: &[Symbols], entry: &str, builtins: &HashSet<String>,
+               errors: &mut Vec<String>)
+    -> Vec<(usize, HashMap<String, String>)>
+{
+    let all: Vec<&Symbols> = units.iter().chain(libs.iter()).collect();
+
+    // A library is needed when something already linked uses one of its
+    // globals without defining the label itself.
+    let mut linked: Vec<usize> = (0..units.len()).collect();
+    loop {
+        let wanted: HashSet<&String> = linked.iter()
+            .flat_map(|&i| all[i].refs.iter()
+                      .filter(|&&(ref label, _)| !all[i].defined.contains_key(label))
+                      .map(|&(ref label, _)| label)
+                      .collect::<Vec<_>>().into_iter())
+            .collect();
+        let provided: HashSet<&String> = linked.iter()
+            .flat_map(|&i| all[i].globals.iter().collect::<Vec<_>>().into_iter())
+            .collect();
+        let next = (units.len()..all.len()).find(|i| {
+            !linked.contains(i) &&
+                all[*i].globals.iter().any(|g| wanted.contains(g) && !provided.contains(g))
+        });
+        match next {
+            Some(i) => linked.push(i),
+            None => break,
+        }
+    }
+
+    // Where each global is defined.
+    let mut globals: HashMap<&str, (usize, Location)> = HashMap::new();
+    for &i in linked.iter() {
+        let mut names: Vec<&String> = all[i].globals.iter().collect();
+        names.sort();
+        for name in names.into_iter() {
+            let loc = all[i].defined[name];
+            if let Some(&(j, first)) = globals.get(&name[..]) {
+                errors.push(format!("{}: duplicate symbol '{}', first defined at {}",
+                                    at(all[i], loc), name, at(all[j], first)));
+            } else {
+                globals.insert(&name[..], (i, loc));
+            }
+        }
+    }
+    if !globals.contains_key(entry) && errors.is_empty() {
+        errors.push(format!("the entry point {} is not defined", entry));
+    }
+
+    for &i in linked.iter() {
+        let syms = all[i];
+        for &(ref label, loc) in syms.refs.iter() {
+            if syms.defined.contains_key(label) || globals.contains_key(&label[..]) ||
+                builtins.contains(label)
+            {
+                continue;
+            }
+            let owner = linked.iter().find(|&&j| all[j].defined.contains_key(label));
+            match owner {
+                Some(&j) => errors.push(format!(
+                    "{}: undefined symbol '{}' (it is local to {}; declare it with .global)",
+                    at(syms, loc), label, all[j].filename)),
+                None => errors.push(format!(
+                    "{}: undefined symbol '{}'", at(syms, loc), label)),
+            }
+        }
+    }
+
+    // How many units define each label, to find the local ones that clash.
+    let mut owners: HashMap<&str, usize> = HashMap::new();
+    for &i in linked.iter() {
+        for label in all[i].defined.keys() {
+            *owners.entry(&label[..]).or_insert(0) += 1;
+        }
+    }
+    linked.iter().map(|&i| {
+        let syms = all[i];
+        let renames = syms.defined.keys()
+            .filter(|label| !syms.globals.contains(*label) && owners[&label[..]] > 1)
+            .map(|label| (label.clone(), format!("{}@{}", label, syms.filename)))
+            .collect();
+        (i, renames)
+    }).collect()
+}
+
+// Links the program's units, and whichever libraries they use, into one list
+// of statements, each with the name of the file it came from.  Every error
+// found is returned, not just the first.
+pub fn link(units: Vec<Unit>, libs: Vec<Unit>, entry: &str)
+    -> Result<Vec<(String, SpannedStatement)>, Vec<String>>
+{
+    let mut errors = vec!();
+    let unit_syms: Vec<Symbols> =
+        units.iter().map(|u| symbols(u, entry, &mut errors)).collect();
+    let lib_syms: Vec<Symbols> =
+        libs.iter().map(|u| symbols(u, entry, &mut errors)).collect();
+    let linked = resolve(&unit_syms[..], &lib_syms[..], entry, &HashSet::new(), &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let all: Vec<&Unit> = units.iter().chain(libs.iter()).collect();
+    let mut out = vec!();
+    for (i, renames) in linked.into_iter() {
+        let unit = all[i];
+        for stmt in unit.stmts.iter() {
+            let mut stmt = stmt.clone();
+            match stmt.item {
+                LabelStmt(ref mut label) => {
+                    if let Some(name) = renames.get(label) {
+                        *label = name.clone();
+                    }
+                }
+                Instr(_, ref mut op) => {
+                    if let Some(label) = label_ref_mut(op) {
+                        if let Some(name) = renames.get(label) {
+                            *label = name.clone();
+                        }
+                    }
+                }
+            }
+            out.push((unit.file_name(stmt.start).to_string(), stmt));
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+fn link_sources(units: &[(&str, &str)], libs: &[(&str, &str)])
+    -> Result<Vec<String>, Vec<String>>
+{
+    let parse = |sources: &[(&str, &str)]| -> Vec<Unit> {
+        sources.iter().map(|&(name, src)| Unit::parse(name, src)).collect()
+    };
+    let stmts = try!(link(parse(units), parse(libs), "_start"));
+    Ok(stmts.into_iter().map(|(_, s)| match s.item {
+        LabelStmt(label) => format!("{}:", label),
+        Instr(_, BrL(label)) | Instr(_, BrLnkL(label)) => format!("b {}", label),
+        Instr(_, _) => "instr".to_string(),
+    }).collect())
+}
+
+#[test]
+fn test_link() {
+    let main = ("main.s", "_start:\nbl =double\nb =loop\nloop:\nhalt\n");
+    let math = ("math.s", ".global double\ndouble:\nb =loop\nloop:\nb lr\n");
+    let unused = ("unused.s", ".global triple\ntriple:\nhalt\n");
+
+    // Only the library that is used is linked, and the clashing local labels
+    // are renamed.
+    assert_eq!(
+        vec!("_start:", "b double", "b loop@main.s", "loop@main.s:", "instr",
+             "double:", "b loop@math.s", "loop@math.s:", "instr"),
+        link_sources(&[main], &[unused, math]).unwrap());
+
+    // A single file keeps its names.
+    assert_eq!(vec!("_start:", "b loop", "loop:", "instr"),
+               link_sources(&[("a.s", "_start:\nb =loop\nloop:\nhalt\n")], &[]).unwrap());
+
+    let errors = link_sources(&[main, ("other.s", "double:\nb lr\n")], &[]).unwrap_err();
+    assert_eq!(
+        vec!("main.s:2:1: undefined symbol 'double' (it is local to other.s; declare it \
+              with .global)".to_string()),
+        errors);
+
+    let errors = link_sources(&[main, math, ("dup.s", ".global double\ndouble:\n")], &[])
+        .unwrap_err();
+    assert_eq!(vec!("dup.s:2:1: duplicate symbol 'double', first defined at math.s:2:1"
+                    .to_string()),
+               errors);
+
+    let errors = link_sources(&[("a.s", "_start:\nb =nowhere\n.global gone\n")], &[])
+        .unwrap_err();
+    assert_eq!(2, errors.len());
+    assert!(errors[0].starts_with("a.s:3:9: 'gone' is declared"), "{}", errors[0]);
+    assert_eq!("a.s:2:1: undefined symbol 'nowhere'", &errors[1][..]);
+}
@@ -0,0 +1,361 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// The language server protocol, for the questions that ide answers.  Each
+// message is a JSON-RPC request, response or notification, after a header
+// that gives its length.  Documents are synced whole: the client sends the
+// full text on every change, and gets back the diagnostics for it.
+//
+// Positions in the protocol count lines from zero, and characters in UTF-16
+// code units.
+
+use check::Severity;
+use ide::{CompletionKind, Document, Symbol};
+
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// Reads the body of the next message, or None at the end of the input.
+pub fn read_message(r: &mut BufRead) -> io::Result<Option<String>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if try!(r.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_right();
+        if header.is_empty() {
+            break;
+        }
+        if header.starts_with("Content-Length:") {
+            len = header["Content-Length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let len = match len {
+        Some(len) => len,
+        None => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput, "message has no Content-Length")),
+    };
+
+    let mut body = vec!();
+    try!((&mut *r).take(len as u64).read_to_end(&mut body));
+    if body.len() < len {
+        return Ok(None);
+    }
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message is not UTF-8"))
+}
+
+pub fn write_message(w: &mut Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    try!(write!(w, "Content-Length: {}\r\n\r\n{}", body.len(), body));
+    w.flush()
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn response(id: Json, result: Json) -> Json {
+    object(vec!(("jsonrpc", "2.0".to_json()), ("id", id), ("result", result)))
+}
+
+fn error_response(id: Json, code: i64, msg: &str) -> Json {
+    let error = object(vec!(("code", code.to_json()), ("message", msg.to_json())));
+    object(vec!(("jsonrpc", "2.0".to_json()), ("id", id), ("error", error)))
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    object(vec!(("jsonrpc", "2.0".to_json()), ("method", method.to_json()),
+                ("params", params)))
+}
+
+// The reply to a message that is not JSON.
+pub fn parse_error(msg: &str) -> Json {
+    error_response(Json::Null, PARSE_ERROR, msg)
+}
+
+// The path in a file: URI, which is what the parser needs to find the files
+// that a document includes.
+fn uri_path(uri: &str) -> String {
+    let path = if uri.starts_with("file://") { &uri[7..] } else { uri };
+    let bytes = path.as_bytes();
+    let mut decoded = vec!();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+            if let Ok(byte) = u8::from_str_radix(&hex[..], 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded[..]).into_owned()
+}
+
+fn position(text: &str, offset: usize) -> Json {
+    let mut line = 0u64;
+    let mut character = 0u64;
+    for (i, c) in text.char_indices() {
+        if i >= offset {
+            break;
+        }
+        if c == '\n' {
+            line += 1;
+            character = 0;
+        } else {
+            character += c.len_utf16() as u64;
+        }
+    }
+    object(vec!(("line", line.to_json()), ("character", character.to_json())))
+}
+
+fn range(text: &str, start: usize, end: usize) -> Json {
+    object(vec!(("start", position(text, start)), ("end", position(text, end))))
+}
+
+// The offset of a position, which is kept within its line.
+fn offset(text: &str, line: u64, character: u64) -> usize {
+    let mut cur_line = 0;
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if cur_line == line && (units >= character || c == '\n') {
+            return i;
+        }
+        if c == '\n' {
+            cur_line += 1;
+        } else if cur_line == line {
+            units += c.len_utf16() as u64;
+        }
+    }
+    text.len()
+}
+
+pub struct LanguageServer {
+    // By URI.
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    exit_code: Option<i32>,
+}
+
+impl LanguageServer {
+    pub fn new() -> LanguageServer {
+        LanguageServer {
+            documents: HashMap::new(),
+            shutdown: false,
+            exit_code: None,
+        }
+    }
+
+    // Set once the client has told the server to exit.  The code is only zero
+    // if the client asked it to shut down first.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // Handles a message from the client, and gives the messages to send back.
+    pub fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg.find("method").and_then(|m| m.as_string()).unwrap_or("");
+        let params = msg.find("params").cloned().unwrap_or(Json::Null);
+        match msg.find("id") {
+            // The server sends no requests, so there are no responses to
+            // handle.
+            Some(_) if method.is_empty() => vec!(),
+            Some(id) => vec!(match self.request(method, &params) {
+                Ok(result) => response(id.clone(), result),
+                Err((code, msg)) => error_response(id.clone(), code, &msg[..]),
+            }),
+            None => self.notify(method, &params),
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        match method {
+            "initialize" => {
+                let completion = object(vec!(("triggerCharacters", Json::Array(vec!("=".to_json())))));
+                let capabilities = object(vec!(
+                    ("textDocumentSync", 1.to_json()),
+                    ("definitionProvider", true.to_json()),
+                    ("referencesProvider", true.to_json()),
+                    ("hoverProvider", true.to_json()),
+                    ("completionProvider", completion)));
+                Ok(object(vec!(("capabilities", capabilities))))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/definition" => {
+                let (doc, offset) = try!(self.document_position(params));
+                Ok(doc.definition(offset).map_or(Json::Null, |s| location(doc, params, s)))
+            }
+            "textDocument/references" => {
+                let (doc, offset) = try!(self.document_position(params));
+                let include_definition = params.find_path(&["context", "includeDeclaration"])
+                    .and_then(|b| b.as_boolean())
+                    .unwrap_or(true);
+                Ok(Json::Array(doc.references(offset, include_definition).into_iter()
+                               .map(|s| location(doc, params, s))
+                               .collect()))
+            }
+            "textDocument/hover" => {
+                let (doc, offset) = try!(self.document_position(params));
+                Ok(doc.hover(offset).map_or(Json::Null, |text| {
+                    object(vec!(("contents", object(vec!(
+                        ("kind", "plaintext".to_json()),
+                        ("value", text.to_json()))))))
+                }))
+            }
+            "textDocument/completion" => {
+                let (doc, offset) = try!(self.document_position(params));
+                Ok(Json::Array(doc.completions(offset).into_iter().map(|c| {
+                    let kind = match c.kind {
+                        CompletionKind::Mnemonic => 14,
+                        CompletionKind::Register => 6,
+                        CompletionKind::Label => 18,
+                    };
+                    object(vec!(("label", c.label.to_json()), ("kind", kind.to_json()),
+                                ("detail", c.detail.to_json())))
+                }).collect()))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params.find_path(&["textDocument", "uri"])
+            .and_then(|u| u.as_string())
+            .unwrap_or("")
+            .to_string();
+        let text = match method {
+            "textDocument/didOpen" => {
+                params.find_path(&["textDocument", "text"]).and_then(|t| t.as_string())
+            }
+            "textDocument/didChange" => {
+                params.find("contentChanges")
+                    .and_then(|c| c.as_array())
+                    .and_then(|c| c.last())
+                    .and_then(|c| c.find("text"))
+                    .and_then(|t| t.as_string())
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec!(self.publish(&uri[..]));
+            }
+            "exit" => {
+                self.exit_code = Some(if self.shutdown { 0 } else { 1 });
+                return vec!();
+            }
+            _ => return vec!(),
+        };
+        match text {
+            Some(text) if !uri.is_empty() => {
+                let doc = Document::new(&uri_path(&uri[..])[..], text);
+                self.documents.insert(uri.clone(), doc);
+                vec!(self.publish(&uri[..]))
+            }
+            _ => vec!(),
+        }
+    }
+
+    fn document_position(&self, params: &Json) -> Result<(&Document, usize), (i64, String)> {
+        let uri = params.find_path(&["textDocument", "uri"]).and_then(|u| u.as_string());
+        let line = params.find_path(&["position", "line"]).and_then(|l| l.as_u64());
+        let character = params.find_path(&["position", "character"]).and_then(|c| c.as_u64());
+        match (uri, line, character) {
+            (Some(uri), Some(line), Some(character)) => match self.documents.get(uri) {
+                Some(doc) => Ok((doc, offset(&doc.text[..], line, character))),
+                None => Err((INVALID_PARAMS, format!("{} is not open", uri))),
+            },
+            _ => Err((INVALID_PARAMS, "expected a document and a position".to_string())),
+        }
+    }
+
+    fn publish(&self, uri: &str) -> Json {
+        let diagnostics = match self.documents.get(uri) {
+            Some(doc) => doc.diagnostics.iter().map(|d| {
+                let text = &doc.text[..];
+                let start = d.loc.map_or(0, |loc| loc.offset);
+                // The diagnostic covers the word it starts at.
+                let end = text.char_indices()
+                    .skip_while(|&(i, _)| i < start)
+                    .find(|&(_, c)| c.is_whitespace() || c == ',')
+                    .map_or(text.len(), |(i, _)| i);
+                let severity = match d.severity {
+                    Severity::Error => 1,
+                    Severity::Warning => 2,
+                };
+                object(vec!(("range", range(text, start, end)),
+                            ("severity", severity.to_json()),
+                            ("source", "sbbm-asm".to_json()),
+                            ("message", d.msg.to_json())))
+            }).collect(),
+            None => vec!(),
+        };
+        notification("textDocument/publishDiagnostics", object(vec!(
+            ("uri", uri.to_json()), ("diagnostics", Json::Array(diagnostics)))))
+    }
+}
+
+fn location(doc: &Document, params: &Json, sym: &Symbol) -> Json {
+    let uri = params.find_path(&["textDocument", "uri"]).cloned().unwrap_or(Json::Null);
+    object(vec!(("uri", uri),
+                ("range", range(&doc.text[..], sym.start.offset, sym.end.offset))))
+}
+
+#[cfg(test)]
+fn exchange(server: &mut LanguageServer, msg: &str) -> Vec<Json> {
+    let mut framed = vec!();
+    write_message(&mut framed, &Json::from_str(msg).unwrap()).unwrap();
+    let body = read_message(&mut &framed[..]).unwrap().unwrap();
+    server.handle(&Json::from_str(&body[..]).unwrap())
+}
+
+#[test]
+fn test_session() {
+    let mut server = LanguageServer::new();
+    let init = exchange(&mut server, r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#);
+    assert_eq!(Some(&Json::Boolean(true)),
+               init[0].find_path(&["result", "capabilities", "hoverProvider"]));
+
+    let open = exchange(&mut server, r#"{"jsonrpc":"2.0","method":"textDocument/didOpen",
+        "params":{"textDocument":{"uri":"file:///tmp/a%20b.s","text":"_start:\n  b =oops\n"}}}"#);
+    let diagnostics = open[0].find_path(&["params", "diagnostics"]).unwrap().as_array().unwrap();
+    assert_eq!(1, diagnostics.len());
+    assert_eq!(Some(&Json::I64(2)), diagnostics[0].find("severity"));
+    assert_eq!(Some(&Json::U64(1)), diagnostics[0].find_path(&["range", "start", "line"]));
+
+    // The position is in UTF-16, and the é before it is one code unit.
+    exchange(&mut server, r#"{"jsonrpc":"2.0","method":"textDocument/didChange",
+        "params":{"textDocument":{"uri":"file:///tmp/a%20b.s"},
+                  "contentChanges":[{"text":"_start:\n  mov r0, #'é'\n  b =_start\n"}]}}"#);
+    let def = exchange(&mut server, r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition",
+        "params":{"textDocument":{"uri":"file:///tmp/a%20b.s"},
+                  "position":{"line":2,"character":6}}}"#);
+    assert_eq!(Some(&Json::U64(0)), def[0].find_path(&["result", "range", "start", "line"]));
+    assert_eq!(Some(&Json::U64(6)), def[0].find_path(&["result", "range", "end", "character"]));
+
+    let hover = exchange(&mut server, r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/hover",
+        "params":{"textDocument":{"uri":"file:///tmp/a%20b.s"},
+                  "position":{"line":1,"character":12}}}"#);
+    let value = hover[0].find_path(&["result", "contents", "value"]).unwrap().as_string();
+    assert!(value.unwrap().starts_with("mov r0, #233\n"), "{:?}", value);
+
+    let unknown = exchange(&mut server, r#"{"jsonrpc":"2.0","id":4,"method":"foo"}"#);
+    assert_eq!(Some(&Json::I64(METHOD_NOT_FOUND)), unknown[0].find_path(&["error", "code"]));
+
+    exchange(&mut server, r#"{"jsonrpc":"2.0","id":5,"method":"shutdown"}"#);
+    exchange(&mut server, r#"{"jsonrpc":"2.0","method":"exit"}"#);
+    assert_eq!(Some(0), server.exit_code());
+    assert_eq!("/tmp/a b.s", &uri_path("file:///tmp/a%20b.s")[..]);
+}
@@ -0,0 +1,511 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Preprocesses tokens between the lexer and the parser: reads in the files
+// named by .include "file", and expands macros.  Included files are found
+// relative to the file that includes them.  A macro is defined with
+//
+//     .macro name param, param=default, ...
+//         body
+//     .endm
+//
+// and used like an instruction, with its arguments in order.  Arguments may
+// be left off the end when their parameters have defaults.  In the body,
+// $param stands for the argument, both as a token and inside raw commands.
+// Every label the body defines is renamed for each expansion, so that a
+// macro with a loop can be used more than once.
+//
+// Tokens from included files keep their own locations, with the index of
+// their file in files().  Tokens from expansions are located where the macro
+// was used.  The text of included files, and whatever an expansion makes up,
+// is kept in the arena that the tokens borrow from.
+
+use assembler::unique_label;
+use lexer::{Arena, Lexer, LexResult, Location, SpannedToken, Token, unquote};
+use lexer::Token::*;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Protects against macros that expand to themselves.  An expansion is nested
+// in the one that its macro was used in.
+const MAX_EXPANSION_DEPTH: u32 = 64;
+// Files that include themselves are caught by name, and this stops the ones
+// that are reached by different names.
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+struct Macro<'a> {
+    params: Vec<(&'a str, Option<Vec<Token<'a>>>)>,
+    body: Vec<Token<'a>>,
+    // The labels defined in the body, without their colons.
+    labels: HashSet<&'a str>,
+}
+
+pub struct MacroExpander<'a> {
+    lexer: Lexer<'a>,
+    arena: &'a Arena,
+    // The names of the files read so far, indexed by Location::file.
+    files: Vec<&'a str>,
+    // The file that included each file, if any.
+    included_from: Vec<Option<usize>>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    // Tokens from expansions, which are read before the lexer's, each with
+    // how deeply nested the expansion it came from is.
+    pending: VecDeque<(SpannedToken<'a>, u32)>,
+    // How deeply nested the last token read is, 0 if it is not from an
+    // expansion.
+    depth: u32,
+    // Whether the next token is where an instruction would start, which is
+    // the only place a macro can be used.
+    at_stmt_start: bool,
+    expansions: u32,
+}
+
+// The source text of tokens, for substituting arguments into raw commands.
+fn tokens_text(tokens: &[Token]) -> String {
+    let texts: Vec<String> = tokens.iter().map(|t| match *t {
+        Raw(_, _, cmd) => cmd.to_string(),
+        _ => t.to_string(),
+    }).collect();
+    texts.connect(" ")
+}
+
+fn is_line_end(token: &Token) -> bool {
+    match *token {
+        Newline(_) | Eof => true,
+        _ => false,
+    }
+}
+
+// Splits tokens at the commas that are not inside braces or brackets.
+fn split_args(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut args = vec!();
+    let mut arg = vec!();
+    let mut depth = 0;
+    for token in tokens.into_iter() {
+        match token {
+            LBrace | LBracket | LParen => depth += 1,
+            RBrace | RBracket | RParen => depth -= 1,
+            Comma if depth == 0 => {
+                args.push(arg);
+                arg = vec!();
+                continue;
+            }
+            _ => { }
+        }
+        arg.push(token);
+    }
+    if !arg.is_empty() || !args.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+impl<'a> MacroExpander<'a> {
+    pub fn new(lexer: Lexer<'a>, arena: &'a Arena) -> MacroExpander<'a> {
+        MacroExpander {
+            files: vec!(lexer.filename()),
+            included_from: vec!(None),
+            lexer: lexer,
+            arena: arena,
+            macros: HashMap::new(),
+            pending: VecDeque::new(),
+            depth: 0,
+            at_stmt_start: true,
+            expansions: 0,
+        }
+    }
+
+    pub fn filename(&self) -> &'a str {
+        self.lexer.filename()
+    }
+
+    // The name of the file that a location is in.
+    pub fn file_name(&self, loc: Location) -> &'a str {
+        self.files[loc.file]
+    }
+
+    pub fn files(&self) -> &[&'a str] {
+        &self.files[..]
+    }
+
+    pub fn next(&mut self) -> LexResult<SpannedToken<'a>> {
+        loop {
+            let token = try!(self.next_raw());
+            let at_stmt_start = self.at_stmt_start;
+            self.at_stmt_start = match token.item {
+                Newline(_) | Label(_) => true,
+                _ => false,
+            };
+
+            match token.item {
+                Meta(".macro") => {
+                    try!(self.define(token.start));
+                    // The definition takes the whole line, newline and all.
+                    self.at_stmt_start = true;
+                }
+                Meta(".endm") => {
+                    return Err(self.error(token.start, ".endm without .macro".to_string()));
+                }
+                Meta(".include") => {
+                    try!(self.include(token.start));
+                    self.at_stmt_start = true;
+                }
+                Ident(name) if at_stmt_start && self.macros.contains_key(name) => {
+                    try!(self.expand(name, token.start, token.end));
+                    self.at_stmt_start = true;
+                }
+                _ => return Ok(token),
+            }
+        }
+    }
+
+    fn next_raw(&mut self) -> LexResult<SpannedToken<'a>> {
+        match self.pending.pop_front() {
+            Some((token, depth)) => {
+                self.depth = depth;
+                Ok(token)
+            }
+            None => {
+                self.depth = 0;
+                self.lexer.next()
+            }
+        }
+    }
+
+    fn error(&self, loc: Location, msg: String) -> String {
+        format!("{}:{}: {}", self.file_name(loc), loc, msg)
+    }
+
+    // Reads the rest of the line, and the newline.
+    fn read_line(&mut self) -> LexResult<Vec<Token<'a>>> {
+        let mut tokens = vec!();
+        loop {
+            let token = try!(self.next_raw()).item;
+            if is_line_end(&token) {
+                if token == Eof {
+                    self.pending.push_front((SpannedToken {
+                        item: Eof,
+                        start: Location { offset: 0, col: 0, line: 0, file: 0 },
+                        end: Location { offset: 0, col: 0, line: 0, file: 0 },
+                    }, 0));
+                }
+                return Ok(tokens);
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn include(&mut self, loc: Location) -> LexResult<()> {
+        // An .include in an expansion is as nested as the expansion.
+        let depth = self.depth;
+        let line = try!(self.read_line());
+        let name = match line.first() {
+            Some(&LitStr(name)) if line.len() == 1 => {
+                try!(unquote(name).map_err(|e| self.error(loc, e)))
+            }
+            _ => return Err(self.error(loc, ".include needs a quoted file name".to_string())),
+        };
+        let path = Path::new(self.file_name(loc)).with_file_name(&name[..]);
+
+        // The files that are open, from the one with the .include out.
+        let mut open = vec!();
+        let mut file = Some(loc.file);
+        while let Some(i) = file {
+            open.push(i);
+            file = self.included_from[i];
+        }
+        if open.iter().any(|&i| Path::new(self.files[i]) == path) {
+            return Err(self.error(loc, format!("{} includes itself", path.display())));
+        }
+        if open.len() >= MAX_INCLUDE_DEPTH {
+            return Err(self.error(loc, format!(
+                ".includes are nested more than {} deep; does a file include itself?",
+                MAX_INCLUDE_DEPTH)));
+        }
+
+        let mut input = String::new();
+        try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut input))
+             .map_err(|e| self.error(loc, format!("cannot include {}: {}", path.display(), e))));
+
+        let input = self.arena.alloc(input);
+        let filename = self.arena.alloc(path.to_string_lossy().into_owned());
+        let mut lexer = Lexer::new(input, filename);
+        lexer.set_file(self.files.len());
+        self.files.push(filename);
+        self.included_from.push(Some(loc.file));
+        let mut tokens = vec!();
+        let mut end = Location { offset: 0, col: 0, line: 0, file: self.files.len() - 1 };
+        loop {
+            let token = try!(lexer.next());
+            if token.item == Eof {
+                break;
+            }
+            end = token.end;
+            tokens.push(token);
+        }
+        tokens.push(SpannedToken { item: Newline("\n"), start: end, end: end });
+        for token in tokens.into_iter().rev() {
+            self.pending.push_front((token, depth));
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, loc: Location) -> LexResult<()> {
+        let mut header = try!(self.read_line()).into_iter();
+        let name = match header.next() {
+            Some(Ident(name)) => name,
+            _ => return Err(self.error(loc, ".macro needs a name".to_string())),
+        };
+
+        let mut params = vec!();
+        for param in split_args(header.collect()).into_iter() {
+            let mut param = param.into_iter();
+            let param_name = match param.next() {
+                Some(Ident(param_name)) => param_name,
+                _ => return Err(self.error(loc, format!("invalid parameter in macro {}", name))),
+            };
+            let rest: Vec<Token> = param.collect();
+            let default = match rest.first() {
+                None => None,
+                Some(&Equals) if rest.len() > 1 => Some(rest[1..].to_vec()),
+                // name=value lexes as a label reference when the value looks
+                // like a name, so lex the value again on its own.
+                Some(&LabelRef(value)) if rest.len() == 1 => {
+                    let mut lexer = Lexer::mem(&value[1..]);
+                    Some(vec!(try!(lexer.next()).item))
+                }
+                _ => return Err(self.error(loc, format!(
+                    "invalid default for parameter {} of macro {}", param_name, name))),
+            };
+            if params.iter().any(|&(p, _)| p == param_name) {
+                return Err(self.error(loc, format!(
+                    "macro {} has more than one parameter named {}", name, param_name)));
+            }
+            params.push((param_name, default));
+        }
+
+        let mut body = vec!();
+        let mut labels = HashSet::new();
+        loop {
+            let token = try!(self.next_raw()).item;
+            match token {
+                Meta(".endm") => break,
+                Meta(".macro") => {
+                    return Err(self.error(loc, format!(
+                        "macro {} contains another macro definition", name)));
+                }
+                Eof => return Err(self.error(loc, format!("macro {} has no .endm", name))),
+                Label(label) => { labels.insert(&label[..label.len() - 1]); }
+                _ => { }
+            }
+            body.push(token);
+        }
+        // Drop whatever follows .endm on its line.
+        try!(self.read_line());
+
+        self.macros.insert(name, Macro { params: params, body: body, labels: labels });
+        Ok(())
+    }
+
+    fn expand(&mut self, name: &'a str, start: Location, end: Location) -> LexResult<()> {
+        // The macro's name was the last token read.
+        let depth = self.depth + 1;
+        if depth > MAX_EXPANSION_DEPTH {
+            return Err(self.error(start, format!(
+                "macros are nested more than {} deep; does {} use itself?",
+                MAX_EXPANSION_DEPTH, name)));
+        }
+        self.expansions += 1;
+
+        let args = split_args(try!(self.read_line()));
+        let expansion = {
+            let mac = &self.macros[name];
+            if args.len() > mac.params.len() {
+                return Err(self.error(start, format!(
+                    "macro {} takes {} argument(s) but was given {}",
+                    name, mac.params.len(), args.len())));
+            }
+
+            let mut values = HashMap::new();
+            for (i, &(param, ref default)) in mac.params.iter().enumerate() {
+                let value = match (args.get(i), default) {
+                    (Some(arg), _) if !arg.is_empty() => arg.clone(),
+                    (_, &Some(ref default)) => default.clone(),
+                    _ => return Err(self.error(start, format!(
+                        "macro {} needs a value for {}", name, param))),
+                };
+                values.insert(format!("${}", param), value);
+            }
+
+            let arena = self.arena;
+            let local = |label: &str| unique_label(
+                &format!("@{}.{}.", name, label)[..], self.expansions);
+            let mut expansion = vec!();
+            for token in mac.body.iter() {
+                match *token {
+                    Ident(ident) if ident.starts_with("$") => {
+                        match values.get(ident) {
+                            Some(value) => expansion.extend(value.iter().cloned()),
+                            None => return Err(self.error(start, format!(
+                                "macro {} has no parameter named {}", name, &ident[1..]))),
+                        }
+                    }
+                    Label(label) if mac.labels.contains(&label[..label.len() - 1]) => {
+                        let label = format!("{}:", local(&label[..label.len() - 1]));
+                        expansion.push(Label(arena.alloc(label)));
+                    }
+                    LabelRef(label) if mac.labels.contains(&label[1..]) => {
+                        let label = format!("={}", local(&label[1..]));
+                        expansion.push(LabelRef(arena.alloc(label)));
+                    }
+                    Raw(chars, regs, raw) => {
+                        let mut raw = raw.to_string();
+                        // Longer names first, so that $ab isn't replaced as $a.
+                        let mut params: Vec<&String> = values.keys().collect();
+                        params.sort_by(|a, b| b.len().cmp(&a.len()));
+                        for param in params.into_iter() {
+                            raw = raw.replace(&param[..], &tokens_text(&values[param])[..]);
+                        }
+                        expansion.push(Raw(chars, regs, arena.alloc(raw)));
+                    }
+                    token => expansion.push(token),
+                }
+            }
+            expansion
+        };
+
+        // The expansion takes the place of the line that used the macro, and
+        // is located where the macro was used.
+        let newline = SpannedToken { item: Newline("\n"), start: end, end: end };
+        self.pending.push_front((newline, depth));
+        for token in expansion.into_iter().rev() {
+            self.pending.push_front((SpannedToken { item: token, start: start, end: end }, depth));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn expand_all<'a>(input: &'a str, arena: &'a Arena) -> Result<Vec<Token<'a>>, String> {
+    let mut expander = MacroExpander::new(Lexer::mem(input), arena);
+    let mut tokens = vec!();
+    loop {
+        match try!(expander.next()).item {
+            Eof => return Ok(tokens),
+            Newline(_) => { }
+            token => tokens.push(token),
+        }
+    }
+}
+
+#[test]
+fn test_macros() {
+    use std::iter;
+
+    let src = "
+.macro inc reg, n=#1
+    add $reg, $n
+.endm
+.macro spin reg
+loop:
+    sub $reg, #1
+    raw say $reg
+    {p0, #1, *} b =loop
+.endm
+_start:
+    inc r0
+    inc r1, #5
+    spin r2
+    spin r3
+";
+    let arena = Arena::new();
+    let reg = |r| GenReg(r);
+    let lit = |n| LitInt(n);
+    let labels: Vec<(String, String)> = (3..5).map(|n| {
+        let label = format!("@spin.loop.{}", n);
+        (format!("{}:", label), format!("={}", label))
+    }).collect();
+    let raws = ["say r2", "say r3"];
+    let mut expected = vec!(
+        Label("_start:"),
+        Ident("add"), reg("r0"), Comma, lit("#1"),
+        Ident("add"), reg("r1"), Comma, lit("#5"));
+    for (i, r) in ["r2", "r3"].iter().enumerate() {
+        expected.extend(vec!(
+            Label(&labels[i].0[..]),
+            Ident("sub"), reg(*r), Comma, lit("#1"),
+            Raw("", "", raws[i]),
+            LBrace, PredReg("p0"), Comma, lit("#1"), Comma, Star, RBrace,
+            Ident("b"), LabelRef(&labels[i].1[..])).into_iter());
+    }
+    assert_eq!(expected, expand_all(src, &arena).unwrap());
+
+    // Macros are only expanded where an instruction could be.
+    assert_eq!(
+        vec!(Ident("mov"), reg("r0"), Comma, Ident("inc"),
+             Comma, Ident("score")),
+        expand_all(".macro inc\n.endm\nmov r0, inc, score", &arena).unwrap());
+
+    assert!(expand_all(".macro inc reg\nadd $reg, #1\n.endm\ninc", &arena).is_err());
+    assert!(expand_all(".macro inc reg\nadd $rge, #1\n.endm\ninc r0", &arena).is_err());
+    assert!(expand_all(".macro loop\nloop\n.endm\nloop", &arena).unwrap_err()
+            .contains("nested more than"));
+    // Only nesting is limited, not how many times macros are used.
+    let uses: String = iter::repeat("inc\n").take(20000).collect();
+    let many = format!(".macro inc\nadd r0, #1\n.endm\n{}", uses);
+    assert_eq!(20000 * 4, expand_all(&many[..], &arena).unwrap().len());
+    assert!(expand_all(".macro inc reg\nadd $reg, #1\n", &arena).is_err());
+}
+
+#[test]
+fn test_include() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = env::temp_dir().join("sbbm-test-include");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    let write = |name: &str, src: &str| {
+        File::create(dir.join(name)).unwrap().write_all(src.as_bytes()).unwrap();
+    };
+    write("main.s", ".include \"lib/inc.s\"\n_start:\ninc r0\n");
+    write("lib/inc.s", ".include \"defs.s\"\n");
+    write("lib/defs.s", ".macro inc reg\nadd $reg, #1\n.endm\n");
+    write("loop.s", ".include \"loop.s\"\n");
+    write("outer.s", ".include \"lib/inner.s\"\n");
+    write("lib/inner.s", ".include \"../outer.s\"\n");
+    write("twice.s", ".include \"lib/defs.s\"\n.include \"lib/defs.s\"\n");
+    write("bad.s", "_start:\n.include \"lib/bad.s\"\n");
+    write("lib/bad.s", "add r0, #1\n\n.macro\n");
+
+    let arena = Arena::new();
+    let read = |name: &str| -> Result<Vec<String>, String> {
+        let path = dir.join(name);
+        let path = path.to_str().unwrap();
+        let mut input = String::new();
+        File::open(path).unwrap().read_to_string(&mut input).unwrap();
+        let mut expander = MacroExpander::new(Lexer::new(&input[..], path), &arena);
+        let mut tokens = vec!();
+        loop {
+            match try!(expander.next()).item {
+                Eof => return Ok(tokens),
+                Newline(_) => { }
+                token => tokens.push(token.to_string()),
+            }
+        }
+    };
+
+    assert_eq!(vec!("_start:", "add", "r0", ",", "#1"), read("main.s").unwrap());
+    assert!(read("loop.s").unwrap_err().contains("includes itself"));
+    assert!(read("outer.s").unwrap_err().contains("nested more than"));
+    // A file may be included more than once, as long as it isn't inside itself.
+    assert!(read("twice.s").is_ok());
+    // Errors in an included file are reported where they are in it.
+    let bad = dir.join("lib/bad.s");
+    assert!(read("bad.s").unwrap_err().starts_with(&format!("{}:3:1:", bad.display())[..]));
+    assert!(expand_all(".include \"missing.s\"", &arena).unwrap_err().contains("cannot include"));
+    assert!(expand_all(".include missing", &arena).is_err());
+}
@@ -6,28 +6,85 @@ extern crate rustc_serialize;
 extern crate sbbm_asm;
 
 use docopt::Docopt;
-use sbbm_asm::assembler::{Assembler, AssembledItem};
+use sbbm_asm::assembler::{Assembler, AssembledItem, Usage};
+use sbbm_asm::cfg::{self, Cfg};
+use sbbm_asm::check::{self, Severity};
 use sbbm_asm::commands::{self, Command};
 use sbbm_asm::fab;
-use sbbm_asm::hw::{Computer, MemoryRegion, MemoryStride};
-use sbbm_asm::layout::{Layout, LayoutMotion, LinearMotion, PackedMotion};
-use sbbm_asm::lexer::Lexer;
+use sbbm_asm::format;
+use sbbm_asm::hw::{Computer, ReservationMap};
+use sbbm_asm::layout::{
+    BoundedMotion, Layout, LayoutMotion, LinearMotion, PackedMotion, StableMotion};
+use sbbm_asm::lexer::Location;
+use sbbm_asm::link::{self, Unit};
+use sbbm_asm::manifest::{self, Manifest};
 use sbbm_asm::nbt::Nbt;
-use sbbm_asm::parser::Parser;
+use sbbm_asm::object::{self, Object};
+use sbbm_asm::profile::Profile;
+use sbbm_asm::rcon::{self, Rcon};
+use sbbm_asm::render::Scene;
+use sbbm_asm::stats::ProgramStats;
+use sbbm_asm::trace::{self, Trace};
 use sbbm_asm::types::{Extent, Vec3};
+use sbbm_asm::world::Obstacles;
 
+use std::cmp;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, Read, Write};
+use std::io::{self, BufReader, Read, Write};
 use std::path::Path;
+use std::rc::Rc;
 
 static ENTRY_LABEL: &'static str = "_start";
+// How far --auto-shift may move the program before giving up.
+const MAX_AUTO_SHIFT: i32 = 1024;
 
 static USAGE: &'static str = "
-usage: sbbm-asm [-l LAYOUT] [-k INIT] [-b BOOT] [-d DESTROY] [-o OUTPUT] <x> <y> <z> <source>
+usage: sbbm-asm [options] <x> <y> <z> <source>...
+       sbbm-asm render [options] <x> <y> <z> <source>...
+       sbbm-asm deploy [options] --rcon ADDR <x> <y> <z> <source>...
+       sbbm-asm trace <map> [<log>]
+       sbbm-asm diff <old> <new>
+       sbbm-asm -c [options] <source>...
+       sbbm-asm fmt [options] <source>...
+
+The sources are linked into one program.  A label is only visible in the
+file that defines it, unless the file declares it with .global, and _start
+must be defined by exactly one of them.  Any of the sources and libraries
+can instead be objects made by the sixth form.
+
+The second form lays the program out as usual, but instead of commands, it
+writes a picture of the layout: a slice of each level in ASCII, with a
+letter for each label.  Use --svg for a map that can be viewed in a browser.
+
+The third form assembles the program and sends the commands straight to a
+running server over RCON, instead of writing them out.  The server needs
+enable-rcon=true in its server.properties.
+
+The fourth form reads server output (from <log>, or stdin) and prints the
+instructions that were traced, using a map written by --trace.
+
+The fifth form compares two manifests written by --manifest, and prints the
+commands that turn the old layout into the new one.
+
+The sixth form assembles each source into a relocatable object, without
+laying it out, so that it does not have to be assembled again when another
+file changes.  Each object is written next to its source, with a .o
+extension, or to --output when there is only one source.  Objects hold
+assembled code, so --dce, --cfg, --trace and --profile cannot be used with
+them.
+
+The seventh form formats each source in the canonical style and prints it,
+or writes it back over the source with --in-place.
 
 Options:
     -o, --output OUTPUT    Output file.
-    -l, --layout LAYOUT    Layout kind (packed or linear).
+    -c, --compile          Write relocatable objects instead of commands.
+    -l, --layout LAYOUT    Layout kind (packed, linear, bounded or stable).
+                           The stable layout is like the linear one, but
+                           keeps each line where --previous had it.
+    --bounds BOUNDS        The size of the volume used by the bounded layout,
+                           as X,Y,Z.  [default: 32,16,32]
     -k, --init INIT        A filename that will be used to write out the
                            commands needed to initialize the circuit.  (Creates
                            the necessary entities and objectives, and performs
@@ -41,26 +98,128 @@ Options:
                            TrackOutput enabled, so that the result of previous
                            executions can be viewed in-game.  (Mainly useful for
                            debugging.)
+    --dce                  Remove code that cannot be reached from the entry
+                           point before assembling.
+    --lib FILES            A comma-separated list of library sources.  Each is
+                           only linked in when the program uses one of its
+                           .global labels.
+    --cfg DOT              A filename that will be used to write out the
+                           program's control-flow graph, in Graphviz format.
+    --profile MAP          Count how many times each label is entered, using
+                           a scoreboard objective per run of adjacent labels,
+                           which can't be told apart.  MAP is a filename that
+                           will be used to write out which objective counts
+                           each label.  The objectives are created by the init
+                           script, and named after the labels they count.
+    --profile-dump DUMP    A filename that will be used to write out the
+                           commands that list the profiling counters.
+    --trace MAP            Name each instruction's first command block so
+                           that its output announces the instruction in the
+                           server log.  Predicated instructions are not
+                           announced.  MAP is a filename that will be used to
+                           write out the source line of each instruction.  The
+                           init script turns tracing off; run 'gamerule
+                           logAdminCommands true' to turn it on.
+    --spawn-radius CHUNKS  The radius of the spawn chunks, which are always
+                           loaded.  A warning is given when the program or
+                           memory does not fit in them.  [default: 8]
+    --reservations FILE    A JSON file listing volumes that the program must
+                           stay out of, such as the circuits of other
+                           computers.  Each entry is an object with an owner
+                           name, and min and max corners (objects with x, y
+                           and z).
+    --auto-shift           Move the program along x, away from anything it
+                           would overlap, rather than failing.
+    --obstacles FILES      A comma-separated list of files describing blocks
+                           that are already in the world.  The layout routes
+                           around them, so that none of them are overwritten.
+                           Each file is a JSON array of boxes (objects with
+                           min and max corners), a schematic (.schematic), or
+                           an Anvil region file (.mca).
+    --obstacles-origin POS
+                           Where the minimum corner of each schematic goes, as
+                           X,Y,Z.  Only needed for schematics that do not
+                           record where they were copied from.
+    --svg SVG              With render, a filename that will be used to write
+                           out a top-down map of the layout, in SVG format.
+    --manifest MANIFEST    A filename that will be used to write out where
+                           every block went, in JSON format.
+    --previous MANIFEST    A manifest from an earlier assembly that is already
+                           built.  Only the blocks that changed since then are
+                           written out.
+    --stats                Print a report of the assembled program's size and
+                           estimated cost to stderr.
+    --stats-json STATS     A filename that will be used to write out the same
+                           report, in JSON format.
+    --rcon ADDR            With deploy, the server's RCON address, as
+                           HOST:PORT.  RCON takes commands of at most 1446
+                           bytes, so longer ones are errors before anything
+                           is sent.
+    --password PASSWORD    With deploy, the server's rcon.password.
+    --scripts SCRIPTS      With deploy, a comma-separated list of the scripts
+                           to send, in order: init, blocks, boot or destroy.
+                           [default: init,blocks,boot]
+    --batch N              With deploy, how many commands to send before
+                           pausing.  [default: 64]
+    --delay MS             With deploy, how long to pause for after each
+                           batch, in milliseconds.  [default: 50]
+    --in-place             With fmt, rewrite each source instead of printing
+                           it.
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
-    arg_x: i32,
-    arg_y: i32,
-    arg_z: i32,
-    arg_source: String,
+    cmd_render: bool,
+    cmd_deploy: bool,
+    cmd_trace: bool,
+    cmd_diff: bool,
+    cmd_fmt: bool,
+    flag_compile: bool,
+    arg_x: Option<i32>,
+    arg_y: Option<i32>,
+    arg_z: Option<i32>,
+    arg_source: Vec<String>,
+    arg_map: String,
+    arg_log: Option<String>,
+    arg_old: String,
+    arg_new: String,
     flag_output: Option<String>,
     flag_layout: Option<LayoutKind>,
+    flag_bounds: String,
     flag_init: Option<String>,
     flag_boot: Option<String>,
     flag_destroy: Option<String>,
     flag_track_output: bool,
+    flag_dce: bool,
+    flag_lib: Option<String>,
+    flag_cfg: Option<String>,
+    flag_profile: Option<String>,
+    flag_profile_dump: Option<String>,
+    flag_trace: Option<String>,
+    flag_spawn_radius: u32,
+    flag_reservations: Option<String>,
+    flag_auto_shift: bool,
+    flag_obstacles: Option<String>,
+    flag_obstacles_origin: Option<String>,
+    flag_svg: Option<String>,
+    flag_manifest: Option<String>,
+    flag_previous: Option<String>,
+    flag_stats: bool,
+    flag_stats_json: Option<String>,
+    flag_rcon: Option<String>,
+    flag_password: Option<String>,
+    flag_scripts: String,
+    flag_batch: usize,
+    flag_delay: u32,
+    flag_in_place: bool,
 }
 
 #[derive(RustcDecodable, Debug)]
 enum LayoutKind {
     Linear,
     Packed,
+    Bounded,
+    Stable,
 }
 
 #[cfg(not(test))]
@@ -69,93 +228,595 @@ fn main() {
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
+    if args.cmd_trace {
+        decode_trace(args);
+    } else if args.cmd_diff {
+        diff_manifests(args);
+    } else if args.cmd_fmt {
+        format_sources(args);
+    } else if args.flag_compile {
+        compile(args);
+    } else {
+        assemble(args);
+    }
+}
+
+fn decode_trace(args: Args) {
+    let map = File::open(Path::new(&args.arg_map[..])).unwrap();
+    let mut map = BufReader::new(map);
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Some(log) = args.arg_log {
+        let log = File::open(Path::new(&log[..])).unwrap();
+        trace::decode(&mut map, &mut BufReader::new(log), &mut stdout).unwrap();
+    } else {
+        let stdin = io::stdin();
+        trace::decode(&mut map, &mut stdin.lock(), &mut stdout).unwrap();
+    }
+}
+
+fn read_manifest(path: &str) -> Manifest {
+    let mut f = File::open(Path::new(path))
+        .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    Manifest::read_json(&mut f).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+}
+
+fn write_diff(w: &mut Write, old: &Manifest, new: &Manifest) -> io::Result<()> {
+    let diff = manifest::diff(old, new);
+    for warning in diff.warnings.iter() {
+        try!(write!(io::stderr(), "warning: {}; destroy and initialize the \
+                                   computer again\n", warning));
+    }
+    for cmd in diff.commands.iter() {
+        try!(write!(w, "{}\n", cmd));
+    }
+    Ok(())
+}
+
+fn diff_manifests(args: Args) {
+    let old = read_manifest(&args.arg_old[..]);
+    let new = read_manifest(&args.arg_new[..]);
+    write_diff(&mut io::stdout(), &old, &new).unwrap();
+}
+
+fn format_sources(args: Args) {
+    for path in args.arg_source.iter() {
+        let src = read_source(path);
+        let formatted = format::format(&src[..], &path[..]).unwrap_or_else(|e| fail(e));
+        if args.flag_in_place {
+            let mut f = File::create(Path::new(&path[..]))
+                .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            f.write_all(formatted.as_bytes()).unwrap();
+        } else {
+            print!("{}", formatted);
+        }
+    }
+}
+
+fn compile(args: Args) {
+    if args.flag_output.is_some() && args.arg_source.len() > 1 {
+        fail("--output only works when compiling a single source".to_string());
+    }
+    // Only the memory's addresses matter to an object, not where it is.
+    let computer = Computer::new(Vec3::new(0, 0, 0));
+    for path in args.arg_source.iter() {
+        let object = Object::assemble(
+            &read_unit(path), &computer, args.flag_track_output, ENTRY_LABEL)
+            .unwrap_or_else(|e| fail_all(e));
+        let outfile = args.flag_output.clone().unwrap_or_else(
+            || Path::new(path).with_extension("o").to_string_lossy().into_owned());
+        let mut f = File::create(Path::new(&outfile[..]))
+            .unwrap_or_else(|e| fail(format!("{}: {}", outfile, e)));
+        write!(f, "{}\n", object.to_json()).unwrap();
+    }
+}
+
+// Links a program from objects, assembling any sources among them first.
+// Returns the files and locations of the items, for diagnostics, along
+// with the items and what they use.
+fn link_objects(paths: &[String], lib_paths: &[String], computer: &Computer, track_output: bool)
+    -> (Vec<String>, Vec<Location>, Vec<AssembledItem>, Usage)
+{
+    let read = |path: &String| if path.ends_with(".o") {
+        let mut f = File::open(Path::new(&path[..]))
+            .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+        Object::read_json(&mut f).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+    } else {
+        Object::assemble(&read_unit(path), computer, track_output, ENTRY_LABEL)
+            .unwrap_or_else(|e| fail_all(e))
+    };
+    let objects: Vec<Object> = paths.iter().map(|p| read(p)).collect();
+    let libs: Vec<Object> = lib_paths.iter().map(|p| read(p)).collect();
+
+    let mut files = vec!();
+    let mut locs = vec!();
+    for o in objects.iter().chain(libs.iter()) {
+        for loc in o.items.iter().filter_map(object::item_loc) {
+            files.push(o.files[loc.file].clone());
+            locs.push(loc);
+        }
+    }
+    let (items, usage) = object::link(objects, libs, computer, track_output, ENTRY_LABEL)
+        .unwrap_or_else(|e| fail_all(e));
+    (files, locs, items, usage)
+}
+
+fn assemble(args: Args) {
     let mut output : Box<Write> = if let Some(outfile) = args.flag_output {
         Box::new(File::create(Path::new(&outfile[..])).unwrap())
     } else {
         Box::new(std::io::stdout())
     };
 
-    let mut file = File::open(Path::new(&args.arg_source[..])).unwrap();
-    let mut input = String::new();
-    if let Ok(_) = file.read_to_string(&mut input) {
-        let origin = Vec3::new(args.arg_x, args.arg_y, args.arg_z);
-        let computer = Computer {
-            name: "computer".to_string(),
-            origin: origin,
-            memory: vec![
-                MemoryRegion {
-                    start: 0x10,
-                    size: 0x8000,
-                    origin: Vec3::new(origin.x - 1, 0, origin.z),
-                    growth: Vec3::new(-1, 1, 1),
-                    stride: MemoryStride::XY(32, 256)
-                }]
-        };
-
-        let mut parser = Parser::new(Lexer::new(&input[..], &args.arg_source[..]));
-        let stmts = parser.parse_program();
-
-        // FIXME: Check for warnings/errors before starting to place blocks.
+    let instrumented = args.flag_trace.is_some() || args.flag_profile.is_some();
+    if instrumented && (args.arg_source.len() > 1 || args.flag_lib.is_some()) {
+        fail("--trace and --profile only work with a single source file, and no --lib"
+             .to_string());
+    }
+    let lib_paths: Vec<String> = args.flag_lib.as_ref()
+        .map(|paths| paths.split(',').map(|p| p.to_string()).collect())
+        .unwrap_or(vec!());
+    let objects = args.arg_source.iter().chain(lib_paths.iter()).any(|p| p.ends_with(".o"));
+    if objects && (instrumented || args.flag_dce || args.flag_cfg.is_some()) {
+        fail("--dce, --cfg, --trace and --profile need source files, not objects".to_string());
+    }
+    let origin = Vec3::new(
+        args.arg_x.unwrap(), args.arg_y.unwrap(), args.arg_z.unwrap());
+    let mut computer = Computer::new(origin);
+
+    let mut trace = None;
+    let mut profile = None;
+    let mut announcements = vec!();
+    // FIXME: Check for warnings/errors before starting to place blocks.
+    let (files, locs, items, usage) = if objects {
+        link_objects(&args.arg_source[..], &lib_paths[..], &computer, args.flag_track_output)
+    } else {
+        let units: Vec<Unit> = args.arg_source.iter().map(|path| read_unit(path)).collect();
+        let libs: Vec<Unit> = lib_paths.iter().map(|path| read_unit(path)).collect();
+        let linked = link::link(units, libs, ENTRY_LABEL).unwrap_or_else(|e| fail_all(e));
+        let (mut files, mut spanned): (Vec<_>, Vec<_>) = linked.into_iter().unzip();
+
+        if let Some(ref map) = args.flag_trace {
+            let input = read_source(&args.arg_source[0][..]);
+            let (t, a) = Trace::instrument(&spanned[..], &input[..]);
+            announcements = a;
+            let mut f = File::create(Path::new(&map[..])).unwrap();
+            t.write_map(&mut f, &args.arg_source[0][..]).unwrap();
+            trace = Some(t);
+        }
+
+        if args.flag_profile.is_some() {
+            let (p, counted) = Profile::instrument(spanned.clone());
+            // The counter increments go unannounced.
+            if trace.is_some() {
+                let mut traced = spanned.iter().zip(announcements.into_iter()).peekable();
+                announcements = counted.iter().map(|s| {
+                    match traced.peek() {
+                        Some(&(t, _)) if t.item == s.item && t.start == s.start => {}
+                        _ => return None,
+                    }
+                    traced.next().unwrap().1
+                }).collect();
+            }
+            spanned = counted;
+            profile = Some(p);
+        }
+        if trace.is_none() {
+            announcements = vec![None; spanned.len()];
+        }
+
+        // Instrumented programs come from a single file.
+        if instrumented {
+            files = vec![args.arg_source[0].clone(); spanned.len()];
+        }
+
+        let (mut stmts, mut tags): (Vec<_>, Vec<_>) = spanned.into_iter()
+            .zip(files.into_iter().zip(announcements.into_iter()))
+            .map(|(s, (file, announcement))| (s.item, (file, s.start, announcement)))
+            .unzip();
+
+        if let Some(ref profile) = profile {
+            if let Some(ref map) = args.flag_profile {
+                let mut f = File::create(Path::new(&map[..])).unwrap();
+                profile.write_map(&mut f, &args.arg_source[0][..]).unwrap();
+            }
+            if let Some(ref dump) = args.flag_profile_dump {
+                let mut f = File::create(Path::new(&dump[..])).unwrap();
+                profile.write_dump_script(&computer, &mut f).unwrap();
+            }
+        }
+
+        if let Some(ref dot) = args.flag_cfg {
+            let mut f = File::create(Path::new(&dot[..])).unwrap();
+            Cfg::new(&stmts[..]).write_dot(&mut f, ENTRY_LABEL).unwrap();
+        }
+
+        if args.flag_dce {
+            let (live_stmts, live_tags) =
+                cfg::eliminate_dead_code_tagged(stmts, tags, ENTRY_LABEL);
+            stmts = live_stmts;
+            tags = live_tags;
+        }
+        let mut files = vec!();
+        let mut locs = vec!();
+        let mut announcements = vec!();
+        for (file, loc, announcement) in tags.into_iter() {
+            files.push(file);
+            locs.push(loc);
+            announcements.push(announcement);
+        }
+
         let mut assembler = Assembler::new(&computer, stmts.into_iter());
         assembler.set_track_output(args.flag_track_output);
-        let motion : Box<LayoutMotion> = match args.flag_layout {
-            Some(LayoutKind::Linear) => Box::new(LinearMotion::new(origin)),
-            Some(LayoutKind::Packed) | None => Box::new(PackedMotion::new(origin)),
-        };
-        let mem_controllers = {
-            let mut c = vec!();
-            for region in computer.memory.iter() {
-                c.extend(fab::make_mem_ctrl(region));
-            }
-            c };
-        let mut layout = Layout::new(motion, assembler.chain(mem_controllers));
+        assembler.set_locations(locs.clone());
+        assembler.set_announcements(announcements);
+        let items: Vec<AssembledItem> = assembler.by_ref().collect();
+        (files, locs, items, assembler.usage().clone())
+    };
+    // Create as many scratch registers as the expansions took.
+    computer.temps = cmp::max(computer.temps, usage.temps);
+    let items: Vec<AssembledItem> =
+        items.into_iter().chain(mem_controllers(&computer)).collect();
+
+    let mut reservations = ReservationMap::new();
+    computer.reserve(&mut reservations).unwrap_or_else(|e| fail(e));
+    if let Some(ref path) = args.flag_reservations {
+        let mut f = File::open(Path::new(&path[..])).unwrap();
+        reservations.read_json(&mut f).unwrap_or_else(|e| fail(e));
+    }
 
-        let mut extent = Extent::Empty;
-        for (pos, block) in &mut layout {
-            extent.add(pos);
-            write!(output, "{}\n", Command::SetBlock(
-                pos.as_abs(), block.id, None, None,
-                Some(Nbt::Compound(block.nbt)))).unwrap();
+    let mut obstacles = Obstacles::new();
+    if let Some(ref paths) = args.flag_obstacles {
+        let origin = args.flag_obstacles_origin.as_ref()
+            .map(|o| parse_vec3(&o[..]).unwrap_or_else(|e| fail(e)));
+        for path in paths.split(',') {
+            read_obstacles(&mut obstacles, path, origin)
+                .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
         }
-        // FIXME: This is to accommodate the linear layout motion, whose power
-        // blocks are not contained by the extent.  But really, layout motions
-        // should be prepared to be interrogated about the extent.  That way we
-        // don't need to make any assumptions here.
-        if let Some(entry_extent) = layout.get_power_extent(ENTRY_LABEL) {
-            extent.union(&entry_extent);
+    }
+    let obstacles = Rc::new(obstacles);
+
+    let previous = args.flag_previous.as_ref().map(|path| read_manifest(&path[..]));
+    let columns = previous.as_ref().map(|m| m.line_columns()).unwrap_or(HashMap::new());
+    // With a previous manifest, only the differences are written out.
+    let incremental = previous.is_some() && !args.cmd_render;
+
+    // Lay the program out once without writing anything, to find out
+    // whether it runs into anything that is already there.
+    // The program's extent is only a bounding box, so the blocks and power
+    // positions themselves are what get checked.
+    let (program_extent, program_used) = {
+        let motion = make_motion(
+            &args.flag_layout, &args.flag_bounds[..], origin, &obstacles, &columns);
+        let mut layout = Layout::new(motion, items.clone().into_iter());
+        let mut used: Vec<Extent> =
+            (&mut layout).map(|(pos, _)| Extent::MinMax(pos, pos)).collect();
+        used.extend(layout.power_extents().into_iter().map(|(_, e)| e));
+        if let Some(e) = layout.error() {
+            fail(e.to_string());
+        }
+        (layout.extent(), used)
+    };
+    let mut start = origin;
+    let conflicts: Vec<String> = reservations.conflicts_any(&program_used[..]).iter()
+        .map(|r| format!("{} at {}", r.owner, r.extent()))
+        .collect();
+    if !conflicts.is_empty() {
+        if args.flag_auto_shift {
+            start.x += reservations.clear_x(&program_extent, MAX_AUTO_SHIFT)
+                .unwrap_or_else(|e| fail(format!(
+                    "cannot move the program at {}: {}", program_extent, e)));
+        } else {
+            fail(format!(
+                "the program at {} overlaps {}; move it, or use --auto-shift",
+                program_extent, conflicts.connect(", ")));
         }
+    }
+
+    let motion = make_motion(
+        &args.flag_layout, &args.flag_bounds[..], start, &obstacles, &columns);
+    let mut stats = if args.flag_stats || args.flag_stats_json.is_some() {
+        Some(ProgramStats::new(&items[..], &usage))
+    } else {
+        None
+    };
+    let mut layout = Layout::new(motion, items.into_iter());
 
-        if let Some(init) = args.flag_init {
-            let mut f = File::create(Path::new(&init[..])).unwrap();
-            computer.write_init_script(&mut f).unwrap();
+    // The scripts are kept in memory until everything has been checked,
+    // then written out, or sent to the server by deploy.
+    let mut blocks_script = vec!();
+    let mut diags = vec!();
+    let mut placed = vec!();
+    let mut manifest = Manifest::new(&computer);
+    for (pos, block) in &mut layout {
+        diags.extend(check::check_block(&block).into_iter());
+        placed.push(pos);
+        manifest.add_block(pos, &block);
+        let loc = block.loc;
+        let setblock = Command::SetBlock(
+            pos.as_abs(), block.id, None, None, Some(Nbt::Compound(block.nbt))).to_string();
+        if args.cmd_deploy {
+            diags.extend(check::check_deploy(loc, &setblock[..]).into_iter());
         }
+        if !args.cmd_render && !incremental {
+            write!(blocks_script, "{}\n", setblock).unwrap();
+        }
+    }
+    if let Some(e) = layout.error() {
+        fail(e.to_string());
+    }
+    let extent = layout.extent();
+
+    // The program may be laid out differently where it was moved to, so what
+    // was placed is checked again.
+    if start != origin {
+        let mut used: Vec<Extent> = placed.iter().map(|&pos| Extent::MinMax(pos, pos)).collect();
+        used.extend(layout.power_extents().into_iter().map(|(_, e)| e));
+        let conflicts: Vec<String> = reservations.conflicts_any(&used[..]).iter()
+            .map(|r| format!("{} at {}", r.owner, r.extent()))
+            .collect();
+        if !conflicts.is_empty() {
+            fail(format!("the program at {} still overlaps {} after moving it",
+                         extent, conflicts.connect(", ")));
+        }
+    }
+
+    for (label, power_extent) in layout.power_extents().into_iter() {
+        manifest.add_label(label, power_extent);
+    }
+    for &(ref name, pos) in layout.lines().iter() {
+        manifest.add_line(name.clone(), pos);
+    }
+    manifest.sort();
+    if let Some(ref previous) = previous {
+        if incremental {
+            write_diff(&mut blocks_script, previous, &manifest).unwrap();
+        }
+    }
+    if !args.cmd_deploy {
+        output.write_all(&blocks_script[..]).unwrap();
+    }
+    if let Some(ref path) = args.flag_manifest {
+        let mut f = File::create(Path::new(&path[..])).unwrap();
+        write!(f, "{}\n", manifest.to_json()).unwrap();
+    }
+
+    diags.extend(check::check_extent("program", &extent, args.flag_spawn_radius));
+    for region in computer.memory.iter() {
+        let what = format!("memory region {}", fab::mem_name(region));
+        diags.extend(check::check_extent(
+            &what[..], &region.extent(), args.flag_spawn_radius));
+        diags.extend(check::check_obstacles(
+            &what[..], &region.extent(), &obstacles).into_iter());
+    }
+    for diag in diags.iter() {
+        // The file of the statement the diagnostic is about.
+        let file = diag.loc.and_then(|loc| locs.iter().position(|&l| l == loc))
+            .map(|i| &files[i][..])
+            .unwrap_or(&args.arg_source[0][..]);
+        write!(io::stderr(), "{}\n", diag.describe(file)).unwrap();
+    }
+
+    if args.cmd_render {
+        let mut scene = Scene::new(&layout);
+        for region in computer.memory.iter() {
+            scene.add_region(fab::mem_name(region), region.extent());
+        }
+        scene.write_ascii(&mut output).unwrap();
+        if let Some(ref path) = args.flag_svg {
+            let mut f = File::create(Path::new(&path[..])).unwrap();
+            scene.write_svg(&mut f).unwrap();
+        }
+    }
 
-        if let Some(destroy) = args.flag_destroy {
-            let mut f = File::create(Path::new(&destroy[..])).unwrap();
-            computer.write_destroy_script(&mut f).unwrap();
+    if let Some(ref mut stats) = stats {
+        stats.measure(extent, |label| layout.get_power_extent(label));
+        if args.flag_stats {
+            stats.write_report(&mut io::stderr()).unwrap();
+        }
+        if let Some(ref path) = args.flag_stats_json {
+            let mut f = File::create(Path::new(&path[..])).unwrap();
+            write!(f, "{}\n", stats.to_json()).unwrap();
+        }
+    }
+
+    let mut init_script = vec!();
+    {
+        let f = &mut init_script;
+        computer.write_init_script(f).unwrap();
+        if let Some(ref profile) = profile {
+            profile.write_init_script(&computer, f).unwrap();
+        }
+        if trace.is_some() {
+            trace::write_init_script(f).unwrap();
+        }
+    }
+
+    let mut destroy_script = vec!();
+    {
+        let f = &mut destroy_script;
+        computer.write_destroy_script(f).unwrap();
+        if let Some(ref profile) = profile {
+            profile.write_destroy_script(f).unwrap();
+        }
+        if trace.is_some() {
+            trace::write_destroy_script(f).unwrap();
+        }
+        if obstacles.is_empty() {
             for cmd in commands::safe_fill(
                 extent, "minecraft:air".to_string(), None, None, None)
             {
                 write!(f, "{}\n", cmd).unwrap();
             }
+        } else {
+            // Filling the whole extent would take the obstacles with it,
+            // so clear only what the program uses.
+            for pos in placed.iter() {
+                write!(f, "{}\n", Command::SetBlock(
+                    pos.as_abs(), "minecraft:air".to_string(), None, None, None)).unwrap();
+            }
+            for (_, power_extent) in layout.power_extents().into_iter() {
+                for cmd in commands::safe_fill(
+                    power_extent, "minecraft:air".to_string(), None, None, None)
+                {
+                    write!(f, "{}\n", cmd).unwrap();
+                }
+            }
         }
+    }
+
+    let mut boot_script = vec!();
+    layout.write_boot_script(&mut boot_script, ENTRY_LABEL).unwrap();
+
+    for &(ref path, ref script) in [(&args.flag_init, &init_script),
+                                    (&args.flag_destroy, &destroy_script),
+                                    (&args.flag_boot, &boot_script)].iter() {
+        if let Some(ref path) = **path {
+            let mut f = File::create(Path::new(&path[..])).unwrap();
+            f.write_all(&script[..]).unwrap();
+        }
+    }
+
+    if diags.iter().any(|d| d.severity == Severity::Error) {
+        std::process::exit(1);
+    }
 
-        if let Some(boot) = args.flag_boot {
-            let mut f = File::create(Path::new(&boot[..])).unwrap();
-            boot_computer(&mut f, &layout).unwrap()
+    if args.cmd_deploy {
+        let mut scripts = vec!();
+        for name in args.flag_scripts.split(',') {
+            let script = match name.trim() {
+                "init" => &init_script,
+                "blocks" => &blocks_script,
+                "boot" => &boot_script,
+                "destroy" => &destroy_script,
+                _ => fail(format!("unknown script '{}', expected init, blocks, boot \
+                                   or destroy", name)),
+            };
+            scripts.push((name.trim(), script));
         }
+        let addr = args.flag_rcon.unwrap();
+        let password = args.flag_password.unwrap_or(String::new());
+        deploy(&addr[..], &password[..], &scripts[..], args.flag_batch, args.flag_delay)
+            .unwrap_or_else(|e| fail(e));
     }
 
 }
 
-fn boot_computer<Source>(w: &mut Write, layout: &Layout<Source>) -> io::Result<()>
-    where Source : Iterator<Item=AssembledItem>
+fn read_source(path: &str) -> String {
+    let mut input = String::new();
+    File::open(Path::new(path))
+        .and_then(|mut f| f.read_to_string(&mut input))
+        .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    input
+}
+
+fn read_unit(path: &str) -> Unit {
+    Unit::parse(path, &read_source(path)[..])
+}
+
+fn deploy(addr: &str, password: &str, scripts: &[(&str, &Vec<u8>)], batch: usize, delay_ms: u32)
+    -> Result<(), String>
 {
-    if let Some(Extent::MinMax(min, max)) = layout.get_power_extent(ENTRY_LABEL) {
-        let cmd = Command::Fill(
-            min.as_abs(), max.as_abs(),
-            "minecraft:redstone_block".to_string(), None, None, None);
-        try!(write!(w, "{}\n", cmd));
+    let scripts: Vec<(&str, Vec<String>)> = scripts.iter().map(|&(name, script)| {
+        let text = String::from_utf8_lossy(&script[..]);
+        (name, text.lines().map(|l| l.to_string()).collect())
+    }).collect();
+    for &(name, ref cmds) in scripts.iter() {
+        try!(rcon::check_script(&cmds[..]).map_err(|e| format!("{} script: {}", name, e)));
+    }
+
+    let mut rcon = try!(Rcon::connect(addr, password));
+    for &(name, ref cmds) in scripts.iter() {
+        let sent = try!(rcon::send_script(&mut rcon, &cmds[..], batch, delay_ms)
+            .map_err(|e| format!("{} script: {}", name, e)));
+        try!(write!(io::stderr(), "sent {} {} command(s)\n", sent, name)
+            .map_err(|e| e.to_string()));
     }
     Ok(())
 }
+
+fn fail(msg: String) -> ! {
+    write!(io::stderr(), "error: {}\n", msg).unwrap();
+    std::process::exit(1);
+}
+
+fn fail_all(errors: Vec<String>) -> ! {
+    for error in errors.iter() {
+        write!(io::stderr(), "error: {}\n", error).unwrap();
+    }
+    std::process::exit(1);
+}
+
+fn make_motion(kind: &Option<LayoutKind>, bounds: &str, start: Vec3,
+               obstacles: &Rc<Obstacles>, columns: &HashMap<String, i32>)
+    -> Box<LayoutMotion>
+{
+    let avoid = !obstacles.is_empty();
+    match *kind {
+        Some(LayoutKind::Linear) => {
+            let mut motion = LinearMotion::new(start);
+            if avoid { motion.set_obstacles(obstacles.clone()); }
+            Box::new(motion)
+        }
+        Some(LayoutKind::Packed) | None => {
+            let mut motion = PackedMotion::new(start);
+            if avoid { motion.set_obstacles(obstacles.clone()); }
+            Box::new(motion)
+        }
+        Some(LayoutKind::Bounded) => {
+            let size = parse_bounds(bounds).unwrap_or_else(|e| fail(e));
+            let mut motion = BoundedMotion::new(start, size);
+            if avoid { motion.set_obstacles(obstacles.clone()); }
+            Box::new(motion)
+        }
+        Some(LayoutKind::Stable) => {
+            if avoid {
+                fail("the stable layout cannot route around obstacles".to_string());
+            }
+            Box::new(StableMotion::new(start, columns.clone()))
+        }
+    }
+}
+
+fn read_obstacles(obstacles: &mut Obstacles, path: &str, origin: Option<Vec3>)
+    -> Result<(), String>
+{
+    let mut f = try!(File::open(Path::new(path)).map_err(|e| e.to_string()));
+    if path.ends_with(".schematic") {
+        obstacles.read_schematic(&mut f, origin)
+    } else if path.ends_with(".mca") {
+        obstacles.read_region(&mut f)
+    } else {
+        obstacles.read_json(&mut f)
+    }
+}
+
+fn mem_controllers(computer: &Computer) -> Vec<AssembledItem> {
+    let mut c = vec!();
+    for region in computer.memory.iter() {
+        c.extend(fab::make_mem_ctrl(region));
+    }
+    c
+}
+
+fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    let err = format!("invalid position '{}', expected X,Y,Z", s);
+    let mut parts = vec!();
+    for part in s.split(',') {
+        match part.trim().parse::<i32>() {
+            Ok(n) => parts.push(n),
+            _ => return Err(err),
+        }
+    }
+    if parts.len() != 3 {
+        return Err(err);
+    }
+    Ok(Vec3::new(parts[0], parts[1], parts[2]))
+}
+
+fn parse_bounds(bounds: &str) -> Result<Vec3, String> {
+    match parse_vec3(bounds) {
+        Ok(size) if size.x > 0 && size.y > 0 && size.z > 0 => Ok(size),
+        _ => Err(format!("invalid bounds '{}', expected X,Y,Z", bounds)),
+    }
+}
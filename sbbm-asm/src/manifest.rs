@@ -0,0 +1,228 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use commands::Command;
+use fab;
+use hw::Computer;
+use nbt::Nbt;
+use types::{Block, Extent, Vec3};
+
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::io::Read;
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct PlacedBlock {
+    pub pos: Vec3,
+    pub id: String,
+    // The block's data tag, with the keys of every compound sorted, so that
+    // the same block always gives the same tag.
+    pub nbt: String,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct NamedExtent {
+    pub name: String,
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct LineStart {
+    pub name: String,
+    pub pos: Vec3,
+}
+
+// A record of what an assembly put where, so that a later assembly of the same
+// program can replace only what changed.
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Manifest {
+    pub computer: String,
+    pub origin: Vec3,
+    pub memory: Vec<NamedExtent>,
+    pub labels: Vec<NamedExtent>,
+    pub lines: Vec<LineStart>,
+    pub blocks: Vec<PlacedBlock>,
+}
+
+pub struct Diff {
+    pub commands: Vec<String>,
+    // Changes that updating blocks cannot take care of.  The old machine has
+    // to be destroyed and the new one initialized.
+    pub warnings: Vec<String>,
+}
+
+fn canonical_nbt(nbt: &Nbt) -> String {
+    match *nbt {
+        Nbt::Compound(ref compound) => {
+            let mut entries: Vec<_> = compound.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            let entries: Vec<String> = entries.iter()
+                .map(|&(k, v)| format!("{}: {}", k, canonical_nbt(v)))
+                .collect();
+            format!("{{{}}}", entries.connect(", "))
+        }
+        _ => format!("{}", nbt),
+    }
+}
+
+fn named_extent(name: String, extent: Extent) -> Option<NamedExtent> {
+    match extent {
+        Extent::MinMax(min, max) => Some(NamedExtent { name: name, min: min, max: max }),
+        Extent::Empty => None,
+    }
+}
+
+impl Manifest {
+    pub fn new(computer: &Computer) -> Manifest {
+        let memory = computer.memory.iter()
+            .filter_map(|r| named_extent(fab::mem_name(r), r.extent()))
+            .collect();
+        Manifest {
+            computer: computer.name.clone(),
+            origin: computer.origin,
+            memory: memory,
+            labels: vec!(),
+            lines: vec!(),
+            blocks: vec!(),
+        }
+    }
+
+    pub fn add_block(&mut self, pos: Vec3, block: &Block) {
+        self.blocks.push(PlacedBlock {
+            pos: pos,
+            id: block.id.clone(),
+            nbt: canonical_nbt(&Nbt::Compound(block.nbt.clone())),
+        });
+    }
+
+    pub fn add_label(&mut self, label: String, extent: Extent) {
+        self.labels.extend(named_extent(label, extent).into_iter());
+    }
+
+    pub fn add_line(&mut self, name: String, pos: Vec3) {
+        self.lines.push(LineStart { name: name, pos: pos });
+    }
+
+    // Puts everything in a fixed order, so that assembling the same program
+    // twice gives the same manifest.
+    pub fn sort(&mut self) {
+        self.labels.sort_by(|a, b| a.name.cmp(&b.name));
+        self.blocks.sort_by(|a, b| {
+            (a.pos.x, a.pos.y, a.pos.z).cmp(&(b.pos.x, b.pos.y, b.pos.z))
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        json::as_pretty_json(self).to_string()
+    }
+
+    pub fn read_json(r: &mut Read) -> Result<Manifest, String> {
+        let mut text = String::new();
+        try!(r.read_to_string(&mut text).map_err(|e| e.to_string()));
+        json::decode(&text[..]).map_err(|e| e.to_string())
+    }
+
+    // The x of each line's column, by the line's name, for StableMotion.
+    pub fn line_columns(&self) -> HashMap<String, i32> {
+        self.lines.iter().map(|l| (l.name.clone(), l.pos.x)).collect()
+    }
+}
+
+fn air() -> String {
+    "minecraft:air".to_string()
+}
+
+// The data tag is already formatted, so this can't go through Command.
+fn setblock(block: &PlacedBlock) -> String {
+    format!("setblock {} {} 0 replace {}", block.pos.as_abs(), block.id, block.nbt)
+}
+
+// The commands that turn the blocks of old into the blocks of new: a setblock
+// for each block that is new or different, and air for each block that is
+// gone.  Power positions that are no longer used are cleared too, since they
+// may hold a redstone block.
+pub fn diff(old: &Manifest, new: &Manifest) -> Diff {
+    let mut warnings = vec!();
+    if old.computer != new.computer || old.origin != new.origin {
+        warnings.push(format!(
+            "the computer changed from {} at {} {} {} to {} at {} {} {}",
+            old.computer, old.origin.x, old.origin.y, old.origin.z,
+            new.computer, new.origin.x, new.origin.y, new.origin.z));
+    }
+    if old.memory != new.memory {
+        let names = |m: &Manifest| -> Vec<String> {
+            m.memory.iter()
+                .map(|r| format!("{} at {}", r.name, Extent::MinMax(r.min, r.max)))
+                .collect()
+        };
+        warnings.push(format!(
+            "memory changed from [{}] to [{}]",
+            names(old).connect(", "), names(new).connect(", ")));
+    }
+
+    let old_blocks: HashMap<Vec3, &PlacedBlock> =
+        old.blocks.iter().map(|b| (b.pos, b)).collect();
+    let new_blocks: HashMap<Vec3, &PlacedBlock> =
+        new.blocks.iter().map(|b| (b.pos, b)).collect();
+
+    let mut commands = vec!();
+    for block in old.blocks.iter() {
+        if !new_blocks.contains_key(&block.pos) {
+            commands.push(
+                Command::SetBlock(block.pos.as_abs(), air(), None, None, None).to_string());
+        }
+    }
+
+    let new_power: Vec<Extent> =
+        new.labels.iter().map(|l| Extent::MinMax(l.min, l.max)).collect();
+    for label in old.labels.iter() {
+        let old_power = Extent::MinMax(label.min, label.max);
+        if !new_power.contains(&old_power) {
+            commands.push(Command::Fill(
+                label.min.as_abs(), label.max.as_abs(), air(), None, None, None).to_string());
+        }
+    }
+
+    for block in new.blocks.iter() {
+        if old_blocks.get(&block.pos) != Some(&block) {
+            commands.push(setblock(block));
+        }
+    }
+
+    Diff { commands: commands, warnings: warnings }
+}
+
+#[test]
+fn test_diff() {
+    let computer = Computer {
+        name: "computer".to_string(),
+        origin: Vec3::new(0, 0, 0),
+        memory: vec!(),
+        temps: 0,
+    };
+    let say = |msg: &str| fab::cmd_block(Command::Say(msg.to_string()), false);
+
+    let mut old = Manifest::new(&computer);
+    old.add_block(Vec3::new(0, 1, 0), &say("a"));
+    old.add_block(Vec3::new(0, 1, 1), &say("b"));
+    old.add_block(Vec3::new(0, 1, 2), &say("c"));
+    old.add_label("foo".to_string(), Extent::MinMax(Vec3::new(0, 0, 0), Vec3::new(0, 0, 2)));
+
+    let mut new = Manifest::new(&computer);
+    new.add_block(Vec3::new(0, 1, 0), &say("a"));
+    new.add_block(Vec3::new(0, 1, 1), &say("x"));
+    new.add_label("foo".to_string(), Extent::MinMax(Vec3::new(0, 0, 0), Vec3::new(0, 0, 1)));
+
+    let diff = diff(&old, &new);
+    assert!(diff.warnings.is_empty());
+    assert_eq!(
+        vec!("setblock 0 1 2 minecraft:air 0 replace".to_string(),
+             "fill 0 0 0 0 0 2 minecraft:air 0 replace".to_string(),
+             "setblock 0 1 1 minecraft:command_block 0 replace \
+              {Command: \"say x\", TrackOutput: 0b}".to_string()),
+        diff.commands);
+
+    let json = new.to_json();
+    assert_eq!(new, Manifest::read_json(&mut json.as_bytes()).unwrap());
+}
@@ -1,12 +1,14 @@
 // Copyright 2015, Christopher Chambers
 // Distributed under the GNU GPL v3. See COPYING for details.
 
-use std::collections::HashMap;
+use ordered_map::OrderedMap;
 use std::fmt;
+use std::f64;
+use std::io::{self, Read};
 
-pub type NbtCompound = HashMap<String, Nbt>;
+pub type NbtCompound = OrderedMap<String, Nbt>;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Nbt {
     Byte(i8),
     Short(i16),
@@ -47,3 +49,318 @@ impl fmt::Display for Nbt {
         }
     }
 }
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn read_bytes(r: &mut Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    let mut done = 0;
+    while done < len {
+        match try!(r.read(&mut buf[done..])) {
+            0 => return Err(invalid("unexpected end of NBT data".to_string())),
+            n => done += n,
+        }
+    }
+    Ok(buf)
+}
+
+// NBT is big-endian throughout.
+fn read_u64(r: &mut Read, len: usize) -> io::Result<u64> {
+    let bytes = try!(read_bytes(r, len));
+    Ok(bytes.iter().fold(0, |n, &b| (n << 8) | b as u64))
+}
+
+// Puts an IEEE 754 float back together from its bits, given how many of them
+// are exponent and how many are mantissa.  Every float and double is exactly
+// a double, so floats are read this way too, and narrowed afterwards.
+fn float_from_bits(bits: u64, exp_bits: u32, mantissa_bits: u32) -> f64 {
+    let sign = if (bits >> (exp_bits + mantissa_bits)) & 1 == 0 { 1.0 } else { -1.0 };
+    let max_exp = (1 << exp_bits) - 1;
+    let exp = ((bits >> mantissa_bits) & max_exp) as i32;
+    let mantissa = bits & ((1 << mantissa_bits) - 1);
+    let bias = (max_exp >> 1) as i32;
+
+    let (significand, scale) = if exp == max_exp as i32 {
+        return if mantissa == 0 { sign * f64::INFINITY } else { f64::NAN };
+    } else if exp == 0 {
+        // Subnormal, with no implicit leading one.
+        (mantissa, 1 - bias - mantissa_bits as i32)
+    } else {
+        (mantissa | (1 << mantissa_bits), exp - bias - mantissa_bits as i32)
+    };
+    // The scale is applied in two halves, as the smallest doubles are too
+    // small for a single power of two to reach.
+    let half = scale / 2;
+    sign * significand as f64 * 2f64.powi(half) * 2f64.powi(scale - half)
+}
+
+fn read_len(r: &mut Read) -> io::Result<usize> {
+    let len = try!(read_u64(r, 4)) as i32;
+    if len < 0 {
+        return Err(invalid(format!("negative NBT length {}", len)));
+    }
+    Ok(len as usize)
+}
+
+fn read_string(r: &mut Read) -> io::Result<String> {
+    let len = try!(read_u64(r, 2)) as usize;
+    let bytes = try!(read_bytes(r, len));
+    Ok(String::from_utf8_lossy(&bytes[..]).into_owned())
+}
+
+fn read_payload(r: &mut Read, tag: u8) -> io::Result<Nbt> {
+    Ok(match tag {
+        1 => Nbt::Byte(try!(read_u64(r, 1)) as i8),
+        2 => Nbt::Short(try!(read_u64(r, 2)) as i16),
+        3 => Nbt::Int(try!(read_u64(r, 4)) as i32),
+        4 => Nbt::Long(try!(read_u64(r, 8)) as i64),
+        5 => Nbt::Float(float_from_bits(try!(read_u64(r, 4)), 8, 23) as f32),
+        6 => Nbt::Double(float_from_bits(try!(read_u64(r, 8)), 11, 52)),
+        7 => {
+            let len = try!(read_len(r));
+            Nbt::ByteArray(try!(read_bytes(r, len)))
+        }
+        8 => Nbt::String(try!(read_string(r))),
+        9 => {
+            let elem_tag = try!(read_u64(r, 1)) as u8;
+            let len = try!(read_len(r));
+            let mut list = vec!();
+            for _ in 0..len {
+                list.push(try!(read_payload(r, elem_tag)));
+            }
+            Nbt::List(list)
+        }
+        10 => {
+            let mut compound = NbtCompound::new();
+            loop {
+                match try!(read_named(r)) {
+                    Some((name, value)) => { compound.insert(name, value); }
+                    None => break,
+                }
+            }
+            Nbt::Compound(compound)
+        }
+        11 => {
+            let len = try!(read_len(r));
+            let mut ints = vec!();
+            for _ in 0..len {
+                ints.push(try!(read_u64(r, 4)) as i32);
+            }
+            Nbt::IntArray(ints)
+        }
+        _ => return Err(invalid(format!("unknown NBT tag {}", tag))),
+    })
+}
+
+// Reads a tag and its name, or None at the end of a compound.
+fn read_named(r: &mut Read) -> io::Result<Option<(String, Nbt)>> {
+    let tag = try!(read_u64(r, 1)) as u8;
+    if tag == 0 {
+        return Ok(None);
+    }
+    let name = try!(read_string(r));
+    let value = try!(read_payload(r, tag));
+    Ok(Some((name, value)))
+}
+
+// Reads the root tag of uncompressed, binary NBT, as found in schematics and
+// region files once they have been inflated.
+pub fn read(r: &mut Read) -> io::Result<(String, Nbt)> {
+    match try!(read_named(r)) {
+        Some(root) => Ok(root),
+        None => Err(invalid("the NBT data has no root tag".to_string())),
+    }
+}
+
+// Parses a data tag as written in commands, e.g. {Command: "say hi", Foo: 1b}.
+// Like the server, it accepts unquoted strings, and numbers without a suffix
+// are ints, or doubles if they have a decimal point.
+pub fn parse(s: &str) -> Result<Nbt, String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut pos = 0;
+    let nbt = try!(parse_value(&chars[..], &mut pos));
+    skip_space(&chars[..], &mut pos);
+    if pos < chars.len() {
+        return Err(format!("unexpected '{}' after the data tag", chars[pos]));
+    }
+    Ok(nbt)
+}
+
+fn skip_space(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+}
+
+fn expect(chars: &[char], pos: &mut usize, c: char) -> Result<(), String> {
+    skip_space(chars, pos);
+    if *pos < chars.len() && chars[*pos] == c {
+        *pos += 1;
+        Ok(())
+    } else {
+        Err(format!("expected '{}' in the data tag", c))
+    }
+}
+
+// A quoted string, or everything up to the next delimiter.
+fn parse_text(chars: &[char], pos: &mut usize) -> Result<(String, bool), String> {
+    skip_space(chars, pos);
+    let mut text = String::new();
+    if *pos < chars.len() && chars[*pos] == '"' {
+        *pos += 1;
+        loop {
+            match chars.get(*pos) {
+                Some(&'"') => { *pos += 1; return Ok((text, true)); }
+                Some(&'\\') if *pos + 1 < chars.len() => {
+                    text.push(chars[*pos + 1]);
+                    *pos += 2;
+                }
+                Some(&c) => { text.push(c); *pos += 1; }
+                None => return Err("unterminated string in the data tag".to_string()),
+            }
+        }
+    }
+    while *pos < chars.len() && !",:[]{}".contains(chars[*pos]) {
+        text.push(chars[*pos]);
+        *pos += 1;
+    }
+    Ok((text.trim().to_string(), false))
+}
+
+fn parse_number(text: &str) -> Option<Nbt> {
+    if text.is_empty() {
+        return None;
+    }
+    let (digits, suffix) = text.split_at(text.len() - 1);
+    let parsed = match suffix {
+        "b" | "B" => digits.parse().ok().map(Nbt::Byte),
+        "s" | "S" => digits.parse().ok().map(Nbt::Short),
+        "i" | "I" => digits.parse().ok().map(Nbt::Int),
+        "l" | "L" => digits.parse().ok().map(Nbt::Long),
+        "f" | "F" => digits.parse().ok().map(Nbt::Float),
+        "d" | "D" => digits.parse().ok().map(Nbt::Double),
+        _ => None,
+    };
+    parsed
+        .or_else(|| text.parse().ok().map(Nbt::Int))
+        .or_else(|| if text.contains('.') { text.parse().ok().map(Nbt::Double) } else { None })
+}
+
+fn parse_value(chars: &[char], pos: &mut usize) -> Result<Nbt, String> {
+    skip_space(chars, pos);
+    match chars.get(*pos) {
+        Some(&'{') => {
+            *pos += 1;
+            let mut compound = NbtCompound::new();
+            skip_space(chars, pos);
+            if chars.get(*pos) == Some(&'}') {
+                *pos += 1;
+                return Ok(Nbt::Compound(compound));
+            }
+            loop {
+                let (key, _) = try!(parse_text(chars, pos));
+                try!(expect(chars, pos, ':'));
+                let value = try!(parse_value(chars, pos));
+                compound.insert(key, value);
+                skip_space(chars, pos);
+                match chars.get(*pos) {
+                    Some(&',') => { *pos += 1; }
+                    Some(&'}') => { *pos += 1; return Ok(Nbt::Compound(compound)); }
+                    _ => return Err("expected ',' or '}' in the data tag".to_string()),
+                }
+            }
+        }
+        Some(&'[') => {
+            *pos += 1;
+            let mut list = vec!();
+            skip_space(chars, pos);
+            if chars.get(*pos) == Some(&']') {
+                *pos += 1;
+                return Ok(Nbt::List(list));
+            }
+            loop {
+                list.push(try!(parse_value(chars, pos)));
+                skip_space(chars, pos);
+                match chars.get(*pos) {
+                    Some(&',') => { *pos += 1; }
+                    Some(&']') => { *pos += 1; return Ok(Nbt::List(list)); }
+                    _ => return Err("expected ',' or ']' in the data tag".to_string()),
+                }
+            }
+        }
+        Some(_) => {
+            let (text, quoted) = try!(parse_text(chars, pos));
+            if !quoted {
+                if let Some(number) = parse_number(&text[..]) {
+                    return Ok(number);
+                }
+            }
+            Ok(Nbt::String(text))
+        }
+        None => Err("the data tag ended early".to_string()),
+    }
+}
+
+#[test]
+fn test_read() {
+    let data: &[u8] = &[
+        10, 0, 5, b'h', b'e', b'l', b'l', b'o',
+          2, 0, 1, b's', 0xff, 0xfe,
+          7, 0, 1, b'b', 0, 0, 0, 2, 1, 2,
+          9, 0, 1, b'l', 3, 0, 0, 0, 1, 0, 0, 1, 0,
+          8, 0, 1, b't', 0, 2, b'h', b'i',
+        0];
+    let (name, root) = read(&mut &data[..]).unwrap();
+    assert_eq!("hello", &name[..]);
+
+    let mut expected = NbtCompound::new();
+    expected.insert("s".to_string(), Nbt::Short(-2));
+    expected.insert("b".to_string(), Nbt::ByteArray(vec!(1, 2)));
+    expected.insert("l".to_string(), Nbt::List(vec!(Nbt::Int(256))));
+    expected.insert("t".to_string(), Nbt::String("hi".to_string()));
+    assert_eq!(Nbt::Compound(expected), root);
+
+    assert!(read(&mut &data[..10]).is_err());
+}
+
+#[test]
+fn test_float_from_bits() {
+    use std::f32;
+
+    let float = |bits: u32| float_from_bits(bits as u64, 8, 23) as f32;
+    assert_eq!(1.5, float(0x3fc00000));
+    assert_eq!(-2.0, float(0xc0000000));
+    assert_eq!(0.0, float(0));
+    assert_eq!(f32::MAX, float(0x7f7fffff));
+    assert_eq!(f32::MIN_POSITIVE, float(0x00800000));
+    assert_eq!(f32::MIN_POSITIVE / 8388608.0, float(1));
+    assert_eq!(f32::NEG_INFINITY, float(0xff800000));
+    assert!(float(0x7fc00000).is_nan());
+
+    let double = |bits: u64| float_from_bits(bits, 11, 52);
+    assert_eq!(1.5, double(0x3ff8000000000000));
+    assert_eq!(-0.1, double(0xbfb999999999999a));
+    assert_eq!(f64::MAX, double(0x7fefffffffffffff));
+    assert_eq!(f64::MIN_POSITIVE / 4503599627370496.0, double(1));
+    assert_eq!(f64::INFINITY, double(0x7ff0000000000000));
+}
+
+#[test]
+fn test_parse() {
+    let nbt = parse("{Command: \"say \\\"hi\\\"\", TrackOutput: 0b, \
+                     Stats: {Name: \"@e[name=computer]\", N: -12}, L: [1.5, 2s]}").unwrap();
+    let mut stats = NbtCompound::new();
+    stats.insert("Name".to_string(), Nbt::String("@e[name=computer]".to_string()));
+    stats.insert("N".to_string(), Nbt::Int(-12));
+    let mut expected = NbtCompound::new();
+    expected.insert("Command".to_string(), Nbt::String("say \"hi\"".to_string()));
+    expected.insert("TrackOutput".to_string(), Nbt::Byte(0));
+    expected.insert("Stats".to_string(), Nbt::Compound(stats));
+    expected.insert("L".to_string(), Nbt::List(vec!(Nbt::Double(1.5), Nbt::Short(2))));
+    assert_eq!(Nbt::Compound(expected), nbt);
+
+    assert!(parse("{Command: \"say hi}").is_err());
+    assert!(parse("{A: 1} x").is_err());
+}
@@ -0,0 +1,314 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Relocatable objects, for assembling the files of a program separately and
+// linking them later.  An object is a file's assembled items, in JSON.  The
+// blocks that refer to labels are kept as pending items, which are the
+// relocations: the layout fills in where the labels are, and the linker
+// fills in the return addresses of branches with link.  Alongside the items
+// are the labels the file defines, which of them are global, and the
+// addresses it gave the labels that code returns to.  The computer has no
+// data section, so code is all an object holds.
+//
+// Linking follows the same rules as linking sources, in link.rs.
+
+use assembler::{self, AssembledItem, Assembler, PendingKind, Usage};
+use assembler::AssembledItem::*;
+use fab;
+use hw::Computer;
+use lexer::Location;
+use link::{self, Symbols, Unit};
+
+use rustc_serialize::json;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+// Objects written by a different version are refused, rather than misread.
+const FORMAT: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Symbol {
+    pub name: String,
+    pub global: bool,
+    pub loc: Location,
+}
+
+#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+pub struct Object {
+    pub format: u32,
+    pub filename: String,
+    // The file and the ones it includes, indexed by Location::file.
+    pub files: Vec<String>,
+    // The start and size of each memory region the code was assembled for.
+    pub memory: Vec<(u32, u32)>,
+    pub items: Vec<AssembledItem>,
+    // Every label the items define, including the ones the assembler made.
+    pub symbols: Vec<Symbol>,
+    // The addresses of the labels that code returns to, numbered from 1.
+    pub addresses: Vec<(String, i32)>,
+    pub usage: Usage,
+}
+
+fn memory_regions(computer: &Computer) -> Vec<(u32, u32)> {
+    computer.memory.iter().map(|r| (r.start, r.size)).collect()
+}
+
+// Where the source of an item is, if it came from a statement.
+pub fn item_loc(item: &AssembledItem) -> Option<Location> {
+    match *item {
+        Complete(ref block) => block.loc,
+        Pending(_, ref pending) => pending.loc,
+        _ => None,
+    }
+}
+
+impl Object {
+    // Assembles a unit into an object, after checking its declarations.
+    pub fn assemble(unit: &Unit, computer: &Computer, track_output: bool, entry: &str)
+        -> Result<Object, Vec<String>>
+    {
+        let mut errors = vec!();
+        let syms = link::symbols(unit, entry, &mut errors);
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let (stmts, locs): (Vec<_>, Vec<_>) =
+            unit.stmts.iter().map(|s| (s.item.clone(), s.start)).unzip();
+        let mut assembler = Assembler::new(computer, stmts.into_iter());
+        assembler.set_track_output(track_output);
+        assembler.set_relocatable(true);
+        assembler.set_locations(locs);
+        let items: Vec<AssembledItem> = assembler.by_ref().collect();
+
+        // The labels the assembler made are located where the code after
+        // them came from.
+        let mut symbols = vec!();
+        for (i, item) in items.iter().enumerate() {
+            if let Label(ref name) = *item {
+                let loc = syms.defined.get(name).cloned()
+                    .or_else(|| items[i..].iter().filter_map(item_loc).next())
+                    .unwrap_or(Location { offset: 0, col: 0, line: 0, file: 0 });
+                symbols.push(Symbol {
+                    name: name.clone(),
+                    global: syms.globals.contains(name),
+                    loc: loc,
+                });
+            }
+        }
+
+        Ok(Object {
+            format: FORMAT,
+            filename: unit.filename.clone(),
+            files: unit.files.clone(),
+            memory: memory_regions(computer),
+            symbols: symbols,
+            addresses: assembler.label_addrs(),
+            usage: assembler.usage().clone(),
+            items: items,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        json::as_pretty_json(self).to_string()
+    }
+
+    pub fn read_json(r: &mut Read) -> Result<Object, String> {
+        let mut text = String::new();
+        try!(r.read_to_string(&mut text).map_err(|e| e.to_string()));
+        let object: Object = try!(json::decode(&text[..]).map_err(|e| e.to_string()));
+        if object.format != FORMAT {
+            return Err(format!("the object is in format {}, but this assembler reads format {}",
+                               object.format, FORMAT));
+        }
+        Ok(object)
+    }
+
+    fn symbols(&self) -> Symbols {
+        let zero = Location { offset: 0, col: 0, line: 0, file: 0 };
+        Symbols {
+            filename: self.filename.clone(),
+            files: self.files.clone(),
+            defined: self.symbols.iter().map(|s| (s.name.clone(), s.loc)).collect(),
+            globals: self.symbols.iter().filter(|s| s.global).map(|s| s.name.clone()).collect(),
+            refs: self.items.iter().filter_map(|item| match *item {
+                Pending(ref label, ref pending) => {
+                    Some((label.clone(), pending.loc.unwrap_or(zero)))
+                }
+                _ => None,
+            }).collect(),
+        }
+    }
+}
+
+// Links objects, and whichever of the library objects they use, into the
+// items of one program, ready to lay out along with the memory controllers.
+// Each object's return addresses follow on from the last one's, and the
+// indirect jump table for all of them goes at the end.
+pub fn link(objects: Vec<Object>, libs: Vec<Object>, computer: &Computer, track_output: bool,
+            entry: &str)
+    -> Result<(Vec<AssembledItem>, Usage), Vec<String>>
+{
+    let mut errors = vec!();
+    let memory = memory_regions(computer);
+    for object in objects.iter().chain(libs.iter()) {
+        if object.memory != memory {
+            errors.push(format!("{}: assembled for different memory regions than {}'s",
+                                object.filename, computer.name));
+        }
+    }
+
+    let mut builtins: HashSet<String> =
+        computer.memory.iter().map(|r| fab::mem_label(r)).collect();
+    builtins.insert("@jump_indirect".to_string());
+    let unit_syms: Vec<Symbols> = objects.iter().map(|o| o.symbols()).collect();
+    let lib_syms: Vec<Symbols> = libs.iter().map(|o| o.symbols()).collect();
+    let linked = link::resolve(&unit_syms[..], &lib_syms[..], entry, &builtins, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let all: Vec<&Object> = objects.iter().chain(libs.iter()).collect();
+    let renamer = |renames: &HashMap<String, String>, label: &String| {
+        renames.get(label).unwrap_or(label).clone()
+    };
+
+    // Each object's addresses follow on from the last one's.
+    let mut addrs: HashMap<String, i32> = HashMap::new();
+    let mut next_addr = 0;
+    for &(i, ref renames) in linked.iter() {
+        let base = next_addr;
+        for &(ref label, addr) in all[i].addresses.iter() {
+            addrs.insert(renamer(renames, label), base + addr);
+            next_addr = cmp::max(next_addr, base + addr);
+        }
+    }
+
+    let mut items = vec!();
+    let mut usage = Usage::default();
+    for (i, renames) in linked.into_iter() {
+        let object = all[i];
+        let rename = |label: &String| renamer(&renames, label);
+
+        for item in object.items.iter().cloned() {
+            items.push(match item {
+                Label(label) => Label(rename(&label)),
+                Pending(label, pending) => {
+                    let is_address = match pending.kind {
+                        PendingKind::Address(_) => true,
+                        PendingKind::Fill(_) => false,
+                    };
+                    let label = rename(&label);
+                    if is_address {
+                        // A label whose address is only taken gets one now.
+                        if !addrs.contains_key(&label) {
+                            next_addr += 1;
+                            addrs.insert(label.clone(), next_addr);
+                        }
+                        Complete(pending.resolve_address(addrs[&label]))
+                    } else {
+                        Pending(label, pending)
+                    }
+                }
+                item => item,
+            });
+        }
+
+        usage.memory = usage.memory || object.usage.memory;
+        usage.bitwise = usage.bitwise || object.usage.bitwise;
+        usage.temps = cmp::max(usage.temps, object.usage.temps);
+        usage.memory_labels.extend(object.usage.memory_labels.iter().map(|l| rename(l)));
+        usage.bitwise_labels.extend(object.usage.bitwise_labels.iter().map(|l| rename(l)));
+    }
+    let mut addrs: Vec<(String, i32)> = addrs.into_iter().collect();
+    addrs.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    items.extend(assembler::indirect_jump_table(addrs, track_output).into_iter());
+    Ok((items, usage))
+}
+
+#[cfg(test)]
+fn test_computer(size: u32) -> Computer {
+    use hw::{MemoryRegion, MemoryStride, REGISTER_FILE};
+    use types::Vec3;
+
+    Computer {
+        name: "computer".to_string(),
+        origin: Vec3::new(0, 0, 0),
+        memory: vec!(MemoryRegion {
+            start: 0x10,
+            size: size,
+            origin: Vec3::new(-1, 0, 0),
+            growth: Vec3::new(-1, 1, 1),
+            stride: MemoryStride::XY(8, 8),
+        }),
+        temps: REGISTER_FILE.temps,
+    }
+}
+
+#[cfg(test)]
+fn compile(filename: &str, src: &str, computer: &Computer) -> Object {
+    let object = Object::assemble(&Unit::parse(filename, src), computer, false, "_start")
+        .unwrap();
+    // Selectors come back from the file as text, so the round trip is
+    // checked on the text.
+    let json = object.to_json();
+    assert_eq!(json, Object::read_json(&mut json.as_bytes()).unwrap().to_json());
+    object
+}
+
+#[test]
+fn test_object() {
+    let computer = test_computer(0x100);
+    let src = "_start:\nmov r0, #5\nldr r1, [r0]\nbl =double\nb r0\ndouble:\nadd r0, r0\nb lr\n";
+
+    // A program in one object links to exactly what the assembler makes of
+    // it directly.
+    let unit = Unit::parse("main.s", src);
+    let (stmts, locs): (Vec<_>, Vec<_>) =
+        unit.stmts.iter().map(|s| (s.item.clone(), s.start)).unzip();
+    let mut assembler = Assembler::new(&computer, stmts.into_iter());
+    assembler.set_locations(locs);
+    let direct: Vec<AssembledItem> = assembler.by_ref().collect();
+    let (items, usage) =
+        link(vec!(compile("main.s", src, &computer)), vec!(), &computer, false, "_start")
+        .unwrap();
+    assert_eq!(direct, items);
+    assert!(usage.memory_labels.contains("_start"));
+
+    // Each object's return addresses and made-up labels are its own.
+    let main = "_start:\nbl =double\nmov r0, #1\n";
+    let lib = ".global double\ndouble:\nbl =half\nb lr\nhalf:\nb lr\n";
+    let (items, _) = link(vec!(compile("main.s", main, &computer)),
+                          vec!(compile("lib.s", lib, &computer)),
+                          &computer, false, "_start").unwrap();
+    let labels: Vec<&str> = items.iter().filter_map(|item| match *item {
+        Label(ref label) => Some(&label[..]),
+        _ => None,
+    }).collect();
+    assert!(labels.contains(&"@br_cont_0@main.s") && labels.contains(&"@br_cont_0@lib.s"),
+            "{:?}", labels);
+    let table: Vec<String> = items.iter()
+        .skip_while(|item| **item != Label("@jump_indirect".to_string()))
+        .filter_map(|item| match *item {
+            Pending(ref label, ref pending) if label != "@jump_indirect" => {
+                let cond = pending.cond.as_ref().unwrap().to_string();
+                let addr = cond.rsplit('=').next().unwrap().trim_right_matches(']');
+                Some(format!("{} {}", label, addr))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(vec!("_start 1", "@br_cont_0@main.s 2", "double 3", "@br_cont_0@lib.s 4",
+                    "@br_cont_1 5", "half 5", "@br_cont_2 6"),
+               table);
+
+    let errors = link(vec!(compile("main.s", src, &test_computer(0x200))), vec!(),
+                      &computer, false, "_start").unwrap_err();
+    assert_eq!(vec!("main.s: assembled for different memory regions than computer's"
+                    .to_string()),
+               errors);
+    let errors = link(vec!(compile("main.s", "_start:\nb =nowhere\n", &computer)), vec!(),
+                      &computer, false, "_start").unwrap_err();
+    assert_eq!(vec!("main.s:2:1: undefined symbol 'nowhere'".to_string()), errors);
+}
@@ -0,0 +1,161 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use std::borrow::Borrow;
+use std::iter::FromIterator;
+use std::slice;
+use std::vec;
+
+// A map that remembers the order its keys were first inserted in, and iterates
+// in that order.  It is used wherever a map ends up in generated commands,
+// such as NBT compounds and selector scores, so that the same program always
+// assembles to the same text.  The maps involved hold a handful of entries, so
+// lookups simply search the entries in order.
+#[derive(Clone, Debug)]
+pub struct OrderedMap<K, V> {
+    entries: Vec<(K, V)>,
+}
+
+impl<K : PartialEq, V> OrderedMap<K, V> {
+    pub fn new() -> OrderedMap<K, V> {
+        OrderedMap { entries: vec!() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn position<Q : ?Sized>(&self, key: &Q) -> Option<usize>
+        where K : Borrow<Q>, Q : PartialEq
+    {
+        self.entries.iter().position(|e| e.0.borrow() == key)
+    }
+
+    // Replacing the value of a key leaves the key where it was.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.position(&key) {
+            Some(i) => Some(::std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn get<Q : ?Sized>(&self, key: &Q) -> Option<&V>
+        where K : Borrow<Q>, Q : PartialEq
+    {
+        self.position(key).map(|i| &self.entries[i].1)
+    }
+
+    pub fn get_mut<Q : ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+        where K : Borrow<Q>, Q : PartialEq
+    {
+        match self.position(key) {
+            Some(i) => Some(&mut self.entries[i].1),
+            None => None,
+        }
+    }
+
+    pub fn contains_key<Q : ?Sized>(&self, key: &Q) -> bool
+        where K : Borrow<Q>, Q : PartialEq
+    {
+        self.position(key).is_some()
+    }
+
+    pub fn remove<Q : ?Sized>(&mut self, key: &Q) -> Option<V>
+        where K : Borrow<Q>, Q : PartialEq
+    {
+        self.position(key).map(|i| self.entries.remove(i).1)
+    }
+
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.entries.iter() }
+    }
+}
+
+// Two maps are equal when they hold the same entries, whatever their order,
+// the same as for HashMap.
+impl<K : PartialEq, V : PartialEq> PartialEq for OrderedMap<K, V> {
+    fn eq(&self, other: &OrderedMap<K, V>) -> bool {
+        self.len() == other.len() &&
+            self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+// Maps are written out as a list of their entries, to keep the order.
+impl<K : Encodable, V : Encodable> Encodable for OrderedMap<K, V> {
+    fn encode<S : Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.entries.encode(s)
+    }
+}
+
+impl<K : Decodable + PartialEq, V : Decodable> Decodable for OrderedMap<K, V> {
+    fn decode<D : Decoder>(d: &mut D) -> Result<OrderedMap<K, V>, D::Error> {
+        let entries: Vec<(K, V)> = try!(Decodable::decode(d));
+        Ok(entries.into_iter().collect())
+    }
+}
+
+pub struct Iter<'a, K : 'a, V : 'a> {
+    inner: slice::Iter<'a, (K, V)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|e| (&e.0, &e.1))
+    }
+}
+
+impl<'a, K : PartialEq, V> IntoIterator for &'a OrderedMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K : PartialEq, V> IntoIterator for OrderedMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> vec::IntoIter<(K, V)> {
+        self.entries.into_iter()
+    }
+}
+
+impl<K : PartialEq, V> FromIterator<(K, V)> for OrderedMap<K, V> {
+    fn from_iter<I : IntoIterator<Item=(K, V)>>(iter: I) -> OrderedMap<K, V> {
+        let mut map = OrderedMap::new();
+        for (k, v) in iter {
+            map.insert(k, v);
+        }
+        map
+    }
+}
+
+#[test]
+fn test_ordered_map() {
+    let mut map = OrderedMap::new();
+    map.insert("c".to_string(), 1);
+    map.insert("a".to_string(), 2);
+    map.insert("b".to_string(), 3);
+    assert_eq!(Some(2), map.insert("a".to_string(), 4));
+    assert_eq!(Some(&4), map.get("a"));
+
+    let keys: Vec<&str> = map.iter().map(|(k, _)| &k[..]).collect();
+    assert_eq!(vec!("c", "a", "b"), keys);
+
+    assert_eq!(Some(1), map.remove("c"));
+    let other: OrderedMap<String, i32> =
+        vec!(("b".to_string(), 3), ("a".to_string(), 4)).into_iter().collect();
+    assert_eq!(map, other);
+}
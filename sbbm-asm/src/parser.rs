@@ -5,44 +5,69 @@ use ast::{CommandBlockOut, Cond, Op, Register, Statement};
 use ast::Op::*;
 use ast::Statement::*;
 use commands::{Objective, Target};
-use lexer::{Lexer, SpannedToken, Token, unquote};
+use expr::{self, BinOp, Expr, FromValue};
+use hw::REGISTER_FILE;
+use lexer::{Arena, Lexer, Location, Spanned, SpannedToken, Token, raw_outs, unquote};
+use link::Visibility;
+use macros::MacroExpander;
 use lexer::Token::*;
 use types::Interval;
 
-use std::collections::VecDeque;
-use std::fmt::Display;
-use std::str::FromStr;
+use std::collections::HashMap;
 
+// Parses statements as they are asked for, so a program is never held in
+// memory as tokens.
 pub struct Parser<'a> {
-    lexer: Lexer<'a>,
-    lookahead: VecDeque<SpannedToken>,
+    tokens: MacroExpander<'a>,
+    lookahead: Option<SpannedToken<'a>>,
+    last_end: Location,
+    // The labels named by .global and .local, for the linker.
+    declarations: Vec<(String, Visibility, Location)>,
+    // The constants defined so far by .equ.
+    constants: HashMap<String, i64>,
 }
 
 type ParseResult<T> = Result<T, String>;
 
+pub type SpannedStatement = Spanned<Statement, Location>;
+
 impl<'a> Parser<'a> {
-    pub fn new(lexer: Lexer) -> Parser {
+    pub fn new(lexer: Lexer<'a>, arena: &'a Arena) -> Parser<'a> {
         Parser {
-            lexer: lexer,
-            lookahead: VecDeque::new(),
+            tokens: MacroExpander::new(lexer, arena),
+            lookahead: None,
+            last_end: Location { offset: 0, col: 0, line: 0, file: 0 },
+            declarations: vec!(),
+            constants: HashMap::new(),
         }
     }
 
-    fn cur(&mut self) -> SpannedToken {
-        if self.lookahead.is_empty() {
-            match self.lexer.next() {
-                Ok(token) => self.lookahead.push_back(token),
+    pub fn declarations(&self) -> &[(String, Visibility, Location)] {
+        &self.declarations[..]
+    }
+
+    // The file being parsed and the ones it included, indexed by
+    // Location::file.
+    pub fn files(&self) -> &[&'a str] {
+        self.tokens.files()
+    }
+
+    fn cur(&mut self) -> SpannedToken<'a> {
+        if self.lookahead.is_none() {
+            match self.tokens.next() {
+                Ok(token) => self.lookahead = Some(token),
                 Err(msg) => panic!(msg),
             }
         }
-        // FIXME: This is inefficient, but I am not good enough with lifetimes
-        // yet to do better.
-        self.lookahead[0].clone()
+        self.lookahead.unwrap()
     }
 
-    fn accept(&mut self) -> SpannedToken {
-        match self.lookahead.pop_front() {
-            Some(token) => token,
+    fn accept(&mut self) -> SpannedToken<'a> {
+        match self.lookahead.take() {
+            Some(token) => {
+                self.last_end = token.end;
+                token
+            }
             None => panic!("nothing to accept"),
         }
     }
@@ -58,37 +83,56 @@ impl<'a> Parser<'a> {
     }
 
     pub fn parse_program(&mut self) -> Vec<Statement> {
-        let stmts = self.parse_items();
-        // FIXME: try!
-        self.expect_tok(Eof).unwrap();
-        stmts
+        self.parse_program_spanned().into_iter().map(|s| s.item).collect()
     }
 
-    fn parse_items(&mut self) -> Vec<Statement> {
-        let mut stmts = vec!();
-        while self.cur().item != Eof {
-            match self.parse_item() {
-                Some(stmt) => stmts.push(stmt),
-                None => (),
-            }
-        }
+    pub fn parse_program_spanned(&mut self) -> Vec<SpannedStatement> {
+        let stmts = self.by_ref().collect();
+        // FIXME: try!
+        self.expect_tok(Eof).unwrap();
         stmts
     }
 
-    fn parse_item(&mut self) -> Option<Statement> {
+    fn parse_item(&mut self) -> ParseResult<Option<Statement>> {
         match self.cur().item {
             Label(label) => {
                 self.accept();
-                Some(LabelStmt(label[..label.len()-1].to_string()))
+                Ok(Some(LabelStmt(label[..label.len()-1].to_string())))
             }
-            Ident(_) | Raw(_, _) => {
-                let op = self.parse_op().unwrap();
-                Some(Instr(vec!(), op))
+            Ident(_) | Raw(_, _, _) => {
+                let op = try!(self.parse_op());
+                Ok(Some(Instr(vec!(), op)))
             }
             LBrace => {
-                let conds = self.parse_conds().unwrap();
-                let op = self.parse_op().unwrap();
-                Some(Instr(conds, op))
+                let conds = try!(self.parse_conds());
+                let op = try!(self.parse_op());
+                Ok(Some(Instr(conds, op)))
+            }
+            Meta(meta) if meta == ".global" || meta == ".local" => {
+                let visibility = if meta == ".global" {
+                    Visibility::Global
+                } else {
+                    Visibility::Local
+                };
+                self.accept();
+                loop {
+                    let tok = self.cur();
+                    match tok.item {
+                        Ident(label) => {
+                            self.declarations.push((label.to_string(), visibility, tok.start));
+                        }
+                        Comma => { }
+                        Newline(_) | Eof => break,
+                        _ => return Err(format!("expected label name but found {:?}", tok)),
+                    }
+                    self.accept();
+                }
+                Ok(None)
+            }
+            Meta(".equ") => {
+                self.accept();
+                try!(self.parse_equ());
+                Ok(None)
             }
             Meta(_) => {
                 loop {
@@ -98,19 +142,18 @@ impl<'a> Parser<'a> {
                     }
                 }
                 self.accept();
-                None
+                Ok(None)
             }
             Newline(_) => {
                 self.accept();
-                None
+                Ok(None)
             }
-            _ => panic!("unexpected token: {:?}", self.cur()),
+            _ => Err(format!("unexpected token: {:?}", self.cur())),
         }
     }
 
     fn parse_any_reg(&mut self) -> ParseResult<Register> {
-        if let Some(reg) = try_parse_reg(self.cur().item) {
-            self.accept();
+        if let Some(reg) = try!(self.try_parse_any_reg()) {
             Ok(reg)
         } else {
             // FIXME: Move to Display, rather than Debug
@@ -118,15 +161,27 @@ impl<'a> Parser<'a> {
         }
     }
 
+    // Parses a register if there is one.  A register that is written
+    // correctly but does not exist is an error, rather than something else.
+    fn try_parse_any_reg(&mut self) -> ParseResult<Option<Register>> {
+        if let Some(reg) = try_parse_reg(self.cur().item) {
+            try!(REGISTER_FILE.check_named(&reg));
+            self.accept();
+            Ok(Some(reg))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn parse_target(&mut self) -> ParseResult<Target> {
         match self.cur().item {
             Ident(raw_sel) | Selector(raw_sel) => {
                 self.accept();
-                Ok(Target::Raw(raw_sel))
+                Ok(Target::Raw(raw_sel.to_string()))
             }
             LitStr(s) => {
                 self.accept();
-                Ok(Target::Raw(unquote(&s[..])))
+                Ok(Target::Raw(try!(unquote(s))))
             }
             _ => {
                 // FIXME: Move to Display, rather than Debug
@@ -135,35 +190,122 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_int<T>(&mut self) -> ParseResult<T>
-        where T : FromStr, T::Err : Display
-    {
+    // .equ NAME, expr
+    fn parse_equ(&mut self) -> ParseResult<()> {
+        let name = match self.cur().item {
+            Ident(name) => { self.accept(); name.to_string() }
+            // FIXME: Print with Display, rather than Debug
+            _ => return Err(format!("expected constant name but found {:?}", self.cur())),
+        };
+        try!(self.expect_tok(Comma));
+        if self.cur().item == Hash {
+            self.accept();
+        }
+        let expr = try!(self.parse_expr());
+        let value = try!(expr.eval(&self.constants));
+        try!(i32::from_value(value));
+        if self.constants.contains_key(&name) {
+            return Err(format!("constant '{}' is already defined", name));
+        }
+        self.constants.insert(name, value);
+
         match self.cur().item {
+            Newline(_) => { self.accept(); Ok(()) }
+            Eof => Ok(()),
+            // FIXME: Print with Display, rather than Debug
+            _ => Err(format!("expected end of line but found {:?}", self.cur())),
+        }
+    }
+
+    fn parse_expr(&mut self) -> ParseResult<Expr> {
+        self.parse_binary(1)
+    }
+
+    // Parses operators that bind at least as tightly as min_prec.
+    fn parse_binary(&mut self, min_prec: u32) -> ParseResult<Expr> {
+        let mut lhs = try!(self.parse_unary());
+        loop {
+            let op = match binary_op(&self.cur().item) {
+                Some(op) if op.precedence() >= min_prec => op,
+                _ => return Ok(lhs),
+            };
+            self.accept();
+            let rhs = try!(self.parse_binary(op.precedence() + 1));
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> ParseResult<Expr> {
+        match self.cur().item {
+            Minus => {
+                self.accept();
+                Ok(Expr::Neg(Box::new(try!(self.parse_unary()))))
+            }
+            Tilde => {
+                self.accept();
+                Ok(Expr::Not(Box::new(try!(self.parse_unary()))))
+            }
+            LParen => {
+                self.accept();
+                let expr = try!(self.parse_expr());
+                try!(self.expect_tok(RParen));
+                Ok(expr)
+            }
             LitInt(s) => {
-                // No need to handle errors: the lexer has already ensured that
-                // s contains a valid int prefixed with #.
                 self.accept();
-                if let Ok(value) = s[1..].parse::<T>() {
-                    Ok(value)
-                } else {
-                    // FIXME: It would be nice to just call .unwrap, but I can't
-                    // seem to get the type constraints right for it.
-                    unreachable!();
-                }
+                Ok(Expr::Num(try!(expr::parse_int_literal(s))))
+            }
+            LitChar(s) => {
+                self.accept();
+                Ok(Expr::Num(try!(expr::parse_char_literal(s))))
+            }
+            Ident(name) => {
+                self.accept();
+                Ok(Expr::Const(name.to_string()))
+            }
+            LabelRef(label) => {
+                self.accept();
+                Ok(Expr::LabelAddr(label[1..].to_string()))
             }
             // FIXME: Print with Display, rather than Debug
-            _ => Err(format!("expected integer but found {:?}", self.cur())),
+            _ => Err(format!("expected a value but found {:?}", self.cur())),
         }
     }
 
-    fn parse_opt_int<T>(&mut self) -> ParseResult<Option<T>>
-        where T : FromStr, T::Err : Display
-    {
-        let cur = self.cur();
+    // Whether an immediate starts here: a plain #123, or # and an expression.
+    fn at_imm(&mut self) -> bool {
+        match self.cur().item {
+            LitInt(s) => s.starts_with("#"),
+            Hash => true,
+            _ => false,
+        }
+    }
 
-        if let Ok(value) = self.parse_int() {
-            Ok(Some(value))
-        } else if (cur.item == Star) {
+    fn parse_imm(&mut self) -> ParseResult<Expr> {
+        if !self.at_imm() {
+            // FIXME: Print with Display, rather than Debug
+            return Err(format!("expected integer but found {:?}", self.cur()));
+        }
+        if self.cur().item == Hash {
+            self.accept();
+        }
+        self.parse_expr()
+    }
+
+    fn eval<T: FromValue>(&self, expr: &Expr) -> ParseResult<T> {
+        let value = try!(expr.eval(&self.constants));
+        T::from_value(value)
+    }
+
+    fn parse_int<T: FromValue>(&mut self) -> ParseResult<T> {
+        let expr = try!(self.parse_imm());
+        self.eval(&expr)
+    }
+
+    fn parse_opt_int<T: FromValue>(&mut self) -> ParseResult<Option<T>> {
+        if self.at_imm() {
+            Ok(Some(try!(self.parse_int())))
+        } else if self.cur().item == Star {
             self.accept();
             Ok(None)
         } else {
@@ -174,8 +316,8 @@ impl<'a> Parser<'a> {
 
     fn parse_objective(&mut self) -> ParseResult<String> {
         match self.cur().item {
-            Ident(obj) => { self.accept(); Ok(obj) }
-            LitStr(s) => { self.accept(); Ok(unquote(&s[..])) }
+            Ident(obj) => { self.accept(); Ok(obj.to_string()) }
+            LitStr(s) => { self.accept(); unquote(s) }
             // FIXME: Print with Display, rather than Debug
             _ => Err(format!("expected objective but found {:?}", self.cur())),
         }
@@ -203,7 +345,7 @@ impl<'a> Parser<'a> {
     fn parse_op(&mut self) -> ParseResult<Op> {
         match self.cur().item {
             Ident(mnemonic) => {
-                let res = match &mnemonic[..] {
+                let res = match mnemonic {
                     m @ "ldr" => self.parse_ldr_str(m, LdrRR, LdrRL),
                     m @ "str" => self.parse_ldr_str(m, StrRR, StrRL),
                     m @ "add" => self.parse_addsub(m, AddRR, AddRI, AddXI, AddXR),
@@ -229,10 +371,10 @@ impl<'a> Parser<'a> {
                 };
                 res
             }
-            Raw(mods, raw) => {
+            Raw(chars, regs, raw) => {
                 self.accept();
-                let mut raw_outs = vec!();
-                for (c, reg) in mods.into_iter() {
+                let mut outs = vec!();
+                for (c, reg) in raw_outs(chars, regs).into_iter() {
                     let m = match c {
                         's' => CommandBlockOut::SuccessCount,
                         'b' => CommandBlockOut::AffectedBlocks,
@@ -243,9 +385,11 @@ impl<'a> Parser<'a> {
                             "unknown raw modifier: {} in {:?}",
                             c, self.cur())
                     };
-                    raw_outs.push((m, try_parse_reg(reg).unwrap()));
+                    let reg = try_parse_reg(reg).unwrap();
+                    try!(REGISTER_FILE.check_named(&reg));
+                    outs.push((m, reg));
                 }
-                Ok(RawCmd(raw_outs, raw))
+                Ok(RawCmd(outs, raw.to_string()))
             }
             // FIXME: Move to Display instead of Debug.
             _ => Err(format!("expected mnemonic but found {:?}", self.cur())),
@@ -255,7 +399,7 @@ impl<'a> Parser<'a> {
     fn parse_instr_rr<F>(&mut self, mnemo: &str, op: F) -> ParseResult<Op>
         where F : FnOnce(Register, Register) -> Op
     {
-        try!(self.expect_tok(Ident(mnemo.to_string())));
+        try!(self.expect_tok(Ident(mnemo)));
         let dst = try!(self.parse_any_reg());
         try!(self.expect_tok(Comma));
         let src = try!(self.parse_any_reg());
@@ -267,7 +411,7 @@ impl<'a> Parser<'a> {
         where RR : FnOnce(Register, Register) -> Op,
               RL : FnOnce(Register, String) -> Op,
     {
-        try!(self.expect_tok(Ident(mnemo.to_string())));
+        try!(self.expect_tok(Ident(mnemo)));
         let dst = try!(self.parse_any_reg());
         try!(self.expect_tok(Comma));
 
@@ -295,14 +439,14 @@ impl<'a> Parser<'a> {
               XI : FnOnce(Target, Objective, i32, Register) -> Op,
               XR : FnOnce(Target, Objective, Register, Register) -> Op
     {
-        try!(self.expect_tok(Ident(mnemo.to_string())));
+        try!(self.expect_tok(Ident(mnemo)));
 
-        if let Ok(dst) = self.parse_any_reg() {
+        if let Some(dst) = try!(self.try_parse_any_reg()) {
             try!(self.expect_tok(Comma));
-            if let Ok(src) = self.parse_any_reg() {
+            if let Some(src) = try!(self.try_parse_any_reg()) {
                 Ok(rr(dst, src))
-            } else if let Ok(imm) = self.parse_int() {
-                Ok(ri(dst, imm))
+            } else if self.at_imm() {
+                Ok(ri(dst, try!(self.parse_int())))
             } else {
                 unimplemented!();
             }
@@ -311,11 +455,12 @@ impl<'a> Parser<'a> {
             let obj = try!(self.parse_objective());
             try!(self.expect_tok(Comma));
 
-            if let Ok(imm) = self.parse_int() {
+            if self.at_imm() {
+                let imm = try!(self.parse_int());
                 try!(self.expect_tok(Comma));
                 let out_reg = try!(self.parse_any_reg());
                 Ok(xi(target, obj, imm, out_reg))
-            } else if let Ok(reg) = self.parse_any_reg() {
+            } else if let Some(reg) = try!(self.try_parse_any_reg()) {
                 try!(self.expect_tok(Comma));
                 let out_reg = try!(self.parse_any_reg());
                 Ok(xr(target, obj, reg, out_reg))
@@ -333,14 +478,17 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_mov(&mut self) -> ParseResult<Op> {
-        try!(self.expect_tok(Ident("mov".to_string())));
+        try!(self.expect_tok(Ident("mov")));
 
-        if let Ok(dst) = self.parse_any_reg() {
+        if let Some(dst) = try!(self.try_parse_any_reg()) {
             try!(self.expect_tok(Comma));
-            if let Ok(src) = self.parse_any_reg() {
+            if let Some(src) = try!(self.try_parse_any_reg()) {
                 Ok(MovRR(dst, src))
-            } else if let Ok(imm) = self.parse_int() {
-                Ok(MovRI(dst, imm))
+            } else if self.at_imm() {
+                match try!(self.parse_imm()) {
+                    Expr::LabelAddr(label) => Ok(AdrRL(dst, label)),
+                    imm => Ok(MovRI(dst, try!(self.eval(&imm)))),
+                }
             } else if let Ok(target) = self.parse_target() {
                 try!(self.expect_tok(Comma));
                 let obj = try!(self.parse_objective());
@@ -356,11 +504,12 @@ impl<'a> Parser<'a> {
             let obj = try!(self.parse_objective());
             try!(self.expect_tok(Comma));
 
-            if let Ok(src) = self.parse_any_reg() {
+            if let Some(src) = try!(self.try_parse_any_reg()) {
                 try!(self.expect_tok(Comma));
                 let out_reg = try!(self.parse_any_reg());
                 Ok(MovXR(target, obj, src, out_reg))
-            } else if let Ok(imm) = self.parse_int() {
+            } else if self.at_imm() {
+                let imm = try!(self.parse_int());
                 try!(self.expect_tok(Comma));
                 let out_reg = try!(self.parse_any_reg());
                 Ok(MovXI(target, obj, imm, out_reg))
@@ -380,9 +529,9 @@ impl<'a> Parser<'a> {
 
     fn parse_rng<F, T>(&mut self, mnemo: &str, op: F) -> ParseResult<Op>
         where F : FnOnce(Register, Register, Option<T>, Option<T>) -> Op,
-              T : FromStr, T::Err : Display
+              T : FromValue
     {
-        try!(self.expect_tok(Ident(mnemo.to_string())));
+        try!(self.expect_tok(Ident(mnemo)));
         let dst = try!(self.parse_any_reg());
         try!(self.expect_tok(Comma));
         let reg = try!(self.parse_any_reg());
@@ -399,9 +548,9 @@ impl<'a> Parser<'a> {
         where R : FnOnce(Register) -> Op,
     L : FnOnce(String) -> Op
     {
-        try!(self.expect_tok(Ident(mnemo.to_string())));
+        try!(self.expect_tok(Ident(mnemo)));
 
-        if let Ok(reg) = self.parse_any_reg() {
+        if let Some(reg) = try!(self.try_parse_any_reg()) {
             Ok(regop(reg))
         } else {
             match self.cur().item {
@@ -421,23 +570,62 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_halt(&mut self) -> ParseResult<Op> {
-        try!(self.expect_tok(Ident("halt".to_string())));
+        try!(self.expect_tok(Ident("halt")));
         Ok(Halt)
     }
 }
 
+impl<'a> Iterator for Parser<'a> {
+    type Item = SpannedStatement;
+
+    fn next(&mut self) -> Option<SpannedStatement> {
+        while self.cur().item != Eof {
+            let start = self.cur().start;
+            match self.parse_item() {
+                Ok(Some(stmt)) => {
+                    return Some(Spanned { item: stmt, start: start, end: self.last_end });
+                }
+                Ok(None) => { }
+                Err(msg) => {
+                    // Errors are reported where the parser got stuck.
+                    let at = self.lookahead.map_or(start, |t| t.start);
+                    panic!("{}:{}: {}", self.tokens.file_name(at), at, msg)
+                }
+            }
+        }
+        None
+    }
+}
+
+fn binary_op(token: &Token) -> Option<BinOp> {
+    match *token {
+        Star => Some(BinOp::Mul),
+        Slash => Some(BinOp::Div),
+        Percent => Some(BinOp::Rem),
+        Plus => Some(BinOp::Add),
+        Minus => Some(BinOp::Sub),
+        Shl => Some(BinOp::Shl),
+        Shr => Some(BinOp::Shr),
+        Amp => Some(BinOp::And),
+        Pipe => Some(BinOp::Or),
+        _ => None,
+    }
+}
+
 fn try_parse_reg(token: Token) -> Option<Register> {
     match token {
+        // A number too big for an i32 is too big to be a register, which the
+        // register file says.
         GenReg(reg) => {
-            let num = reg[1..].parse::<i32>().unwrap();
+            let num = reg[1..].parse::<i32>().unwrap_or(::std::i32::MAX);
             Some(Register::Gen(num))
         }
         PredReg(reg) => {
-            let num = reg[1..].parse::<i32>().unwrap();
+            let num = reg[1..].parse::<i32>().unwrap_or(::std::i32::MAX);
             Some(Register::Pred(num))
         }
         SpecReg(reg) => {
-            Some(Register::Spec(reg))
+            Some(Register::Spec(reg.to_string()))
         }
         _ => None,
     }
@@ -445,7 +633,8 @@ fn try_parse_reg(token: Token) -> Option<Register> {
 
 #[test]
 fn test_label() {
-    let mut parser = Parser::new(Lexer::mem("foo:"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("foo:"), &arena);
     assert_eq!(
         vec!(LabelStmt("foo".to_string())),
         parser.parse_program());
@@ -453,7 +642,8 @@ fn test_label() {
 
 #[test]
 fn test_cond_op() {
-    let mut parser = Parser::new(Lexer::mem("{p0, #0, #0} ldr r0, [r1]"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("{p0, #0, #0} ldr r0, [r1]"), &arena);
     assert_eq!(
         vec!(Instr(
             vec!(Cond::eq(Register::Pred(0), 0)),
@@ -463,7 +653,8 @@ fn test_cond_op() {
 
 #[test]
 fn test_ldr_rr() {
-    let mut parser = Parser::new(Lexer::mem("ldr r0, [r1]"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("ldr r0, [r1]"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), LdrRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -471,7 +662,8 @@ fn test_ldr_rr() {
 
 #[test]
 fn test_ldr_rl() {
-    let mut parser = Parser::new(Lexer::mem("ldr r0, =foo"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("ldr r0, =foo"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), LdrRL(Register::Gen(0), "foo".to_string()))),
         parser.parse_program());
@@ -479,7 +671,8 @@ fn test_ldr_rl() {
 
 #[test]
 fn test_str_rr() {
-    let mut parser = Parser::new(Lexer::mem("str r0, [r1]"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("str r0, [r1]"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), StrRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -487,7 +680,8 @@ fn test_str_rr() {
 
 #[test]
 fn test_str_rl() {
-    let mut parser = Parser::new(Lexer::mem("str r0, =foo"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("str r0, =foo"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), StrRL(Register::Gen(0), "foo".to_string()))),
         parser.parse_program());
@@ -495,7 +689,8 @@ fn test_str_rl() {
 
 #[test]
 fn test_add_rr() {
-    let mut parser = Parser::new(Lexer::mem("add r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("add r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), AddRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -504,7 +699,8 @@ fn test_add_rr() {
 
 #[test]
 fn test_add_xi() {
-    let mut parser = Parser::new(Lexer::mem("add @a, foo, #10, r0"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("add @a, foo, #10, r0"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), AddXI(
             Target::Raw("@a".to_string()), "foo".to_string(), 10, Register::Gen(0)))),
@@ -513,7 +709,8 @@ fn test_add_xi() {
 
 #[test]
 fn test_add_xr() {
-    let mut parser = Parser::new(Lexer::mem("add @a, foo, r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("add @a, foo, r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), AddXR(
             Target::Raw("@a".to_string()), "foo".to_string(), Register::Gen(0), Register::Gen(1)))),
@@ -523,7 +720,8 @@ fn test_add_xr() {
 
 #[test]
 fn test_sub_rr() {
-    let mut parser = Parser::new(Lexer::mem("sub r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("sub r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), SubRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -532,7 +730,8 @@ fn test_sub_rr() {
 
 #[test]
 fn test_sub_xi() {
-    let mut parser = Parser::new(Lexer::mem("sub @a, foo, #10, r0"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("sub @a, foo, #10, r0"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), SubXI(
             Target::Raw("@a".to_string()), "foo".to_string(), 10, Register::Gen(0)))),
@@ -541,7 +740,8 @@ fn test_sub_xi() {
 
 #[test]
 fn test_sub_xr() {
-    let mut parser = Parser::new(Lexer::mem("sub @a, foo, r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("sub @a, foo, r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), SubXR(
             Target::Raw("@a".to_string()), "foo".to_string(), Register::Gen(0), Register::Gen(1)))),
@@ -550,7 +750,8 @@ fn test_sub_xr() {
 
 #[test]
 fn test_mov_rr() {
-    let mut parser = Parser::new(Lexer::mem("mov r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mov r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MovRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -558,15 +759,41 @@ fn test_mov_rr() {
 
 #[test]
 fn test_mov_ri() {
-    let mut parser = Parser::new(Lexer::mem("mov r0, #37"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mov r0, #37"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MovRI(Register::Gen(0), 37))),
         parser.parse_program());
 }
 
+#[test]
+fn test_expressions() {
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem(
+        ".equ BASE, 0x100\n.equ SIZE, #BASE >> 4\n\
+         {r0, #-SIZE, #'a' | 0b1} mov r0, #BASE + SIZE * (2 - 1) - 1\n\
+         urng p0, r0, #0xffffffff, *\nmov r1, #~0\nmov r2, #=foo\n"), &arena);
+    assert_eq!(
+        vec!(Instr(vec!(Cond::bounded(Register::Gen(0), -0x10, 97)),
+                   MovRI(Register::Gen(0), 0x10f)),
+             Instr(vec!(), Urng(Register::Pred(0), Register::Gen(0), Some(0xffffffff), None)),
+             Instr(vec!(), MovRI(Register::Gen(1), -1)),
+             Instr(vec!(), AdrRL(Register::Gen(2), "foo".to_string()))),
+        parser.parse_program());
+
+    let int = |input| Parser::new(Lexer::mem(input), &arena).parse_int::<i32>();
+    assert_eq!(Ok(-1), int("#0xffffffff"));
+    assert_eq!(Ok(7), int("#1 + 2 * 3"));
+    assert_eq!(Ok(1), int("#1 << 2 >> 2 & 1 | 0"));
+    assert_eq!(Err("4294967296 does not fit in 32 bits".to_string()), int("#1 << 31 << 1"));
+    assert_eq!(Err("undefined constant 'FOO'".to_string()), int("#FOO"));
+    assert!(int("#=foo + 1").is_err());
+}
+
 #[test]
 fn test_mov_rx() {
-    let mut parser = Parser::new(Lexer::mem("mov r0, @r, foo"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mov r0, @r, foo"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MovRX(Register::Gen(0), Target::Raw("@r".to_string()), "foo".to_string()))),
         parser.parse_program());
@@ -574,7 +801,8 @@ fn test_mov_rx() {
 
 #[test]
 fn test_mov_xr() {
-    let mut parser = Parser::new(Lexer::mem("mov @r, foo, r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mov @r, foo, r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MovXR(Target::Raw("@r".to_string()), "foo".to_string(), Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -582,7 +810,8 @@ fn test_mov_xr() {
 
 #[test]
 fn test_mov_xi() {
-    let mut parser = Parser::new(Lexer::mem("mov @r, foo, #15, r0"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mov @r, foo, #15, r0"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MovXI(Target::Raw("@r".to_string()), "foo".to_string(), 15, Register::Gen(0)))),
         parser.parse_program());
@@ -590,7 +819,8 @@ fn test_mov_xi() {
 
 #[test]
 fn test_mul() {
-    let mut parser = Parser::new(Lexer::mem("mul r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("mul r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), MulRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -598,7 +828,8 @@ fn test_mul() {
 
 #[test]
 fn test_sdiv() {
-    let mut parser = Parser::new(Lexer::mem("sdiv r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("sdiv r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), SdivRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -606,7 +837,8 @@ fn test_sdiv() {
 
 #[test]
 fn test_udiv() {
-    let mut parser = Parser::new(Lexer::mem("udiv r0, r1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("udiv r0, r1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), UdivRR(Register::Gen(0), Register::Gen(1)))),
         parser.parse_program());
@@ -614,7 +846,8 @@ fn test_udiv() {
 
 #[test]
 fn test_br_r() {
-    let mut parser = Parser::new(Lexer::mem("b lr"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("b lr"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), BrR(Register::Spec("lr".to_string())))),
         parser.parse_program());
@@ -622,7 +855,8 @@ fn test_br_r() {
 
 #[test]
 fn test_br_l() {
-    let mut parser = Parser::new(Lexer::mem("b =foo"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("b =foo"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), BrL("foo".to_string()))),
         parser.parse_program());
@@ -630,7 +864,8 @@ fn test_br_l() {
 
 #[test]
 fn test_br_lnk_r() {
-    let mut parser = Parser::new(Lexer::mem("bl lr"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("bl lr"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), BrLnkR(Register::Spec("lr".to_string())))),
         parser.parse_program());
@@ -638,7 +873,8 @@ fn test_br_lnk_r() {
 
 #[test]
 fn test_srng() {
-    let mut parser = Parser::new(Lexer::mem("srng p0, r0, #0, #1"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("srng p0, r0, #0, #1"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), Srng(Register::Pred(0), Register::Gen(0), Some(0), Some(1)))),
         parser.parse_program());
@@ -646,7 +882,8 @@ fn test_srng() {
 
 #[test]
 fn test_urng() {
-    let mut parser = Parser::new(Lexer::mem("urng p0, r0, #0, *"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("urng p0, r0, #0, *"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), Urng(Register::Pred(0), Register::Gen(0), Some(0), None))),
         parser.parse_program());
@@ -654,13 +891,15 @@ fn test_urng() {
 
 #[test]
 fn test_halt() {
-    let mut parser = Parser::new(Lexer::mem("halt"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("halt"), &arena);
     assert_eq!(vec!(Instr(vec!(), Halt)), parser.parse_program());
 }
 
 #[test]
 fn test_raw_cmd() {
-    let mut parser = Parser::new(Lexer::mem("raw foo bar baz"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("raw foo bar baz"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), RawCmd(vec!(), "foo bar baz".to_string()))),
         parser.parse_program());
@@ -669,7 +908,8 @@ fn test_raw_cmd() {
 #[test]
 fn test_raw_cmd_outs() {
     use ast::CommandBlockOut::*;
-    let mut parser = Parser::new(Lexer::mem("raw~siq r0, r1, r2, foo bar baz"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("raw~siq r0, r1, r2, foo bar baz"), &arena);
     let outs = vec!(
         (SuccessCount, Register::Gen(0)),
         (AffectedItems, Register::Gen(1)),
@@ -681,7 +921,8 @@ fn test_raw_cmd_outs() {
 
 #[test]
 fn test_raw_cmd_cond() {
-    let mut parser = Parser::new(Lexer::mem("{p0, #1, #1} raw foo bar baz"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("{p0, #1, #1} raw foo bar baz"), &arena);
     assert_eq!(
         vec!(Instr(
             vec!(Cond::eq(Register::Pred(0), 1)),
@@ -692,7 +933,8 @@ fn test_raw_cmd_cond() {
 
 #[test]
 fn test_parse_strlit_target() {
-    let mut parser = Parser::new(Lexer::mem("add \"#computer\", foo, #10, r0"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("add \"#computer\", foo, #10, r0"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), AddXI(
             Target::Raw("#computer".to_string()), "foo".to_string(), 10, Register::Gen(0)))),
@@ -701,9 +943,97 @@ fn test_parse_strlit_target() {
 
 #[test]
 fn test_parse_strlit_objective() {
-    let mut parser = Parser::new(Lexer::mem("add foo, \"bar\", #10, r0"));
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("add foo, \"bar\", #10, r0"), &arena);
     assert_eq!(
         vec!(Instr(vec!(), AddXI(
             Target::Raw("foo".to_string()), "bar".to_string(), 10, Register::Gen(0)))),
         parser.parse_program());
 }
+
+#[test]
+fn test_parse_declarations() {
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem(".global foo, bar\n.local baz\nfoo:\n"), &arena);
+    assert_eq!(vec!(LabelStmt("foo".to_string())), parser.parse_program());
+    let declared: Vec<(&str, Visibility)> = parser.declarations().iter()
+        .map(|&(ref label, visibility, _)| (&label[..], visibility))
+        .collect();
+    assert_eq!(
+        vec!(("foo", Visibility::Global), ("bar", Visibility::Global), ("baz", Visibility::Local)),
+        declared);
+}
+
+#[test]
+fn test_parse_spanned() {
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("foo:\n  mov r0, #1\n"), &arena);
+    let stmts = parser.parse_program_spanned();
+    assert_eq!(2, stmts.len());
+    assert_eq!(LabelStmt("foo".to_string()), stmts[0].item);
+    assert_eq!(0, stmts[0].start.line);
+    assert_eq!(1, stmts[1].start.line);
+    assert_eq!(2, stmts[1].start.col);
+    assert_eq!(1, stmts[1].end.line);
+}
+
+#[test]
+fn test_display_round_trip() {
+    let src = "\
+foo:
+    {p0, #1, #1} {r2, *, #-3} ldr r0, [r1]
+    ldr r0, =foo
+    str lr, [sp]
+    add r0, #-7
+    sub \"#computer\", \"my obj\", r0, r1
+    mov r0, @e[type=Pig], health
+    mov @a, foo, #-2147483648, r0
+    mov r0, #=foo
+    srng r0, r1, *, #10
+    urng r0, r1, #1, #4294967295
+    bl =foo
+    b lr
+    halt
+    raw~sq r0, r1, say hi
+    raw kill @e";
+    let arena = Arena::new();
+    let stmts = Parser::new(Lexer::mem(src), &arena).parse_program();
+    let printed: Vec<String> = stmts.iter().map(|stmt| match *stmt {
+        LabelStmt(_) => stmt.to_string(),
+        _ => format!("    {}", stmt),
+    }).collect();
+    assert_eq!(src, printed.connect("\n"));
+}
+
+#[test]
+fn test_streaming() {
+    // Each statement comes out as soon as it is parsed, before the parser has
+    // read the rest of the input, which here would not parse.
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem("foo:\nmov r0, #1\nbogus r0, r1\n"), &arena);
+    assert_eq!(Some(LabelStmt("foo".to_string())), parser.next().map(|s| s.item));
+    assert_eq!(Some(Instr(vec!(), MovRI(Register::Gen(0), 1))), parser.next().map(|s| s.item));
+}
+
+#[test]
+fn test_unknown_registers() {
+    let arena = Arena::new();
+    let parse_reg = |src| Parser::new(Lexer::mem(src), &arena).parse_any_reg();
+    assert_eq!(Ok(Register::Gen(31)), parse_reg("r31"));
+    assert_eq!(Ok(Register::Pred(7)), parse_reg("p7"));
+    assert_eq!(Err("no such register r40: the general registers are r0 to r31".to_string()),
+               parse_reg("r40"));
+    assert!(parse_reg("p8").is_err());
+    assert!(parse_reg("r99999999999").is_err());
+
+    let mut parser = Parser::new(Lexer::mem("raw~s r32, say hi"), &arena);
+    assert!(parser.parse_op().is_err());
+
+    // The error reaches instructions that could take something else there.
+    let parse_op = |src| Parser::new(Lexer::mem(src), &arena).parse_op();
+    let r40 = Err("no such register r40: the general registers are r0 to r31".to_string());
+    assert_eq!(r40, parse_op("add r0, r40"));
+    assert_eq!(r40, parse_op("mov r0, r40"));
+    assert_eq!(r40, parse_op("add r40, r0"));
+    assert_eq!(Ok(AddRR(Register::Gen(31), Register::Gen(0))), parse_op("add r31, r0"));
+}
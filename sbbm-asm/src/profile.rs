@@ -0,0 +1,178 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use ast::Register;
+use ast::Op::AddRI;
+use ast::Statement::*;
+use commands::{Objective, ToTarget, objectives, players};
+use hw::Computer;
+use lexer::{Location, Spanned};
+use parser::SpannedStatement;
+
+use std::io::{self, Write};
+
+// The longest display name an objective can have.
+const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+// A scoreboard objective that counts how many times control entered a run of
+// labels.  Adjacent labels share one place in the circuit, so a branch to any
+// of them powers the same blocks, and entering one can't be told from
+// entering another.  They share the counter, which is named after all of
+// them.
+pub struct Counter {
+    pub objective: Objective,
+    // Each label in the run, and where it is defined.
+    pub labels: Vec<(String, Location)>,
+}
+
+impl Counter {
+    // The labels in the run, e.g. "_start,loop", cut short to fit in an
+    // objective's display name.
+    pub fn display_name(&self) -> String {
+        let names: Vec<&str> = self.labels.iter().map(|&(ref l, _)| &l[..]).collect();
+        names.connect(",").chars().take(MAX_DISPLAY_NAME_LEN).collect()
+    }
+}
+
+pub struct Profile {
+    pub counters: Vec<Counter>,
+}
+
+impl Profile {
+    // Inserts a counter increment after every run of labels, so that the
+    // counter ticks up each time control enters the labels, whether by a
+    // branch or by falling through.
+    pub fn instrument(stmts: Vec<SpannedStatement>)
+        -> (Profile, Vec<SpannedStatement>)
+    {
+        let mut profile = Profile { counters: vec!() };
+        let mut out = vec!();
+        let mut run: Option<Counter> = None;
+
+        for stmt in stmts.into_iter() {
+            match stmt.item {
+                LabelStmt(ref label) => {
+                    if run.is_none() {
+                        run = Some(Counter { objective: String::new(), labels: vec!() });
+                    }
+                    if let Some(ref mut counter) = run {
+                        counter.labels.push((label.clone(), stmt.start));
+                    }
+                }
+                Instr(_, _) => {
+                    if let Some(counter) = run.take() {
+                        out.push(profile.count(counter));
+                    }
+                }
+            }
+            out.push(stmt);
+        }
+        if let Some(counter) = run.take() {
+            out.push(profile.count(counter));
+        }
+
+        (profile, out)
+    }
+
+    fn count(&mut self, mut counter: Counter) -> SpannedStatement {
+        counter.objective = format!("prof_{}", self.counters.len());
+        let reg = Register::Spec(counter.objective.clone());
+        let loc = counter.labels[0].1;
+        self.counters.push(counter);
+        Spanned { item: Instr(vec!(), AddRI(reg, 1)), start: loc, end: loc }
+    }
+
+    // Writes one line per label: the objective that counts it, the label, and
+    // where it is defined.  The labels in a run each get a line, with the
+    // same objective.
+    pub fn write_map(&self, w: &mut Write, filename: &str) -> io::Result<()> {
+        for counter in self.counters.iter() {
+            for &(ref label, loc) in counter.labels.iter() {
+                try!(write!(w, "{} {} {}:{}\n", counter.objective, label, filename, loc));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_init_script(&self, computer: &Computer, w: &mut Write) -> io::Result<()> {
+        let sel = computer.selector();
+        for counter in self.counters.iter() {
+            let obj = counter.objective.clone();
+            try!(write!(w, "{}\n", objectives::add(
+                obj.clone(), "dummy".to_string(), Some(counter.display_name()))));
+            try!(write!(w, "{}\n", players::set(sel.to_target(), obj, 0, None)));
+        }
+        Ok(())
+    }
+
+    pub fn write_destroy_script(&self, w: &mut Write) -> io::Result<()> {
+        for counter in self.counters.iter() {
+            try!(write!(w, "{}\n", objectives::remove(counter.objective.clone())));
+        }
+        Ok(())
+    }
+
+    // Writes a command that lists every score held by the computer entity,
+    // which includes all of the counters.
+    pub fn write_dump_script(&self, computer: &Computer, w: &mut Write) -> io::Result<()> {
+        let sel = computer.selector();
+        write!(w, "{}\n", players::list(Some(sel.to_target())))
+    }
+}
+
+#[cfg(test)]
+fn instrument(input: &str) -> (Profile, Vec<::ast::Statement>) {
+    use lexer::{Arena, Lexer};
+    use parser::Parser;
+    let stmts = Parser::new(Lexer::mem(input), &Arena::new()).parse_program_spanned();
+    let (profile, stmts) = Profile::instrument(stmts);
+    (profile, stmts.into_iter().map(|s| s.item).collect())
+}
+
+#[test]
+fn test_instrument() {
+    use ast::Op::*;
+
+    let (profile, stmts) = instrument("
+_start:
+loop:
+mov r0, #1
+b =loop
+done:");
+    assert_eq!(2, profile.counters.len());
+    assert_eq!("prof_0", &profile.counters[0].objective[..]);
+    let labels: Vec<(&str, usize)> = profile.counters[0].labels.iter()
+        .map(|&(ref l, loc)| (&l[..], loc.line))
+        .collect();
+    assert_eq!(vec!(("_start", 1), ("loop", 2)), labels);
+    assert_eq!("_start,loop", &profile.counters[0].display_name()[..]);
+    assert_eq!("done", &profile.counters[1].labels[0].0[..]);
+
+    assert_eq!(
+        Instr(vec!(), AddRI(Register::Spec("prof_0".to_string()), 1)),
+        stmts[2]);
+    assert_eq!(
+        Instr(vec!(), AddRI(Register::Spec("prof_1".to_string()), 1)),
+        stmts[6]);
+}
+
+#[test]
+fn test_map() {
+    use types::Vec3;
+
+    let (profile, _) = instrument("
+_start:
+loop:
+mov r0, #1
+b =loop");
+    let mut map = vec!();
+    profile.write_map(&mut map, "foo.s").unwrap();
+    assert_eq!("prof_0 _start foo.s:2:1\nprof_0 loop foo.s:3:1\n",
+               &String::from_utf8(map).unwrap()[..]);
+
+    let computer = Computer::new(Vec3::new(0, 0, 0));
+    let mut init = vec!();
+    profile.write_init_script(&computer, &mut init).unwrap();
+    let init = String::from_utf8(init).unwrap();
+    assert!(init.starts_with("scoreboard objectives add prof_0 dummy _start,loop\n"), "{}", init);
+}
@@ -0,0 +1,255 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// A client for the Source RCON protocol, which the server speaks when
+// enable-rcon is set in server.properties, and a way to send it whole
+// command scripts.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+// Packet types.  Responses to both commands and logins come back as
+// RESPONSE, except that a login's comes back as EXEC_COMMAND.
+const RESPONSE: i32 = 0;
+const EXEC_COMMAND: i32 = 2;
+const LOGIN: i32 = 3;
+
+// The server reads each request into a fixed buffer, so the id, type, body
+// and padding have to fit in 1460 bytes.
+pub const MAX_COMMAND_LEN: usize = 1446;
+
+// Responses that mean a command did nothing.  Anything after one of these
+// would run against a half-built circuit, so deploying stops at the first.
+static FAILURES: [&'static str; 9] = [
+    "Cannot place block",
+    "Unknown command",
+    "Usage: ",
+    "There is no such block",
+    "Data tag parsing failed",
+    "Too many blocks in the specified area",
+    "is not a valid number",
+    "You do not have permission",
+    "No objective was found",
+];
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_i32(buf: &mut Vec<u8>, n: i32) {
+    for i in 0..4 {
+        buf.push((n >> (i * 8)) as u8);
+    }
+}
+
+fn read_bytes(r: &mut Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    let mut done = 0;
+    while done < len {
+        match try!(r.read(&mut buf[done..])) {
+            0 => return Err(invalid("the server closed the connection".to_string())),
+            n => done += n,
+        }
+    }
+    Ok(buf)
+}
+
+// RCON is little-endian, unlike NBT.
+fn read_i32(r: &mut Read) -> io::Result<i32> {
+    let bytes = try!(read_bytes(r, 4));
+    Ok(bytes.iter().rev().fold(0, |n, &b| (n << 8) | b as i32))
+}
+
+pub fn write_packet(w: &mut Write, id: i32, kind: i32, body: &str) -> io::Result<()> {
+    let mut buf = vec!();
+    write_i32(&mut buf, (body.len() + 10) as i32);
+    write_i32(&mut buf, id);
+    write_i32(&mut buf, kind);
+    buf.extend(body.as_bytes().iter().cloned());
+    buf.push(0);
+    buf.push(0);
+    w.write_all(&buf[..])
+}
+
+// Reads a packet's id, type and body.
+pub fn read_packet(r: &mut Read) -> io::Result<(i32, i32, String)> {
+    let len = try!(read_i32(r));
+    if len < 10 {
+        return Err(invalid(format!("invalid RCON packet length {}", len)));
+    }
+    let id = try!(read_i32(r));
+    let kind = try!(read_i32(r));
+    let mut body = try!(read_bytes(r, len as usize - 8));
+    // Drop the two bytes of padding.
+    body.truncate(len as usize - 10);
+    Ok((id, kind, String::from_utf8_lossy(&body[..]).into_owned()))
+}
+
+// The reason a command failed, going by its response, if it did.
+pub fn response_error(response: &str) -> Option<&'static str> {
+    FAILURES.iter().find(|f| response.contains(*f)).map(|f| *f)
+}
+
+pub struct Rcon {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl Rcon {
+    pub fn connect(addr: &str, password: &str) -> Result<Rcon, String> {
+        let stream = try!(TcpStream::connect(addr)
+            .map_err(|e| format!("cannot connect to {}: {}", addr, e)));
+        let mut rcon = Rcon { stream: stream, next_id: 1 };
+
+        let id = rcon.take_id();
+        try!(write_packet(&mut rcon.stream, id, LOGIN, password).map_err(|e| e.to_string()));
+        let (reply_id, kind, _) = try!(read_packet(&mut rcon.stream).map_err(|e| e.to_string()));
+        if reply_id == -1 {
+            return Err(format!("{} refused the password", addr));
+        }
+        if reply_id != id || kind != EXEC_COMMAND {
+            return Err(format!("{} sent an unexpected reply to the login", addr));
+        }
+        Ok(rcon)
+    }
+
+    fn take_id(&mut self) -> i32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    // Runs a command and returns the server's response to it.  A long
+    // response is split over several packets, with nothing to mark the last
+    // one, so an empty request follows the command.  The server answers
+    // requests in order, so the command's response is everything before the
+    // empty request's comes back.
+    pub fn command(&mut self, cmd: &str) -> Result<String, String> {
+        try!(check_command(cmd));
+        let id = self.take_id();
+        try!(write_packet(&mut self.stream, id, EXEC_COMMAND, cmd).map_err(|e| e.to_string()));
+        let sentinel = self.take_id();
+        try!(write_packet(&mut self.stream, sentinel, EXEC_COMMAND, "")
+             .map_err(|e| e.to_string()));
+
+        let mut response = String::new();
+        loop {
+            let (reply_id, kind, body) = try!(
+                read_packet(&mut self.stream).map_err(|e| e.to_string()));
+            if reply_id == sentinel && kind == RESPONSE {
+                return Ok(response);
+            }
+            if reply_id != id || kind != RESPONSE {
+                return Err(format!("unexpected reply to request {}: id {}, type {}",
+                                   id, reply_id, kind));
+            }
+            response.push_str(&body[..]);
+        }
+    }
+}
+
+// Checks that a command fits in a request.
+pub fn check_command(cmd: &str) -> Result<(), String> {
+    if cmd.len() > MAX_COMMAND_LEN {
+        return Err(format!("the command is {} bytes long, but RCON takes at most {}",
+                           cmd.len(), MAX_COMMAND_LEN));
+    }
+    Ok(())
+}
+
+// Checks that every command in a script fits in a request, so that a script
+// that can't be sent is refused before any of it is.
+pub fn check_script(cmds: &[String]) -> Result<(), String> {
+    for (i, cmd) in cmds.iter().enumerate() {
+        try!(check_command(&cmd[..]).map_err(|e| format!("command {}: {}", i + 1, e)));
+    }
+    Ok(())
+}
+
+// Sends a script's commands in order, pausing for delay_ms after every batch
+// of them, so that a large circuit doesn't stall the server.  Stops at the
+// first command that fails, and returns how many were sent.
+pub fn send_script(rcon: &mut Rcon, cmds: &[String], batch: usize, delay_ms: u32)
+    -> Result<usize, String>
+{
+    for (i, cmd) in cmds.iter().enumerate() {
+        if i > 0 && batch > 0 && i % batch == 0 && delay_ms > 0 {
+            thread::sleep_ms(delay_ms);
+        }
+        let response = try!(rcon.command(&cmd[..])
+            .map_err(|e| format!("command {}: {}", i + 1, e)));
+        if let Some(_) = response_error(&response[..]) {
+            return Err(format!("command {} failed: {}\n  {}", i + 1, response.trim(), cmd));
+        }
+    }
+    Ok(cmds.len())
+}
+
+#[cfg(test)]
+fn mock_server(password: &'static str) -> (String, thread::JoinHandle<Vec<String>>) {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        let (id, kind, body) = read_packet(&mut stream).unwrap();
+        assert_eq!(LOGIN, kind);
+        let reply_id = if body == password { id } else { -1 };
+        write_packet(&mut stream, reply_id, EXEC_COMMAND, "").unwrap();
+
+        let mut received = vec!();
+        while let Ok((id, _, cmd)) = read_packet(&mut stream) {
+            if cmd.is_empty() {
+                write_packet(&mut stream, id, RESPONSE, "Unknown command").unwrap();
+                continue;
+            }
+            if cmd == "list" {
+                // As if it were too long for one packet.
+                write_packet(&mut stream, id, RESPONSE, "There are 2/20 players ").unwrap();
+                write_packet(&mut stream, id, RESPONSE, "online:\nalice, bob").unwrap();
+            } else if cmd.starts_with("setblock 0 300") {
+                write_packet(&mut stream, id, RESPONSE,
+                             "Cannot place block outside of the world").unwrap();
+            } else {
+                write_packet(&mut stream, id, RESPONSE, "Block placed").unwrap();
+            }
+            received.push(cmd);
+        }
+        received
+    });
+    (addr, handle)
+}
+
+#[test]
+fn test_rcon() {
+    let (addr, server) = mock_server("hunter2");
+    assert!(Rcon::connect(&addr[..], "wrong").is_err());
+    drop(server.join());
+
+    let (addr, server) = mock_server("hunter2");
+    let cmds: Vec<String> = vec!(
+        "setblock 0 56 0 minecraft:stone",
+        "setblock 0 57 0 minecraft:stone",
+        "setblock 0 300 0 minecraft:stone",
+        "setblock 0 58 0 minecraft:stone").into_iter().map(|c| c.to_string()).collect();
+    {
+        let mut rcon = Rcon::connect(&addr[..], "hunter2").unwrap();
+        assert_eq!("Block placed", rcon.command("setblock 1 56 0 minecraft:stone").unwrap());
+        assert_eq!("There are 2/20 players online:\nalice, bob", rcon.command("list").unwrap());
+        assert_eq!(2, send_script(&mut rcon, &cmds[..2], 1, 1).unwrap());
+        let err = send_script(&mut rcon, &cmds[..], 2, 1).unwrap_err();
+        assert!(err.starts_with("command 3 failed: Cannot place block"), "{}", err);
+
+        let long = format!("say {}", (0..MAX_COMMAND_LEN).map(|_| "x").collect::<String>());
+        assert!(rcon.command(&long[..]).is_err());
+        let err = check_script(&[cmds[0].clone(), long]).unwrap_err();
+        assert!(err.starts_with("command 2: the command is 1450 bytes long"), "{}", err);
+        assert!(check_script(&cmds[..]).is_ok());
+    }
+
+    let received = server.join().unwrap();
+    assert_eq!(7, received.len());
+    assert_eq!(&cmds[2], received.last().unwrap());
+}
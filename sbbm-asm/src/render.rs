@@ -0,0 +1,277 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use assembler::AssembledItem;
+use layout::Layout;
+use types::{Extent, Vec3};
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Each label's blocks are drawn with one of these in the ASCII slices, in the
+// order the labels were laid out.  Blocks that refer to another label use the
+// upper case letter, so there are only letters.
+static SYMBOLS: &'static str = "abcdefghijklmnopqrstuvwxyz";
+
+// The size of a block in the SVG map, in pixels.
+const CELL: i32 = 12;
+
+enum Cell {
+    Block(usize, bool),
+    Power,
+    Memory,
+}
+
+// Everything that gets drawn: where the blocks went and which label each
+// belongs to, the power positions, the references between labels, and the
+// memory regions.
+pub struct Scene {
+    labels: Vec<String>,
+    blocks: HashMap<Vec3, (usize, bool)>,
+    power: Vec<(String, Extent)>,
+    references: Vec<(Vec3, String)>,
+    regions: Vec<(String, Extent)>,
+    extent: Extent,
+}
+
+fn symbol(label: usize, reference: bool) -> char {
+    let c = SYMBOLS.chars().nth(label % SYMBOLS.len()).unwrap();
+    if reference { c.to_ascii_uppercase() } else { c }
+}
+
+// Spreads the labels' hues around the color wheel, so that neighbors differ.
+fn color(label: usize) -> String {
+    format!("hsl({}, 65%, 55%)", label * 137 % 360)
+}
+
+fn contains(extent: &Extent, pos: Vec3) -> bool {
+    extent.intersects(&Extent::MinMax(pos, pos))
+}
+
+// The middle of an extent in the SVG map, once it has been moved to the map's
+// origin.
+fn center(extent: &Extent) -> Option<(i32, i32)> {
+    match *extent {
+        Extent::MinMax(min, max) => Some((
+            min.x * CELL + (max.x - min.x + 1) * CELL / 2,
+            min.z * CELL + (max.z - min.z + 1) * CELL / 2)),
+        Extent::Empty => None,
+    }
+}
+
+impl Scene {
+    pub fn new<S>(layout: &Layout<S>) -> Scene
+        where S : Iterator<Item=AssembledItem>
+    {
+        let mut labels: Vec<String> = vec!();
+        let mut indices = HashMap::new();
+        let mut blocks = HashMap::new();
+        let references: Vec<(Vec3, String)> = layout.references().to_vec();
+        for &(pos, ref label) in layout.owners().iter() {
+            if !indices.contains_key(label) {
+                indices.insert(label.clone(), labels.len());
+                labels.push(label.clone());
+            }
+            let is_reference = references.iter().any(|&(p, _)| p == pos);
+            blocks.insert(pos, (indices[label], is_reference));
+        }
+
+        let mut power = layout.power_extents();
+        power.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Scene {
+            labels: labels,
+            blocks: blocks,
+            power: power,
+            references: references,
+            regions: vec!(),
+            extent: layout.extent(),
+        }
+    }
+
+    pub fn add_region(&mut self, name: String, extent: Extent) {
+        self.regions.push((name, extent));
+    }
+
+    fn cell(&self, pos: Vec3) -> Option<Cell> {
+        if let Some(&(label, reference)) = self.blocks.get(&pos) {
+            Some(Cell::Block(label, reference))
+        } else if self.power.iter().any(|&(_, ref e)| contains(e, pos)) {
+            Some(Cell::Power)
+        } else if self.regions.iter().any(|&(_, ref e)| contains(e, pos)) {
+            Some(Cell::Memory)
+        } else {
+            None
+        }
+    }
+
+    // The area to draw.  Memory regions widen it, but only the program's own
+    // levels get slices, since memory can be as tall as the world.
+    fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        let (mut min, mut max) = match self.extent {
+            Extent::MinMax(min, max) => (min, max),
+            Extent::Empty => return None,
+        };
+        for &(_, region) in self.regions.iter() {
+            if let Extent::MinMax(rmin, rmax) = region {
+                min.x = cmp::min(min.x, rmin.x);
+                min.z = cmp::min(min.z, rmin.z);
+                max.x = cmp::max(max.x, rmax.x);
+                max.z = cmp::max(max.z, rmax.z);
+            }
+        }
+        Some((min, max))
+    }
+
+    // Writes one slice per level, from the top down, with x running across
+    // and z running down, followed by a legend.
+    pub fn write_ascii(&self, w: &mut Write) -> io::Result<()> {
+        let (min, max) = match self.bounds() {
+            Some(bounds) => bounds,
+            None => return write!(w, "nothing was laid out\n"),
+        };
+
+        for y in (min.y..max.y + 1).rev() {
+            try!(write!(w, "y={}, x={}..{} across, z={}..{} down\n",
+                        y, min.x, max.x, min.z, max.z));
+            for z in min.z..max.z + 1 {
+                let mut row = String::new();
+                for x in min.x..max.x + 1 {
+                    row.push(match self.cell(Vec3::new(x, y, z)) {
+                        Some(Cell::Block(label, reference)) => symbol(label, reference),
+                        Some(Cell::Power) => '+',
+                        Some(Cell::Memory) => '~',
+                        None => '.',
+                    });
+                }
+                try!(write!(w, "{}\n", row));
+            }
+            try!(write!(w, "\n"));
+        }
+
+        try!(write!(w, "+  power position\n~  memory\n"));
+        for (i, label) in self.labels.iter().enumerate() {
+            try!(write!(w, "{}  {}\n", symbol(i, false), label));
+        }
+        try!(write!(w, "\nreferences (upper case):\n"));
+        for &(pos, ref label) in self.references.iter() {
+            try!(write!(w, "{} {} {} -> {}\n", pos.x, pos.y, pos.z, label));
+        }
+        Ok(())
+    }
+
+    // Writes a top-down map, with x running right and z running down.  Lower
+    // levels are drawn first, so the top of the layout is what shows.  Each
+    // reference is an arrow to the power positions of the label it refers to.
+    pub fn write_svg(&self, w: &mut Write) -> io::Result<()> {
+        let (min, max) = match self.bounds() {
+            Some(bounds) => bounds,
+            None => (Vec3::new(0, 0, 0), Vec3::new(0, 0, 0)),
+        };
+        let width = (max.x - min.x + 1) * CELL;
+        let height = (max.z - min.z + 1) * CELL;
+        // Everything is drawn relative to the minimum corner.
+        let origin = Vec3::new(-min.x, 0, -min.z);
+
+        try!(write!(w, "<svg xmlns=\"http://www.w3.org/2000/svg\" \
+                        width=\"{}\" height=\"{}\">\n", width, height));
+        try!(write!(w, "<defs><marker id=\"arrow\" markerWidth=\"8\" markerHeight=\"8\" \
+                        refX=\"7\" refY=\"4\" orient=\"auto\">\
+                        <path d=\"M0,0 L8,4 L0,8 z\"/></marker></defs>\n"));
+        try!(write!(w, "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>\n", width, height));
+
+        for &(ref name, region) in self.regions.iter() {
+            if let Extent::MinMax(rmin, rmax) = region.translate(origin) {
+                try!(write!(w, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                                fill=\"gray\" fill-opacity=\"0.15\" stroke=\"gray\" \
+                                stroke-dasharray=\"4\"><title>{}</title></rect>\n",
+                            rmin.x * CELL, rmin.z * CELL,
+                            (rmax.x - rmin.x + 1) * CELL, (rmax.z - rmin.z + 1) * CELL,
+                            name));
+            }
+        }
+
+        let mut blocks: Vec<(&Vec3, &(usize, bool))> = self.blocks.iter().collect();
+        blocks.sort_by(|a, b| (a.0.y, a.0.x, a.0.z).cmp(&(b.0.y, b.0.x, b.0.z)));
+        for &(ref label, extent) in self.power.iter() {
+            if let Extent::MinMax(pmin, pmax) = extent.translate(origin) {
+                try!(write!(w, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                                fill=\"#333\"><title>{} power, y={}</title></rect>\n",
+                            pmin.x * CELL, pmin.z * CELL,
+                            (pmax.x - pmin.x + 1) * CELL, (pmax.z - pmin.z + 1) * CELL,
+                            label, pmin.y));
+            }
+        }
+        for &(pos, &(label, reference)) in blocks.iter() {
+            let p = Vec3::new(pos.x + origin.x, pos.y, pos.z + origin.z);
+            let stroke = if reference { "black" } else { "none" };
+            try!(write!(w, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                            fill=\"{}\" stroke=\"{}\"><title>{} at {} {} {}</title></rect>\n",
+                        p.x * CELL + 1, p.z * CELL + 1, CELL - 2, CELL - 2,
+                        color(label), stroke, self.labels[label], pos.x, pos.y, pos.z));
+        }
+
+        let targets: HashMap<&str, Extent> =
+            self.power.iter().map(|&(ref l, e)| (&l[..], e)).collect();
+        for &(pos, ref label) in self.references.iter() {
+            let target = targets.get(&label[..]).and_then(|e| center(&e.translate(origin)));
+            if let Some((tx, tz)) = target {
+                try!(write!(w, "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" \
+                                stroke=\"black\" stroke-opacity=\"0.5\" \
+                                marker-end=\"url(#arrow)\"/>\n",
+                            (pos.x + origin.x) * CELL + CELL / 2,
+                            (pos.z + origin.z) * CELL + CELL / 2,
+                            tx, tz));
+            }
+        }
+        write!(w, "</svg>\n")
+    }
+}
+
+#[test]
+fn test_ascii() {
+    use assembler::AssembledItem::*;
+    use commands::Command;
+    use fab;
+    use layout::LinearMotion;
+
+    let block = fab::cmd_block(Command::Say("hi".to_string()), false);
+    let items = vec!(
+        Label("foo".to_string()), Complete(block.clone()), Complete(block), Terminal,
+        Label("bar".to_string()),
+        fab::power_off("foo".to_string(), false),
+        Terminal);
+    let motion = Box::new(LinearMotion::new(Vec3::new(0, 0, 0)));
+    let mut layout = Layout::new(motion, items.into_iter());
+    for _ in &mut layout { }
+
+    let mut scene = Scene::new(&layout);
+    scene.add_region("mem".to_string(), Extent::MinMax(Vec3::new(-1, 0, 0), Vec3::new(-1, 1, 0)));
+    let mut out = vec!();
+    scene.write_ascii(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+y=1, x=-1..3 across, z=0..1 down
+~.a.B
+..a..
+
+y=0, x=-1..3 across, z=0..1 down
+~.+.+
+..+..
+
+";
+    assert!(out.starts_with(expected), "{}", out);
+    assert!(out.contains("b  bar\n"));
+    assert!(out.contains("3 1 0 -> foo\n"));
+}
+
+#[test]
+fn test_symbols() {
+    // Every label's references can be told from its own blocks.
+    for label in 0..SYMBOLS.len() {
+        assert!(symbol(label, false) != symbol(label, true));
+    }
+    assert_eq!('A', symbol(SYMBOLS.len(), true));
+}
@@ -0,0 +1,134 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// A stand-in for the vanilla server, for running the server tests without
+// Java or the server jar.  It reads commands from stdin, one per line, runs
+// them against the command simulator, and writes the server's log lines to
+// stdout, so that server/run can pipe it through the same FIFOs.
+
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate sbbm_asm;
+
+use docopt::Docopt;
+use sbbm_asm::sim::World;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+static USAGE: &'static str = "
+usage: sbbm-server [options]
+
+options:
+    --init FILE            A file of commands to run before reading any from
+                           stdin, one per line.
+    --tps N                How many ticks to run per second while command
+                           blocks are waiting, or 0 to run them as fast as
+                           possible.  [default: 0]
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_init: Option<String>,
+    flag_tps: u32,
+}
+
+const TICKS_PER_SECOND: u64 = 20;
+
+// Writes a line the way the server's logger does, with the time of day
+// taken from how long the world has been running.
+fn log(out: &mut Write, world: &World, msg: &str) {
+    let secs = world.time() / TICKS_PER_SECOND;
+    write!(out, "[{:02}:{:02}:{:02}] [Server thread/INFO]: {}\n",
+           secs / 3600 % 24, secs / 60 % 60, secs % 60, msg).unwrap();
+}
+
+fn flush_log(out: &mut Write, world: &mut World) {
+    for line in world.take_log().iter() {
+        log(out, world, &line[..]);
+    }
+    out.flush().unwrap();
+}
+
+fn run_line(world: &mut World, line: &str) -> bool {
+    let line = line.trim();
+    if line.is_empty() {
+        return true;
+    }
+    if line == "stop" || line == "/stop" {
+        return false;
+    }
+    world.run_console(line);
+    true
+}
+
+#[cfg(not(test))]
+fn main() {
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.decode())
+        .unwrap_or_else(|e| e.exit());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut world = World::new();
+
+    if let Some(init) = args.flag_init {
+        let f = File::open(&init[..]).unwrap_or_else(|e| panic!("{}: {}", init, e));
+        for line in BufReader::new(f).lines() {
+            run_line(&mut world, &line.unwrap()[..]);
+        }
+        // Like the server's, the init script's feedback is not shown.
+        world.take_log();
+    }
+    log(&mut out, &world, "Done (0.000s)! For help, type \"help\" or \"?\"");
+    out.flush().unwrap();
+
+    // Reading stdin on its own thread lets command blocks keep running while
+    // no commands arrive.
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => if tx.send(line).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+
+    loop {
+        // Commands run at the start of a tick, before the command blocks
+        // that are due.  With nothing due, wait for the next command.
+        if world.is_idle() {
+            match rx.recv() {
+                Ok(line) => if !run_line(&mut world, &line[..]) { break },
+                Err(_) => break,
+            }
+        }
+        let mut stopped = false;
+        loop {
+            match rx.try_recv() {
+                Ok(line) => if !run_line(&mut world, &line[..]) { stopped = true; break },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        flush_log(&mut out, &mut world);
+        if stopped {
+            break;
+        }
+
+        if !world.is_idle() {
+            world.tick();
+            flush_log(&mut out, &mut world);
+            if args.flag_tps > 0 {
+                thread::sleep_ms(1000 / args.flag_tps);
+            }
+        }
+    }
+
+    log(&mut out, &world, "Stopping the server");
+    out.flush().unwrap();
+}
@@ -0,0 +1,1553 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// An in-process stand-in for the parts of a Minecraft 1.8 server that
+// assembled programs use: the scoreboard, entities, blocks, command blocks
+// triggered by redstone blocks, and the commands that act on them.  Commands
+// are taken as text, the way the server's console takes them, and their
+// feedback is written to a log the way the server writes it.
+//
+// The rules follow the server's where programs can tell the difference:
+// selectors that can match several entities run the command once per
+// entity, command blocks run the tick after they are powered, in the order
+// they were powered, and fill notifies neighbors only after placing every
+// block.  Things programs don't use, such as players, items and physics, are
+// not simulated.
+
+use nbt::{self, Nbt, NbtCompound};
+use types::Vec3;
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::i32;
+
+static COMMAND_BLOCK: &'static str = "minecraft:command_block";
+static REDSTONE_BLOCK: &'static str = "minecraft:redstone_block";
+static AIR: &'static str = "minecraft:air";
+
+// The largest volume fill accepts, and the most scheduled block updates the
+// server runs in a tick.  Updates past the limit wait for the next tick.
+const MAX_FILL: i32 = 32768;
+const MAX_TICK_UPDATES: usize = 1000;
+
+// Every entity is given the bounding box of an armor stand.
+const ENTITY_WIDTH: f64 = 0.5;
+const ENTITY_HEIGHT: f64 = 1.975;
+
+// West, east, down, up, north, south: the order the server notifies
+// neighbors in, which decides the order command blocks around a power
+// position run in.
+const NEIGHBORS: [(i32, i32, i32); 6] = [
+    (-1, 0, 0), (1, 0, 0), (0, -1, 0), (0, 1, 0), (0, 0, -1), (0, 0, 1)];
+
+// The command stats a command block can store into scores.  Only these are
+// tracked; AffectedItems and QueryResult are never set.
+static SUCCESS_COUNT: &'static str = "SuccessCount";
+static AFFECTED_BLOCKS: &'static str = "AffectedBlocks";
+static AFFECTED_ENTITIES: &'static str = "AffectedEntities";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimBlock {
+    pub id: String,
+    pub data: u8,
+    pub nbt: Option<NbtCompound>,
+    // Whether a command block has been powered since it last lost power.
+    triggered: bool,
+}
+
+impl SimBlock {
+    fn new(id: String, data: u8, nbt: Option<NbtCompound>) -> SimBlock {
+        SimBlock { id: id, data: data, nbt: nbt, triggered: false }
+    }
+
+    fn is_command_block(&self) -> bool {
+        self.id == COMMAND_BLOCK
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entity {
+    // The entity's scoreboard name, as it is for entities on the server.
+    pub uuid: String,
+    pub kind: String,
+    pub custom_name: Option<String>,
+    pub pos: (f64, f64, f64),
+}
+
+impl Entity {
+    pub fn name(&self) -> &str {
+        match self.custom_name {
+            Some(ref name) => &name[..],
+            None => &self.kind[..],
+        }
+    }
+}
+
+// Who a command runs as: the console, a command block, or an entity that
+// execute ran it as.  Feedback goes back to the console only when the console
+// started the chain.
+#[derive(Clone)]
+struct Sender {
+    name: String,
+    pos: (f64, f64, f64),
+    block_pos: Vec3,
+    entity: Option<String>,
+    is_console: bool,
+    from_console: bool,
+    // Command blocks record stats for their CommandStats.
+    records_stats: bool,
+}
+
+impl Sender {
+    fn console() -> Sender {
+        Sender {
+            name: "Server".to_string(),
+            pos: (0.0, 0.0, 0.0),
+            block_pos: Vec3::new(0, 0, 0),
+            entity: None,
+            is_console: true,
+            from_console: true,
+            records_stats: false,
+        }
+    }
+
+    fn command_block(pos: Vec3) -> Sender {
+        Sender {
+            name: "@".to_string(),
+            pos: (pos.x as f64 + 0.5, pos.y as f64 + 0.5, pos.z as f64 + 0.5),
+            block_pos: pos,
+            entity: None,
+            is_console: false,
+            from_console: false,
+            records_stats: false,
+        }
+    }
+}
+
+fn floor(pos: (f64, f64, f64)) -> Vec3 {
+    Vec3::new(pos.0.floor() as i32, pos.1.floor() as i32, pos.2.floor() as i32)
+}
+
+fn offset(pos: Vec3, d: (i32, i32, i32)) -> Vec3 {
+    Vec3::new(pos.x + d.0, pos.y + d.1, pos.z + d.2)
+}
+
+// The superflat world the test server generates: bedrock, stone, then
+// sandstone up to y=55.
+fn terrain(y: i32) -> &'static str {
+    match y {
+        0 => "minecraft:bedrock",
+        1...3 => "minecraft:stone",
+        4...55 => "minecraft:sandstone",
+        _ => AIR,
+    }
+}
+
+fn block_id(text: &str) -> Result<String, String> {
+    let valid = !text.is_empty() && text.chars().all(|c| {
+        c.is_alphanumeric() || c == '_' || c == ':'
+    });
+    if !valid {
+        return Err(format!("There is no such block with name {}", text));
+    }
+    if text.contains(':') {
+        Ok(text.to_string())
+    } else {
+        Ok(format!("minecraft:{}", text))
+    }
+}
+
+fn parse_int(text: &str) -> Result<i32, String> {
+    text.parse().map_err(|_| format!("'{}' is not a valid number", text))
+}
+
+fn parse_min(text: &str, min: i32) -> Result<i32, String> {
+    let n = try!(parse_int(text));
+    if n < min {
+        return Err(format!(
+            "The number you have entered ({}) is too small, it must be at least {}", n, min));
+    }
+    Ok(n)
+}
+
+fn parse_data(text: &str) -> Result<u8, String> {
+    let n = try!(parse_min(text, 0));
+    if n > 15 {
+        return Err(format!(
+            "The number you have entered ({}) is too big, it must be at most 15", n));
+    }
+    Ok(n as u8)
+}
+
+// A coordinate, which is relative to base when it starts with ~.  Whole
+// numbers are moved to the middle of the block when center is set, as the
+// server does for entity positions.
+fn coord(base: f64, text: &str, center: bool) -> Result<f64, String> {
+    if text.starts_with("~") {
+        let rest = &text[1..];
+        if rest.is_empty() {
+            return Ok(base);
+        }
+        return rest.parse::<f64>().map(|d| base + d)
+            .map_err(|_| format!("'{}' is not a valid number", text));
+    }
+    let value = try!(text.parse::<f64>().map_err(|_| format!("'{}' is not a valid number", text)));
+    if center && !text.contains('.') {
+        Ok(value + 0.5)
+    } else {
+        Ok(value)
+    }
+}
+
+fn usage(usage: &str) -> String {
+    format!("Usage: {}", usage)
+}
+
+fn is_selector(text: &str) -> bool {
+    text.starts_with("@")
+}
+
+// A parsed entity selector: its kind (p, a, r or e) and its arguments.
+struct Selector {
+    kind: char,
+    args: HashMap<String, String>,
+}
+
+impl Selector {
+    fn parse(text: &str) -> Result<Selector, String> {
+        let invalid = || format!("'{}' is not a valid selector", text);
+        let mut chars = text.chars();
+        if chars.next() != Some('@') {
+            return Err(invalid());
+        }
+        let kind = match chars.next() {
+            Some(c) if "pare".contains(c) => c,
+            _ => return Err(invalid()),
+        };
+        let rest = &text[2..];
+        let mut args = HashMap::new();
+        if !rest.is_empty() {
+            if !rest.starts_with("[") || !rest.ends_with("]") {
+                return Err(invalid());
+            }
+            // Arguments without a name are x, y, z and r, in that order.
+            let positional = ["x", "y", "z", "r"];
+            for (i, arg) in rest[1..rest.len() - 1].split(',').enumerate() {
+                if arg.is_empty() {
+                    continue;
+                }
+                match arg.find('=') {
+                    Some(eq) => { args.insert(arg[..eq].to_string(), arg[eq + 1..].to_string()); }
+                    None if i < positional.len() => {
+                        args.insert(positional[i].to_string(), arg.to_string());
+                    }
+                    None => return Err(invalid()),
+                }
+            }
+        }
+        Ok(Selector { kind: kind, args: args })
+    }
+
+    fn int(&self, key: &str) -> Option<i32> {
+        self.args.get(key).and_then(|v| v.parse().ok())
+    }
+
+    fn count(&self) -> i32 {
+        let default = if self.kind == 'a' || self.kind == 'e' { 0 } else { 1 };
+        self.int("c").unwrap_or(default)
+    }
+
+    // Whether the server runs a command once per entity the selector matches,
+    // rather than requiring it to match exactly one.
+    fn matches_multiple(&self) -> bool {
+        self.count() != 1
+    }
+}
+
+// Tests an argument like name=foo or name=!foo.
+fn matches_negatable(arg: Option<&String>, value: &str) -> bool {
+    match arg {
+        Some(arg) if arg.starts_with("!") => &arg[1..] != value,
+        Some(arg) => &arg[..] == value,
+        None => true,
+    }
+}
+
+pub struct World {
+    blocks: HashMap<Vec3, SimBlock>,
+    entities: Vec<Entity>,
+    next_entity: u64,
+    // Each objective's criteria, and each objective's scores by holder.
+    objectives: BTreeMap<String, String>,
+    scores: BTreeMap<String, BTreeMap<String, i32>>,
+    teams: BTreeMap<String, Vec<String>>,
+    game_rules: BTreeMap<String, String>,
+    time: u64,
+    // Command blocks waiting to run: the tick to run on, the order they were
+    // scheduled in, and where they are.
+    scheduled: Vec<(u64, u64, Vec3)>,
+    scheduled_at: HashSet<Vec3>,
+    next_schedule: u64,
+    rng: u64,
+    // Stats recorded by the command block that is running.
+    stats: Vec<(&'static str, i32)>,
+    log: Vec<String>,
+}
+
+impl World {
+    pub fn new() -> World {
+        let mut game_rules = BTreeMap::new();
+        for rule in ["commandBlockOutput", "logAdminCommands", "sendCommandFeedback"].iter() {
+            game_rules.insert(rule.to_string(), "true".to_string());
+        }
+        World {
+            blocks: HashMap::new(),
+            entities: vec!(),
+            next_entity: 0,
+            objectives: BTreeMap::new(),
+            scores: BTreeMap::new(),
+            teams: BTreeMap::new(),
+            game_rules: game_rules,
+            time: 0,
+            scheduled: vec!(),
+            scheduled_at: HashSet::new(),
+            next_schedule: 0,
+            rng: 0x2545f4914f6cdd1d,
+            stats: vec!(),
+            log: vec!(),
+        }
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    // Whether any command blocks are waiting to run.
+    pub fn is_idle(&self) -> bool {
+        self.scheduled.is_empty()
+    }
+
+    // Everything logged since the last call, one line per message.
+    pub fn take_log(&mut self) -> Vec<String> {
+        ::std::mem::replace(&mut self.log, vec!())
+    }
+
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities[..]
+    }
+
+    pub fn score(&self, holder: &str, objective: &str) -> Option<i32> {
+        self.scores.get(objective).and_then(|s| s.get(holder)).map(|s| *s)
+    }
+
+    pub fn block(&self, pos: Vec3) -> SimBlock {
+        match self.blocks.get(&pos) {
+            Some(block) => block.clone(),
+            None => SimBlock::new(terrain(pos.y).to_string(), 0, None),
+        }
+    }
+
+    // Runs a command the way the console would, and returns how many times
+    // it succeeded.
+    pub fn run_console(&mut self, line: &str) -> i32 {
+        let line = line.trim();
+        let line = if line.starts_with("/") { &line[1..] } else { line };
+        self.run(&Sender::console(), line)
+    }
+
+    // Advances the world a tick, running the command blocks that were powered
+    // in the last one.
+    pub fn tick(&mut self) {
+        self.time += 1;
+        let now = self.time;
+        self.scheduled.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+        let due = cmp::min(
+            MAX_TICK_UPDATES, self.scheduled.iter().take_while(|s| s.0 <= now).count());
+        let due: Vec<(u64, u64, Vec3)> = self.scheduled.drain(..due).collect();
+        for &(_, _, pos) in due.iter() {
+            self.scheduled_at.remove(&pos);
+            if self.block(pos).is_command_block() {
+                self.trigger(pos);
+            }
+        }
+    }
+
+    fn rule(&self, name: &str) -> bool {
+        self.game_rules.get(name).map_or(false, |v| v == "true")
+    }
+
+    fn notify(&mut self, sender: &Sender, msg: String) {
+        if sender.from_console {
+            self.log.push(msg.clone());
+        }
+        if !sender.is_console && self.rule("logAdminCommands") {
+            self.log.push(format!("[{}: {}]", sender.name, msg));
+        }
+    }
+
+    fn error(&mut self, sender: &Sender, msg: String) {
+        if sender.from_console {
+            self.log.push(msg);
+        }
+    }
+
+    fn set_stat(&mut self, sender: &Sender, stat: &'static str, value: i32) {
+        if sender.records_stats {
+            self.stats.push((stat, value));
+        }
+    }
+
+    // Runs a command line, once per matched entity when its target selector
+    // can match several, as the server's command handler does.
+    fn run(&mut self, sender: &Sender, line: &str) -> i32 {
+        let mut args: Vec<String> = line.split(' ').map(|s| s.to_string()).collect();
+        let expand = self.expanded_index(&args[..]);
+
+        let mut count = 0;
+        match expand {
+            Some(i) => {
+                let original = args[i].clone();
+                let matched = match self.select(sender, &original[..]) {
+                    Ok(matched) => matched,
+                    Err(e) => {
+                        self.error(sender, e);
+                        return 0;
+                    }
+                };
+                self.set_stat(sender, AFFECTED_ENTITIES, matched.len() as i32);
+                for uuid in matched.into_iter() {
+                    args[i] = uuid;
+                    if self.run_once(sender, &args[..]) {
+                        count += 1;
+                    }
+                }
+            }
+            None => {
+                if self.run_once(sender, &args[..]) {
+                    count += 1;
+                }
+            }
+        }
+        self.set_stat(sender, SUCCESS_COUNT, count);
+        count
+    }
+
+    fn expanded_index(&self, args: &[String]) -> Option<usize> {
+        let candidates: Vec<usize> = match &args[0][..] {
+            "execute" | "kill" | "tp" | "testfor" => vec!(1),
+            "scoreboard" if args.len() > 2 && args[1] == "players" => {
+                if args[2] == "operation" { vec!(3, 6) } else { vec!(3) }
+            }
+            _ => vec!(),
+        };
+        candidates.into_iter().find(|&i| {
+            i < args.len() && is_selector(&args[i][..]) &&
+                Selector::parse(&args[i][..]).map(|s| s.matches_multiple()).unwrap_or(false)
+        })
+    }
+
+    fn run_once(&mut self, sender: &Sender, args: &[String]) -> bool {
+        let args: Vec<&str> = args.iter().map(|a| &a[..]).collect();
+        let result = match args[0] {
+            "blockdata" => self.cmd_blockdata(sender, &args[..]),
+            "execute" => self.cmd_execute(sender, &args[..]),
+            "fill" => self.cmd_fill(sender, &args[..]),
+            "gamerule" => self.cmd_gamerule(sender, &args[..]),
+            "kill" => self.cmd_kill(sender, &args[..]),
+            "say" => self.cmd_say(sender, &args[..]),
+            "scoreboard" => self.cmd_scoreboard(sender, &args[..]),
+            "setblock" => self.cmd_setblock(sender, &args[..]),
+            "setworldspawn" => self.cmd_setworldspawn(sender, &args[..]),
+            "summon" => self.cmd_summon(sender, &args[..]),
+            "testfor" => self.cmd_testfor(sender, &args[..]),
+            "testforblock" => self.cmd_testforblock(sender, &args[..]),
+            "time" => self.cmd_time(sender, &args[..]),
+            "tp" => self.cmd_tp(sender, &args[..]),
+            "weather" => self.cmd_weather(sender, &args[..]),
+            _ => Err("Unknown command. Try /help for a list of commands".to_string()),
+        };
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                self.error(sender, e);
+                false
+            }
+        }
+    }
+
+    // The entities a selector matches, by scoreboard name, in the order the
+    // server would list them.
+    fn select(&mut self, sender: &Sender, text: &str) -> Result<Vec<String>, String> {
+        let sel = try!(Selector::parse(text));
+        // Only entity selectors, and random ones with a type, can match
+        // anything but players, and there are no players.
+        let has_type = sel.args.contains_key("type");
+        if sel.kind != 'e' && !(sel.kind == 'r' && has_type) {
+            return Ok(vec!());
+        }
+        if ["l", "lm", "m"].iter().any(|k| sel.args.contains_key(*k)) {
+            return Ok(vec!());
+        }
+
+        let base = Vec3::new(
+            sel.int("x").unwrap_or(sender.block_pos.x),
+            sel.int("y").unwrap_or(sender.block_pos.y),
+            sel.int("z").unwrap_or(sender.block_pos.z));
+        let volume = if ["dx", "dy", "dz"].iter().any(|k| sel.args.contains_key(*k)) {
+            let d = Vec3::new(
+                sel.int("dx").unwrap_or(0), sel.int("dy").unwrap_or(0), sel.int("dz").unwrap_or(0));
+            let min = Vec3::new(cmp::min(base.x, base.x + d.x), cmp::min(base.y, base.y + d.y),
+                                cmp::min(base.z, base.z + d.z));
+            let max = Vec3::new(cmp::max(base.x, base.x + d.x) + 1, cmp::max(base.y, base.y + d.y) + 1,
+                                cmp::max(base.z, base.z + d.z) + 1);
+            Some((min, max))
+        } else {
+            None
+        };
+
+        let center = (base.x as f64 + 0.5, base.y as f64 + 0.5, base.z as f64 + 0.5);
+        let mut matched: Vec<&Entity> = vec!();
+        for entity in self.entities.iter() {
+            if !matches_negatable(sel.args.get("type"), &entity.kind[..]) ||
+                !matches_negatable(sel.args.get("name"), entity.name())
+            {
+                continue;
+            }
+            if let Some(team) = sel.args.get("team") {
+                let on = self.team_of(&entity.uuid[..]);
+                let ok = if team.is_empty() {
+                    on.is_none()
+                } else if team.starts_with("!") {
+                    on != Some(&team[1..])
+                } else {
+                    on == Some(&team[..])
+                };
+                if !ok {
+                    continue;
+                }
+            }
+            if !self.matches_scores(&sel, &entity.uuid[..]) {
+                continue;
+            }
+            // Entities never turn, so their rotation is always zero.
+            let rotation_ok = ["rxm", "rym"].iter().all(|k| sel.int(k).map_or(true, |v| v <= 0)) &&
+                ["rx", "ry"].iter().all(|k| sel.int(k).map_or(true, |v| v >= 0));
+            if !rotation_ok {
+                continue;
+            }
+            let dist_sq = {
+                let (x, y, z) = entity.pos;
+                (x - center.0).powi(2) + (y - center.1).powi(2) + (z - center.2).powi(2)
+            };
+            if let Some(r) = sel.int("r") {
+                if dist_sq > (r as f64).powi(2) {
+                    continue;
+                }
+            }
+            if let Some(rm) = sel.int("rm") {
+                if dist_sq < (rm as f64).powi(2) {
+                    continue;
+                }
+            }
+            if let Some((min, max)) = volume {
+                let (x, y, z) = entity.pos;
+                let half = ENTITY_WIDTH / 2.0;
+                let inside = x + half > min.x as f64 && x - half < max.x as f64 &&
+                    y + ENTITY_HEIGHT > min.y as f64 && y < max.y as f64 &&
+                    z + half > min.z as f64 && z - half < max.z as f64;
+                if !inside {
+                    continue;
+                }
+            }
+            matched.push(entity);
+        }
+
+        if sel.kind != 'r' {
+            let dist_sq = |e: &Entity| {
+                let (x, y, z) = e.pos;
+                (x - base.x as f64).powi(2) + (y - base.y as f64).powi(2) +
+                    (z - base.z as f64).powi(2)
+            };
+            matched.sort_by(|a, b| dist_sq(a).partial_cmp(&dist_sq(b)).unwrap_or(cmp::Ordering::Equal));
+        }
+        let mut uuids: Vec<String> = matched.iter().map(|e| e.uuid.clone()).collect();
+        if sel.kind == 'r' {
+            // Random selection shuffles with the world's own generator, so
+            // that runs repeat.
+            for i in (1..uuids.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                uuids.swap(i, j);
+            }
+        }
+
+        let count = sel.count();
+        if let Some(ref me) = sender.entity {
+            if count == 1 && sel.kind != 'r' && uuids.contains(me) {
+                return Ok(vec!(me.clone()));
+            }
+        }
+        if count < 0 {
+            uuids.reverse();
+        }
+        if count != 0 {
+            uuids.truncate(count.abs() as usize);
+        }
+        Ok(uuids)
+    }
+
+    fn matches_scores(&self, sel: &Selector, holder: &str) -> bool {
+        for (key, value) in sel.args.iter() {
+            if !key.starts_with("score_") {
+                continue;
+            }
+            let (objective, is_min) = if key.ends_with("_min") {
+                (&key[6..key.len() - 4], true)
+            } else {
+                (&key[6..], false)
+            };
+            let bound: i32 = match value.parse() {
+                Ok(bound) => bound,
+                Err(_) => continue,
+            };
+            match self.score(holder, objective) {
+                Some(score) if is_min && score >= bound => { }
+                Some(score) if !is_min && score <= bound => { }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn entity(&self, uuid: &str) -> Option<&Entity> {
+        self.entities.iter().find(|e| e.uuid == uuid)
+    }
+
+    // The one entity text names, by selector or by scoreboard name.
+    fn entity_one(&mut self, sender: &Sender, text: &str) -> Result<String, String> {
+        if is_selector(text) {
+            let mut matched = try!(self.select(sender, text));
+            if matched.len() == 1 {
+                return Ok(matched.pop().unwrap());
+            }
+        } else if self.entity(text).is_some() {
+            return Ok(text.to_string());
+        }
+        Err("Entity cannot be found".to_string())
+    }
+
+    // The scoreboard name of a score holder: the entity's, for a selector or
+    // an entity's name, or the text itself, for a fake player.
+    fn holder(&mut self, sender: &Sender, text: &str) -> Result<String, String> {
+        if is_selector(text) {
+            self.entity_one(sender, text)
+        } else {
+            Ok(text.to_string())
+        }
+    }
+
+    fn display_name(&self, holder: &str) -> String {
+        match self.entity(holder) {
+            Some(entity) => entity.name().to_string(),
+            None => holder.to_string(),
+        }
+    }
+
+    fn team_of(&self, holder: &str) -> Option<&str> {
+        self.teams.iter()
+            .find(|&(_, members)| members.iter().any(|m| m == holder))
+            .map(|(team, _)| &team[..])
+    }
+
+    fn sender_for(&self, sender: &Sender, entity: &Entity, pos: (f64, f64, f64)) -> Sender {
+        Sender {
+            name: entity.name().to_string(),
+            pos: pos,
+            block_pos: floor(pos),
+            entity: Some(entity.uuid.clone()),
+            is_console: false,
+            from_console: sender.from_console,
+            records_stats: false,
+        }
+    }
+
+    fn block_pos(&self, sender: &Sender, args: &[&str]) -> Result<Vec3, String> {
+        let base = sender.block_pos;
+        Ok(floor((
+            try!(coord(base.x as f64, args[0], false)),
+            try!(coord(base.y as f64, args[1], false)),
+            try!(coord(base.z as f64, args[2], false)))))
+    }
+
+    // Places a block without notifying its neighbors.  Returns whether
+    // anything changed.
+    fn place(&mut self, pos: Vec3, block: SimBlock) -> Result<bool, String> {
+        if pos.y < 0 || pos.y > 255 {
+            return Err("Cannot place blocks outside of the world".to_string());
+        }
+        let old = self.block(pos);
+        if old.id == block.id && old.data == block.data && !old.is_command_block() &&
+            !block.is_command_block()
+        {
+            return Ok(false);
+        }
+        let mut block = block;
+        if block.is_command_block() && block.nbt.is_none() {
+            block.nbt = Some(NbtCompound::new());
+        }
+        self.blocks.insert(pos, block);
+        Ok(true)
+    }
+
+    fn notify_neighbors(&mut self, pos: Vec3) {
+        for &d in NEIGHBORS.iter() {
+            self.neighbor_changed(offset(pos, d));
+        }
+    }
+
+    fn is_powered(&self, pos: Vec3) -> bool {
+        NEIGHBORS.iter().any(|&d| self.block(offset(pos, d)).id == REDSTONE_BLOCK)
+    }
+
+    // A command block that becomes powered runs on the next tick.
+    fn neighbor_changed(&mut self, pos: Vec3) {
+        let powered = self.is_powered(pos);
+        let schedule = match self.blocks.get_mut(&pos) {
+            Some(block) if block.is_command_block() => {
+                if powered && !block.triggered {
+                    block.triggered = true;
+                    true
+                } else {
+                    if !powered {
+                        block.triggered = false;
+                    }
+                    false
+                }
+            }
+            _ => false,
+        };
+        if schedule && !self.scheduled_at.contains(&pos) {
+            self.scheduled_at.insert(pos);
+            self.scheduled.push((self.time + 1, self.next_schedule, pos));
+            self.next_schedule += 1;
+        }
+    }
+
+    fn trigger(&mut self, pos: Vec3) {
+        let nbt = self.block(pos).nbt.unwrap_or(NbtCompound::new());
+        let command = match nbt.get("Command") {
+            Some(&Nbt::String(ref command)) => command.clone(),
+            _ => String::new(),
+        };
+        let mut sender = Sender::command_block(pos);
+        sender.records_stats = true;
+        self.stats.clear();
+        self.run(&sender, &command[..]);
+        let stats = ::std::mem::replace(&mut self.stats, vec!());
+
+        let config = match nbt.get("CommandStats") {
+            Some(&Nbt::Compound(ref config)) => config.clone(),
+            _ => return,
+        };
+        sender.records_stats = false;
+        for &(stat, value) in stats.iter() {
+            let name = config.get(&format!("{}Name", stat)[..]);
+            let objective = config.get(&format!("{}Objective", stat)[..]);
+            if let (Some(&Nbt::String(ref name)), Some(&Nbt::String(ref objective))) = (name, objective) {
+                if !self.objectives.contains_key(objective) {
+                    continue;
+                }
+                if let Ok(holder) = self.holder(&sender, &name[..]) {
+                    self.set_score(&holder[..], &objective[..], value);
+                }
+            }
+        }
+    }
+
+    fn set_score(&mut self, holder: &str, objective: &str, value: i32) {
+        self.scores.entry(objective.to_string()).or_insert(BTreeMap::new())
+            .insert(holder.to_string(), value);
+    }
+
+    fn require_objective(&self, objective: &str) -> Result<(), String> {
+        if self.objectives.contains_key(objective) {
+            Ok(())
+        } else {
+            Err(format!("No objective was found by the name '{}'", objective))
+        }
+    }
+
+    fn cmd_blockdata(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 5 {
+            return Err(usage("/blockdata <x> <y> <z> <dataTag>"));
+        }
+        let pos = try!(self.block_pos(sender, &args[1..4]));
+        let tag = match try!(nbt::parse(&args[4..].connect(" ")[..])) {
+            Nbt::Compound(tag) => tag,
+            _ => return Err("Data tag parsing failed: not a compound".to_string()),
+        };
+        let mut block = self.block(pos);
+        let mut merged = match block.nbt {
+            Some(ref nbt) => nbt.clone(),
+            None => return Err("The target block is not a data holder block".to_string()),
+        };
+        for (key, value) in tag.into_iter() {
+            merged.insert(key, value);
+        }
+        if Some(&merged) == block.nbt.as_ref() {
+            return Err("The data tag did not change".to_string());
+        }
+        let text = Nbt::Compound(merged.clone()).to_string();
+        block.nbt = Some(merged);
+        self.blocks.insert(pos, block);
+        self.notify(sender, format!("Block data updated to: {}", text));
+        Ok(())
+    }
+
+    fn cmd_execute(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 6 {
+            return Err(usage("/execute <entity> <x> <y> <z> <command> OR /execute <entity> \
+                              <x> <y> <z> detect <x> <y> <z> <block> <data> <command>"));
+        }
+        let uuid = try!(self.entity_one(sender, args[1]));
+        let entity = self.entity(&uuid[..]).unwrap().clone();
+        let pos = (
+            try!(coord(entity.pos.0, args[2], false)),
+            try!(coord(entity.pos.1, args[3], false)),
+            try!(coord(entity.pos.2, args[4], false)));
+
+        let mut start = 5;
+        if args[5] == "detect" && args.len() > 11 {
+            let at = floor((
+                try!(coord(pos.0, args[6], false)),
+                try!(coord(pos.1, args[7], false)),
+                try!(coord(pos.2, args[8], false))));
+            let id = try!(block_id(args[9]));
+            let data = try!(parse_min(args[10], -1));
+            let block = self.block(at);
+            if block.id != id || (data >= 0 && block.data as i32 != data) {
+                return Err(format!("Failed to execute '{}' as {}", "detect", entity.name()));
+            }
+            start = 11;
+        }
+
+        let command = args[start..].connect(" ");
+        let inner = self.sender_for(sender, &entity, pos);
+        if self.run(&inner, &command[..]) < 1 {
+            return Err(format!("Failed to execute '{}' as {}", command, entity.name()));
+        }
+        Ok(())
+    }
+
+    fn cmd_fill(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 8 {
+            return Err(usage("/fill <x1> <y1> <z1> <x2> <y2> <z2> <TileName> [dataValue] \
+                              [oldBlockHandling] [dataTag]"));
+        }
+        let a = try!(self.block_pos(sender, &args[1..4]));
+        let b = try!(self.block_pos(sender, &args[4..7]));
+        let min = Vec3::new(cmp::min(a.x, b.x), cmp::min(a.y, b.y), cmp::min(a.z, b.z));
+        let max = Vec3::new(cmp::max(a.x, b.x), cmp::max(a.y, b.y), cmp::max(a.z, b.z));
+        let volume = (max.x - min.x + 1) * (max.y - min.y + 1) * (max.z - min.z + 1);
+        if volume > MAX_FILL {
+            return Err(format!(
+                "Too many blocks in the specified area ({} > {})", volume, MAX_FILL));
+        }
+        if min.y < 0 || max.y > 255 {
+            return Err("Cannot place blocks outside of the world".to_string());
+        }
+
+        let id = try!(block_id(args[7]));
+        let data = if args.len() > 8 { try!(parse_data(args[8])) } else { 0 };
+        let mode = if args.len() > 9 { args[9] } else { "replace" };
+        if !["replace", "destroy", "keep", "hollow", "outline"].contains(&mode) {
+            return Err(usage("/fill ... [replace|destroy|keep|hollow|outline] [dataTag]"));
+        }
+        let mut nbt_tag = None;
+        let mut filter = None;
+        if args.len() > 10 {
+            if id == COMMAND_BLOCK {
+                match try!(nbt::parse(&args[10..].connect(" ")[..])) {
+                    Nbt::Compound(tag) => nbt_tag = Some(tag),
+                    _ => return Err("Data tag parsing failed: not a compound".to_string()),
+                }
+            } else if mode == "replace" {
+                let filter_id = try!(block_id(args[10]));
+                let filter_data = if args.len() > 11 { Some(try!(parse_data(args[11]))) } else { None };
+                filter = Some((filter_id, filter_data));
+            }
+        }
+
+        let mut changed = vec!();
+        for z in min.z..max.z + 1 {
+            for y in min.y..max.y + 1 {
+                for x in min.x..max.x + 1 {
+                    let pos = Vec3::new(x, y, z);
+                    let edge = x == min.x || x == max.x || y == min.y || y == max.y ||
+                        z == min.z || z == max.z;
+                    let old = self.block(pos);
+                    let mut new = SimBlock::new(id.clone(), data, nbt_tag.clone());
+                    match mode {
+                        "hollow" if !edge => new = SimBlock::new(AIR.to_string(), 0, None),
+                        "outline" if !edge => continue,
+                        "keep" if old.id != AIR => continue,
+                        _ => { }
+                    }
+                    if let Some((ref filter_id, filter_data)) = filter {
+                        if old.id != *filter_id || filter_data.map_or(false, |d| d != old.data) {
+                            continue;
+                        }
+                    }
+                    if try!(self.place(pos, new)) {
+                        changed.push(pos);
+                    }
+                }
+            }
+        }
+        for &pos in changed.iter() {
+            self.notify_neighbors(pos);
+        }
+
+        self.set_stat(sender, AFFECTED_BLOCKS, changed.len() as i32);
+        if changed.is_empty() {
+            return Err("No blocks filled".to_string());
+        }
+        self.notify(sender, format!("{} blocks filled", changed.len()));
+        Ok(())
+    }
+
+    fn cmd_gamerule(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        match args.len() {
+            1 => {
+                let rules: Vec<&str> = self.game_rules.keys().map(|k| &k[..]).collect();
+                let msg = rules.connect(", ");
+                self.notify(sender, msg);
+            }
+            2 => {
+                let value = match self.game_rules.get(args[1]) {
+                    Some(value) => value.clone(),
+                    None => return Err(format!("No game rule called '{}' is available", args[1])),
+                };
+                self.notify(sender, format!("{} = {}", args[1], value));
+            }
+            _ => {
+                self.game_rules.insert(args[1].to_string(), args[2..].connect(" "));
+                self.notify(sender, "Game rule has been updated".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_kill(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        let uuid = match args.get(1) {
+            Some(target) => try!(self.entity_one(sender, target)),
+            None => match sender.entity {
+                Some(ref uuid) => uuid.clone(),
+                None => return Err("You must specify which player you wish to perform \
+                                    this action on.".to_string()),
+            },
+        };
+        let name = self.display_name(&uuid[..]);
+        self.entities.retain(|e| e.uuid != uuid);
+        self.notify(sender, format!("Killed {}", name));
+        Ok(())
+    }
+
+    // Replaces each word that is a selector with the names of what it
+    // matches, as say does.
+    fn expand_names(&mut self, sender: &Sender, words: &[&str]) -> Result<String, String> {
+        let mut out = vec!();
+        for word in words.iter() {
+            if is_selector(word) && Selector::parse(word).is_ok() {
+                let matched = try!(self.select(sender, word));
+                let names: Vec<String> = matched.iter().map(|u| self.display_name(&u[..])).collect();
+                out.push(match names.len() {
+                    0 => return Err("Selector found nothing".to_string()),
+                    1 => names[0].clone(),
+                    n => format!("{} and {}", names[..n - 1].connect(", "), names[n - 1]),
+                });
+            } else {
+                out.push(word.to_string());
+            }
+        }
+        Ok(out.connect(" "))
+    }
+
+    fn cmd_say(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 2 || args[1..].iter().all(|a| a.is_empty()) {
+            return Err(usage("/say <message ...>"));
+        }
+        let msg = try!(self.expand_names(sender, &args[1..]));
+        self.log.push(format!("[{}] {}", sender.name, msg));
+        Ok(())
+    }
+
+    fn cmd_scoreboard(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        match (args.get(1).map(|a| *a), args.get(2).map(|a| *a)) {
+            (Some("objectives"), Some("list")) => self.cmd_objectives_list(sender),
+            (Some("objectives"), Some("add")) => self.cmd_objectives_add(sender, &args[3..]),
+            (Some("objectives"), Some("remove")) => self.cmd_objectives_remove(sender, &args[3..]),
+            (Some("objectives"), Some("setdisplay")) => {
+                match args.get(4) {
+                    Some(objective) => {
+                        try!(self.require_objective(objective));
+                        self.notify(sender, format!(
+                            "Set the display objective in slot '{}' to '{}'", args[3], objective));
+                    }
+                    None if args.len() > 3 => {
+                        self.notify(sender, format!("Cleared objective display slot '{}'", args[3]));
+                    }
+                    None => return Err(usage("/scoreboard objectives setdisplay <slot> [objective]")),
+                }
+                Ok(())
+            }
+            (Some("players"), Some("set")) |
+            (Some("players"), Some("add")) |
+            (Some("players"), Some("remove")) => self.cmd_players_set(sender, &args[2..]),
+            (Some("players"), Some("reset")) => self.cmd_players_reset(sender, &args[3..]),
+            (Some("players"), Some("list")) => self.cmd_players_list(sender, &args[3..]),
+            (Some("players"), Some("test")) => self.cmd_players_test(sender, &args[3..]),
+            (Some("players"), Some("operation")) => self.cmd_players_operation(sender, &args[3..]),
+            (Some("teams"), Some("add")) => self.cmd_teams_add(sender, &args[3..]),
+            (Some("teams"), Some("remove")) => self.cmd_teams_remove(sender, &args[3..]),
+            (Some("teams"), Some("join")) => self.cmd_teams_join(sender, &args[3..]),
+            (Some("teams"), Some("leave")) => self.cmd_teams_leave(sender, &args[3..]),
+            (Some("teams"), Some("empty")) => self.cmd_teams_empty(sender, &args[3..]),
+            _ => Err(usage("/scoreboard <objectives|players|teams>")),
+        }
+    }
+
+    fn cmd_objectives_list(&mut self, sender: &Sender) -> Result<(), String> {
+        if self.objectives.is_empty() {
+            return Err("There are no objectives on the scoreboard".to_string());
+        }
+        let lines: Vec<String> = self.objectives.iter()
+            .map(|(name, criteria)| format!(
+                "- {}: displays as '{}' and is type '{}'", name, name, criteria))
+            .collect();
+        self.notify(sender, format!("Showing {} objective(s) on scoreboard:", lines.len()));
+        for line in lines.into_iter() {
+            self.notify(sender, line);
+        }
+        Ok(())
+    }
+
+    fn cmd_objectives_add(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 2 {
+            return Err(usage("/scoreboard objectives add <name> <criteriaType> [display name ...]"));
+        }
+        let name = args[0];
+        if self.objectives.contains_key(name) {
+            return Err(format!("An objective with the name '{}' already exists", name));
+        }
+        if name.len() > 16 {
+            return Err(format!(
+                "The name '{}' is too long for an objective, it can be at most 16 characters long",
+                name));
+        }
+        self.objectives.insert(name.to_string(), args[1].to_string());
+        self.notify(sender, format!("Added new objective '{}' successfully", name));
+        Ok(())
+    }
+
+    fn cmd_objectives_remove(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard objectives remove <name>"));
+        }
+        try!(self.require_objective(args[0]));
+        self.objectives.remove(args[0]);
+        self.scores.remove(args[0]);
+        self.notify(sender, format!("Removed objective '{}' successfully", args[0]));
+        Ok(())
+    }
+
+    // set, add and remove, with args starting at the subcommand.
+    fn cmd_players_set(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 4 {
+            return Err(usage(&format!(
+                "/scoreboard players {} <player> <objective> <score> [dataTag]", args[0])[..]));
+        }
+        let holder = try!(self.holder(sender, args[1]));
+        let objective = args[2];
+        try!(self.require_objective(objective));
+        let old = self.score(&holder[..], objective).unwrap_or(0);
+        let value = match args[0] {
+            "set" => try!(parse_int(args[3])),
+            "add" => old.wrapping_add(try!(parse_min(args[3], 0))),
+            _ => old.wrapping_sub(try!(parse_min(args[3], 0))),
+        };
+        self.set_score(&holder[..], objective, value);
+        self.notify(sender, format!(
+            "Set score of {} for player {} to {}", objective, holder, value));
+        Ok(())
+    }
+
+    fn cmd_players_reset(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard players reset <player> [objective]"));
+        }
+        let holder = try!(self.holder(sender, args[0]));
+        match args.get(1) {
+            Some(objective) => {
+                try!(self.require_objective(objective));
+                if let Some(scores) = self.scores.get_mut(*objective) {
+                    scores.remove(&holder);
+                }
+                self.notify(sender, format!("Reset score {} of player {}", objective, holder));
+            }
+            None => {
+                for scores in self.scores.values_mut() {
+                    scores.remove(&holder);
+                }
+                self.notify(sender, format!("Reset scores of player {}", holder));
+            }
+        }
+        Ok(())
+    }
+
+    fn cmd_players_list(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        match args.get(0) {
+            Some(target) => {
+                let holder = try!(self.holder(sender, target));
+                let scores: Vec<String> = self.scores.iter()
+                    .filter_map(|(objective, scores)| scores.get(&holder).map(|score| {
+                        format!("- {}: {} ({})", objective, score, objective)
+                    }))
+                    .collect();
+                if scores.is_empty() {
+                    return Err(format!("Player {} has no scores recorded", holder));
+                }
+                self.notify(sender, format!(
+                    "Showing {} tracked objective(s) for {}:", scores.len(), holder));
+                for line in scores.into_iter() {
+                    self.notify(sender, line);
+                }
+            }
+            None => {
+                let mut holders: Vec<String> = self.scores.values()
+                    .flat_map(|scores| scores.keys().cloned())
+                    .collect();
+                holders.sort();
+                holders.dedup();
+                if holders.is_empty() {
+                    return Err("There are no tracked players on the scoreboard".to_string());
+                }
+                self.notify(sender, format!(
+                    "Showing {} tracked players on the scoreboard:", holders.len()));
+                self.notify(sender, holders.connect(", "));
+            }
+        }
+        Ok(())
+    }
+
+    fn bound(text: &str, default: i32) -> Result<i32, String> {
+        if text == "*" { Ok(default) } else { parse_int(text) }
+    }
+
+    fn cmd_players_test(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 3 {
+            return Err(usage("/scoreboard players test <player> <objective> <min> [max]"));
+        }
+        let holder = try!(self.holder(sender, args[0]));
+        try!(self.require_objective(args[1]));
+        let min = try!(World::bound(args[2], i32::MIN));
+        let max = match args.get(3) {
+            Some(max) => try!(World::bound(max, i32::MAX)),
+            None => i32::MAX,
+        };
+        let score = match self.score(&holder[..], args[1]) {
+            Some(score) => score,
+            None => return Err(format!("No {} score for {} found", args[1], holder)),
+        };
+        if score < min || score > max {
+            return Err(format!("Score {} is NOT in range {} to {}", score, min, max));
+        }
+        self.notify(sender, format!("Score {} is in range {} to {}", score, min, max));
+        Ok(())
+    }
+
+    fn cmd_players_operation(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 5 {
+            return Err(usage("/scoreboard players operation <targetName> <targetObjective> \
+                              <operation> <selector> <objective>"));
+        }
+        let target = try!(self.holder(sender, args[0]));
+        let target_obj = args[1];
+        let source = try!(self.holder(sender, args[3]));
+        let source_obj = args[4];
+        try!(self.require_objective(target_obj));
+        try!(self.require_objective(source_obj));
+        let b = match self.score(&source[..], source_obj) {
+            Some(b) => b,
+            None => return Err(format!("No {} score for {} found", source_obj, source)),
+        };
+        let a = self.score(&target[..], target_obj).unwrap_or(0);
+
+        let (a, b) = match args[2] {
+            "+=" => (a.wrapping_add(b), b),
+            "-=" => (a.wrapping_sub(b), b),
+            "*=" => (a.wrapping_mul(b), b),
+            "/=" => (if b != 0 { a.wrapping_div(b) } else { a }, b),
+            "%=" => (if b != 0 { a.wrapping_rem(b) } else { a }, b),
+            "=" => (b, b),
+            "<" => (cmp::min(a, b), b),
+            ">" => (cmp::max(a, b), b),
+            "><" => (b, a),
+            op => return Err(format!("Invalid operation {}", op)),
+        };
+        self.set_score(&target[..], target_obj, a);
+        if args[2] == "><" {
+            self.set_score(&source[..], source_obj, b);
+        }
+        self.notify(sender, "Operation applied successfully".to_string());
+        Ok(())
+    }
+
+    fn cmd_teams_add(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard teams add <name> [display name ...]"));
+        }
+        if self.teams.contains_key(args[0]) {
+            return Err(format!("A team with the name '{}' already exists", args[0]));
+        }
+        self.teams.insert(args[0].to_string(), vec!());
+        self.notify(sender, format!("Added new team '{}' successfully", args[0]));
+        Ok(())
+    }
+
+    fn require_team(&self, team: &str) -> Result<(), String> {
+        if self.teams.contains_key(team) {
+            Ok(())
+        } else {
+            Err(format!("No team was found by the name '{}'", team))
+        }
+    }
+
+    fn cmd_teams_remove(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard teams remove <name>"));
+        }
+        try!(self.require_team(args[0]));
+        self.teams.remove(args[0]);
+        self.notify(sender, format!("Removed team {}", args[0]));
+        Ok(())
+    }
+
+    // The scoreboard names of every member listed, or of the sender when
+    // none are.  Selectors may match any number of entities.
+    fn members(&mut self, sender: &Sender, args: &[&str]) -> Result<Vec<String>, String> {
+        if args.is_empty() {
+            return match sender.entity {
+                Some(ref uuid) => Ok(vec!(uuid.clone())),
+                None if sender.is_console => Err("You must specify which player you wish to \
+                                                  perform this action on.".to_string()),
+                None => Ok(vec!(sender.name.clone())),
+            };
+        }
+        let mut members = vec!();
+        for arg in args.iter() {
+            if is_selector(arg) {
+                members.extend(try!(self.select(sender, arg)).into_iter());
+            } else {
+                members.push(arg.to_string());
+            }
+        }
+        Ok(members)
+    }
+
+    fn leave_teams(&mut self, members: &[String]) {
+        for team in self.teams.values_mut() {
+            team.retain(|m| !members.contains(m));
+        }
+    }
+
+    fn cmd_teams_join(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard teams join <team> [player]"));
+        }
+        try!(self.require_team(args[0]));
+        let members = try!(self.members(sender, &args[1..]));
+        self.leave_teams(&members[..]);
+        self.teams.get_mut(args[0]).unwrap().extend(members.iter().cloned());
+        let names: Vec<String> = members.iter().map(|m| self.display_name(&m[..])).collect();
+        self.notify(sender, format!(
+            "Added {} player(s) to team {}: {}", members.len(), args[0], names.connect(", ")));
+        Ok(())
+    }
+
+    fn cmd_teams_leave(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        let members = try!(self.members(sender, args));
+        self.leave_teams(&members[..]);
+        let names: Vec<String> = members.iter().map(|m| self.display_name(&m[..])).collect();
+        self.notify(sender, format!(
+            "Removed {} player(s) from their teams: {}", members.len(), names.connect(", ")));
+        Ok(())
+    }
+
+    fn cmd_teams_empty(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 1 {
+            return Err(usage("/scoreboard teams empty <team>"));
+        }
+        try!(self.require_team(args[0]));
+        let count = self.teams[args[0]].len();
+        if count == 0 {
+            return Err(format!("Team {} is already empty, cannot remove nonexistant players",
+                               args[0]));
+        }
+        self.teams.get_mut(args[0]).unwrap().clear();
+        self.notify(sender, format!("Removed all {} player(s) from team {}", count, args[0]));
+        Ok(())
+    }
+
+    fn cmd_setblock(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 5 {
+            return Err(usage("/setblock <x> <y> <z> <TileName> [dataValue] \
+                              [oldBlockHandling] [dataTag]"));
+        }
+        let pos = try!(self.block_pos(sender, &args[1..4]));
+        let id = try!(block_id(args[4]));
+        let data = if args.len() > 5 { try!(parse_data(args[5])) } else { 0 };
+        let mode = if args.len() > 6 { args[6] } else { "replace" };
+        let nbt_tag = if args.len() > 7 && id == COMMAND_BLOCK {
+            match try!(nbt::parse(&args[7..].connect(" ")[..])) {
+                Nbt::Compound(tag) => Some(tag),
+                _ => return Err("Data tag parsing failed: not a compound".to_string()),
+            }
+        } else {
+            None
+        };
+        match mode {
+            "replace" | "destroy" => { }
+            "keep" if self.block(pos).id == AIR => { }
+            "keep" => return Err("The block couldn't be placed".to_string()),
+            _ => return Err(usage("/setblock ... [replace|destroy|keep] [dataTag]")),
+        }
+        if !try!(self.place(pos, SimBlock::new(id, data, nbt_tag))) {
+            return Err("The block couldn't be placed".to_string());
+        }
+        self.notify_neighbors(pos);
+        self.set_stat(sender, AFFECTED_BLOCKS, 1);
+        self.notify(sender, "Block placed".to_string());
+        Ok(())
+    }
+
+    fn cmd_setworldspawn(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        let pos = if args.len() >= 4 {
+            try!(self.block_pos(sender, &args[1..4]))
+        } else {
+            sender.block_pos
+        };
+        self.notify(sender, format!(
+            "Set the world spawn point to ({}, {}, {})", pos.x, pos.y, pos.z));
+        Ok(())
+    }
+
+    fn cmd_summon(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 2 {
+            return Err(usage("/summon <EntityName> [x] [y] [z] [dataTag]"));
+        }
+        let pos = if args.len() >= 5 {
+            (try!(coord(sender.pos.0, args[2], true)),
+             try!(coord(sender.pos.1, args[3], false)),
+             try!(coord(sender.pos.2, args[4], true)))
+        } else {
+            sender.pos
+        };
+        let tag = if args.len() > 5 {
+            match try!(nbt::parse(&args[5..].connect(" ")[..])) {
+                Nbt::Compound(tag) => tag,
+                _ => return Err("Data tag parsing failed: not a compound".to_string()),
+            }
+        } else {
+            NbtCompound::new()
+        };
+        let custom_name = match tag.get("CustomName") {
+            Some(&Nbt::String(ref name)) => Some(name.clone()),
+            _ => None,
+        };
+
+        self.next_entity += 1;
+        self.entities.push(Entity {
+            uuid: format!("00000000-0000-4000-8000-{:012x}", self.next_entity),
+            kind: args[1].to_string(),
+            custom_name: custom_name,
+            pos: pos,
+        });
+        self.notify(sender, "Object successfully summoned".to_string());
+        Ok(())
+    }
+
+    fn cmd_testfor(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 2 {
+            return Err(usage("/testfor <player> [dataTag]"));
+        }
+        let uuid = try!(self.entity_one(sender, args[1]));
+        let name = self.display_name(&uuid[..]);
+        self.notify(sender, format!("Found {}", name));
+        Ok(())
+    }
+
+    fn cmd_testforblock(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 5 {
+            return Err(usage("/testforblock <x> <y> <z> <TileName> [dataValue] [dataTag]"));
+        }
+        let pos = try!(self.block_pos(sender, &args[1..4]));
+        let id = try!(block_id(args[4]));
+        let data = if args.len() > 5 { try!(parse_min(args[5], -1)) } else { -1 };
+        let block = self.block(pos);
+        if block.id != id {
+            return Err(format!("The block at {},{},{} is {} (expected: {}).",
+                               pos.x, pos.y, pos.z, block.id, id));
+        }
+        if data >= 0 && block.data as i32 != data {
+            return Err(format!("The block at {},{},{} had the data value of {} (expected: {}).",
+                               pos.x, pos.y, pos.z, block.data, data));
+        }
+        self.notify(sender, format!("Successfully found the block at {},{},{}.",
+                                    pos.x, pos.y, pos.z));
+        Ok(())
+    }
+
+    fn cmd_time(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        if args.len() < 3 {
+            return Err(usage("/time <set|add|query> <value>"));
+        }
+        self.notify(sender, format!("Set the time to {}", args[2]));
+        Ok(())
+    }
+
+    fn cmd_tp(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        let (uuid, coords) = match args.len() {
+            2 | 3 => {
+                let (uuid, dest) = if args.len() == 2 {
+                    match sender.entity {
+                        Some(ref uuid) => (uuid.clone(), args[1]),
+                        None => return Err("You must specify which player you wish to perform \
+                                            this action on.".to_string()),
+                    }
+                } else {
+                    (try!(self.entity_one(sender, args[1])), args[2])
+                };
+                let dest = try!(self.entity_one(sender, dest));
+                let pos = self.entity(&dest[..]).unwrap().pos;
+                (uuid, pos)
+            }
+            4 | 6 => {
+                let uuid = match sender.entity {
+                    Some(ref uuid) => uuid.clone(),
+                    None => return Err("You must specify which player you wish to perform \
+                                        this action on.".to_string()),
+                };
+                let pos = self.entity(&uuid[..]).unwrap().pos;
+                (uuid, (try!(coord(pos.0, args[1], true)),
+                        try!(coord(pos.1, args[2], false)),
+                        try!(coord(pos.2, args[3], true))))
+            }
+            5 | 7 => {
+                let uuid = try!(self.entity_one(sender, args[1]));
+                let pos = self.entity(&uuid[..]).unwrap().pos;
+                (uuid, (try!(coord(pos.0, args[2], true)),
+                        try!(coord(pos.1, args[3], false)),
+                        try!(coord(pos.2, args[4], true))))
+            }
+            _ => return Err(usage("/tp [target player] <x> <y> <z> [<y-rot> <x-rot>] OR \
+                                   /tp [target player] <destination player>")),
+        };
+
+        let name = {
+            let entity = self.entities.iter_mut().find(|e| e.uuid == uuid).unwrap();
+            entity.pos = coords;
+            entity.name().to_string()
+        };
+        self.notify(sender, format!(
+            "Teleported {} to {:.2}, {:.2}, {:.2}", name, coords.0, coords.1, coords.2));
+        Ok(())
+    }
+
+    fn cmd_weather(&mut self, sender: &Sender, args: &[&str]) -> Result<(), String> {
+        match args.get(1) {
+            Some(weather) => {
+                self.notify(sender, format!("Changing to {} weather", weather));
+                Ok(())
+            }
+            None => Err(usage("/weather <clear|rain|thunder> [duration in seconds]")),
+        }
+    }
+}
+
+#[cfg(test)]
+fn find(world: &World, name: &str) -> String {
+    world.entities().iter().find(|e| e.name() == name).unwrap().uuid.clone()
+}
+
+#[test]
+fn test_selectors() {
+    let mut world = World::new();
+    world.run_console("scoreboard objectives add t dummy");
+    world.run_console("scoreboard teams add bits");
+    for i in 0..3 {
+        world.run_console(&format!("summon ArmorStand {} 56 0 {{CustomName: \"bit_{}\"}}", i, i)[..]);
+        world.run_console(&format!("scoreboard players set @e[name=bit_{}] t {}", i, i + 1)[..]);
+    }
+    world.run_console("scoreboard teams join bits @e[name=bit_0] @e[name=bit_2]");
+    world.run_console("scoreboard players set total t 0");
+
+    // Runs once per team member, and c=1 picks the member itself.
+    assert_eq!(2, world.run_console(
+        "execute @e[team=bits] ~ ~ ~ scoreboard players operation total t += @e[c=1,team=bits] t"));
+    assert_eq!(Some(1 + 3), world.score("total", "t"));
+
+    assert_eq!(1, world.run_console("scoreboard players add @e[score_t_min=2,score_t=2] t 10"));
+    assert_eq!(Some(12), world.score(&find(&world, "bit_1")[..], "t"));
+    assert_eq!(0, world.run_console("scoreboard players add @e[score_missing_min=0] t 1"));
+
+    let bit_0 = find(&world, "bit_0");
+    world.take_log();
+    world.run_console("scoreboard players add @e[name=bit_0] t 0");
+    assert_eq!(vec!(format!("Set score of t for player {} to 1", bit_0)), world.take_log());
+}
+
+#[test]
+fn test_command_blocks() {
+    let mut world = World::new();
+    world.run_console("gamerule logAdminCommands false");
+    world.run_console("scoreboard objectives add r0 dummy");
+    world.run_console("scoreboard players set x r0 1");
+    // Two blocks around a power position, then a third powered by the first.
+    world.run_console("setblock 0 57 0 minecraft:command_block 0 replace \
+                       {Command: \"scoreboard players operation x r0 *= x r0\"}");
+    world.run_console("setblock 2 57 0 minecraft:command_block 0 replace \
+                       {Command: \"scoreboard players add x r0 2\", \
+                        CommandStats: {SuccessCountName: \"y\", SuccessCountObjective: \"r0\"}}");
+    world.run_console("setblock 1 56 0 minecraft:command_block 0 replace \
+                       {Command: \"fill 1 57 1 1 57 1 minecraft:redstone_block\"}");
+    world.run_console("setblock 1 57 2 minecraft:command_block 0 replace {Command: \"say done\"}");
+    world.take_log();
+
+    world.run_console("setblock 1 57 0 minecraft:redstone_block");
+    assert_eq!(vec!("Block placed".to_string()), world.take_log());
+    assert!(!world.is_idle());
+    world.tick();
+    // West runs before east, then down.
+    assert_eq!(Some(3), world.score("x", "r0"));
+    assert_eq!(Some(1), world.score("y", "r0"));
+    assert!(world.take_log().is_empty());
+    world.tick();
+    assert_eq!(vec!("[@] done".to_string()), world.take_log());
+    assert!(world.is_idle());
+
+    // Blocks only run again once they have lost power.
+    world.run_console("setblock 1 57 0 minecraft:redstone_block");
+    world.tick();
+    assert_eq!(Some(3), world.score("x", "r0"));
+    world.run_console("setblock 1 57 0 minecraft:air");
+    world.run_console("setblock 1 57 0 minecraft:redstone_block");
+    world.tick();
+    assert_eq!(Some(11), world.score("x", "r0"));
+}
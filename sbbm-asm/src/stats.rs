@@ -0,0 +1,231 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use assembler::{self, AssembledItem, Usage};
+use assembler::AssembledItem::*;
+use types::Extent;
+
+use rustc_serialize::json;
+use std::cmp;
+use std::io::{self, Write};
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct LabelStats {
+    pub label: String,
+    // Labels the assembler generated to carry on with this label's code on a
+    // later tick.
+    pub continuations: Vec<String>,
+    pub blocks: u32,
+    pub pending_refs: u32,
+    pub uses_memory: bool,
+    pub uses_bitwise: bool,
+    // The total volume of the power extents of the label and its
+    // continuations.  Zero until the program has been laid out.
+    pub volume: u64,
+    // The most command blocks this label runs in a single tick.
+    pub longest_chain: u32,
+    // How many ticks it takes to run the label's own code once, one for each
+    // line the code spans.  The code it branches or falls through to isn't
+    // counted, so this is not how long it takes to get from the label to a
+    // halt.
+    pub own_ticks: u32,
+}
+
+impl LabelStats {
+    fn new(label: String) -> LabelStats {
+        LabelStats {
+            label: label,
+            continuations: vec!(),
+            blocks: 0,
+            pending_refs: 0,
+            uses_memory: false,
+            uses_bitwise: false,
+            volume: 0,
+            longest_chain: 0,
+            own_ticks: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct ProgramStats {
+    pub labels: Vec<LabelStats>,
+    pub blocks: u32,
+    pub pending_refs: u32,
+    pub uses_memory: bool,
+    pub uses_bitwise: bool,
+    // The volume of every placed block.  Zero until the program has been laid
+    // out.
+    pub volume: u64,
+}
+
+impl ProgramStats {
+    pub fn new(items: &[AssembledItem], usage: &Usage) -> ProgramStats {
+        let mut stats = ProgramStats {
+            labels: vec!(),
+            blocks: 0,
+            pending_refs: 0,
+            uses_memory: usage.memory,
+            uses_bitwise: usage.bitwise,
+            volume: 0,
+        };
+
+        // Labels that have been seen on the current line, along with how long
+        // the line was when each of them was seen.  Code falls through from
+        // one label into the next, so a label's chain runs to the end of the
+        // line.
+        let mut open: Vec<(usize, u32)> = vec!();
+        let mut chain = 0;
+
+        for item in items.iter() {
+            match *item {
+                Label(ref label) => {
+                    let owner = stats.labels.len();
+                    if assembler::is_continuation_label(&label[..]) && owner > 0 {
+                        let cur = &mut stats.labels[owner - 1];
+                        cur.continuations.push(label.clone());
+                        cur.own_ticks += 1;
+                        open.push((owner - 1, chain));
+                    } else {
+                        let mut cur = LabelStats::new(label.clone());
+                        cur.uses_memory = usage.memory_labels.contains(label);
+                        cur.uses_bitwise = usage.bitwise_labels.contains(label);
+                        stats.labels.push(cur);
+                        open.push((owner, chain));
+                    }
+                }
+                Complete(_) | Pending(_, _) => {
+                    let is_pending = if let Pending(_, _) = *item { 1 } else { 0 };
+                    stats.blocks += 1;
+                    stats.pending_refs += is_pending;
+                    if let Some(cur) = stats.labels.last_mut() {
+                        cur.blocks += 1;
+                        cur.pending_refs += is_pending;
+                    }
+                    chain += 1;
+                }
+                Terminal => {
+                    stats.close_line(&mut open, chain);
+                    chain = 0;
+                }
+            }
+        }
+        stats.close_line(&mut open, chain);
+        stats
+    }
+
+    fn close_line(&mut self, open: &mut Vec<(usize, u32)>, chain: u32) {
+        for &(index, start) in open.iter() {
+            let cur = &mut self.labels[index];
+            cur.longest_chain = cmp::max(cur.longest_chain, chain - start);
+        }
+        open.clear();
+    }
+
+    // Fills in the volumes, once the program has been laid out.  extent is the
+    // extent of every placed block, and extent_of gives the power extent of a
+    // label.
+    pub fn measure<F>(&mut self, extent: Extent, extent_of: F)
+        where F : Fn(&str) -> Option<Extent>
+    {
+        for cur in self.labels.iter_mut() {
+            let mut volume = 0;
+            for label in Some(&cur.label).into_iter().chain(cur.continuations.iter()) {
+                if let Some(power_extent) = extent_of(&label[..]) {
+                    volume += power_extent.volume();
+                }
+            }
+            cur.volume = volume;
+        }
+        self.volume = extent.volume();
+    }
+
+    pub fn write_report(&self, w: &mut Write) -> io::Result<()> {
+        fn flag(value: bool) -> &'static str {
+            if value { "yes" } else { "-" }
+        }
+
+        try!(write!(w, "{:<24} {:>7} {:>7} {:>6} {:>9} {:>7} {:>4} {:>4}\n",
+                    "label", "blocks", "pending", "chain", "own ticks", "volume",
+                    "mem", "bit"));
+        for cur in self.labels.iter() {
+            try!(write!(w, "{:<24} {:>7} {:>7} {:>6} {:>9} {:>7} {:>4} {:>4}\n",
+                        cur.label, cur.blocks, cur.pending_refs,
+                        cur.longest_chain, cur.own_ticks, cur.volume,
+                        flag(cur.uses_memory), flag(cur.uses_bitwise)));
+        }
+        write!(w, "{:<24} {:>7} {:>7} {:>6} {:>9} {:>7} {:>4} {:>4}\n",
+               "total", self.blocks, self.pending_refs, "", "", self.volume,
+               flag(self.uses_memory), flag(self.uses_bitwise))
+    }
+
+    pub fn to_json(&self) -> String {
+        json::as_pretty_json(self).to_string()
+    }
+}
+
+#[cfg(test)]
+fn collect(input: &str) -> ProgramStats {
+    use assembler::Assembler;
+    use hw::{Computer, REGISTER_FILE};
+    use lexer::{Arena, Lexer};
+    use parser::Parser;
+    use types::Vec3;
+
+    let computer = Computer {
+        name: "computer".to_string(),
+        origin: Vec3::new(0, 0, 0),
+        memory: vec!(),
+        temps: REGISTER_FILE.temps,
+    };
+    let arena = Arena::new();
+    let parser = Parser::new(Lexer::mem(input), &arena);
+    let mut assembler = Assembler::new(&computer, parser.map(|s| s.item));
+    let items: Vec<AssembledItem> = assembler.by_ref().collect();
+    ProgramStats::new(&items[..], assembler.usage())
+}
+
+#[test]
+fn test_label_stats() {
+    let stats = collect("
+_start:
+mov r0, #1
+bl =foo
+halt
+foo:
+and r0, r1
+b lr");
+    // The indirect jump table gets an entry of its own.
+    assert_eq!(3, stats.labels.len());
+    assert_eq!("@jump_indirect", &stats.labels[2].label[..]);
+
+    let start = &stats.labels[0];
+    assert_eq!("_start", &start.label[..]);
+    assert_eq!(1, start.continuations.len());
+    assert_eq!(2, start.own_ticks);
+    assert!(!start.uses_bitwise);
+
+    let foo = &stats.labels[1];
+    assert_eq!("foo", &foo.label[..]);
+    // Branches continue on the next tick when they are not taken.
+    assert_eq!(2, foo.own_ticks);
+    assert!(foo.uses_bitwise);
+    assert!(stats.uses_bitwise);
+    assert!(!stats.uses_memory);
+
+    let blocks = stats.labels.iter().fold(0, |n, l| n + l.blocks);
+    assert_eq!(stats.blocks, blocks);
+}
+
+#[test]
+fn test_fallthrough_chain() {
+    let stats = collect("
+foo:
+mov r0, #1
+bar:
+mov r0, #2
+halt");
+    assert!(stats.labels[0].longest_chain > stats.labels[1].longest_chain);
+    // The ticks bar's code takes aren't foo's, even though foo runs it.
+    assert_eq!(1, stats.labels[0].own_ticks);
+}
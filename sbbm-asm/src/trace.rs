@@ -0,0 +1,222 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use ast::Statement::*;
+use lexer::Location;
+use parser::SpannedStatement;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+// Every traced instruction's first command block is named this, followed by
+// its trace id and name, so that trace lines can be picked out of the server
+// log.
+static TRACE_MARKER: &'static str = "sbbm-trace";
+
+// The game rule that switches tracing on and off at runtime.  Command blocks'
+// output only reaches the server log while it is true.
+static TRACE_RULE: &'static str = "logAdminCommands";
+
+pub struct TracePoint {
+    // The nearest label before the instruction, and the instruction's index
+    // after it, e.g. "loop+2".
+    pub name: String,
+    pub loc: Location,
+    pub source: String,
+}
+
+pub struct Trace {
+    pub points: Vec<TracePoint>,
+}
+
+impl Trace {
+    // Gives every instruction an announcement, for Assembler::set_announcements
+    // to name its first command block with.  The block's output is logged
+    // under that name whenever it runs, so no blocks are added.  Predicated
+    // instructions run their commands as the computer, so their output is
+    // logged under its name instead, and they go unannounced.
+    pub fn instrument(stmts: &[SpannedStatement], input: &str)
+        -> (Trace, Vec<Option<String>>)
+    {
+        let lines: Vec<&str> = input.lines().collect();
+        let mut trace = Trace { points: vec!() };
+        let mut announcements = vec!();
+        let mut label = String::new();
+        let mut index = 0;
+
+        for stmt in stmts.iter() {
+            let announcement = match stmt.item {
+                LabelStmt(ref l) => {
+                    label = l.clone();
+                    index = 0;
+                    None
+                }
+                Instr(..) => {
+                    let id = trace.points.len();
+                    let name = format!("{}+{}", label, index);
+                    let announcement = format!("{} {} {}", TRACE_MARKER, id, name);
+                    index += 1;
+
+                    trace.points.push(TracePoint {
+                        name: name,
+                        loc: stmt.start,
+                        source: lines.get(stmt.start.line)
+                            .map(|l| l.trim().to_string())
+                            .unwrap_or(String::new()),
+                    });
+                    Some(announcement)
+                }
+            };
+            announcements.push(announcement);
+        }
+
+        (trace, announcements)
+    }
+
+    // Writes one tab-separated line per trace point: the id, the name, the
+    // source location and the source text.
+    pub fn write_map(&self, w: &mut Write, filename: &str) -> io::Result<()> {
+        for (id, point) in self.points.iter().enumerate() {
+            try!(write!(w, "{}\t{}\t{}:{}\t{}\n",
+                        id, point.name, filename, point.loc, point.source));
+        }
+        Ok(())
+    }
+}
+
+// Tracing starts off, as every instruction would otherwise fill the server
+// log.  "gamerule logAdminCommands true" turns it on.
+pub fn write_init_script(w: &mut Write) -> io::Result<()> {
+    write!(w, "gamerule {} false\n", TRACE_RULE)
+}
+
+// Puts the game rule back to its default.
+pub fn write_destroy_script(w: &mut Write) -> io::Result<()> {
+    write!(w, "gamerule {} true\n", TRACE_RULE)
+}
+
+// Picks the trace id and name out of a line of server output.
+pub fn parse_log_line(line: &str) -> Option<(usize, String)> {
+    let start = match line.find(TRACE_MARKER) {
+        Some(start) => start + TRACE_MARKER.len(),
+        None => return None,
+    };
+    // The block's name is followed by a colon in command output.
+    let mut words = line[start..].split(|c| c == ' ' || c == ':').filter(|w| !w.is_empty());
+    match (words.next().and_then(|id| id.parse().ok()), words.next()) {
+        (Some(id), Some(name)) => Some((id, name.to_string())),
+        _ => None,
+    }
+}
+
+// Turns captured server output back into a readable trace, using a map
+// written by Trace::write_map.  Lines that are not part of the trace are
+// dropped.
+pub fn decode(map: &mut BufRead, log: &mut BufRead, w: &mut Write) -> io::Result<()> {
+    let mut points = HashMap::new();
+    for line in map.lines() {
+        let line = try!(line);
+        let mut fields = line.splitn(4, '\t');
+        if let Some(Ok(id)) = fields.next().map(|id| id.parse::<usize>()) {
+            let fields: Vec<&str> = fields.collect();
+            points.insert(id, fields.connect("  "));
+        }
+    }
+
+    for line in log.lines() {
+        let line = try!(line);
+        if let Some((id, name)) = parse_log_line(&line[..]) {
+            match points.get(&id) {
+                Some(point) => try!(write!(w, "{}\n", point)),
+                None => try!(write!(w, "{}  (unknown trace point {})\n", name, id)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn instrument(input: &str) -> (Trace, Vec<SpannedStatement>, Vec<Option<String>>) {
+    use lexer::{Arena, Lexer};
+    use parser::Parser;
+    let stmts = Parser::new(Lexer::mem(input), &Arena::new()).parse_program_spanned();
+    let (trace, announcements) = Trace::instrument(&stmts[..], input);
+    (trace, stmts, announcements)
+}
+
+#[test]
+fn test_instrument() {
+    let (trace, _, announcements) = instrument("
+main:
+mov r0, #1
+{p0, #1, #1} halt");
+    assert_eq!(2, trace.points.len());
+    assert_eq!("main+1", &trace.points[1].name[..]);
+    assert_eq!(3, trace.points[1].loc.line);
+    assert_eq!("{p0, #1, #1} halt", &trace.points[1].source[..]);
+    assert_eq!(
+        vec!(None,
+             Some("sbbm-trace 0 main+0".to_string()),
+             Some("sbbm-trace 1 main+1".to_string())),
+        announcements);
+}
+
+#[test]
+fn test_announce() {
+    use assembler::{Assembler, AssembledItem};
+    use hw::Computer;
+    use nbt::Nbt;
+    use types::Vec3;
+
+    let (_, stmts, announcements) = instrument("
+_start:
+mov r0, #1
+b =_start");
+    let computer = Computer::new(Vec3::new(0, 0, 0));
+    let stmts: Vec<_> = stmts.into_iter().map(|s| s.item).collect();
+    let plain: Vec<_> = Assembler::new(&computer, stmts.clone().into_iter()).collect();
+    let mut assembler = Assembler::new(&computer, stmts.into_iter());
+    assembler.set_announcements(announcements);
+    let traced: Vec<_> = assembler.collect();
+
+    // The announcements name blocks that are there anyway.
+    assert_eq!(plain.len(), traced.len());
+    let names: Vec<Option<String>> = traced.into_iter().filter_map(|item| {
+        let block = match item {
+            AssembledItem::Complete(block) => block,
+            AssembledItem::Pending(_, pending) =>
+                return Some(pending.name),
+            _ => return None,
+        };
+        match block.nbt.get("CustomName") {
+            Some(&Nbt::String(ref name)) => Some(Some(name.clone())),
+            _ => Some(None),
+        }
+    }).collect();
+    // The label's power-off block comes before the first instruction's.
+    assert_eq!(None, names[0]);
+    assert_eq!(Some("sbbm-trace 0 _start+0".to_string()), names[1]);
+    assert_eq!(Some("sbbm-trace 1 _start+1".to_string()), names[2]);
+    assert_eq!(2, names.iter().filter(|n| n.is_some()).count());
+}
+
+#[test]
+fn test_decode() {
+    let (trace, _, _) = instrument("
+main:
+mov r0, #1
+b =main");
+    let mut map = vec!();
+    trace.write_map(&mut map, "foo.s").unwrap();
+
+    let log = "\
+[12:00:00] [Server thread/INFO]: [sbbm-trace 1 main+1: 1 blocks filled]
+[12:00:00] [Server thread/INFO]: [computer: Set score of r0 for player computer to 1]
+[12:00:00] [Server thread/INFO]: [sbbm-trace 0 main+0: Set score of r0 for player computer to 1]
+";
+    let mut out = vec!();
+    decode(&mut &map[..], &mut log.as_bytes(), &mut out).unwrap();
+    assert_eq!(
+        "main+1  foo.s:4:1  b =main\nmain+0  foo.s:3:1  mov r0, #1\n",
+        &String::from_utf8(out).unwrap()[..]);
+}
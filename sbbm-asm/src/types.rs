@@ -1,16 +1,19 @@
 // Copyright 2015, Christopher Chambers
 // Distributed under the GNU GPL v3. See COPYING for details.
 
+use lexer::Location;
 use nbt::NbtCompound;
 use std::cmp::{min, max};
 use std::fmt;
 use self::AbsRel::*;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct Block {
     pub id: String,
     pub data: u8,
     pub nbt: NbtCompound,
+    // Where in the source the block came from, if anywhere.
+    pub loc: Option<Location>,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -106,7 +109,7 @@ impl fmt::Display for Pos3 {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
 pub struct Vec3 {
     pub x: i32,
     pub y: i32,
@@ -179,4 +182,48 @@ impl Extent {
             _ => { }
         }
     }
+
+    pub fn intersects(&self, extent: &Extent) -> bool {
+        use self::Extent::*;
+
+        match (*self, *extent) {
+            (MinMax(min_a, max_a), MinMax(min_b, max_b)) => {
+                min_a.x <= max_b.x && min_b.x <= max_a.x &&
+                    min_a.y <= max_b.y && min_b.y <= max_a.y &&
+                    min_a.z <= max_b.z && min_b.z <= max_a.z
+            }
+            _ => false,
+        }
+    }
+
+    pub fn translate(&self, delta: Vec3) -> Extent {
+        match *self {
+            Extent::Empty => Extent::Empty,
+            Extent::MinMax(min, max) => Extent::MinMax(
+                Vec3::new(min.x + delta.x, min.y + delta.y, min.z + delta.z),
+                Vec3::new(max.x + delta.x, max.y + delta.y, max.z + delta.z)),
+        }
+    }
+
+    pub fn volume(&self) -> u64 {
+        match *self {
+            Extent::Empty => 0,
+            Extent::MinMax(min, max) => {
+                let dx = (max.x - min.x).abs() as u64 + 1;
+                let dy = (max.y - min.y).abs() as u64 + 1;
+                let dz = (max.z - min.z).abs() as u64 + 1;
+                dx * dy * dz
+            }
+        }
+    }
+}
+
+impl fmt::Display for Extent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Extent::Empty => write!(f, "nothing"),
+            Extent::MinMax(min, max) => write!(
+                f, "{} {} {} to {} {} {}", min.x, min.y, min.z, max.x, max.y, max.z),
+        }
+    }
 }
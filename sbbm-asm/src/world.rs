@@ -0,0 +1,286 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+use nbt::{self, Nbt, NbtCompound};
+use types::{Extent, Vec3};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::io::Read;
+
+// Things already in the world that the layout has to build around.  Boxes
+// are kept as they are given.  Single blocks, such as the terrain read from a
+// schematic or a region file, are kept in a bitmap per 16x16x16 section, so
+// that a whole region's worth of terrain stays small.
+#[derive(Clone)]
+pub struct Obstacles {
+    boxes: Vec<Extent>,
+    sections: HashMap<Vec3, Vec<u64>>,
+}
+
+#[derive(RustcDecodable)]
+struct ObstacleBox {
+    min: Vec3,
+    max: Vec3,
+}
+
+// The section a position is in, and the position's index in the section.
+// The index is ordered y, z, x, like a chunk section's Blocks.
+fn section_of(pos: Vec3) -> (Vec3, usize) {
+    let section = Vec3::new(pos.x >> 4, pos.y >> 4, pos.z >> 4);
+    let index = ((pos.y & 15) << 8 | (pos.z & 15) << 4 | (pos.x & 15)) as usize;
+    (section, index)
+}
+
+fn get<'a>(compound: &'a NbtCompound, key: &str) -> Result<&'a Nbt, String> {
+    compound.get(key).ok_or(format!("{} is missing", key))
+}
+
+fn get_int(compound: &NbtCompound, key: &str) -> Result<i32, String> {
+    match *try!(get(compound, key)) {
+        Nbt::Byte(n) => Ok(n as i32),
+        Nbt::Short(n) => Ok(n as i32),
+        Nbt::Int(n) => Ok(n),
+        _ => Err(format!("{} is not an integer", key)),
+    }
+}
+
+fn get_bytes<'a>(compound: &'a NbtCompound, key: &str) -> Result<&'a [u8], String> {
+    match *try!(get(compound, key)) {
+        Nbt::ByteArray(ref bytes) => Ok(&bytes[..]),
+        _ => Err(format!("{} is not a byte array", key)),
+    }
+}
+
+fn as_compound<'a>(nbt: &'a Nbt, what: &str) -> Result<&'a NbtCompound, String> {
+    match *nbt {
+        Nbt::Compound(ref compound) => Ok(compound),
+        _ => Err(format!("{} is not a compound", what)),
+    }
+}
+
+// Blocks with more than 8 bits of id keep the rest in a nibble array.
+// Schematics put the even entries in the high nibble, and chunk sections put
+// them in the low nibble.
+fn nibble(nibbles: Option<&[u8]>, index: usize, even_high: bool) -> u16 {
+    match nibbles.and_then(|n| n.get(index / 2)) {
+        Some(&byte) => {
+            let high = (index % 2 == 0) == even_high;
+            (if high { byte >> 4 } else { byte & 15 }) as u16
+        }
+        None => 0,
+    }
+}
+
+impl Obstacles {
+    pub fn new() -> Obstacles {
+        Obstacles {
+            boxes: vec!(),
+            sections: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boxes.is_empty() && self.sections.is_empty()
+    }
+
+    pub fn add_box(&mut self, mut extent: Extent) {
+        if let Extent::MinMax(_, _) = extent {
+            extent.normalize();
+            self.boxes.push(extent);
+        }
+    }
+
+    pub fn add_block(&mut self, pos: Vec3) {
+        let (section, index) = section_of(pos);
+        let bits = self.sections.entry(section).or_insert_with(|| vec![0; 64]);
+        bits[index / 64] |= 1u64 << (index % 64);
+    }
+
+    pub fn contains(&self, pos: Vec3) -> bool {
+        let point = Extent::MinMax(pos, pos);
+        if self.boxes.iter().any(|b| b.intersects(&point)) {
+            return true;
+        }
+        let (section, index) = section_of(pos);
+        match self.sections.get(&section) {
+            Some(bits) => bits[index / 64] & (1u64 << (index % 64)) != 0,
+            None => false,
+        }
+    }
+
+    // Whether anything at all lies within extent.
+    pub fn intersects(&self, extent: &Extent) -> bool {
+        if self.boxes.iter().any(|b| b.intersects(extent)) {
+            return true;
+        }
+        for (section, bits) in self.sections.iter() {
+            let min = Vec3::new(section.x * 16, section.y * 16, section.z * 16);
+            let max = Vec3::new(min.x + 15, min.y + 15, min.z + 15);
+            if !Extent::MinMax(min, max).intersects(extent) {
+                continue;
+            }
+            for index in 0..4096 {
+                if bits[index / 64] & (1u64 << (index % 64)) == 0 {
+                    continue;
+                }
+                let index = index as i32;
+                let pos = Vec3::new(
+                    min.x + (index & 15), min.y + (index >> 8), min.z + (index >> 4 & 15));
+                if Extent::MinMax(pos, pos).intersects(extent) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Reads a JSON array of boxes, each an object with min and max corners
+    // (objects with x, y and z).
+    pub fn read_json(&mut self, r: &mut Read) -> Result<(), String> {
+        let mut text = String::new();
+        try!(r.read_to_string(&mut text).map_err(|e| e.to_string()));
+        let boxes: Vec<ObstacleBox> =
+            try!(json::decode(&text[..]).map_err(|e| e.to_string()));
+        for b in boxes.into_iter() {
+            self.add_box(Extent::MinMax(b.min, b.max));
+        }
+        Ok(())
+    }
+
+    // Reads the non-air blocks of an MCEdit or WorldEdit schematic.  The
+    // schematic's minimum corner is placed at origin, or where WorldEdit
+    // copied it from when origin is None.
+    pub fn read_schematic(&mut self, r: &mut Read, origin: Option<Vec3>) -> Result<(), String> {
+        let mut r = try!(GzDecoder::new(r).map_err(|e| e.to_string()));
+        let (_, root) = try!(nbt::read(&mut r).map_err(|e| e.to_string()));
+        let schematic = try!(as_compound(&root, "the schematic"));
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => Vec3::new(
+                try!(get_int(schematic, "WEOriginX").map_err(|_| no_origin())),
+                try!(get_int(schematic, "WEOriginY").map_err(|_| no_origin())),
+                try!(get_int(schematic, "WEOriginZ").map_err(|_| no_origin()))),
+        };
+        let width = try!(get_int(schematic, "Width")) as usize;
+        let height = try!(get_int(schematic, "Height")) as usize;
+        let length = try!(get_int(schematic, "Length")) as usize;
+        let blocks = try!(get_bytes(schematic, "Blocks"));
+        let add = get_bytes(schematic, "AddBlocks").ok();
+        if blocks.len() < width * height * length {
+            return Err(format!(
+                "the schematic is {}x{}x{}, but only has {} blocks",
+                width, height, length, blocks.len()));
+        }
+
+        for index in 0..width * height * length {
+            let id = blocks[index] as u16 | nibble(add, index, true) << 8;
+            if id != 0 {
+                let x = (index % width) as i32;
+                let z = (index / width % length) as i32;
+                let y = (index / (width * length)) as i32;
+                self.add_block(Vec3::new(origin.x + x, origin.y + y, origin.z + z));
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the non-air blocks of an Anvil region (.mca) file.  Chunks record
+    // their own positions, so the blocks land where they are in the world.
+    pub fn read_region(&mut self, r: &mut Read) -> Result<(), String> {
+        let mut data = vec!();
+        try!(r.read_to_end(&mut data).map_err(|e| e.to_string()));
+        if data.len() < 4096 {
+            return Err("the region file has no header".to_string());
+        }
+
+        // The header holds the offset and size of each chunk, in 4KiB
+        // sectors.  Each chunk starts with its length and compression.
+        for i in 0..1024 {
+            let entry = &data[i * 4..i * 4 + 4];
+            let offset = ((entry[0] as usize) << 16 | (entry[1] as usize) << 8 |
+                          entry[2] as usize) * 4096;
+            if offset == 0 {
+                continue;
+            }
+            if offset + 5 > data.len() {
+                return Err(format!("chunk {} is past the end of the region file", i));
+            }
+            let len = data[offset..offset + 4].iter()
+                .fold(0, |n, &b| (n << 8) | b as usize);
+            if len < 1 || offset + 4 + len > data.len() {
+                return Err(format!("chunk {} is truncated", i));
+            }
+
+            let chunk = &data[offset + 5..offset + 4 + len];
+            let root = match data[offset + 4] {
+                1 => {
+                    let mut r = try!(GzDecoder::new(chunk).map_err(|e| e.to_string()));
+                    nbt::read(&mut r)
+                }
+                2 => nbt::read(&mut ZlibDecoder::new(chunk)),
+                compression => {
+                    return Err(format!(
+                        "chunk {} has unknown compression {}", i, compression));
+                }
+            };
+            let (_, root) = try!(root.map_err(|e| format!("chunk {}: {}", i, e)));
+            try!(self.add_chunk(&root).map_err(|e| format!("chunk {}: {}", i, e)));
+        }
+        Ok(())
+    }
+
+    fn add_chunk(&mut self, root: &Nbt) -> Result<(), String> {
+        let root = try!(as_compound(root, "the chunk"));
+        let level = try!(as_compound(try!(get(root, "Level")), "Level"));
+        let chunk_x = try!(get_int(level, "xPos"));
+        let chunk_z = try!(get_int(level, "zPos"));
+        let sections = match level.get("Sections") {
+            Some(&Nbt::List(ref sections)) => sections,
+            _ => return Ok(()),
+        };
+
+        for section in sections.iter() {
+            let section = try!(as_compound(section, "a section"));
+            let section_y = try!(get_int(section, "Y"));
+            let blocks = try!(get_bytes(section, "Blocks"));
+            let add = get_bytes(section, "Add").ok();
+            for index in 0..blocks.len() {
+                let id = blocks[index] as u16 | nibble(add, index, false) << 8;
+                if id != 0 {
+                    let index = index as i32;
+                    self.add_block(Vec3::new(
+                        chunk_x * 16 + (index & 15),
+                        section_y * 16 + (index >> 8),
+                        chunk_z * 16 + (index >> 4 & 15)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn no_origin() -> String {
+    "the schematic does not say where it was copied from, so its origin must \
+     be given".to_string()
+}
+
+#[test]
+fn test_obstacles() {
+    let mut obstacles = Obstacles::new();
+    assert!(obstacles.is_empty());
+
+    obstacles.add_box(Extent::MinMax(Vec3::new(3, 0, 3), Vec3::new(0, 2, 0)));
+    obstacles.add_block(Vec3::new(-1, 70, -20));
+    assert!(obstacles.contains(Vec3::new(1, 1, 1)));
+    assert!(obstacles.contains(Vec3::new(-1, 70, -20)));
+    assert!(!obstacles.contains(Vec3::new(-1, 70, -19)));
+    assert!(!obstacles.contains(Vec3::new(15, 70, -20)));
+
+    let near = Extent::MinMax(Vec3::new(-4, 60, -30), Vec3::new(-2, 80, 0));
+    assert!(!obstacles.intersects(&near));
+    let touching = Extent::MinMax(Vec3::new(-4, 60, -30), Vec3::new(-1, 70, -20));
+    assert!(obstacles.intersects(&touching));
+}
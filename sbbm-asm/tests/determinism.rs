@@ -0,0 +1,21 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+extern crate sbbm_asm;
+
+mod programs;
+
+use sbbm_asm::layout::{LayoutMotion, LinearMotion};
+
+#[test]
+fn test_assembly_is_deterministic() {
+    for &(ref path, ref src) in programs::programs().iter() {
+        let linear = || Box::new(LinearMotion::new(programs::ORIGIN)) as Box<LayoutMotion>;
+        for motion in [&linear as &Fn() -> Box<LayoutMotion>, &programs::packed].iter() {
+            let first = programs::assemble(&src[..], motion()).to_snapshot();
+            let second = programs::assemble(&src[..], motion()).to_snapshot();
+            assert!(first.contains("setblock"), "{} assembled to nothing", path.display());
+            assert!(first == second, "{} assembled differently twice", path.display());
+        }
+    }
+}
@@ -346,3 +346,20 @@ mov r1, #5678");
     assert_eq!(1234, server.get_computer("r0").unwrap());
     assert_eq!(5678, server.get_computer("r1").unwrap());
 }
+
+#[test]
+fn test_trace() {
+    let server = Server::new();
+    let path = server.run_asm_traced("
+main:
+mov r0, #1
+b =skip
+mov r0, #2
+skip:
+add r0, #1");
+
+    let expected: Vec<String> = vec!("main+0", "main+1", "skip+0")
+        .into_iter().map(|s| s.to_string()).collect();
+    assert_eq!(expected, path[..3].to_vec());
+    assert_eq!(2, server.get_computer("r0").unwrap());
+}
@@ -0,0 +1,16 @@
+; Arithmetic on registers and on scoreboard objectives.
+_start:
+mov r0, #100
+mov r1, #37
+add r0, r1
+sub r0, #5
+mul r0, r1
+sdiv r0, r1
+urem r0, r1
+and r0, r1
+orr r0, r1
+eor r0, r1
+lsl r0, r1
+add @a, score, r0, r1
+mov @r, score, r0, r1
+halt
@@ -0,0 +1,17 @@
+; Calls, returns and conditional execution.
+_start:
+mov r0, #1
+srng p0, r0, #0, #10
+urng p1, r0, #5, *
+{p0, #1, #1} {p1, #1, #1} mov r1, #2
+{p0, #0, #0} b =skip
+bl =double
+halt
+
+double:
+add r0, r0
+b lr
+
+skip:
+raw~siq r0, r1, r2, say skipped
+b =double
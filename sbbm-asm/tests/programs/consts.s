@@ -0,0 +1,12 @@
+; Constants, expressions, and a branch through a label's address.
+.equ BASE, 0x10
+.equ COUNT, 1 << 3
+
+_start:
+mov r0, #BASE + COUNT * 2
+mov r1, #'A' - 'a' + (0b11 | 4)
+mov r2, #=done
+b r2
+mov r0, #0
+done:
+halt
@@ -0,0 +1,19 @@
+; Macros with parameters, default values and local labels.
+.macro inc reg, n=#1
+add $reg, $n
+.endm
+
+.macro countdown reg, from
+mov $reg, $from
+loop:
+sub $reg, #1
+urng p0, $reg, #1, *
+{p0, #1, #1} b =loop
+.endm
+
+_start:
+inc r0
+inc r0, #10
+countdown r1, #3
+countdown r2, #2
+halt
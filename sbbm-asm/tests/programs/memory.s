@@ -0,0 +1,9 @@
+; Loads and stores through the memory controllers.
+_start:
+mov r0, #1234
+mov r1, #16
+str r0, [r1]
+add r1, #4
+str r0, [r1]
+ldr r2, [r1]
+halt
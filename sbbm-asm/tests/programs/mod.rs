@@ -0,0 +1,121 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// The example programs in this directory, and a fixed configuration to
+// assemble them with, for the tests that don't need a server.
+
+use sbbm_asm::assembler::Assembler;
+use sbbm_asm::commands::Command;
+use sbbm_asm::fab;
+use sbbm_asm::hw::{Computer, MemoryRegion, MemoryStride, REGISTER_FILE};
+use sbbm_asm::layout::{Layout, LayoutMotion, PackedMotion};
+use sbbm_asm::lexer::{Arena, Lexer};
+use sbbm_asm::nbt::Nbt;
+use sbbm_asm::parser::Parser;
+use sbbm_asm::types::Vec3;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+
+pub const ORIGIN: Vec3 = Vec3 { x: 0, y: 56, z: 0 };
+pub const ENTRY_LABEL: &'static str = "_start";
+
+pub fn dir() -> PathBuf {
+    let mut dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    dir.push("tests");
+    dir.push("programs");
+    dir
+}
+
+// The path and source of each program, by name.
+pub fn programs() -> Vec<(PathBuf, String)> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir()).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().map_or(false, |e| e == "s"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no programs in {}", dir().display());
+
+    paths.into_iter().map(|path| {
+        let mut src = String::new();
+        File::open(&path).unwrap().read_to_string(&mut src).unwrap();
+        (path, src)
+    }).collect()
+}
+
+pub fn computer() -> Computer {
+    Computer {
+        name: "computer".to_string(),
+        origin: ORIGIN,
+        memory: vec![
+            MemoryRegion {
+                start: 0x10,
+                size: 0x100,
+                origin: Vec3::new(ORIGIN.x - 1, ORIGIN.y, ORIGIN.z),
+                growth: Vec3::new(-1, 1, 1),
+                stride: MemoryStride::XY(8, 8),
+            }],
+        temps: REGISTER_FILE.temps,
+    }
+}
+
+pub fn packed() -> Box<LayoutMotion> {
+    Box::new(PackedMotion::new(ORIGIN))
+}
+
+// Everything sbbm-asm writes for a program: the init and destroy scripts,
+// a setblock for each block, and the boot script.
+pub struct Output {
+    pub init: String,
+    pub destroy: String,
+    pub blocks: String,
+    pub boot: String,
+}
+
+impl Output {
+    pub fn to_snapshot(&self) -> String {
+        format!("# init\n{}# destroy\n{}# blocks\n{}# boot\n{}",
+                self.init, self.destroy, self.blocks, self.boot)
+    }
+}
+
+fn utf8(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap()
+}
+
+pub fn assemble(src: &str, motion: Box<LayoutMotion>) -> Output {
+    let computer = computer();
+    let mut init = vec!();
+    computer.write_init_script(&mut init).unwrap();
+    let mut destroy = vec!();
+    computer.write_destroy_script(&mut destroy).unwrap();
+
+    let arena = Arena::new();
+    let mut parser = Parser::new(Lexer::mem(src), &arena);
+    let stmts = parser.parse_program();
+    let assembler = Assembler::new(&computer, stmts.into_iter());
+    let mut mem_controllers = vec!();
+    for region in computer.memory.iter() {
+        mem_controllers.extend(fab::make_mem_ctrl(region));
+    }
+
+    let mut layout = Layout::new(motion, assembler.chain(mem_controllers));
+    let mut blocks = String::new();
+    for (pos, block) in &mut layout {
+        let cmd = Command::SetBlock(
+            pos.as_abs(), block.id, None, None, Some(Nbt::Compound(block.nbt)));
+        blocks.push_str(&format!("{}\n", cmd)[..]);
+    }
+    assert_eq!(None, layout.error());
+    let mut boot = vec!();
+    layout.write_boot_script(&mut boot, ENTRY_LABEL).unwrap();
+
+    Output {
+        init: utf8(init),
+        destroy: utf8(destroy),
+        blocks: blocks,
+        boot: utf8(boot),
+    }
+}
@@ -5,12 +5,13 @@ use regex::Regex;
 use sbbm_asm::assembler::Assembler;
 use sbbm_asm::commands::{Command, Target, IntoTarget, players};
 use sbbm_asm::fab;
-use sbbm_asm::hw::{Computer, MemoryRegion, MemoryStride};
+use sbbm_asm::hw::{Computer, MemoryRegion, MemoryStride, REGISTER_FILE};
 use sbbm_asm::layout::{Layout, LinearMotion};
-use sbbm_asm::lexer::Lexer;
+use sbbm_asm::lexer::{Arena, Lexer};
 
 use sbbm_asm::nbt::Nbt;
 use sbbm_asm::parser::Parser;
+use sbbm_asm::trace::{self, Trace};
 use sbbm_asm::types::{Extent, Vec3};
 
 use std::env;
@@ -153,18 +154,38 @@ impl Server {
     }
 
     pub fn run_asm(&self, input: &str) {
+        self.run_asm_output(input, false);
+    }
+
+    // Runs the program with tracing enabled and returns the name of each
+    // instruction that ran, in order.
+    pub fn run_asm_traced(&self, input: &str) -> Vec<String> {
+        let output = self.run_asm_output(input, true);
+        output.lines()
+            .filter_map(trace::parse_log_line)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    fn run_asm_output(&self, input: &str, traced: bool) -> String {
         // FIXME: Eliminate all unwrap to prevent poisoning the mutex.
 
         let marker = "6ee5dd4a-ea5c-476d-bcab-4c2a912ce2ed";
-        let (dirty_extent, _) = self.capture_until(|s| s.contains(marker), || {
+        let (dirty_extent, output) = self.capture_until(|s| s.contains(marker), || {
             let mut marked = input.to_string();
             marked.push_str("\n\traw say ");
             marked.push_str(marker);
 
-            let mut parser = Parser::new(Lexer::mem(&marked[..]));
+            let arena = Arena::new();
+            let mut parser = Parser::new(Lexer::mem(&marked[..]), &arena);
+            let stmts = parser.parse_program_spanned();
             let mut assembler = Assembler::new(
-                computer(), parser.parse_program().into_iter());
+                computer(), stmts.iter().map(|s| s.item.clone()));
             assembler.set_track_output(true);
+            if traced {
+                assembler.set_announcements(Trace::instrument(&stmts[..], &marked[..]).1);
+            }
+            self.write(&Command::Raw(format!("gamerule logAdminCommands {}", traced))).unwrap();
 
             let mem_controllers = {
                 let mut c = vec!();
@@ -176,23 +197,19 @@ impl Server {
             let motion = Box::new(LinearMotion::new(ORIGIN));
             let mut layout = Layout::new(motion, assembler.chain(mem_controllers));
 
-            let mut dirty_extent = Extent::Empty;
             for (pos, block) in &mut layout {
-                dirty_extent.add(pos);
                 self.write(&Command::SetBlock(
                     pos.as_abs(), block.id, None, None,
                     Some(Nbt::Compound(block.nbt)))).unwrap();
             }
 
             if let Some(Extent::MinMax(min, max)) = layout.get_power_extent("main") {
-                dirty_extent.add(min);
-                dirty_extent.add(max);
                 self.write(&Command::Fill(
                     min.as_abs(), max.as_abs(), "minecraft:redstone_block".to_string(),
                     None, None, None)).unwrap();
             }
 
-            dirty_extent
+            layout.extent()
         });
 
         self.capture(|| {
@@ -202,6 +219,8 @@ impl Server {
                     None, None, None)).unwrap();
             }
         });
+
+        output
     }
 }
 
@@ -221,7 +240,8 @@ fn init_computer() {
                     origin: Vec3::new(ORIGIN.x - 1, ORIGIN.y, ORIGIN.z),
                     growth: Vec3::new(-1, 1, 1),
                     stride: MemoryStride::XY(8, 8),
-                }]
+                }],
+            temps: REGISTER_FILE.temps,
         }))
     }
 
@@ -231,6 +251,7 @@ fn init_computer() {
         .open(input_path())
         .unwrap();
     computer().write_init_script(&mut f).unwrap();
+    trace::write_init_script(&mut f).unwrap();
 }
 
 fn destroy_computer() {
@@ -241,4 +262,5 @@ fn destroy_computer() {
         .open(input_path())
         .unwrap();
     computer().write_destroy_script(&mut f).unwrap();
+    trace::write_destroy_script(&mut f).unwrap();
 }
@@ -0,0 +1,85 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Runs the example programs in tests/programs on the command simulator, the
+// same way sbbm-server runs them for the server tests, and checks the
+// registers they leave behind.
+
+extern crate sbbm_asm;
+
+mod programs;
+
+use sbbm_asm::layout::{BoundedMotion, LayoutMotion};
+use sbbm_asm::sim::World;
+use sbbm_asm::types::Vec3;
+
+// Far more ticks than any of the example programs need.
+const MAX_TICKS: u64 = 10000;
+
+fn run(src: &str, motion: Box<LayoutMotion>) -> World {
+    let output = programs::assemble(src, motion);
+    let mut world = World::new();
+    for line in output.init.lines().chain(output.blocks.lines()).chain(output.boot.lines()) {
+        world.run_console(line);
+    }
+    while !world.is_idle() {
+        assert!(world.time() < MAX_TICKS, "the program did not halt");
+        world.tick();
+    }
+    world
+}
+
+fn register(world: &World, reg: &str) -> i32 {
+    let computer = world.entities().iter()
+        .find(|e| e.name() == "computer")
+        .expect("the computer entity is missing");
+    world.score(&computer.uuid[..], reg).expect("the register has no score")
+}
+
+fn check_example_programs(motion: &Fn() -> Box<LayoutMotion>) {
+    // arith.s ends by storing how many players it changed the score of in
+    // r1, and there are no players.
+    let expected = [
+        ("arith.s", vec!(("r0", 0), ("r1", 0))),
+        ("branches.s", vec!(("r0", 2), ("r1", 0))),
+        ("consts.s", vec!(("r0", 32), ("r1", -25), ("r2", 2))),
+        ("macros.s", vec!(("r0", 11), ("r1", 0), ("r2", 0))),
+        ("memory.s", vec!(("r0", 1234), ("r1", 20), ("r2", 1234))),
+    ];
+    let programs = programs::programs();
+    assert_eq!(expected.len(), programs.len());
+
+    for (&(ref path, ref src), &(name, ref regs)) in programs.iter().zip(expected.iter()) {
+        assert_eq!(name, path.file_name().unwrap().to_str().unwrap());
+        let world = run(&src[..], motion());
+        for &(reg, value) in regs.iter() {
+            assert!(register(&world, reg) == value, "{}: expected {} to be {}, but it is {}",
+                    name, reg, value, register(&world, reg));
+        }
+    }
+}
+
+#[test]
+fn test_example_programs() {
+    check_example_programs(&programs::packed);
+}
+
+#[test]
+fn test_broken_lines() {
+    // Bounds only one power position deep, so that almost every line
+    // continues across tracks, mostly from inside a segment.
+    let shallow = || {
+        Box::new(BoundedMotion::new(programs::ORIGIN, Vec3::new(30, 30, 1))) as Box<LayoutMotion>
+    };
+    check_example_programs(&shallow);
+}
+
+#[test]
+fn test_response_lines() {
+    // The line the server tests read registers back with.
+    let mut world = run("_start:\nmov r0, #-42\nhalt\n", programs::packed());
+    let uuid = world.entities().iter().find(|e| e.name() == "computer").unwrap().uuid.clone();
+    world.take_log();
+    assert_eq!(1, world.run_console("scoreboard players add @e[name=computer] r0 0"));
+    assert_eq!(vec!(format!("Set score of r0 for player {} to -42", uuid)), world.take_log());
+}
@@ -0,0 +1,84 @@
+// Copyright 2015, Christopher Chambers
+// Distributed under the GNU GPL v3. See COPYING for details.
+
+// Assembles each program in tests/programs and compares everything written
+// for it with the checked-in snapshot next to it (foo.s has foo.snap).  Run
+// with SBBM_BLESS=1 to write the snapshots of new programs, or to accept a
+// change in the generated code.
+
+extern crate sbbm_asm;
+
+mod programs;
+
+use std::cmp;
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+
+static BLESS_VAR: &'static str = "SBBM_BLESS";
+
+// Describes the first few lines that differ, so that a failure shows what
+// changed without dumping both outputs.
+fn describe_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    let mut shown = 0;
+    for i in 0..cmp::max(expected.len(), actual.len()) {
+        let e = expected.get(i).map(|l| *l);
+        let a = actual.get(i).map(|l| *l);
+        if e == a {
+            continue;
+        }
+        if shown == 5 {
+            out.push_str("...\n");
+            break;
+        }
+        out.push_str(&format!("line {}:\n- {}\n+ {}\n",
+                              i + 1, e.unwrap_or("<none>"), a.unwrap_or("<none>"))[..]);
+        shown += 1;
+    }
+    if expected.len() != actual.len() {
+        out.push_str(&format!("expected {} lines, got {}\n", expected.len(), actual.len())[..]);
+    }
+    out
+}
+
+#[test]
+fn test_snapshots() {
+    let bless = env::var(BLESS_VAR).map(|v| v == "1").unwrap_or(false);
+    let mut failures = vec!();
+
+    for &(ref path, ref src) in programs::programs().iter() {
+        let actual = programs::assemble(&src[..], programs::packed()).to_snapshot();
+        let snap_path = path.with_extension("snap");
+        let expected = File::open(&snap_path).ok().map(|mut f| {
+            let mut expected = String::new();
+            f.read_to_string(&mut expected).unwrap();
+            expected
+        });
+
+        if bless {
+            if expected.as_ref() != Some(&actual) {
+                let mut f = File::create(&snap_path).unwrap();
+                f.write_all(actual.as_bytes()).unwrap();
+            }
+            continue;
+        }
+
+        match expected {
+            Some(ref expected) if *expected == actual => { }
+            Some(ref expected) => {
+                failures.push(format!("{} differs from its snapshot:\n{}",
+                                      path.display(), describe_diff(expected, &actual[..])));
+            }
+            None => {
+                failures.push(format!("{} has no snapshot", path.display()));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(),
+            "{}\nrun the tests with {}=1 to accept the new output",
+            failures.connect("\n"), BLESS_VAR);
+}